@@ -0,0 +1,48 @@
+//! Measures how fast output is captured from a command printing as fast as it
+//! can, at several read buffer sizes.
+//!
+//! ```sh
+//! cargo bench --bench output
+//! ENSEMBLER_BENCH_BYTES=1073741824 cargo bench --bench output # 1 GiB
+//! ```
+
+use ensembler::CmdLineRunner;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BYTES: u64 = 16 * 1024 * 1024;
+const BUFFER_SIZES: [usize; 4] = [8 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
+const RUNS: u32 = 3;
+
+#[tokio::main]
+async fn main() -> ensembler::Result<()> {
+    if cfg!(not(unix)) {
+        eprintln!("skipped: needs yes and head");
+        return Ok(());
+    }
+    let bytes = std::env::var("ENSEMBLER_BENCH_BYTES")
+        .ok()
+        .and_then(|b| b.parse().ok())
+        .unwrap_or(DEFAULT_BYTES);
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    println!("capturing {mib:.0} MiB of `yes` output, best of {RUNS}");
+    for size in BUFFER_SIZES {
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            let result = CmdLineRunner::new("sh")
+                .args(["-c", &format!("yes | head -c {bytes}")])
+                .read_buffer_size(size)
+                .execute()
+                .await?;
+            best = best.min(start.elapsed());
+            assert_eq!(result.stdout.len() as u64, bytes);
+        }
+        println!(
+            "{:>5} KiB buffer: {:>8.2?} ({:.0} MiB/s)",
+            size / 1024,
+            best,
+            mib / best.as_secs_f64()
+        );
+    }
+    Ok(())
+}
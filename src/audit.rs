@@ -0,0 +1,316 @@
+//! A tamper-evident, append-only log of every command the process runs.
+//!
+//! Once [`install`]ed, the log can't be replaced or turned off, and every
+//! [`CmdLineRunner::execute`](crate::CmdLineRunner::execute) and
+//! [`exec`](crate::CmdLineRunner::exec) in the process writes to it; there
+//! is no per-runner opt-out. A command is only started after its
+//! [`Start`](AuditEvent::Start) record is written, so if the log can't be
+//! written the command fails with [`Error::Io`](crate::Error::Io) instead of
+//! running unaudited.
+//!
+//! Each line is a JSON [`AuditRecord`] holding the SHA-256 hash of the
+//! previous one, so editing, removing or reordering records before the last
+//! one breaks the chain, which [`verify`] detects. The chain has no key, so
+//! anyone who can write the file can still drop records from the end or
+//! rewrite the whole chain. To catch that, store the [`AuditHead`] that
+//! [`verify`] returns somewhere the log's writers can't change, and check
+//! the log later with [`verify_pinned`]. Args are recorded with redactions
+//! applied. Only one process should write to a log at a time.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use ensembler::{audit, CmdLineRunner};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> ensembler::Result<()> {
+//! audit::install("/var/log/mytool/audit.jsonl")?;
+//!
+//! CmdLineRunner::new("deploy").arg("prod").execute().await?;
+//!
+//! let head = audit::verify("/var/log/mytool/audit.jsonl")?;
+//! // later, with `head` kept out of the log writer's reach
+//! audit::verify_pinned("/var/log/mytool/audit.jsonl", &head)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::DryRun;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+static LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The position of the record in the log, starting at 0.
+    pub seq: u64,
+    /// The hash of the previous record, empty for the first.
+    pub prev: String,
+    /// When the record was written.
+    #[serde(with = "crate::history::millis_since_epoch")]
+    pub time: SystemTime,
+    /// What happened.
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    /// The hex SHA-256 of this record serialized with an empty `hash`.
+    pub hash: String,
+}
+
+/// The event an [`AuditRecord`] describes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum AuditEvent {
+    /// A command is about to start.
+    Start {
+        /// The program and args, redacted.
+        argv: Vec<String>,
+        /// The user running this process.
+        user: String,
+        /// The command's working directory.
+        cwd: PathBuf,
+    },
+    /// The command started by the [`Start`](Self::Start) record with
+    /// sequence number `start` finished.
+    Finish {
+        /// The `seq` of the matching start record.
+        start: u64,
+        /// The exit code, or `None` if it was killed by a signal or didn't
+        /// run.
+        exit_code: Option<i32>,
+        /// The error returned instead of a result, redacted.
+        error: Option<String>,
+    },
+    /// This process is being replaced with a command by
+    /// [`exec`](crate::CmdLineRunner::exec).
+    Exec {
+        /// The program and args, redacted.
+        argv: Vec<String>,
+        /// The user running this process.
+        user: String,
+        /// The command's working directory.
+        cwd: PathBuf,
+    },
+}
+
+/// The end of a verified log, returned by [`verify`] to check later
+/// versions of the log against with [`verify_pinned`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct AuditHead {
+    /// The number of records in the log.
+    pub records: u64,
+    /// The hash of the last record, empty if there are none.
+    pub hash: String,
+}
+
+impl AuditHead {
+    /// The head of a log whose last record is `last`.
+    fn ending_with(last: Option<AuditRecord>) -> Self {
+        last.map(|last| Self {
+            records: last.seq + 1,
+            hash: last.hash,
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl AuditRecord {
+    fn compute_hash(&self) -> String {
+        let mut unhashed = self.clone();
+        unhashed.hash.clear();
+        // serializing a plain struct can't fail
+        let json = serde_json::to_vec(&unhashed).unwrap_or_default();
+        Sha256::digest(json)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    seq: u64,
+    prev: String,
+}
+
+/// Starts auditing every command run by this process to the log at `path`,
+/// continuing an existing log.
+///
+/// Fails if a log is already installed, or if the existing log doesn't pass
+/// [`verify`].
+pub fn install(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let (seq, prev) = match read(path)? {
+        Some(last) => (last.seq + 1, last.hash),
+        None => (0, String::new()),
+    };
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let log = AuditLog {
+        state: Mutex::new(State { file, seq, prev }),
+    };
+    LOG.set(log).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "an audit log is already installed",
+        )
+    })
+}
+
+/// Checks that the log at `path` is an unbroken hash chain and returns its
+/// head.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] naming the first line that was
+/// changed, removed or reordered. Records removed from the end, or a chain
+/// rewritten from the start, aren't detected; see [`verify_pinned`].
+pub fn verify(path: impl AsRef<Path>) -> io::Result<AuditHead> {
+    Ok(AuditHead::ending_with(read(path.as_ref())?))
+}
+
+/// Like [`verify`], but also fails with [`io::ErrorKind::InvalidData`]
+/// unless the log still holds the record `pinned` ends with, as returned by
+/// an earlier [`verify`]. This catches records removed from the end and
+/// chains rewritten since, as long as `pinned` was stored where the log's
+/// writers can't change it.
+pub fn verify_pinned(path: impl AsRef<Path>, pinned: &AuditHead) -> io::Result<AuditHead> {
+    let path = path.as_ref();
+    let mut found = pinned.records == 0;
+    let last = read_with(path, |record| {
+        found |= record.seq + 1 == pinned.records && record.hash == pinned.hash;
+    })?;
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: record {} doesn't match the pinned head",
+                path.display(),
+                pinned.records
+            ),
+        ));
+    }
+    Ok(AuditHead::ending_with(last))
+}
+
+/// Checks every command started in the verified log at `path` against the
+/// current environment without running anything.
+///
+/// The log doesn't record env vars, so only the program and working
+/// directory are checked.
+pub fn dry_run(path: impl AsRef<Path>) -> io::Result<Vec<DryRun>> {
+    let mut runs = vec![];
+    read_with(path.as_ref(), |record| match &record.event {
+        AuditEvent::Start { argv, cwd, .. } | AuditEvent::Exec { argv, cwd, .. } => {
+            if let Some((program, args)) = argv.split_first() {
+                runs.push(DryRun::new(program, args, [], Some(cwd.clone())));
+            }
+        }
+        AuditEvent::Finish { .. } => {}
+    })?;
+    Ok(runs)
+}
+
+/// Verifies the log at `path`, returning its last record.
+fn read(path: &Path) -> io::Result<Option<AuditRecord>> {
+    read_with(path, |_| {})
+}
+
+/// Verifies the log at `path`, passing each record to `f`, and returns the
+/// last one.
+fn read_with(path: &Path, mut f: impl FnMut(&AuditRecord)) -> io::Result<Option<AuditRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut last: Option<AuditRecord> = None;
+    for (i, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} line {}: {msg}", path.display(), i + 1),
+            )
+        };
+        let record: AuditRecord =
+            serde_json::from_str(&line).map_err(|e| invalid(&e.to_string()))?;
+        let (seq, prev) = match &last {
+            Some(last) => (last.seq + 1, last.hash.as_str()),
+            None => (0, ""),
+        };
+        if record.seq != seq || record.prev != prev {
+            return Err(invalid("record is out of sequence"));
+        }
+        if record.hash != record.compute_hash() {
+            return Err(invalid("record hash doesn't match its contents"));
+        }
+        f(&record);
+        last = Some(record);
+    }
+    Ok(last)
+}
+
+/// Returns the installed log, if any.
+pub(crate) fn installed() -> Option<&'static AuditLog> {
+    LOG.get()
+}
+
+impl AuditLog {
+    /// Like [`write`](Self::write), on a blocking thread so syncing the file
+    /// doesn't stall the runtime.
+    pub(crate) async fn write_async(&'static self, event: AuditEvent) -> io::Result<u64> {
+        tokio::task::spawn_blocking(move || self.write(event))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Appends a record for `event` and returns its sequence number.
+    pub(crate) fn write(&self, event: AuditEvent) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut record = AuditRecord {
+            seq: state.seq,
+            prev: state.prev.clone(),
+            time: SystemTime::now(),
+            event,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        let mut line = serde_json::to_vec(&record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        state.file.write_all(&line)?;
+        state.file.sync_data()?;
+        state.seq += 1;
+        state.prev = record.hash;
+        Ok(record.seq)
+    }
+}
+
+/// Returns the name of the user running this process.
+pub(crate) fn current_user() -> String {
+    #[cfg(unix)]
+    {
+        let uid = nix::unistd::geteuid();
+        match nix::unistd::User::from_uid(uid) {
+            Ok(Some(user)) => user.name,
+            _ => uid.to_string(),
+        }
+    }
+    #[cfg(windows)]
+    std::env::var("USERNAME").unwrap_or_default()
+}
@@ -0,0 +1,55 @@
+//! Grouping command output in Azure Pipelines logs.
+
+use crate::reporter::{ProgressReporter, ProgressState};
+use crate::GithubGroups;
+
+/// A [`ProgressReporter`] that wraps each command's output in a collapsible
+/// `##[group]` in Azure Pipelines logs, titled with the command line, in
+/// place of interactive progress rendering.
+///
+/// Works like [`GithubGroups`]: groups of commands that run at the same time
+/// are printed whole so they never interleave.
+///
+/// Attach one with [`CmdLineRunner::reporter`](crate::CmdLineRunner::reporter),
+/// or use [`Defaults::ci_groups`](crate::Defaults::ci_groups) to attach one
+/// to every command when running in Azure Pipelines.
+#[derive(Debug)]
+pub struct AzureGroups(GithubGroups);
+
+impl AzureGroups {
+    /// Creates a reporter for one command.
+    pub fn new() -> Self {
+        Self(GithubGroups::with_markers("##[group]", "##[endgroup]"))
+    }
+}
+
+impl Default for AzureGroups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for AzureGroups {
+    fn set_status(&self, status: ProgressState) {
+        self.0.set_status(status);
+    }
+
+    fn prop(&self, key: &str, value: &str) {
+        self.0.prop(key, value);
+    }
+
+    fn println(&self, line: &str) {
+        self.0.println(line);
+    }
+}
+
+/// Renders a `task.logissue` logging command, which Azure Pipelines shows as
+/// an error on the run's summary.
+pub(crate) fn error_issue(title: &str, message: &str) -> String {
+    let message = format!("{title}\n{message}");
+    let message = message
+        .replace('%', "%AZP25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    format!("##vso[task.logissue type=error]{message}")
+}
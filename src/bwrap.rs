@@ -0,0 +1,142 @@
+//! Sandboxing commands with [bubblewrap](https://github.com/containers/bubblewrap).
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// A bubblewrap (`bwrap`) sandbox for a command (Linux only).
+///
+/// bwrap builds the command a private mount namespace from the binds listed
+/// here and needs no privileges, so it works for non-root users where
+/// [`chroot`](crate::CmdLineRunner::chroot) and Landlock don't. Nothing from
+/// the host is visible unless bound; [`system`](Self::system) binds the usual
+/// read-only system directories. Set with
+/// [`CmdLineRunner::bwrap`](crate::CmdLineRunner::bwrap).
+///
+/// The sandbox always gets fresh `/proc` and `/dev`, and is killed if this
+/// process dies.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{Bwrap, CmdLineRunner};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let sandbox = Bwrap::new()
+///     .system()
+///     .bind("/src/project")
+///     .tmpfs_home()
+///     .no_network();
+///
+/// CmdLineRunner::new("make")
+///     .current_dir("/src/project")
+///     .bwrap(sandbox)
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Bwrap {
+    args: Vec<OsString>,
+    tmpfs_home: bool,
+}
+
+impl Bwrap {
+    /// Creates a sandbox with an empty root.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds `/usr`, `/bin`, `/sbin`, `/lib`, `/lib64` and `/etc` read-only,
+    /// skipping any the host doesn't have.
+    pub fn system(mut self) -> Self {
+        for dir in ["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"] {
+            self.args
+                .extend(["--ro-bind-try".into(), dir.into(), dir.into()]);
+        }
+        self
+    }
+
+    /// Makes `path` visible read-only at the same place in the sandbox.
+    pub fn ro_bind(self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        self.ro_bind_to(path, path)
+    }
+
+    /// Makes the host's `src` visible read-only at `dest` in the sandbox.
+    pub fn ro_bind_to(self, src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Self {
+        self.with_paths("--ro-bind", src.as_ref(), dest.as_ref())
+    }
+
+    /// Makes `path` writable at the same place in the sandbox.
+    pub fn bind(self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        self.bind_to(path, path)
+    }
+
+    /// Makes the host's `src` writable at `dest` in the sandbox.
+    pub fn bind_to(self, src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Self {
+        self.with_paths("--bind", src.as_ref(), dest.as_ref())
+    }
+
+    /// Mounts an empty, writable tmpfs at `path`.
+    pub fn tmpfs(mut self, path: impl AsRef<Path>) -> Self {
+        self.args
+            .extend(["--tmpfs".into(), path.as_ref().as_os_str().to_os_string()]);
+        self
+    }
+
+    /// Mounts an empty tmpfs at the command's `HOME`, so it can't read or
+    /// change the real one.
+    pub fn tmpfs_home(mut self) -> Self {
+        self.tmpfs_home = true;
+        self
+    }
+
+    /// Runs the command in its own network namespace with no network access.
+    pub fn no_network(mut self) -> Self {
+        self.args.push("--unshare-net".into());
+        self
+    }
+
+    /// Passes other bwrap options through, e.g. `["--unshare-pid"]`.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    fn with_paths(mut self, option: &str, src: &Path, dest: &Path) -> Self {
+        self.args.extend([
+            option.into(),
+            src.as_os_str().to_os_string(),
+            dest.as_os_str().to_os_string(),
+        ]);
+        self
+    }
+
+    /// Returns the bwrap arguments that come before the command, for a
+    /// command whose home is `home` and which starts in `cwd`.
+    pub(crate) fn command_args(&self, home: Option<&Path>, cwd: Option<&Path>) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            "--die-with-parent".into(),
+            "--proc".into(),
+            "/proc".into(),
+            "--dev".into(),
+            "/dev".into(),
+        ];
+        args.extend(self.args.iter().cloned());
+        if let Some(home) = home.filter(|_| self.tmpfs_home) {
+            args.extend(["--tmpfs".into(), PathBuf::from(home).into_os_string()]);
+        }
+        if let Some(cwd) = cwd {
+            args.extend(["--chdir".into(), cwd.as_os_str().to_os_string()]);
+        }
+        args.push("--".into());
+        args
+    }
+}
@@ -0,0 +1,237 @@
+//! Memoizing command results on disk.
+
+use crate::{CmdResult, EnvSnapshot};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Reuses the result of a command that already ran with the same inputs.
+///
+/// A command's key covers its program, args, stdin, working directory, the
+/// env vars set on the runner, the environment it replaces the inherited
+/// one with (see [`env_clear`](crate::CmdLineRunner::env_clear)), the
+/// inherited vars named with [`env`](Self::env), and the contents of the
+/// files named with [`input`](Self::input). If a successful run with the same key is stored
+/// under the cache directory, [`execute`](crate::CmdLineRunner::execute)
+/// returns it without starting the command. Set with
+/// [`CmdLineRunner::cache`](crate::CmdLineRunner::cache).
+///
+/// Only runs that `execute` returns `Ok` for are stored, with redactions
+/// already applied. Outputs the command writes to files are not tracked, so
+/// only cache commands whose result is their output, or whose output files
+/// are checked separately.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{Cache, CmdLineRunner};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let result = CmdLineRunner::new("tsc")
+///     .args(["--noEmit", "-p", "."])
+///     .cache(
+///         Cache::new("target/ensembler-cache")
+///             .input("src")
+///             .input("tsconfig.json")
+///             .env(["NODE_ENV"]),
+///     )
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+    inputs: Vec<PathBuf>,
+    env: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    stdout: String,
+    stderr: String,
+    combined_output: String,
+    exit_code: i32,
+}
+
+impl Cache {
+    /// Creates a cache that stores results in `dir`.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            inputs: vec![],
+            env: vec![],
+        }
+    }
+
+    /// Adds a file, or a directory and everything in it, whose contents are
+    /// part of the key. Relative paths are resolved from the command's
+    /// working directory. Symlinks are not followed; the path they point to
+    /// is part of the key instead.
+    pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+        self.inputs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds inherited env vars whose values are part of the key.
+    pub fn env<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.env.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Computes the key of a command from its description, hashing the
+    /// declared inputs and looking up the declared env vars with `var`.
+    pub(crate) fn key<'a>(
+        &self,
+        command: impl IntoIterator<Item = &'a OsStr>,
+        stdin: Option<&str>,
+        cwd: Option<&Path>,
+        env_base: Option<&EnvSnapshot>,
+        envs: impl IntoIterator<Item = (&'a OsString, &'a OsString)>,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> io::Result<String> {
+        let cwd = match cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?,
+        };
+        let mut hasher = Sha256::new();
+        let mut field = |tag: &str, value: &[u8]| {
+            hasher.update(tag.as_bytes());
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        };
+        for word in command {
+            field("arg", word.as_encoded_bytes());
+        }
+        if let Some(stdin) = stdin {
+            field("stdin", stdin.as_bytes());
+        }
+        field("cwd", cwd.as_os_str().as_encoded_bytes());
+        if let Some(base) = env_base {
+            let mut vars = base.iter().collect::<Vec<_>>();
+            vars.sort();
+            field("base", b"");
+            for (key, value) in vars {
+                field("env", key.as_encoded_bytes());
+                field("val", value.as_encoded_bytes());
+            }
+        }
+        for (key, value) in envs {
+            field("env", key.as_encoded_bytes());
+            field("val", value.as_encoded_bytes());
+        }
+        for key in &self.env {
+            field("env", key.as_bytes());
+            match var(key) {
+                Some(value) => field("val", value.as_encoded_bytes()),
+                None => field("unset", b""),
+            }
+        }
+        for input in &self.inputs {
+            let path = cwd.join(input);
+            field("input", path.as_os_str().as_encoded_bytes());
+            hash_path(&path, &mut field)?;
+        }
+        let digest = hasher.finalize();
+        Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// Returns the stored result for `key`, if any.
+    pub(crate) fn get(&self, key: &str) -> Option<CmdResult> {
+        let entry = std::fs::read(self.path(key)).ok()?;
+        let entry: Entry = match serde_json::from_slice(&entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Ignoring invalid cache entry {key}: {e}");
+                return None;
+            }
+        };
+        Some(CmdResult {
+            stdout: entry.stdout,
+            stderr: entry.stderr,
+            combined_output: entry.combined_output,
+            status: crate::executor::exit_status(entry.exit_code),
+            peak_memory: None,
+            up_to_date: false,
+            started_at: None,
+            finished_at: None,
+            duration: Duration::ZERO,
+            first_output: None,
+            longest_stall: Duration::ZERO,
+            resource_usage: None,
+            labels: vec![],
+        })
+    }
+
+    /// Stores `result` under `key`.
+    pub(crate) fn put(&self, key: &str, result: &CmdResult) -> io::Result<()> {
+        let entry = Entry {
+            stdout: result.stdout.clone(),
+            stderr: result.stderr.clone(),
+            combined_output: result.combined_output.clone(),
+            exit_code: result.status.code().unwrap_or(1),
+        };
+        let json = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        std::fs::create_dir_all(&self.dir)?;
+        // written to a temp file first so readers never see a partial entry,
+        // named for this write so concurrent writes of a key don't collide
+        let path = self.path(key);
+        let tmp = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+/// Hashes a file's contents, a symlink's target path, or the names and
+/// contents of everything in a directory in sorted order. A missing path
+/// hashes as missing, so creating it changes the key.
+pub(crate) fn hash_path(path: &Path, field: &mut impl FnMut(&str, &[u8])) -> io::Result<()> {
+    // symlinks aren't followed, so loops and links to directories above
+    // don't recurse forever
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            field("missing", b"");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        field("link", target.as_os_str().as_encoded_bytes());
+    } else if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        field("dir", b"");
+        for entry in entries {
+            let name = entry.file_name().unwrap_or_default();
+            field("name", name.as_encoded_bytes());
+            hash_path(&entry, field)?;
+        }
+    } else {
+        field("file", &std::fs::read(path)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! Transient cgroup v2 groups that confine a command and everything it starts.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Limits applied to a command through a transient cgroup (Linux, cgroup v2).
+///
+/// Unlike rlimits, which apply to each process separately, a cgroup covers
+/// the command and every process it starts. Set with
+/// [`CmdLineRunner::cgroup`](crate::CmdLineRunner::cgroup).
+///
+/// The cgroup is created under `parent` and removed when the command exits.
+/// The parent must be writable and have the needed controllers available.
+/// It also can't have processes of its own, since cgroup v2 only lets an
+/// empty cgroup hand controllers to its children. Under systemd, a delegated
+/// unit (`systemd-run --user -p Delegate=yes`) whose processes have moved
+/// into a child cgroup provides one.
+///
+/// Without a parent, the cgroup is created under the cgroup of the current
+/// process. That only works without limits, as the current process is in
+/// it; it then still reports peak memory if the memory controller is enabled
+/// there. Hosts with cgroup v1 or a hybrid hierarchy aren't supported.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{Cgroup, CmdLineRunner};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let limits = Cgroup::new()
+///     .parent("/sys/fs/cgroup/user.slice/user-1000.slice/builds.service")
+///     .memory_max(4 << 30)
+///     .cpu_max(2.0);
+/// let result = CmdLineRunner::new("make")
+///     .arg("-j8")
+///     .cgroup(limits)
+///     .execute()
+///     .await?;
+///
+/// println!("peak memory: {:?}", result.peak_memory);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Cgroup {
+    parent: Option<PathBuf>,
+    memory_max: Option<u64>,
+    cpu_max: Option<f64>,
+    pids_max: Option<u64>,
+}
+
+impl Cgroup {
+    /// Creates a cgroup with no limits, which still reports peak memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the cgroup under `parent`, e.g. `/sys/fs/cgroup/builds`, which
+    /// must have no processes of its own if limits are set.
+    pub fn parent(mut self, parent: impl AsRef<Path>) -> Self {
+        self.parent = Some(parent.as_ref().to_path_buf());
+        self
+    }
+
+    /// Limits the memory of all processes in the cgroup to `bytes`
+    /// (`memory.max`). The kernel's OOM killer stops them past the limit.
+    pub fn memory_max(mut self, bytes: u64) -> Self {
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    /// Limits the cgroup to `cpus` CPUs worth of time (`cpu.max`), e.g.
+    /// `0.5` for half of one CPU.
+    pub fn cpu_max(mut self, cpus: f64) -> Self {
+        self.cpu_max = Some(cpus);
+        self
+    }
+
+    /// Limits the number of processes and threads in the cgroup (`pids.max`).
+    pub fn pids_max(mut self, n: u64) -> Self {
+        self.pids_max = Some(n);
+        self
+    }
+
+    /// Creates the cgroup and writes its limits.
+    pub(crate) fn create(&self) -> io::Result<TransientCgroup> {
+        let parent = match &self.parent {
+            Some(parent) => parent.clone(),
+            None => current_cgroup()?,
+        };
+        if !parent.join("cgroup.controllers").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} is not a cgroup v2 directory", parent.display()),
+            ));
+        }
+        let controllers = [
+            ("memory", self.memory_max.is_some()),
+            ("cpu", self.cpu_max.is_some()),
+            ("pids", self.pids_max.is_some()),
+        ];
+        let available = std::fs::read_to_string(parent.join("cgroup.controllers"))
+            .map_err(|e| context(e, "read", &parent.join("cgroup.controllers")))?;
+        let mut enable = vec![];
+        for (name, _) in controllers.iter().filter(|(_, used)| *used) {
+            if !available.split_whitespace().any(|c| c == *name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "the {name} controller isn't available in {}",
+                        parent.display()
+                    ),
+                ));
+            }
+            enable.push(format!("+{name}"));
+        }
+        if !enable.is_empty() {
+            // already enabled controllers are accepted again, so this only
+            // fails if the parent can't delegate them
+            let path = parent.join("cgroup.subtree_control");
+            if let Err(e) = std::fs::write(&path, enable.join(" ")) {
+                if e.raw_os_error() == Some(libc::EBUSY) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        format!(
+                            "can't enable controllers in {}, which has processes of its \
+                             own; set a parent cgroup without any with `Cgroup::parent`",
+                            parent.display()
+                        ),
+                    ));
+                }
+                return Err(context(
+                    e,
+                    &format!("write {:?} to", enable.join(" ")),
+                    &path,
+                ));
+            }
+        }
+        let name = format!(
+            "ensembler-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = parent.join(name);
+        std::fs::create_dir(&path).map_err(|e| context(e, "create", &path))?;
+        let cgroup = TransientCgroup { path };
+        if let Some(bytes) = self.memory_max {
+            write(&cgroup.path, "memory.max", &bytes.to_string())?;
+        }
+        if let Some(cpus) = self.cpu_max {
+            const PERIOD: u64 = 100_000;
+            let quota = ((cpus * PERIOD as f64) as u64).max(1_000);
+            write(&cgroup.path, "cpu.max", &format!("{quota} {PERIOD}"))?;
+        }
+        if let Some(n) = self.pids_max {
+            write(&cgroup.path, "pids.max", &n.to_string())?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// A cgroup created for one command, removed when dropped.
+#[derive(Debug)]
+pub(crate) struct TransientCgroup {
+    path: PathBuf,
+}
+
+impl TransientCgroup {
+    /// The `cgroup.procs` file a process writes `0` to in order to join.
+    pub(crate) fn procs_path(&self) -> CString {
+        let path = self.path.join("cgroup.procs");
+        // cgroup paths come from /proc or the caller and never contain NUL
+        CString::new(path.as_os_str().as_bytes()).unwrap_or_default()
+    }
+
+    /// Kills anything left in the cgroup, removes it, and returns its peak
+    /// memory usage if the kernel reports one.
+    pub(crate) async fn remove(self) -> Option<u64> {
+        let peak = std::fs::read_to_string(self.path.join("memory.peak"))
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok());
+        let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+        // processes take a moment to leave after being killed
+        for _ in 0..50 {
+            if std::fs::remove_dir(&self.path).is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        peak
+    }
+}
+
+impl Drop for TransientCgroup {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+            if let Err(e) = std::fs::remove_dir(&self.path) {
+                debug!("Failed to remove cgroup {}: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Returns the cgroup v2 directory of the current process.
+fn current_cgroup() -> io::Result<PathBuf> {
+    // on cgroup v1 and hybrid hosts /sys/fs/cgroup is a tmpfs of v1
+    // hierarchies, and the v2 one (if any) has no controllers
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cgroup v2 isn't mounted at {CGROUP_ROOT}; cgroup v1 and hybrid hosts aren't supported"),
+        ));
+    }
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+    let relative = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "cgroup v2 is not available"))?;
+    Ok(Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/')))
+}
+
+fn write(dir: &Path, file: &str, contents: &str) -> io::Result<()> {
+    let path = dir.join(file);
+    std::fs::write(&path, contents)
+        .map_err(|e| context(e, &format!("write {contents:?} to"), &path))
+}
+
+fn context(e: io::Error, action: &str, path: &Path) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("failed to {action} {}: {e}", path.display()),
+    )
+}
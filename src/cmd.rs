@@ -1,32 +1,65 @@
 use crate::Result;
 use aho_corasick::AhoCorasick;
 use std::collections::HashSet;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Display, Formatter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::{
     io::BufReader,
-    process::Command,
+    process::{Child, Command},
     select,
-    sync::{oneshot, Mutex},
 };
 use tokio_util::sync::CancellationToken;
 
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 use std::sync::LazyLock as Lazy;
 
+use crate::encoding::OutputEncoding;
+use crate::env::{self, EnvSnapshot};
+use crate::executor::{BoxFuture, Invocation, Process};
+#[cfg(target_os = "linux")]
+use crate::pre_exec::IoPriority;
+#[cfg(unix)]
+use crate::pre_exec::PreExec;
+use crate::reporter::{ProgressReporter, ProgressState};
+use crate::tempfile::TempFile;
 use crate::Error::ScriptFailed;
+use crate::ScriptFailure;
+use crate::Verbosity;
+use crate::{shell_words, which, wsl, Shell};
 #[cfg(feature = "progress")]
 use clx::progress::{self, ProgressJob};
 
+/// Maps a failure to an error of the caller's own, set with
+/// [`CmdLineRunner::map_failure`].
+type FailureMap =
+    dyn Fn(&ScriptFailure) -> Option<Box<dyn std::error::Error + Send + Sync>> + Send + Sync;
+
 /// Holds the Aho-Corasick automaton and replacement strings for redaction.
-struct Redactor {
+#[derive(Debug)]
+pub(crate) struct Redactor {
     automaton: AhoCorasick,
-    replacements: Vec<&'static str>,
+    replacements: Vec<String>,
+}
+
+impl Redactor {
+    pub(crate) fn redact(&self, s: &str) -> String {
+        self.automaton.replace_all(s, &self.replacements)
+    }
+}
+
+/// Converts `s` to a string with `redactor`'s redactions applied, if any.
+fn redact_lossy(redactor: Option<&Redactor>, s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    match redactor {
+        Some(redactor) => redactor.redact(&s),
+        None => s.into_owned(),
+    }
 }
 
 /// A builder for executing external commands with advanced output handling.
@@ -53,130 +86,501 @@ struct Redactor {
 /// }
 /// ```
 pub struct CmdLineRunner {
-    cmd: Command,
     program: String,
-    args: Vec<String>,
+    args: Vec<Arg>,
+    shell_wrap: bool,
+    resolve_program: bool,
+    expand_env: bool,
+    temp_script: bool,
+    log_env_diff: bool,
+    wsl: Option<Option<String>>,
+    env_base: Option<EnvSnapshot>,
+    envs: IndexMap<OsString, OsString>,
+    cwd: Option<PathBuf>,
+    stdin_cfg: Option<Stdio>,
+    stdout_cfg: Option<Stdio>,
+    stderr_cfg: Option<Stdio>,
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    /// The clx job behind `reporter`, if it is one, for what only clx can
+    /// show
     #[cfg(feature = "progress")]
     pr: Option<Arc<ProgressJob>>,
     stdin: Option<String>,
+    /// Whether `stdin` was configured after `stdin_string`, which may leave
+    /// its input without a pipe
+    stdin_replaced: bool,
     redactions: IndexSet<String>,
-    #[cfg(feature = "progress")]
     show_stderr_on_error: bool,
-    #[cfg(feature = "progress")]
     stderr_to_progress: bool,
+    #[cfg(feature = "progress")]
+    auto_progress: bool,
+    #[cfg(feature = "progress")]
+    progress_message: Option<String>,
+    #[cfg(feature = "progress")]
+    progress_parent: Option<Arc<ProgressJob>>,
+    #[cfg(feature = "progress")]
+    progress_tail: usize,
+    collapse_output: bool,
+    progress_parser: Option<crate::ProgressParser>,
+    /// How long the command usually takes, once read from `timings`
+    expected: Option<Duration>,
     cancel: CancellationToken,
     allow_non_zero: bool,
+    success_codes: Vec<i32>,
+    failure_maps: Vec<Box<FailureMap>>,
     timeout: Option<Duration>,
+    temp_files: Vec<TempFile>,
+    creation_flags: u32,
+    encoding: OutputEncoding,
+    read_buffer_size: usize,
+    grace_period: Option<Duration>,
+    #[cfg(windows)]
+    elevated: bool,
+    #[cfg(windows)]
+    cpu_affinity: Option<u64>,
+    #[cfg(unix)]
+    pre_exec: PreExec,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<crate::Cgroup>,
+    #[cfg(target_os = "linux")]
+    sandbox: Option<crate::Sandbox>,
+    #[cfg(target_os = "linux")]
+    bwrap: Option<crate::Bwrap>,
+    #[cfg(target_os = "linux")]
+    systemd_run: Option<crate::SystemdRun>,
+    #[cfg(target_os = "linux")]
+    systemd_unit: Option<crate::systemd::TransientUnit>,
+    #[cfg(unix)]
+    escalation: Option<crate::Escalation>,
+    executor: Option<Arc<dyn crate::Executor>>,
+    wrappers: Vec<Vec<OsString>>,
+    cache: Option<crate::Cache>,
+    freshness: Option<crate::Freshness>,
+    history: Option<crate::History>,
+    junit: Option<crate::JunitReport>,
+    summary: Option<crate::Summary>,
+    run_report: Option<crate::RunReport>,
+    metrics: Option<crate::Metrics>,
+    timings: Option<crate::Timings>,
+    policy: Option<crate::Policy>,
+    style: crate::Style,
+    verbosity: crate::Verbosity,
+    prefix: Option<(String, console::Style)>,
+    labels: Vec<(String, String)>,
+    context: Option<String>,
+    hint: Option<String>,
+    failure_message: Option<crate::FailureMessage>,
+    log_mux: Option<(crate::LogMux, String)>,
+    ci_groups: bool,
+    ci_annotations: bool,
+}
+
+/// Windows process priority class, set with [`CmdLineRunner::priority_class`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityClass {
+    /// `IDLE_PRIORITY_CLASS`: runs only when the system is idle.
+    Idle,
+    /// `BELOW_NORMAL_PRIORITY_CLASS`: suited to background builds and indexing.
+    BelowNormal,
+    /// `NORMAL_PRIORITY_CLASS`.
+    Normal,
+    /// `ABOVE_NORMAL_PRIORITY_CLASS`.
+    AboveNormal,
+    /// `HIGH_PRIORITY_CLASS`.
+    High,
+    /// `REALTIME_PRIORITY_CLASS`. Requires elevation; otherwise Windows
+    /// silently uses `HIGH_PRIORITY_CLASS`.
+    Realtime,
+}
+
+impl PriorityClass {
+    /// Returns the `CreateProcess` creation flag for this class.
+    fn creation_flag(self) -> u32 {
+        match self {
+            PriorityClass::Idle => 0x0000_0040,
+            PriorityClass::BelowNormal => 0x0000_4000,
+            PriorityClass::Normal => 0x0000_0020,
+            PriorityClass::AboveNormal => 0x0000_8000,
+            PriorityClass::High => 0x0000_0080,
+            PriorityClass::Realtime => 0x0000_0100,
+        }
+    }
+}
+
+/// Mask of all priority class creation flags.
+const PRIORITY_CLASS_MASK: u32 = 0x0000_c1e0;
+const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// A single command-line argument.
+///
+/// Raw arguments are appended verbatim on Windows (see [`CmdLineRunner::raw_arg`]).
+#[derive(Debug, Clone)]
+enum Arg {
+    Regular(OsString),
+    Raw(OsString),
+}
+
+impl Arg {
+    fn as_os_str(&self) -> &OsStr {
+        match self {
+            Arg::Regular(s) | Arg::Raw(s) => s,
+        }
+    }
 }
 
 static RUNNING_PIDS: Lazy<std::sync::Mutex<HashSet<u32>>> = Lazy::new(Default::default);
 
+/// The colors [`CmdLineRunner::prefix`] cycles through.
+const PREFIX_COLORS: [console::Color; 6] = [
+    console::Color::Cyan,
+    console::Color::Yellow,
+    console::Color::Green,
+    console::Color::Magenta,
+    console::Color::Blue,
+    console::Color::Red,
+];
+static NEXT_PREFIX_COLOR: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Where [`CmdLineRunner::execute_recorded`] records how a run went.
+struct Recorders {
+    history: Option<crate::History>,
+    junit: Option<crate::JunitReport>,
+    summary: Option<crate::Summary>,
+    run_report: Option<crate::RunReport>,
+    metrics: Option<crate::Metrics>,
+}
+
+impl Recorders {
+    fn is_empty(&self) -> bool {
+        self.history.is_none()
+            && self.junit.is_none()
+            && self.summary.is_none()
+            && self.run_report.is_none()
+            && self.metrics.is_none()
+    }
+}
+
+/// How many lines of output a CI error annotation ends with.
+const ANNOTATION_LINES: usize = 20;
+
 impl CmdLineRunner {
     /// Creates a new command runner for the given program.
     ///
-    /// On Windows, commands are automatically wrapped with `cmd.exe /c`.
+    /// On Windows, the program is resolved through `PATH`, `PATHEXT` and App
+    /// Paths, so `new("npm")` finds `npm.cmd`. Batch files, and programs that
+    /// can't be resolved (like `cmd.exe` builtins), are wrapped with
+    /// `cmd.exe /c`; other executables are spawned directly.
     /// The command is configured with piped stdout/stderr and null stdin by default.
+    /// Any global [`Defaults`](crate::Defaults) are applied to the new runner.
     pub fn new<P: AsRef<OsStr>>(program: P) -> Self {
-        let program = program.as_ref().to_string_lossy().to_string();
-        let cmd = if cfg!(windows) {
-            let mut cmd = Command::new("cmd.exe");
-            cmd.arg("/c").arg(&program);
-            cmd
-        } else {
-            Command::new(&program)
-        };
-        Self::init(cmd, program)
+        Self::init(program.as_ref(), true)
     }
 
     /// Create a runner that invokes `program` directly, bypassing the
     /// Windows auto-wrap in `cmd.exe /c`. Use this when you need precise
     /// control over the command line (e.g. to pair with [`raw_arg`]).
     pub fn new_direct<P: AsRef<OsStr>>(program: P) -> Self {
-        let program = program.as_ref().to_string_lossy().to_string();
-        let cmd = Command::new(&program);
-        Self::init(cmd, program)
+        Self::init(program.as_ref(), false)
+    }
+
+    /// Creates a runner that executes `script` with the platform shell.
+    ///
+    /// On Unix this runs `sh -o errexit -c <script>`; on Windows it runs
+    /// `cmd.exe /d /s /c "<script>"`, passing the script through verbatim so
+    /// its quoting is interpreted by `cmd.exe` only. The shell can be changed
+    /// for every runner with [`Defaults::shell`](crate::Defaults::shell), or
+    /// per command with [`with_shell`](Self::with_shell).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let result = CmdLineRunner::shell("echo hello && echo world")
+    ///     .execute()
+    ///     .await?;
+    ///
+    /// assert_eq!(result.stdout, "hello\nworld\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shell(script: impl AsRef<str>) -> Self {
+        crate::defaults().shell.unwrap_or_default().runner(script)
+    }
+
+    /// Creates a runner that feeds `script` to the default shell on stdin.
+    ///
+    /// Unlike [`shell`](Self::shell), the script isn't passed as an argument,
+    /// so generated scripts of any size run without hitting `ARG_MAX` or
+    /// needing to survive command-line quoting. On Unix this runs
+    /// `sh -o errexit -s`; see [`Shell::stdin_runner`] for other shells.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`](crate::Error::Io) if a temp file is needed
+    /// (for `cmd.exe`) and can't be written.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let script = (1..=3).map(|i| format!("echo step {i}\n")).collect::<String>();
+    /// let result = CmdLineRunner::shell_stdin(script)?.execute().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shell_stdin(script: impl Into<String>) -> Result<Self> {
+        crate::defaults()
+            .shell
+            .unwrap_or_default()
+            .stdin_runner(script)
+    }
+
+    /// Creates a runner that executes `script` with the given [`Shell`].
+    pub fn with_shell(shell: &Shell, script: impl AsRef<str>) -> Self {
+        shell.runner(script)
+    }
+
+    /// Creates a runner that executes `script` with PowerShell.
+    ///
+    /// PowerShell 7 (`pwsh`) is used when it is on `PATH`, falling back to
+    /// Windows PowerShell (`powershell.exe`) on Windows. The script runs with
+    /// `-NoProfile -NonInteractive`, `$ErrorActionPreference = 'Stop'` so
+    /// errors abort the script, and exits with `$LASTEXITCODE` so a failing
+    /// native command is reported with its own exit code.
+    ///
+    /// On Windows the script is passed with `-EncodedCommand` so it reaches
+    /// PowerShell without any command-line quoting.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let result = CmdLineRunner::powershell("Get-ChildItem | Select-Object -First 1")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn powershell(script: impl AsRef<str>) -> Self {
+        Shell::PowerShell.runner(script)
     }
 
-    fn init(mut cmd: Command, program: String) -> Self {
-        cmd.stdin(Stdio::null());
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+    /// Creates a runner by splitting a full command line into program and args.
+    ///
+    /// The string is split with POSIX shell quoting rules (see
+    /// [`shell_words::split`](crate::shell_words::split)) but is not run
+    /// through a shell, so no variable expansion, globbing, or pipes happen.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidCommandLine`](crate::Error::InvalidCommandLine)
+    /// if the string has unbalanced quotes or contains no program.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// CmdLineRunner::parse("docker run -it --rm alpine sh -c 'echo hi'")?
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn parse(command_line: &str) -> Result<Self> {
+        let words = crate::shell_words::split(command_line)?;
+        let Some((program, args)) = words.split_first() else {
+            return Err(crate::Error::InvalidCommandLine(format!(
+                "no program in `{command_line}`"
+            )));
+        };
+        Ok(Self::new(program).args(args))
+    }
 
+    fn init(program: &OsStr, shell_wrap: bool) -> Self {
+        let defaults = crate::defaults();
         Self {
-            cmd,
-            program,
+            program: program.to_string_lossy().to_string(),
             args: vec![],
+            shell_wrap,
+            resolve_program: false,
+            expand_env: false,
+            temp_script: false,
+            log_env_diff: false,
+            wsl: None,
+            env_base: None,
+            envs: defaults.envs,
+            cwd: defaults.cwd,
+            stdin_cfg: None,
+            stdout_cfg: None,
+            stderr_cfg: None,
+            reporter: None,
             #[cfg(feature = "progress")]
             pr: None,
             stdin: None,
-            redactions: Default::default(),
-            #[cfg(feature = "progress")]
+            stdin_replaced: false,
+            redactions: defaults.redactions,
             show_stderr_on_error: true,
-            #[cfg(feature = "progress")]
             stderr_to_progress: false,
+            #[cfg(feature = "progress")]
+            auto_progress: false,
+            #[cfg(feature = "progress")]
+            progress_message: None,
+            #[cfg(feature = "progress")]
+            progress_parent: None,
+            #[cfg(feature = "progress")]
+            progress_tail: 0,
+            collapse_output: false,
+            progress_parser: None,
+            expected: None,
             cancel: CancellationToken::new(),
             allow_non_zero: false,
-            timeout: None,
+            success_codes: vec![],
+            failure_maps: vec![],
+            timeout: defaults.timeout,
+            temp_files: vec![],
+            creation_flags: 0,
+            encoding: OutputEncoding::Auto,
+            read_buffer_size: READ_BUFFER_SIZE,
+            grace_period: None,
+            #[cfg(windows)]
+            elevated: false,
+            #[cfg(windows)]
+            cpu_affinity: None,
+            #[cfg(unix)]
+            pre_exec: PreExec::default(),
+            #[cfg(target_os = "linux")]
+            cgroup: None,
+            #[cfg(target_os = "linux")]
+            sandbox: None,
+            #[cfg(target_os = "linux")]
+            bwrap: None,
+            #[cfg(target_os = "linux")]
+            systemd_run: None,
+            #[cfg(target_os = "linux")]
+            systemd_unit: None,
+            #[cfg(unix)]
+            escalation: None,
+            executor: None,
+            wrappers: defaults.wrappers,
+            cache: None,
+            freshness: None,
+            history: defaults.history,
+            junit: defaults.junit,
+            summary: defaults.summary,
+            run_report: defaults.run_report,
+            metrics: defaults.metrics,
+            timings: defaults.timings,
+            policy: defaults.policy,
+            style: defaults.style,
+            verbosity: defaults.verbosity,
+            prefix: None,
+            labels: vec![],
+            context: None,
+            hint: None,
+            failure_message: defaults.failure_message,
+            log_mux: None,
+            ci_groups: defaults.ci_groups,
+            ci_annotations: defaults.ci_annotations,
         }
     }
 
+    /// Searches `PATH` for an executable named `program`.
+    ///
+    /// On Windows, extensions listed in `PATHEXT` are tried when `program`
+    /// has no extension. Programs containing a path separator are checked
+    /// directly instead of being searched for.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// if let Some(git) = CmdLineRunner::which("git") {
+    ///     println!("git is at {}", git.display());
+    /// }
+    /// ```
+    pub fn which<P: AsRef<OsStr>>(program: P) -> Option<PathBuf> {
+        which::which_in(program.as_ref(), None, None)
+    }
+
     /// Sends a signal to all running child process groups.
     ///
     /// Each child is placed in its own process group at spawn time, so this
     /// kills the entire process tree (not just the direct child).
-    /// This is useful for graceful shutdown scenarios.
+    /// This is useful for graceful shutdown scenarios. Returns the outcome
+    /// for each PID, like the Windows version.
     #[cfg(unix)]
-    pub fn kill_all(signal: nix::sys::signal::Signal) {
+    pub fn kill_all(signal: nix::sys::signal::Signal) -> Vec<(u32, std::io::Result<()>)> {
         let Ok(pids) = RUNNING_PIDS.lock() else {
             debug!("Failed to acquire lock on RUNNING_PIDS");
-            return;
+            return vec![];
         };
-        for pid in pids.iter() {
-            let pgid = nix::unistd::Pid::from_raw(*pid as i32);
-            trace!("{signal}: pgid {pid}");
-            if let Err(e) = nix::sys::signal::killpg(pgid, signal) {
-                debug!("Failed to kill process group {pid}: {e}");
-            }
-        }
+        pids.iter()
+            .map(|pid| {
+                let pgid = nix::unistd::Pid::from_raw(*pid as i32);
+                trace!("{signal}: pgid {pid}");
+                let result = nix::sys::signal::killpg(pgid, signal).map_err(std::io::Error::from);
+                if let Err(e) = &result {
+                    debug!("Failed to kill process group {pid}: {e}");
+                }
+                (*pid, result)
+            })
+            .collect()
     }
 
     /// Terminates all running child processes on Windows.
     ///
-    /// Uses `taskkill /F /T` to forcefully terminate process trees.
+    /// Each child runs in its own Job Object, so terminating the job kills
+    /// the entire process tree. Children that couldn't be assigned to a job
+    /// are killed with `TerminateProcess`. Returns the outcome for each PID,
+    /// like the Unix version; termination is immediate, so there is nothing
+    /// to wait for.
     #[cfg(windows)]
-    pub fn kill_all() {
+    pub fn kill_all() -> Vec<(u32, std::io::Result<()>)> {
         let Ok(pids) = RUNNING_PIDS.lock() else {
             debug!("Failed to acquire lock on RUNNING_PIDS");
-            return;
-        };
-        for pid in pids.iter() {
-            if let Err(e) = Command::new("taskkill")
-                .arg("/F")
-                .arg("/T")
-                .arg("/PID")
-                .arg(pid.to_string())
-                .spawn()
-            {
-                warn!("Failed to kill cmd {pid}: {e}");
-            }
-        }
+            return vec![];
+        };
+        pids.iter()
+            .map(|pid| {
+                let result = crate::job::terminate(*pid);
+                if let Err(e) = &result {
+                    warn!("Failed to kill cmd {pid}: {e}");
+                }
+                (*pid, result)
+            })
+            .collect()
     }
 
     /// Configures stdin handling for the command.
     pub fn stdin<T: Into<Stdio>>(mut self, cfg: T) -> Self {
-        self.cmd.stdin(cfg);
+        self.stdin_cfg = Some(cfg.into());
+        self.stdin_replaced = self.stdin.is_some();
         self
     }
 
     /// Configures stdout handling for the command.
     pub fn stdout<T: Into<Stdio>>(mut self, cfg: T) -> Self {
-        self.cmd.stdout(cfg);
+        self.stdout_cfg = Some(cfg.into());
         self
     }
 
     /// Configures stderr handling for the command.
     pub fn stderr<T: Into<Stdio>>(mut self, cfg: T) -> Self {
-        self.cmd.stderr(cfg);
+        self.stderr_cfg = Some(cfg.into());
         self
     }
 
@@ -218,10 +622,150 @@ impl CmdLineRunner {
     /// This method is only available when the `progress` feature is enabled.
     #[cfg(feature = "progress")]
     pub fn with_pr(mut self, pr: Arc<ProgressJob>) -> Self {
+        self.reporter = Some(pr.clone());
         self.pr = Some(pr);
         self
     }
 
+    /// Reports the command's status and output to `reporter`, for showing
+    /// progress with a UI library other than clx. Replaces a progress job
+    /// attached with [`with_pr`](Self::with_pr).
+    ///
+    /// See [`ProgressReporter`] for an example.
+    pub fn reporter(mut self, reporter: Arc<dyn ProgressReporter>) -> Self {
+        self.reporter = Some(reporter);
+        #[cfg(feature = "progress")]
+        {
+            self.pr = None;
+        }
+        self
+    }
+
+    /// Shows a progress job for the command, creating one when it starts
+    /// unless one was attached with [`with_pr`](Self::with_pr).
+    ///
+    /// The job shows a spinner, the command line (redacted) or the
+    /// [`progress_message`](Self::progress_message), and the latest line of
+    /// output, and is marked done or failed when the command finishes.
+    ///
+    /// This method is only available when the `progress` feature is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// CmdLineRunner::new("npm")
+    ///     .arg("install")
+    ///     .progress_message("Installing dependencies")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "progress")]
+    pub fn with_progress(mut self, enabled: bool) -> Self {
+        self.auto_progress = enabled;
+        self
+    }
+
+    /// Shows a progress job for the command with `message` in place of the
+    /// command line, see [`with_progress`](Self::with_progress).
+    ///
+    /// This method is only available when the `progress` feature is enabled.
+    #[cfg(feature = "progress")]
+    pub fn progress_message(mut self, message: impl Into<String>) -> Self {
+        self.auto_progress = true;
+        self.progress_message = Some(message.into());
+        self
+    }
+
+    /// Creates the progress job as a child of `parent`, for showing a
+    /// group of commands as a tree. Implies [`with_progress`](Self::with_progress).
+    ///
+    /// If the command fails, `parent` is marked failed too. Marking it done
+    /// once every command has finished is left to the caller.
+    ///
+    /// This method is only available when the `progress` feature is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use clx::progress::ProgressJobBuilder;
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let release = ProgressJobBuilder::new()
+    ///     .prop("message", "Release")
+    ///     .start();
+    /// for step in ["test", "build", "publish"] {
+    ///     CmdLineRunner::new("make")
+    ///         .arg(step)
+    ///         .progress_parent(release.clone())
+    ///         .execute()
+    ///         .await?;
+    /// }
+    /// release.set_status(clx::progress::ProgressStatus::Done);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "progress")]
+    pub fn progress_parent(mut self, parent: Arc<ProgressJob>) -> Self {
+        self.auto_progress = true;
+        self.progress_parent = Some(parent);
+        self
+    }
+
+    /// Shows the last `lines` lines of stdout and stderr under the progress
+    /// job while the command runs, removing them when it finishes.
+    ///
+    /// Stderr goes to the tail instead of being printed above the progress
+    /// bars. Nothing is shown in text output mode, where the progress job
+    /// still shows the latest line.
+    ///
+    /// This method is only available when the `progress` feature is enabled.
+    #[cfg(feature = "progress")]
+    pub fn progress_tail(mut self, lines: usize) -> Self {
+        self.progress_tail = lines;
+        self
+    }
+
+    /// Drives the progress job as a determinate bar from positions `parser`
+    /// finds in stdout and stderr lines, such as `45%` or `12/80`.
+    ///
+    /// A job created with [`with_progress`](Self::with_progress) shows the
+    /// bar in place of the latest line once a position is found. A job
+    /// attached with [`with_pr`](Self::with_pr) gets its current and total
+    /// values set, for a body using `progress_bar()`, and other reporters
+    /// get [`ProgressReporter::set_position`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::{CmdLineRunner, ProgressParser};
+    ///
+    /// # #[cfg(feature = "progress")]
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// CmdLineRunner::new("cargo")
+    ///     .arg("build")
+    ///     .progress_message("Building")
+    ///     .progress_parser(ProgressParser::count())
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "progress"))]
+    /// # fn main() {}
+    /// ```
+    pub fn progress_parser(mut self, parser: crate::ProgressParser) -> Self {
+        self.progress_parser = Some(parser);
+        self
+    }
+
     /// Sets a cancellation token for the command.
     ///
     /// When the token is cancelled, the running process will be killed.
@@ -233,9 +777,6 @@ impl CmdLineRunner {
     /// Controls whether stderr is displayed when the command fails.
     ///
     /// Defaults to `true`.
-    ///
-    /// This method is only available when the `progress` feature is enabled.
-    #[cfg(feature = "progress")]
     pub fn show_stderr_on_error(mut self, show: bool) -> Self {
         self.show_stderr_on_error = show;
         self
@@ -245,14 +786,47 @@ impl CmdLineRunner {
     ///
     /// When enabled, stderr lines update the progress bar's status.
     /// When disabled (default), stderr is printed above the progress bar.
-    ///
-    /// This method is only available when the `progress` feature is enabled.
-    #[cfg(feature = "progress")]
     pub fn stderr_to_progress(mut self, enable: bool) -> Self {
         self.stderr_to_progress = enable;
         self
     }
 
+    /// Hides the command's output unless it fails, for CI-style logs.
+    ///
+    /// While the command runs, its output only updates the progress job
+    /// (stderr included, as with [`stderr_to_progress`](Self::stderr_to_progress)),
+    /// and nothing is printed when it succeeds. If it exits with a non-zero
+    /// status or times out, its whole output is printed above the progress
+    /// bars under a `$ <command>` header, in place of
+    /// [`show_stderr_on_error`](Self::show_stderr_on_error). Has no effect
+    /// without a progress job or [`reporter`](Self::reporter).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[cfg(feature = "progress")]
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// for step in ["lint", "test", "build"] {
+    ///     CmdLineRunner::new("make")
+    ///         .arg(step)
+    ///         .with_progress(true)
+    ///         .collapse_output(true)
+    ///         .execute()
+    ///         .await?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # #[cfg(not(feature = "progress"))]
+    /// # fn main() {}
+    /// ```
+    pub fn collapse_output(mut self, enable: bool) -> Self {
+        self.collapse_output = enable;
+        self
+    }
+
     /// Allows the command to exit with a non-zero status without returning an error.
     ///
     /// When enabled, the command result is returned even if the exit code is non-zero.
@@ -284,42 +858,383 @@ impl CmdLineRunner {
         self
     }
 
-    /// Sets a timeout for the command.
-    ///
-    /// If the command does not complete within the specified duration,
-    /// it will be killed and [`Error::TimedOut`] will be returned.
+    /// Sets the exit codes that count as success, in place of just 0, for
+    /// tools like `grep`, `diff` and `robocopy` whose non-zero codes aren't
+    /// all failures. Other codes still fail with
+    /// [`Error::ScriptFailed`](crate::Error::ScriptFailed), and so does 0
+    /// unless it's listed. Commands killed by a signal always fail.
     ///
     /// # Example
     ///
     /// ```no_run
     /// use ensembler::CmdLineRunner;
-    /// use std::time::Duration;
     ///
     /// # #[tokio::main]
-    /// # async fn main() {
-    /// let result = CmdLineRunner::new("sleep")
-    ///     .arg("60")
-    ///     .timeout(Duration::from_secs(1))
+    /// # async fn main() -> ensembler::Result<()> {
+    /// // 1 means no lines matched; 2 is still an error
+    /// let result = CmdLineRunner::new("grep")
+    ///     .args(["TODO", "src/main.rs"])
+    ///     .success_codes([0, 1])
     ///     .execute()
-    ///     .await;
-    ///
-    /// assert!(result.is_err()); // TimedOut error
+    ///     .await?;
+    /// let found = result.status.success();
+    /// # Ok(())
     /// # }
     /// ```
-    pub fn timeout(mut self, duration: Duration) -> Self {
-        self.timeout = Some(duration);
-        self
-    }
-
-    /// Sets the working directory for the command.
-    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
-        self.cmd.current_dir(dir);
+    pub fn success_codes(mut self, codes: impl IntoIterator<Item = i32>) -> Self {
+        self.success_codes = codes.into_iter().collect();
+        self
+    }
+
+    /// Returns `error` as [`Error::Custom`](crate::Error::Custom), in place of
+    /// [`Error::ScriptFailed`](crate::Error::ScriptFailed), when the command
+    /// exits with `code`, so callers can match on their own error type
+    /// rather than parse the command's output.
+    ///
+    /// Mappings are tried in the order they're added, and the first to match
+    /// is used. History, hooks and other records still see the failure.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// #[derive(Debug, Clone, thiserror::Error)]
+    /// enum GitError {
+    ///     #[error("not a git repository")]
+    ///     NotARepo,
+    /// }
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let result = CmdLineRunner::new("git")
+    ///     .arg("status")
+    ///     .map_exit_code(128, GitError::NotARepo)
+    ///     .execute()
+    ///     .await;
+    /// if let Err(e) = result {
+    ///     if let Some(GitError::NotARepo) = e.downcast_ref::<GitError>() {
+    ///         eprintln!("run this inside a repository");
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn map_exit_code<E>(self, code: i32, error: E) -> Self
+    where
+        E: std::error::Error + Clone + Send + Sync + 'static,
+    {
+        self.map_failure(move |failure| (failure.exit_code() == Some(code)).then(|| error.clone()))
+    }
+
+    /// Calls `f` when the command fails with
+    /// [`Error::ScriptFailed`](crate::Error::ScriptFailed), returning the
+    /// error it gives as [`Error::Custom`](crate::Error::Custom) in its place.
+    /// Returning `None` leaves the failure to the next mapping.
+    ///
+    /// Mappings are tried in the order they're added, and the first to match
+    /// is used. History, hooks and other records still see the failure.
+    pub fn map_failure<E>(
+        mut self,
+        f: impl Fn(&ScriptFailure) -> Option<E> + Send + Sync + 'static,
+    ) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.failure_maps.push(Box::new(move |failure| {
+            f(failure).map(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        }));
+        self
+    }
+
+    /// Sets a timeout for the command.
+    ///
+    /// If the command does not complete within the specified duration,
+    /// it will be killed and [`Error::TimedOut`] will be returned.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    /// use std::time::Duration;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let result = CmdLineRunner::new("sleep")
+    ///     .arg("60")
+    ///     .timeout(Duration::from_secs(1))
+    ///     .execute()
+    ///     .await;
+    ///
+    /// assert!(result.is_err()); // TimedOut error
+    /// # }
+    /// ```
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        self.timeout = Some(duration);
+        self
+    }
+
+    /// Runs the command as administrator, showing a UAC prompt.
+    ///
+    /// The command is started with `ShellExecuteEx` and the `runas` verb from
+    /// a temporary batch script. Since an elevated process can't share our
+    /// pipes, its stdin, stdout and stderr are relayed through temp files:
+    /// output is captured once the command exits (stdout before stderr in
+    /// [`CmdResult::combined_output`]) rather than streamed. Env vars set with
+    /// [`env`](Self::env) are applied by the script; a replaced base
+    /// environment ([`env_clear`](Self::env_clear),
+    /// [`env_snapshot`](Self::env_snapshot)) is not.
+    ///
+    /// Returns [`Error::ElevationCancelled`](crate::Error::ElevationCancelled)
+    /// if the user declines the prompt.
+    #[cfg(windows)]
+    pub fn elevated(mut self, elevated: bool) -> Self {
+        self.elevated = elevated;
+        self
+    }
+
+    /// Runs the command as user `uid`.
+    ///
+    /// Unlike [`std::os::unix::process::CommandExt::uid`], supplementary
+    /// groups are left alone unless set with [`groups`](Self::groups), which
+    /// privileged callers dropping to an unprivileged user usually want to do
+    /// (e.g. `.groups([])`). Requires the privileges to switch users.
+    #[cfg(unix)]
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.pre_exec.uid = Some(nix::unistd::Uid::from_raw(uid));
+        self
+    }
+
+    /// Runs the command with primary group `gid`.
+    #[cfg(unix)]
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.pre_exec.gid = Some(nix::unistd::Gid::from_raw(gid));
+        self
+    }
+
+    /// Sets the command's supplementary groups. An empty list drops all of them.
+    #[cfg(unix)]
+    pub fn groups(mut self, groups: impl IntoIterator<Item = u32>) -> Self {
+        self.pre_exec.groups = Some(groups.into_iter().map(nix::unistd::Gid::from_raw).collect());
+        self
+    }
+
+    /// Confines the command to `root` with `chroot(2)`, for running untrusted
+    /// build steps as root.
+    ///
+    /// The program, its libraries and [`current_dir`](Self::current_dir)
+    /// are looked up inside `root`, which defaults the working directory to
+    /// `/`. Fails with a permission error unless the process is root or has
+    /// `CAP_SYS_CHROOT`. Combine with [`uid`](Self::uid) to also drop root
+    /// inside the new root, since root can escape a chroot.
+    #[cfg(unix)]
+    pub fn chroot<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.pre_exec.chroot = Some(crate::pre_exec::Chroot::new(root.as_ref()));
+        self
+    }
+
+    /// Sets the file mode creation mask of the command, e.g. `0o077` so files
+    /// it creates are only accessible to their owner.
+    ///
+    /// Only the permission bits (`0o777`) are used.
+    #[cfg(unix)]
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.pre_exec.umask = Some(nix::sys::stat::Mode::from_bits_truncate(
+            (mask & 0o777) as nix::sys::stat::mode_t,
+        ));
+        self
+    }
+
+    /// Sets the command's scheduling priority, from -20 (highest) to 19
+    /// (lowest), like `nice -n`.
+    ///
+    /// Raising priority (negative values) requires privileges. On Windows the
+    /// level is mapped to the closest [`PriorityClass`]: 15 and up is
+    /// `Idle`, 1 to 14 `BelowNormal`, 0 `Normal`, -1 to -14 `AboveNormal`,
+    /// and -15 and below `High`.
+    pub fn nice(mut self, level: i32) -> Self {
+        let level = level.clamp(-20, 19);
+        #[cfg(unix)]
+        {
+            self.pre_exec.nice = Some(level);
+        }
+        #[cfg(windows)]
+        {
+            self = self.priority_class(match level {
+                15.. => PriorityClass::Idle,
+                1..=14 => PriorityClass::BelowNormal,
+                0 => PriorityClass::Normal,
+                -14..=-1 => PriorityClass::AboveNormal,
+                _ => PriorityClass::High,
+            });
+        }
+        self
+    }
+
+    /// Sets the command's I/O scheduling class, like `ionice`.
+    #[cfg(target_os = "linux")]
+    pub fn io_priority(mut self, priority: IoPriority) -> Self {
+        self.pre_exec.io_priority = Some(priority);
+        self
+    }
+
+    /// Pins the command to the CPUs set in `mask` (bit 0 is CPU 0), like
+    /// `taskset` on Linux or `start /affinity` on Windows.
+    ///
+    /// Processes the command starts inherit the affinity. Has no effect on
+    /// platforms without process affinity, such as macOS.
+    pub fn cpu_affinity(mut self, mask: u64) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            let mut cpus = nix::sched::CpuSet::new();
+            for cpu in (0..64).filter(|cpu| mask & (1 << cpu) != 0) {
+                // CpuSet holds at least 1024 CPUs
+                let _ = cpus.set(cpu);
+            }
+            self.pre_exec.cpu_affinity = Some(cpus);
+        }
+        #[cfg(windows)]
+        {
+            self.cpu_affinity = Some(mask);
+        }
+        self
+    }
+
+    /// Limits the command's virtual address space to `bytes` (`RLIMIT_AS`).
+    ///
+    /// Allocations past the limit fail, which usually makes the program
+    /// abort. Processes the command starts inherit the limit.
+    #[cfg(unix)]
+    pub fn limit_memory(mut self, bytes: u64) -> Self {
+        self.pre_exec.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Limits the CPU time the command can use to `secs` seconds
+    /// (`RLIMIT_CPU`).
+    ///
+    /// The command receives `SIGXCPU` when it reaches the limit and is
+    /// killed a second later. Unlike [`timeout`](Self::timeout), time spent
+    /// sleeping or waiting on I/O does not count.
+    #[cfg(unix)]
+    pub fn limit_cpu_time(mut self, secs: u64) -> Self {
+        self.pre_exec.cpu_time_limit = Some(secs);
+        self
+    }
+
+    /// Limits the number of files the command can have open at once
+    /// (`RLIMIT_NOFILE`).
+    #[cfg(unix)]
+    pub fn limit_open_files(mut self, n: u64) -> Self {
+        self.pre_exec.open_files_limit = Some(n);
+        self
+    }
+
+    /// Runs the command in a transient cgroup with the given limits, which
+    /// also cover every process it starts.
+    ///
+    /// The cgroup's peak memory usage is reported in
+    /// [`CmdResult::peak_memory`]. Fails with [`Error::Io`](crate::Error::Io)
+    /// if the cgroup can't be created, see [`Cgroup`](crate::Cgroup).
+    #[cfg(target_os = "linux")]
+    pub fn cgroup(mut self, cgroup: crate::Cgroup) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Restricts the filesystem paths and syscalls available to the command
+    /// and everything it starts, see [`Sandbox`](crate::Sandbox).
+    ///
+    /// Fails with [`Error::Io`](crate::Error::Io) instead of running the
+    /// command unconfined if the kernel can't enforce the policy.
+    #[cfg(target_os = "linux")]
+    pub fn sandbox(mut self, sandbox: crate::Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Runs the command inside a bubblewrap sandbox, see [`Bwrap`](crate::Bwrap).
+    ///
+    /// `bwrap` must be on `PATH`. The command's environment is passed into
+    /// the sandbox, and its working directory must exist there. Can't be
+    /// combined with [`escalate`](Self::escalate),
+    /// [`temp_script`](Self::temp_script) or [`wsl`](Self::wsl).
+    #[cfg(target_os = "linux")]
+    pub fn bwrap(mut self, bwrap: crate::Bwrap) -> Self {
+        self.bwrap = Some(bwrap);
+        self
+    }
+
+    /// Runs the command in a transient systemd scope, see
+    /// [`SystemdRun`](crate::SystemdRun).
+    ///
+    /// `systemd-run` and `systemctl` must be on `PATH`. The scope is stopped
+    /// once the command exits, on timeout or cancellation.
+    #[cfg(target_os = "linux")]
+    pub fn systemd_run(mut self, systemd_run: crate::SystemdRun) -> Self {
+        self.systemd_run = Some(systemd_run);
+        self
+    }
+
+    /// Runs the command as root, or another user, through `sudo` or `doas`,
+    /// see [`Escalation`](crate::Escalation).
+    ///
+    /// The escalation program must be on `PATH`. A password set with
+    /// [`Escalation::password`](crate::Escalation::password) is redacted like
+    /// those passed to [`redact`](Self::redact). On timeout or cancellation
+    /// the signal goes to `sudo`, which relays it to the command. Can't be
+    /// combined with [`temp_script`](Self::temp_script),
+    /// [`wsl`](Self::wsl) or, on Linux, `bwrap`.
+    #[cfg(unix)]
+    pub fn escalate(mut self, escalation: crate::Escalation) -> Self {
+        if let Some(password) = escalation.password_str() {
+            self.redactions.insert(password.to_string());
+        }
+        self.escalation = Some(escalation);
+        self
+    }
+
+    /// Asks the command to exit before killing it on timeout or cancellation.
+    ///
+    /// On Unix the process group is sent `SIGTERM`, and on Windows the child
+    /// (started in its own process group) is sent `CTRL_BREAK_EVENT`, so
+    /// well-behaved programs can shut down cleanly. Whatever is still running
+    /// after `duration` is killed. Without a grace period the process tree is
+    /// killed immediately.
+    ///
+    /// On Windows the event can only be delivered to console programs that
+    /// share the parent's console, so it is skipped when the parent has none
+    /// or [`no_window`](Self::no_window) is set.
+    pub fn grace_period(mut self, duration: Duration) -> Self {
+        self.grace_period = Some(duration);
+        self
+    }
+
+    /// Sets the working directory for the command.
+    ///
+    /// On Windows, verbatim (`\\?\`) and UNC paths are supported: directories
+    /// longer than `MAX_PATH` are passed by their 8.3 short name, and when the
+    /// command runs through `cmd.exe`, which can't start in a UNC directory,
+    /// it is run from a temporary script that `pushd`s there first.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cwd = Some(dir.as_ref().to_path_buf());
         self
     }
 
     /// Clears all environment variables for the command.
     pub fn env_clear(mut self) -> Self {
-        self.cmd.env_clear();
+        self.env_base = Some(EnvSnapshot::default());
+        self.envs.clear();
+        self
+    }
+
+    /// Uses `snapshot` as the command's entire environment.
+    ///
+    /// Like [`env_clear`](Self::env_clear), this discards any variables set
+    /// earlier; variables set afterwards with [`env`](Self::env) are layered
+    /// on top of the snapshot.
+    pub fn env_snapshot(mut self, snapshot: &EnvSnapshot) -> Self {
+        self.env_base = Some(snapshot.clone());
+        self.envs.clear();
         self
     }
 
@@ -329,7 +1244,8 @@ impl CmdLineRunner {
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        self.cmd.env(key, val);
+        self.envs
+            .insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
         self
     }
 
@@ -340,367 +1256,2704 @@ impl CmdLineRunner {
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        self.cmd.envs(vars);
+        for (key, val) in vars {
+            self = self.env(key, val);
+        }
+        self
+    }
+
+    /// Adds an optional argument to the command.
+    ///
+    /// If `arg` is `None`, no argument is added.
+    pub fn opt_arg<S: AsRef<OsStr>>(mut self, arg: Option<S>) -> Self {
+        if let Some(arg) = arg {
+            self = self.arg(arg);
+        }
+        self
+    }
+
+    /// Adds a single argument to the command.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(Arg::Regular(arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Append a raw, unescaped fragment to the command line on Windows.
+    ///
+    /// Rust's `Command::arg` applies MSVCRT-style argv escaping on Windows,
+    /// which collides with `cmd.exe`'s own parsing rules and mangles strings
+    /// that already contain cmd-appropriate quoting. This method uses
+    /// `CommandExt::raw_arg` to append the string verbatim so shell-quoted
+    /// payloads (like rendered `{{files}}` values) survive intact.
+    ///
+    /// On non-Windows platforms this falls back to a regular `arg`.
+    pub fn raw_arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(Arg::Raw(arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Adds multiple arguments to the command.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self = self.arg(arg);
+        }
+        self
+    }
+
+    /// Resolves the program against `PATH` before spawning it.
+    ///
+    /// The `PATH` set on this runner via [`env`](Self::env) is searched if
+    /// present, otherwise the parent's `PATH` is used. When the program cannot
+    /// be found, [`Error::ProgramNotFound`](crate::Error::ProgramNotFound) is
+    /// returned without trying to spawn it.
+    ///
+    /// On Windows, runners created with [`new`](Self::new) always resolve the
+    /// program this way (`PATH`, `PATHEXT`, App Paths) but fall back to
+    /// `cmd.exe /c` when nothing is found; enabling this reports the missing
+    /// program instead.
+    pub fn resolve_program(mut self, resolve: bool) -> Self {
+        self.resolve_program = resolve;
+        self
+    }
+
+    /// Spawns the program directly instead of through `cmd.exe /c` on Windows.
+    ///
+    /// Runners created with [`new`](Self::new) wrap batch files and programs
+    /// that can't be resolved (such as `cmd.exe` builtins) in `cmd.exe /c`.
+    /// That wrapper re-parses the arguments, reports its own exit code, and is
+    /// the process that gets killed on cancellation; `.raw(true)` always
+    /// spawns the program directly. This is the same as creating the runner
+    /// with [`new_direct`](Self::new_direct), and has no effect on other
+    /// platforms.
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.shell_wrap = !raw;
+        self
+    }
+
+    /// Sets the Windows priority class of the process.
+    ///
+    /// Use [`PriorityClass::BelowNormal`] or [`PriorityClass::Idle`] for
+    /// background tasks so they don't starve interactive work. Has no effect
+    /// on other platforms.
+    pub fn priority_class(mut self, class: PriorityClass) -> Self {
+        self.creation_flags = (self.creation_flags & !PRIORITY_CLASS_MASK) | class.creation_flag();
+        self
+    }
+
+    /// Starts the process without a console window on Windows
+    /// (`CREATE_NO_WINDOW`).
+    ///
+    /// Prevents console programs launched from a GUI application from
+    /// flashing a window. Has no effect on other platforms.
+    pub fn no_window(self, enable: bool) -> Self {
+        self.set_creation_flag(CREATE_NO_WINDOW, enable)
+    }
+
+    /// Starts the process in a new process group on Windows
+    /// (`CREATE_NEW_PROCESS_GROUP`), so Ctrl+C in the parent's console isn't
+    /// delivered to it. Has no effect on other platforms, where every child
+    /// already gets its own process group.
+    pub fn new_process_group(self, enable: bool) -> Self {
+        self.set_creation_flag(CREATE_NEW_PROCESS_GROUP, enable)
+    }
+
+    /// Adds raw `CreateProcess` creation flags on Windows.
+    ///
+    /// The flags are combined with those set by [`priority_class`](Self::priority_class),
+    /// [`no_window`](Self::no_window) and
+    /// [`new_process_group`](Self::new_process_group). Has no effect on
+    /// other platforms.
+    pub fn creation_flags(mut self, flags: u32) -> Self {
+        self.creation_flags |= flags;
+        self
+    }
+
+    fn set_creation_flag(mut self, flag: u32, enable: bool) -> Self {
+        if enable {
+            self.creation_flags |= flag;
+        } else {
+            self.creation_flags &= !flag;
+        }
+        self
+    }
+
+    /// Sets how captured stdout and stderr are decoded.
+    ///
+    /// Defaults to [`OutputEncoding::Auto`], which on Windows decodes lines
+    /// that aren't valid UTF-8 with the console codepage.
+    pub fn output_encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.encoding = encoding;
         self
     }
 
-    /// Adds an optional argument to the command.
-    ///
-    /// If `arg` is `None`, no argument is added.
-    pub fn opt_arg<S: AsRef<OsStr>>(mut self, arg: Option<S>) -> Self {
-        if let Some(arg) = arg {
-            self.cmd.arg(arg);
+    /// Sets how many bytes of stdout and stderr are read at a time. Defaults
+    /// to 8 KiB.
+    ///
+    /// Commands that print a lot of output quickly, such as ones dumping
+    /// logs or data, are captured with fewer reads and wakeups with a
+    /// larger buffer, at the cost of that much memory per stream.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes.max(1);
+        self
+    }
+
+    /// Expands environment variable references in args and the working directory.
+    ///
+    /// Both `${VAR}` and `%VAR%` syntax are supported on every platform, and
+    /// values come from the child's environment (variables set with
+    /// [`env`](Self::env) take precedence over the parent's, and
+    /// [`env_clear`](Self::env_clear) is honored). Unset `${VAR}` references
+    /// expand to nothing; unset `%VAR%` references are left as-is.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let result = CmdLineRunner::new("echo")
+    ///     .arg("${GREETING}, %NAME%")
+    ///     .env("GREETING", "hello")
+    ///     .env("NAME", "world")
+    ///     .expand_env(true)
+    ///     .execute()
+    ///     .await?;
+    ///
+    /// assert_eq!(result.stdout.trim(), "hello, world");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn expand_env(mut self, expand: bool) -> Self {
+        self.expand_env = expand;
+        self
+    }
+
+    /// Runs the command from a temporary script file instead of passing it
+    /// on the command line.
+    ///
+    /// On Windows the command is written to a `.cmd` file run with
+    /// `cmd.exe /d /c`, which sidesteps `cmd.exe /c`'s quote-stripping rules
+    /// and its 8191 character command-line limit. A PowerShell script
+    /// (`.ps1`) is instead called from a `.ps1` file run with PowerShell's
+    /// `-File`. On Unix it is written to a `sh` script that `exec`s the
+    /// command; the program still gets its args on its own command line, so
+    /// this doesn't get around `ARG_MAX`. The file is deleted once the
+    /// command finishes. Can't be combined with [`wsl`](Self::wsl), nor with
+    /// `bwrap` or `escalate` where they're available.
+    pub fn temp_script(mut self, enable: bool) -> Self {
+        self.temp_script = enable;
+        self
+    }
+
+    /// Sets how much of the command is shown, overriding
+    /// [`Defaults::verbosity`](crate::Defaults::verbosity).
+    pub fn verbosity(mut self, verbosity: crate::Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Prints every line of output as it comes, starting with `label` and a
+    /// `|`, like foreman and docker compose do, so the output of commands
+    /// running at the same time can be told apart. Labels get the next of a
+    /// set of colors; pad them to the same width to line the output up.
+    ///
+    /// Lines are printed through the command's
+    /// [`ProgressReporter`](crate::ProgressReporter), or to stderr without
+    /// one, and not at all at [`Verbosity::Quiet`](crate::Verbosity::Quiet).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let (web, worker) = tokio::join!(
+    ///     CmdLineRunner::new("npm").args(["run", "dev"]).prefix("web   ").execute(),
+    ///     CmdLineRunner::new("npm").args(["run", "worker"]).prefix("worker").execute(),
+    /// );
+    /// web?;
+    /// worker?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prefix(mut self, label: impl Into<String>) -> Self {
+        let i = NEXT_PREFIX_COLOR.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let style = console::Style::new().fg(PREFIX_COLORS[i % PREFIX_COLORS.len()]);
+        self.prefix = Some((label.into(), style));
+        self
+    }
+
+    /// Attaches `key=value` metadata to the command, such as the task,
+    /// package or step it belongs to. Setting a key again replaces its value.
+    ///
+    /// Labels are set as progress props, added to the tracing span, recorded
+    /// in the history and reports, and kept in [`CmdResult::labels`], so
+    /// [`Error::ScriptFailed`](crate::Error::ScriptFailed) shows them.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// // fails with "cargo exited with non-zero status: exit code 101 (package=core, step=test)"
+    /// CmdLineRunner::new("cargo")
+    ///     .args(["test", "-p", "core"])
+    ///     .label("package", "core")
+    ///     .label("step", "test")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let (key, value) = (key.into(), value.into());
+        match self.labels.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.labels.push((key, value)),
+        }
+        self
+    }
+
+    /// Describes what the command is for, such as `"installing node 20"`,
+    /// shown before the message of any
+    /// [`Error::ScriptFailed`](crate::Error::ScriptFailed) or
+    /// [`Error::Io`](crate::Error::Io) it fails with, so call sites don't
+    /// have to wrap each error themselves. An I/O error keeps its kind, and
+    /// the original error, with its OS error code, is its
+    /// [`source`](std::error::Error::source).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// // fails with "installing node 20: curl exited with non-zero status: exit code 22"
+    /// CmdLineRunner::new("curl")
+    ///     .args(["-fsSLO", "https://nodejs.org/dist/v20.0.0/node-v20.0.0.tar.gz"])
+    ///     .context("installing node 20")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Sets advice for the user shown after the output when the command fails,
+    /// such as `"try deleting node_modules and running again"`, or wherever
+    /// `{hint}` appears in a [`failure_message`](Self::failure_message).
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Displays [`Error::ScriptFailed`](crate::Error::ScriptFailed) for this
+    /// command with `message`, replacing any set with
+    /// [`Defaults::failure_message`](crate::Defaults::failure_message).
+    pub fn failure_message(mut self, message: crate::FailureMessage) -> Self {
+        self.failure_message = Some(message);
+        self
+    }
+
+    /// Adds every line of output to `mux`, labeled `label`.
+    pub fn log_mux(mut self, mux: crate::LogMux, label: impl Into<String>) -> Self {
+        self.log_mux = Some((mux, label.into()));
+        self
+    }
+
+    /// Sets the style of the label set with [`prefix`](Self::prefix), in
+    /// place of the next color.
+    pub fn prefix_style(mut self, style: console::Style) -> Self {
+        if let Some((_, prefix_style)) = &mut self.prefix {
+            *prefix_style = style;
+        }
+        self
+    }
+
+    /// Logs how the child's environment differs from the parent's.
+    ///
+    /// When enabled and debug logging is on, each added, changed, or removed
+    /// variable is logged alongside the `$ command` line. Values are passed
+    /// through [`redact`](Self::redact), and values of variables whose names
+    /// look like credentials (`*TOKEN*`, `*SECRET*`, `*PASSWORD*`, ...) are
+    /// hidden entirely.
+    pub fn log_env_diff(mut self, enable: bool) -> Self {
+        self.log_env_diff = enable;
+        self
+    }
+
+    /// Runs the command inside WSL with `wsl.exe --exec`.
+    ///
+    /// `distro` selects the distribution (`wsl.exe -d <distro>`); `None` uses
+    /// the default one. The program is looked up on the Linux `PATH` and the
+    /// arguments are passed through as-is, so convert Windows paths with
+    /// [`wsl::to_wsl_path`](crate::wsl::to_wsl_path). The working directory
+    /// is translated automatically and env vars set on the runner are
+    /// forwarded through `WSLENV`. Can't be combined with
+    /// [`temp_script`](Self::temp_script), nor with `bwrap` or `escalate`
+    /// where they're available.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::{wsl, CmdLineRunner};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// let result = CmdLineRunner::new("make")
+    ///     .arg("-C")
+    ///     .arg(wsl::to_wsl_path(r"C:\src\app").unwrap())
+    ///     .wsl(Some("Ubuntu"))
+    ///     .env("CC", "clang")
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wsl(mut self, distro: Option<&str>) -> Self {
+        self.wsl = Some(distro.map(str::to_string));
+        self
+    }
+
+    /// Runs the command through `wrapper`, a program and its leading args,
+    /// e.g. `["nice", "-n", "19"]` or `["aws-vault", "exec", "prod", "--"]`.
+    ///
+    /// Wrappers compose: each one added runs inside the ones before it, after
+    /// any set with [`Defaults::wrap`](crate::Defaults::wrap). They also wrap
+    /// `cmd.exe`, `wsl.exe`, `bwrap` or `sudo` when the runner uses them, and
+    /// are passed to an [`executor`](Self::executor) as part of the command.
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> ensembler::Result<()> {
+    /// // runs `nice -n 19 ionice -c 3 make -j8`
+    /// CmdLineRunner::new("make")
+    ///     .arg("-j8")
+    ///     .wrap(["nice", "-n", "19"])
+    ///     .wrap(["ionice", "-c", "3"])
+    ///     .execute()
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wrap<I, S>(mut self, wrapper: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let wrapper: Vec<OsString> = wrapper
+            .into_iter()
+            .map(|a| a.as_ref().to_os_string())
+            .collect();
+        if !wrapper.is_empty() {
+            self.wrappers.push(wrapper);
+        }
+        self
+    }
+
+    /// Starts the command with `executor` instead of as a local process,
+    /// e.g. on a remote host or in a container.
+    ///
+    /// The executor gets the program, args, environment and working
+    /// directory; output capture, redaction, stdin, timeouts, cancellation and
+    /// progress work as usual. Options that confine or change the identity of
+    /// a local process, such as `uid`, `chroot`, `umask`,
+    /// process limits, cgroups and sandboxing, fail with
+    /// [`Error::Io`](crate::Error::Io) rather than being left out. Other
+    /// local options, such as `nice` and Windows `cmd.exe` wrapping, are
+    /// ignored. See the [`executor`](crate::executor) module.
+    pub fn executor(mut self, executor: impl crate::Executor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Returns a stored result instead of running the command again if it
+    /// already ran with the same inputs, see [`Cache`](crate::Cache).
+    ///
+    /// A cache that can't be read or written is skipped with a debug log,
+    /// but failing to hash a declared input fails with
+    /// [`Error::Io`](crate::Error::Io).
+    pub fn cache(mut self, cache: crate::Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Skips the command if its declared outputs are up to date with its
+    /// inputs, see [`Freshness`](crate::Freshness).
+    pub fn freshness(mut self, freshness: crate::Freshness) -> Self {
+        self.freshness = Some(freshness);
+        self
+    }
+
+    /// Records the command in `history` when it finishes, replacing any
+    /// set with [`Defaults::history`](crate::Defaults::history).
+    pub fn history(mut self, history: crate::History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Records the command as a test case in `report` when it finishes,
+    /// replacing any set with [`Defaults::junit`](crate::Defaults::junit).
+    pub fn junit(mut self, report: crate::JunitReport) -> Self {
+        self.junit = Some(report);
+        self
+    }
+
+    /// Adds the command to `summary` when it finishes, replacing any set
+    /// with [`Defaults::summary`](crate::Defaults::summary).
+    pub fn summary(mut self, summary: crate::Summary) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    /// Records the command in `report` when it finishes, replacing any set
+    /// with [`Defaults::run_report`](crate::Defaults::run_report).
+    pub fn run_report(mut self, report: crate::RunReport) -> Self {
+        self.run_report = Some(report);
+        self
+    }
+
+    /// Counts the command in `metrics`, replacing any set with
+    /// [`Defaults::metrics`](crate::Defaults::metrics).
+    pub fn metrics(mut self, metrics: crate::Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records how long the command takes in `timings` when it succeeds,
+    /// replacing any set with [`Defaults::timings`](crate::Defaults::timings).
+    pub fn timings(mut self, timings: crate::Timings) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Returns how long the command usually takes, from the
+    /// [`timings`](Self::timings) of earlier runs with the same command line.
+    pub fn expected_duration(&self) -> Option<Duration> {
+        let timings = self.timings.as_ref()?;
+        timings.expected(&self.redacted_command_line().ok()?)
+    }
+
+    /// Pipes a string to the command's stdin.
+    ///
+    /// This automatically configures stdin to be piped.
+    pub fn stdin_string(mut self, input: impl Into<String>) -> Self {
+        self.stdin_cfg = Some(Stdio::piped());
+        self.stdin = Some(input.into());
+        self.stdin_replaced = false;
+        self
+    }
+
+    /// Checks the command can be started without starting it, returning
+    /// every problem found in [`Error::Preflight`](crate::Error::Preflight)
+    /// so a plan of many commands can fail before any of them run.
+    ///
+    /// Checks that:
+    /// - the program can be found on `PATH`, unless it runs through an
+    ///   [`executor`](Self::executor) or WSL, or its name has variables for
+    ///   [`expand_env`](Self::expand_env) to expand
+    /// - the working directory exists
+    /// - [`stdin`](Self::stdin) wasn't set after
+    ///   [`stdin_string`](Self::stdin_string), which would leave its input
+    ///   without a pipe
+    /// - environment variable names aren't empty and contain no `=`, and
+    ///   names and values contain no NUL bytes
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// # fn main() -> ensembler::Result<()> {
+    /// let plan = [
+    ///     CmdLineRunner::new("cargo").arg("build"),
+    ///     CmdLineRunner::new("docker").args(["build", "."]),
+    /// ];
+    /// for runner in &plan {
+    ///     runner.validate()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        let remote = self.executor.is_some() || self.wsl.is_some();
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        if let Some(cwd) = self.cwd.as_ref().filter(|cwd| !remote && !cwd.is_dir()) {
+            problems.push(crate::Error::CurrentDirNotFound(
+                self.program.clone(),
+                cwd.clone(),
+            ));
+        }
+        // on Windows, programs that aren't found run through cmd.exe
+        let cmd_exe = cfg!(windows) && self.shell_wrap && !self.resolve_program;
+        let expands = self.expand_env && (self.program.contains('$') || self.program.contains('%'));
+        if !remote && !cmd_exe && !expands {
+            let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+            let cwd = self.cwd.as_deref().filter(|cwd| cwd.is_dir());
+            if which::which_in(OsStr::new(&self.program), path.as_deref(), cwd).is_none() {
+                problems.push(which::not_found(&self.program, path.as_deref()));
+            }
+        }
+        if let Err(e) = self.check_launchers() {
+            problems.push(e.into());
+        }
+        if let Err(e) = self.check_executor() {
+            problems.push(e.into());
+        }
+        if self.stdin_replaced {
+            problems.push(
+                invalid("stdin was set after stdin_string, so its input may not be written".into())
+                    .into(),
+            );
+        }
+        for (key, val) in &self.envs {
+            let key_str = key.to_string_lossy();
+            if key.is_empty() || key_str.contains('=') || key_str.contains('\0') {
+                problems
+                    .push(invalid(format!("invalid environment variable name {key_str:?}")).into());
+            } else if val.to_string_lossy().contains('\0') {
+                problems.push(
+                    invalid(format!(
+                        "environment variable {key_str} contains a NUL byte"
+                    ))
+                    .into(),
+                );
+            }
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(crate::Error::Preflight(problems)),
+        }
+    }
+
+    /// Executes the command and waits for it to complete.
+    ///
+    /// Returns [`CmdResult`] containing captured stdout, stderr, and exit status
+    /// on success. Returns an error if the command fails to start or exits with
+    /// a non-zero status.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::ProgramNotFound`] if the program doesn't exist
+    /// - [`Error::CurrentDirNotFound`] if the working directory doesn't exist
+    /// - [`Error::Io`] if the command fails to start for another reason
+    /// - [`Error::ScriptFailed`] if the command exits with a non-zero status
+    ///
+    /// # Tracing
+    ///
+    /// With the `tracing` feature, the command runs in an `execute` span
+    /// recording the program, a hash of the args, the cwd, the pid, the exit
+    /// code, the duration and the bytes of output, with events when it
+    /// spawns and exits and for each line of output. The span sets
+    /// `otel.name` and `otel.status_code`, so with
+    /// [`tracing-opentelemetry`](https://docs.rs/tracing-opentelemetry)
+    /// commands are exported as OpenTelemetry spans named after the program
+    /// that are marked as errors when they fail.
+    pub async fn execute(mut self) -> Result<CmdResult> {
+        let failure_maps = std::mem::take(&mut self.failure_maps);
+        let context = self.context.clone();
+        let spec = match crate::hooks::registered() {
+            true => Some(self.cmd_spec()?),
+            false => None,
+        };
+        if let Some(spec) = &spec {
+            crate::hooks::before(spec);
+        }
+        let mut running = crate::stats::Running::start(&self.program);
+        #[cfg(feature = "tracing")]
+        let (span, start) = (self.span(), std::time::Instant::now());
+        let execute = async move {
+            match crate::audit::installed() {
+                Some(log) => self.execute_audited(log).await,
+                None => self.run().await,
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let execute = tracing::Instrument::instrument(execute, span.clone());
+        let result = execute.await;
+        #[cfg(feature = "tracing")]
+        record_outcome(&span, &result, start.elapsed());
+        if result.is_err() {
+            running.fail();
+        }
+        if let Some(spec) = &spec {
+            crate::hooks::after(spec, &result);
+        }
+        match result {
+            Err(ScriptFailed(failure)) => {
+                let error = failure_maps.iter().find_map(|map| map(&failure));
+                Err(error.map_or(ScriptFailed(failure), crate::Error::Custom))
+            }
+            Err(crate::Error::Io(e)) => match context {
+                Some(context) => Err(crate::error::IoContext::wrap(context, e).into()),
+                None => Err(e.into()),
+            },
+            result => result,
+        }
+    }
+
+    /// Creates the `tracing` span the command runs in. Args are hashed
+    /// rather than recorded, since they may hold secrets.
+    #[cfg(feature = "tracing")]
+    fn span(&self) -> tracing::Span {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        for arg in &self.args {
+            let arg = arg.as_os_str().as_encoded_bytes();
+            hasher.update((arg.len() as u64).to_le_bytes());
+            hasher.update(arg);
+        }
+        let args_hash: String = hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let cwd = match &self.cwd {
+            Some(cwd) => std::path::absolute(cwd).ok(),
+            None => std::env::current_dir().ok(),
+        };
+        let span = tracing::info_span!(
+            "execute",
+            otel.name = %self.program,
+            otel.status_code = tracing::field::Empty,
+            program = %self.program,
+            args_hash = %args_hash,
+            cwd = %cwd.unwrap_or_default().display(),
+            pid = tracing::field::Empty,
+            exit_code = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+            stdout_bytes = tracing::field::Empty,
+            stderr_bytes = tracing::field::Empty,
+            labels = tracing::field::Empty,
+        );
+        if !self.labels.is_empty() {
+            span.record("labels", crate::error::render_labels(&self.labels));
+        }
+        span
+    }
+
+    /// Runs the command; everything [`execute`](Self::execute) does apart
+    /// from auditing.
+    async fn run(mut self) -> Result<CmdResult> {
+        self.check_policy()?;
+        if self.ci_groups && self.reporter.is_none() {
+            self.reporter = ci_reporter();
+        }
+        #[cfg(feature = "progress")]
+        if self.auto_progress && self.reporter.is_none() {
+            let pr = self.start_progress()?;
+            self.reporter = Some(pr.clone());
+            self.pr = Some(pr);
+            if let Some(parent) = self.progress_parent.take() {
+                let result = Box::pin(self.run()).await;
+                if result.is_err() {
+                    parent.set_status(progress::ProgressStatus::Failed);
+                }
+                return result;
+            }
+        }
+        if let Some(freshness) = self.freshness.take() {
+            return self.execute_fresh(freshness).await;
+        }
+        let recorders = Recorders {
+            history: self.history.take(),
+            junit: self.junit.take(),
+            summary: self.summary.take(),
+            run_report: self.run_report.take(),
+            metrics: self.metrics.take(),
+        };
+        if !recorders.is_empty() {
+            return self.execute_recorded(recorders).await;
+        }
+        if let Some(cache) = self.cache.take() {
+            return self.execute_cached(cache).await;
+        }
+        if let Some(timings) = self.timings.take() {
+            return self.execute_timed(timings).await;
+        }
+        let target = self.log_target();
+        debug!(target: &target, "$ {self}");
+
+        // This is done before spawning to avoid orphan processes on build failure
+        let redactor = self.redactor()?;
+
+        if self.log_env_diff && log_enabled!(target: &target, log::Level::Debug) {
+            for line in self.env_diff_lines(redactor.as_deref()) {
+                debug!(target: &target, "{line}");
+            }
+        }
+        if self.verbosity >= Verbosity::Verbose {
+            self.echo(&self.style.header(&self.redacted_command_line()?));
+        }
+        if self.verbosity >= Verbosity::Trace {
+            let cwd = match &self.cwd {
+                Some(cwd) => std::path::absolute(cwd)?,
+                None => std::env::current_dir()?,
+            };
+            self.echo(&format!("  in {}", cwd.display()));
+            for line in self.env_diff_lines(redactor.as_deref()) {
+                self.echo(&line);
+            }
+        }
+
+        #[cfg(windows)]
+        if self.elevated {
+            return self.execute_elevated(redactor.as_deref()).await;
+        }
+
+        #[cfg(target_os = "linux")]
+        let mut cgroup = None;
+        let mut process: Box<dyn Process> = match self.executor.clone() {
+            Some(executor) => {
+                self.check_executor()?;
+                let invocation = Invocation {
+                    redactor: redactor.clone(),
+                    ..self.invocation()
+                };
+                executor.spawn(&invocation)?
+            }
+            None => {
+                #[cfg(target_os = "linux")]
+                if let Some(limits) = &self.cgroup {
+                    let created = limits.create()?;
+                    self.pre_exec.cgroup_procs = Some(created.procs_path());
+                    cgroup = Some(created);
+                }
+                Box::new(self.spawn_local()?)
+            }
+        };
+        match process.id() {
+            Some(id) => trace!(target: &target, "Started process: {id} for {}", self.program),
+            None => trace!(target: &target, "Started {}", self.program),
+        }
+        #[cfg(feature = "tracing")]
+        {
+            tracing::Span::current().record("pid", process.id());
+            tracing::debug!(pid = process.id(), "spawned");
+        }
+        let started_at = SystemTime::now();
+        let stopwatch = Arc::new(crate::timings::Stopwatch::start(self.expected));
+        if let Some(reporter) = &self.reporter {
+            reporter.prop("ensembler_cmd", &self.redacted_display(redactor.as_deref()));
+            reporter.prop("ensembler_stdout", "");
+            for (key, value) in &self.labels {
+                reporter.prop(key, value);
+            }
+            report_elapsed(reporter.as_ref(), &stopwatch);
+            reporter.set_status(ProgressState::Running);
+        }
+        #[cfg(feature = "progress")]
+        let tail = self
+            .pr
+            .as_ref()
+            .and_then(|pr| crate::tail::OutputTail::start(pr, self.progress_tail))
+            .map(Arc::new);
+        let mut result = CmdResult {
+            labels: self.labels.clone(),
+            ..Default::default()
+        };
+
+        let encoding = self.encoding;
+        let sink = OutputSink {
+            redactor: redactor.clone(),
+            reporter: self.reporter.clone(),
+            #[cfg(feature = "progress")]
+            tail: tail.clone(),
+            parser: self.progress_parser.clone(),
+            stopwatch: stopwatch.clone(),
+            verbosity: self.verbosity,
+            prefix: self
+                .prefix
+                .as_ref()
+                .filter(|_| self.verbosity > Verbosity::Quiet)
+                .map(|(label, style)| format!("{} | ", style.apply_to(label))),
+            stderr_to_progress: self.stderr_to_progress || self.collapse_output,
+            log_mux: self.log_mux.clone(),
+        };
+        let mut captured = Captured::default();
+        let mut stdout = LineReader::new(process.take_stdout(), self.read_buffer_size);
+        let mut stderr = LineReader::new(process.take_stderr(), self.read_buffer_size);
+        let stdin = match self.stdin.take() {
+            Some(text) => match process.take_stdin() {
+                Some(stdin) => Some((stdin, text)),
+                None => {
+                    let _ = process.kill(None).await;
+                    if let Some(reporter) = &self.reporter {
+                        reporter.set_status(ProgressState::Failed);
+                    }
+                    return Err(crate::Error::Internal(
+                        "stdin was requested but not available".to_string(),
+                    ));
+                }
+            },
+            None => None,
+        };
+        // the pipe is closed once the input is written, when this completes
+        let stdin_fut = async {
+            if let Some((mut stdin, text)) = stdin {
+                if let Err(e) = stdin.write_all(text.as_bytes()).await {
+                    debug!(target: &target, "Failed to write to stdin: {e}");
+                }
+            }
+        };
+        tokio::pin!(stdin_fut);
+        let mut stdin_done = false;
+
+        // Create timeout future that either sleeps or waits forever
+        let timeout_fut = async {
+            if let Some(duration) = self.timeout {
+                tokio::time::sleep(duration).await;
+            } else {
+                std::future::pending::<()>().await;
+            }
+        };
+        tokio::pin!(timeout_fut);
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut timed_out = false;
+        let mut was_cancelled = false;
+        // kept across output lines, since executors' waits needn't be cancel
+        // safe; only replaced to kill the process
+        let mut wait = process.wait();
+        let status = loop {
+            // Use biased select to prioritize process completion over timeout/cancellation.
+            // This prevents a race where if process completes at the same instant as timeout,
+            // we'd incorrectly report a timeout instead of success.
+            select! {
+                biased;
+                status = &mut wait => {
+                    break status?;
+                }
+                _ = &mut timeout_fut, if !timed_out => {
+                    timed_out = true;
+                    drop(wait);
+                    if let Err(e) = process.kill(self.grace_period).await {
+                        debug!(target: &target, "Failed to kill {}: {e}", self.program);
+                    }
+                    wait = process.wait();
+                }
+                _ = self.cancel.cancelled(), if !was_cancelled => {
+                    was_cancelled = true;
+                    drop(wait);
+                    if let Err(e) = process.kill(self.grace_period).await {
+                        debug!(target: &target, "Failed to kill {}: {e}", self.program);
+                    }
+                    wait = process.wait();
+                }
+                _ = &mut stdin_fut, if !stdin_done => {
+                    stdin_done = true;
+                }
+                Some(line) = stdout.next_line(encoding) => {
+                    sink.line(&mut captured, crate::LogStream::Stdout, line);
+                }
+                Some(line) = stderr.next_line(encoding) => {
+                    sink.line(&mut captured, crate::LogStream::Stderr, line);
+                }
+                _ = ticker.tick(), if self.reporter.is_some() => {
+                    if let Some(reporter) = &self.reporter {
+                        report_elapsed(reporter.as_ref(), &stopwatch);
+                        reporter.update();
+                    }
+                }
+            }
+        };
+        drop(wait);
+        let duration = stopwatch.elapsed();
+        result.resource_usage = process.resource_usage();
+        drop(process);
+        if let Some(reporter) = &self.reporter {
+            report_elapsed(reporter.as_ref(), &stopwatch);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = cgroup {
+            result.peak_memory = cgroup.remove().await;
+        }
+        result.status = status;
+        result.started_at = Some(started_at);
+        result.finished_at = Some(started_at + duration);
+        result.duration = duration;
+
+        if was_cancelled {
+            self.trace_exit("cancelled", &stopwatch);
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Failed);
+            }
+            return Err(crate::Error::Cancelled);
+        }
+
+        if timed_out {
+            self.trace_exit("timed out", &stopwatch);
+            // output the killed command's descendants are still writing
+            // isn't waited for
+            let output = captured.output();
+            result.longest_stall = stopwatch.longest_silence(duration);
+            captured.fill(&mut result);
+            let error = crate::Error::TimedOut {
+                elapsed: duration,
+                partial: Box::new(result),
+            };
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Failed);
+                if self.collapse_output && self.verbosity > Verbosity::Quiet {
+                    let output = format!("{output}\n{error}");
+                    self.print_expanded(reporter.as_ref(), &output)?;
+                }
+            }
+            return Err(error);
+        }
+
+        // the rest of the output, until the process's descendants close it
+        loop {
+            select! {
+                biased;
+                Some(line) = stdout.next_line(encoding) => {
+                    sink.line(&mut captured, crate::LogStream::Stdout, line);
+                }
+                Some(line) = stderr.next_line(encoding) => {
+                    sink.line(&mut captured, crate::LogStream::Stderr, line);
+                }
+                else => break,
+            }
+        }
+        captured.fill(&mut result);
+        result.longest_stall = stopwatch.longest_silence(duration);
+        #[cfg(feature = "progress")]
+        if let Some(tail) = &tail {
+            tail.finish();
+        }
+        self.trace_exit(&crate::error::render_exit_status(&status), &stopwatch);
+
+        if self.is_success(&status) {
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Done);
+            }
+        } else {
+            let output = result
+                .combined_output
+                .strip_suffix('\n')
+                .unwrap_or_default();
+            self.on_error(output.to_string(), result.clone())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Runs the command between a start and a finish record in the audit
+    /// `log`, without running it if the start can't be recorded.
+    async fn execute_audited(self, log: &'static crate::audit::AuditLog) -> Result<CmdResult> {
+        let start = log.write_async(self.audit_start(false)?).await?;
+        let redactor = self.redactor()?;
+        let result = Box::pin(self.run()).await;
+        let (exit_code, error) = match &result {
+            Ok(result) => (result.status.code(), None),
+            Err(crate::Error::ScriptFailed(failure)) => (failure.exit_code(), None),
+            Err(e) => (
+                None,
+                Some(redact_lossy(redactor.as_deref(), e.to_string().as_ref())),
+            ),
+        };
+        let finish = crate::audit::AuditEvent::Finish {
+            start,
+            exit_code,
+            error,
+        };
+        if let Err(e) = log.write_async(finish).await {
+            warn!("Failed to write to the audit log: {e}");
+        }
+        result
+    }
+
+    /// Describes the command for the audit log, as a start or `exec` event.
+    fn audit_start(&self, exec: bool) -> Result<crate::audit::AuditEvent> {
+        let redactor = self.redactor()?;
+        let argv = self
+            .wrappers
+            .iter()
+            .flatten()
+            .map(OsString::as_os_str)
+            .chain([OsStr::new(&self.program)])
+            .chain(self.args.iter().map(Arg::as_os_str))
+            .map(|word| redact_lossy(redactor.as_deref(), word))
+            .collect();
+        let cwd = match &self.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?,
+        };
+        let user = crate::audit::current_user();
+        Ok(if exec {
+            crate::audit::AuditEvent::Exec { argv, user, cwd }
+        } else {
+            crate::audit::AuditEvent::Start { argv, user, cwd }
+        })
+    }
+
+    /// Fails with [`Error::PolicyDenied`](crate::Error::PolicyDenied) if the
+    /// policy rejects the command, as it will run after
+    /// [`expand_env`](Self::expand_env).
+    fn check_policy(&self) -> Result<()> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        let mut invocation = self.unwrapped_invocation();
+        if self.expand_env {
+            let (args, cwd) = self.expanded_env_vars();
+            invocation.args = args.iter().map(|a| a.as_os_str().to_os_string()).collect();
+            invocation.cwd = cwd;
+        }
+        invocation.cwd = Some(match &invocation.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?,
+        });
+        match policy.check(&invocation) {
+            Some(reason) => {
+                let reason = match self.redactor()? {
+                    Some(redactor) => redactor.redact(&reason),
+                    None => reason,
+                };
+                Err(crate::Error::PolicyDenied(self.program.clone(), reason))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the command and records how it went in `recorders`.
+    async fn execute_recorded(self, recorders: Recorders) -> Result<CmdResult> {
+        let redactor = self.redactor()?;
+        let redact = |s: &OsStr| redact_lossy(redactor.as_deref(), s);
+        let argv: Vec<String> = self
+            .wrappers
+            .iter()
+            .flatten()
+            .map(OsString::as_os_str)
+            .chain([OsStr::new(&self.program)])
+            .chain(self.args.iter().map(Arg::as_os_str))
+            .map(redact)
+            .collect();
+        let mut entry = crate::HistoryEntry {
+            program: self.program.clone(),
+            args: self.args.iter().map(|a| redact(a.as_os_str())).collect(),
+            env: self
+                .envs
+                .iter()
+                .map(|(k, v)| (k.to_string_lossy().into_owned(), redact(v)))
+                .collect(),
+            cwd: self.cwd.clone(),
+            started_at: std::time::SystemTime::now(),
+            duration: Duration::ZERO,
+            exit_code: None,
+            error: None,
+            output: String::new(),
+            first_output: None,
+            longest_stall: None,
+            labels: self.labels.clone(),
+        };
+        if let Some(metrics) = &recorders.metrics {
+            metrics.started(&entry.program);
+        }
+        let target = self.log_target();
+        let start = std::time::Instant::now();
+        let result = Box::pin(self.run()).await;
+        entry.duration = start.elapsed();
+        let finished = match &result {
+            Ok(result) => Some(result),
+            Err(crate::Error::ScriptFailed(failure)) => Some(failure.result()),
+            Err(_) => None,
+        };
+        match (finished, &result) {
+            (Some(finished), _) => {
+                entry.exit_code = finished.status.code();
+                entry.output = finished.combined_output.clone();
+                entry.first_output = finished.first_output;
+                entry.longest_stall = Some(finished.longest_stall);
+            }
+            (None, Err(e)) => entry.error = Some(e.to_string()),
+            (None, Ok(_)) => {}
+        }
+        if let Some(report) = recorders.run_report {
+            report.record(argv, &entry);
+        }
+        if let Some(metrics) = recorders.metrics {
+            metrics.finished(&entry);
+        }
+        if let Some(history) = recorders.history {
+            let mut entry = entry.clone();
+            entry.output = history.truncate_output(&entry.output);
+            if let Err(e) = history.append(&entry) {
+                debug!(target: &target, "Failed to record {} in history: {e}", entry.program);
+            }
+        }
+        if let Some(junit) = recorders.junit {
+            junit.record(entry.clone());
+        }
+        if let Some(summary) = recorders.summary {
+            summary.record(entry);
+        }
+        result
+    }
+
+    /// Runs the command unless `cache` has a result for it, and stores the
+    /// result of a successful run.
+    async fn execute_cached(self, cache: crate::Cache) -> Result<CmdResult> {
+        let command = self
+            .wrappers
+            .iter()
+            .flatten()
+            .map(OsString::as_os_str)
+            .chain([OsStr::new(&self.program)])
+            .chain(self.args.iter().map(Arg::as_os_str));
+        let key = cache.key(
+            command,
+            self.stdin.as_deref(),
+            self.cwd.as_deref(),
+            self.env_base.as_ref(),
+            &self.envs,
+            |key| env::child_var(key, self.env_base.as_ref(), &IndexMap::new()),
+        )?;
+        if let Some(mut result) = cache.get(&key) {
+            result.labels = self.labels.clone();
+            debug!(target: &self.log_target(), "$ {self} (cached)");
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Done);
+            }
+            return Ok(result);
+        }
+        let target = self.log_target();
+        let result = Box::pin(self.run()).await?;
+        if let Err(e) = cache.put(&key, &result) {
+            debug!(target: &target, "Failed to cache result: {e}");
+        }
+        Ok(result)
+    }
+
+    /// Starts the progress job for [`with_progress`](Self::with_progress).
+    #[cfg(feature = "progress")]
+    fn start_progress(&self) -> Result<Arc<ProgressJob>> {
+        let message = match &self.progress_message {
+            Some(message) => message.clone(),
+            None => self
+                .style
+                .truncate(&self.redacted_command_line()?)
+                .into_owned(),
+        };
+        let body = match self.progress_parser {
+            Some(_) => concat!(
+                "{{ spinner() }} {{ message }} ",
+                "{% if total %}{{ progress_bar(flex=true) }} {{ percentage() }}",
+                "{% else %}{{ ensembler_stdout | flex | dim }}{% endif %}"
+            ),
+            None => "{{ spinner() }} {{ message }} {{ ensembler_stdout | flex | dim }}",
+        };
+        let job = progress::ProgressJobBuilder::new()
+            .body(body)
+            .prop("message", &message)
+            .prop("ensembler_stdout", "");
+        Ok(match &self.progress_parent {
+            Some(parent) => parent.add(job.build()),
+            None => job.start(),
+        })
+    }
+
+    /// Runs the command and records how long it took in `timings`.
+    async fn execute_timed(mut self, timings: crate::Timings) -> Result<CmdResult> {
+        let signature = self.redacted_command_line()?;
+        if let Some(reporter) = &self.reporter {
+            self.expected = timings.expected(&signature);
+            if let Some(expected) = self.expected {
+                let expected = crate::timings::format_duration(expected);
+                reporter.prop("ensembler_expected", &expected);
+            }
+        }
+        let target = self.log_target();
+        let result = Box::pin(self.run()).await?;
+        if let Err(e) = timings.record_result(&signature, &result) {
+            debug!(target: &target, "Failed to record timing: {e}");
+        }
+        Ok(result)
+    }
+
+    /// Returns the log target for this command's messages, e.g.
+    /// `ensembler::cmd::git`, so logging can be enabled for one program with
+    /// `RUST_LOG=ensembler::cmd::git=trace`.
+    fn log_target(&self) -> String {
+        let program = Path::new(&self.program)
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_else(|| self.program.as_str().into());
+        format!("{}::{program}", module_path!())
+    }
+
+    /// Returns the command line with redactions applied, which is also the
+    /// key timings are stored under.
+    fn redacted_command_line(&self) -> Result<String> {
+        let line = self.quoted_command_line();
+        Ok(match self.redactor()? {
+            Some(redactor) => redactor.redact(&line),
+            None => line,
+        })
+    }
+
+    /// Returns the command line as shown in progress jobs: redacted and
+    /// shortened to the style's maximum width.
+    fn redacted_display(&self, redactor: Option<&Redactor>) -> String {
+        let line = self.to_string();
+        let line = match redactor {
+            Some(redactor) => redactor.redact(&line),
+            None => line,
+        };
+        self.style.truncate(&line).into_owned()
+    }
+
+    /// Runs the command unless its outputs are up to date.
+    async fn execute_fresh(self, freshness: crate::Freshness) -> Result<CmdResult> {
+        let cwd = match &self.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?,
+        };
+        let command = self
+            .wrappers
+            .iter()
+            .flatten()
+            .map(OsString::as_os_str)
+            .chain([OsStr::new(&self.program)])
+            .chain(self.args.iter().map(Arg::as_os_str));
+        let key = freshness.key(command, &cwd)?;
+        if freshness.is_fresh(&cwd, key.as_deref())? {
+            debug!(target: &self.log_target(), "$ {self} (up to date)");
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Done);
+            }
+            return Ok(CmdResult {
+                status: crate::executor::exit_status(0),
+                up_to_date: true,
+                labels: self.labels.clone(),
+                ..Default::default()
+            });
+        }
+        let target = self.log_target();
+        let result = Box::pin(self.run()).await?;
+        if let Err(e) = freshness.record(&cwd, key.as_deref()) {
+            debug!(target: &target, "Failed to write freshness stamp: {e}");
+        }
+        Ok(result)
+    }
+
+    /// Starts the command as a local child process.
+    fn spawn_local(&mut self) -> Result<LocalProcess> {
+        #[cfg(target_os = "linux")]
+        if let Some(sandbox) = &self.sandbox {
+            self.pre_exec.sandbox = Some(sandbox.prepare()?);
+        }
+
+        let mut cmd = self.build_command()?;
+
+        // Put the child in its own process group so we can kill the entire
+        // tree on timeout/cancellation (not just the direct child).
+        #[cfg(unix)]
+        cmd.process_group(0);
+        #[cfg(unix)]
+        if !self.pre_exec.is_empty() {
+            let pre_exec = self.pre_exec.clone();
+            // SAFETY: `PreExec::apply` only makes async-signal-safe syscalls.
+            unsafe {
+                cmd.pre_exec(move || pre_exec.apply());
+            }
+        }
+
+        let mut cp = match cmd.spawn() {
+            Ok(cp) => cp,
+            #[cfg(unix)]
+            Err(e) if e.raw_os_error() == Some(nix::libc::EPERM) => {
+                return Err(match &self.pre_exec.chroot {
+                    Some(chroot) => std::io::Error::new(
+                        e.kind(),
+                        format!(
+                            "failed to chroot to {}: {e} (requires root or CAP_SYS_CHROOT)",
+                            chroot.root.display()
+                        ),
+                    )
+                    .into(),
+                    None => e.into(),
+                });
+            }
+            Err(e) => return Err(self.spawn_error(cmd.as_std(), e)),
+        };
+        let id = match cp.id() {
+            Some(id) => id,
+            None => {
+                let _ = cp.start_kill();
+                return Err(crate::Error::Internal("process has no id".to_string()));
+            }
+        };
+        if let Err(e) = RUNNING_PIDS
+            .lock()
+            .map(|mut pids| pids.insert(id))
+            .map_err(|e| e.to_string())
+        {
+            let _ = cp.start_kill();
+            return Err(crate::Error::Internal(format!(
+                "failed to lock RUNNING_PIDS: {e}"
+            )));
+        }
+        // Put the child in a job object so the entire tree can be killed, and
+        // dies with us if we crash.
+        #[cfg(windows)]
+        let job = crate::job::track(&cp, id);
+        #[cfg(windows)]
+        if let Some(mask) = self.cpu_affinity {
+            if let Err(e) = crate::job::set_affinity(&cp, mask) {
+                debug!(target: &self.log_target(), "Failed to set CPU affinity of pid {id}: {e}");
+            }
+        }
+        Ok(LocalProcess {
+            child: cp,
+            id,
+            usage: None,
+            killed: false,
+            #[cfg(windows)]
+            job,
+            #[cfg(target_os = "linux")]
+            unit: self.systemd_unit.take(),
+        })
+    }
+
+    /// Describes the command for an [`Executor`](crate::Executor).
+    fn invocation(&self) -> Invocation {
+        let mut invocation = self.unwrapped_invocation();
+        let mut words = self.wrappers.iter().flatten().cloned();
+        if let Some(wrapper) = words.next() {
+            let program = std::mem::replace(
+                &mut invocation.program,
+                wrapper.to_string_lossy().into_owned(),
+            );
+            invocation.args.splice(0..0, words.chain([program.into()]));
+        }
+        invocation
+    }
+
+    /// Describes the command for [`hooks`](crate::hooks).
+    fn cmd_spec(&self) -> Result<crate::hooks::CmdSpec> {
+        let redactor = self.redactor()?;
+        let redact = |s: &OsStr| redact_lossy(redactor.as_deref(), s);
+        Ok(crate::hooks::CmdSpec {
+            program: self.program.clone(),
+            args: self.args.iter().map(|a| redact(a.as_os_str())).collect(),
+            command_line: redact(self.quoted_command_line().as_ref()),
+            cwd: self.cwd.clone(),
+            labels: self.labels.clone(),
+        })
+    }
+
+    /// Describes the command without its wrappers.
+    fn unwrapped_invocation(&self) -> Invocation {
+        Invocation {
+            program: self.program.clone(),
+            args: self
+                .args
+                .iter()
+                .map(|a| a.as_os_str().to_os_string())
+                .collect(),
+            env_base: self.env_base.clone(),
+            envs: self.envs.clone(),
+            cwd: self.cwd.clone(),
+            stdin: self.stdin.is_some(),
+            redactor: None,
+        }
+    }
+
+    /// Replaces the current process with the command, like `exec` in a shell,
+    /// for shims that hand off to another program.
+    ///
+    /// The command gets the configured program, args, environment and working
+    /// directory, and inherits this process's stdin, stdout and stderr. This
+    /// only returns if the command couldn't be started, with redactions
+    /// applied to the error. Output handling, timeouts and cancellation don't
+    /// apply.
+    ///
+    /// Fails without running the command if it needs temp files, which
+    /// couldn't be removed afterwards: with [`temp_script`](Self::temp_script),
+    /// for a [`ScriptRunner`](crate::ScriptRunner) script, or for escalation
+    /// that passes a password or env vars through a file.
+    ///
+    /// Windows can't replace a running process, so there the command runs to
+    /// completion and this process then exits with its exit code.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use ensembler::CmdLineRunner;
+    ///
+    /// let err = CmdLineRunner::new("node")
+    ///     .args(std::env::args_os().skip(1))
+    ///     .env("NODE_OPTIONS", "--enable-source-maps")
+    ///     .exec();
+    /// eprintln!("{err}");
+    /// std::process::exit(127);
+    /// ```
+    pub fn exec(mut self) -> crate::Error {
+        debug!(target: &self.log_target(), "$ exec {self}");
+        let e = match self.try_exec() {
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+        let msg = match &self.context {
+            Some(context) => format!("{context}: failed to exec {}: {e}", self.program),
+            None => format!("failed to exec {}: {e}", self.program),
+        };
+        let msg = match self.redactor() {
+            Ok(Some(redactor)) => redactor.redact(&msg),
+            Ok(None) => msg,
+            Err(e) => return e,
+        };
+        let kind = match &e {
+            crate::Error::Io(e) => e.kind(),
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, msg).into()
+    }
+
+    fn try_exec(&mut self) -> Result<std::convert::Infallible> {
+        self.check_policy()?;
+        #[cfg(target_os = "linux")]
+        if let Some(sandbox) = &self.sandbox {
+            self.pre_exec.sandbox = Some(sandbox.prepare()?);
+        }
+        let mut cmd = self.build_command()?;
+        if !self.temp_files.is_empty() {
+            // they're removed on drop, which never comes once replaced
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "exec can't be used with temp_script, script files or escalation \
+                 that needs temp files, as they'd be left behind",
+            )
+            .into());
+        }
+        if let Some(log) = crate::audit::installed() {
+            log.write(self.audit_start(true)?)?;
+        }
+        let cmd = cmd.as_std_mut();
+        cmd.stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            if !self.pre_exec.is_empty() {
+                let pre_exec = self.pre_exec.clone();
+                // SAFETY: `PreExec::apply` only makes async-signal-safe syscalls.
+                unsafe {
+                    cmd.pre_exec(move || pre_exec.apply());
+                }
+            }
+            let e = cmd.exec();
+            Err(self.current_dir_error(cmd).unwrap_or(e.into()))
+        }
+        #[cfg(windows)]
+        {
+            let status = cmd
+                .status()
+                .map_err(|e| self.current_dir_error(cmd).unwrap_or(e.into()))?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
+
+    /// Builds the matcher for [`redact`](Self::redact)ed strings, if any.
+    fn redactor(&self) -> Result<Option<Arc<Redactor>>> {
+        if self.redactions.is_empty() {
+            return Ok(None);
+        }
+        // Aho-Corasick matches all patterns in a single pass
+        let automaton = AhoCorasick::new(self.redactions.iter()).map_err(|e| {
+            crate::Error::Internal(format!("failed to build redaction matcher: {e}"))
+        })?;
+        let replacements = vec![self.style.redacted.clone(); self.redactions.len()];
+        Ok(Some(Arc::new(Redactor {
+            automaton,
+            replacements,
+        })))
+    }
+
+    /// Explains why spawning `cmd` failed with `e`:
+    /// [`Error::CurrentDirNotFound`](crate::Error::CurrentDirNotFound) if its
+    /// working directory is missing,
+    /// [`Error::ProgramNotFound`](crate::Error::ProgramNotFound) if the program
+    /// doesn't exist, or `e` if it failed for another reason, such as a
+    /// missing script interpreter.
+    fn spawn_error(&self, cmd: &std::process::Command, e: std::io::Error) -> crate::Error {
+        if let Some(e) = self.current_dir_error(cmd) {
+            return e;
+        }
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return e.into();
+        }
+        let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+        let program = cmd.get_program();
+        match which::which_in(program, path.as_deref(), cmd.get_current_dir()) {
+            Some(_) => e.into(),
+            None => which::not_found(&program.to_string_lossy(), path.as_deref()),
+        }
+    }
+
+    /// Returns [`Error::CurrentDirNotFound`](crate::Error::CurrentDirNotFound)
+    /// if `cmd`'s working directory doesn't exist or isn't a directory.
+    fn current_dir_error(&self, cmd: &std::process::Command) -> Option<crate::Error> {
+        let dir = cmd.get_current_dir().filter(|dir| !dir.is_dir())?;
+        Some(crate::Error::CurrentDirNotFound(
+            self.program.clone(),
+            dir.to_path_buf(),
+        ))
+    }
+
+    /// Keeps `file` alive until the runner is dropped.
+    pub(crate) fn with_temp_file(mut self, file: TempFile) -> Self {
+        self.temp_files.push(file);
+        self
+    }
+
+    /// Builds the underlying [`Command`] from the configured program, args,
+    /// environment, working directory and stdio.
+    fn build_command(&mut self) -> Result<Command> {
+        self.check_launchers()?;
+        if self.expand_env {
+            self.expand_env_vars();
+        }
+        let mut program = PathBuf::from(&self.program);
+        let mut shell_wrap = self.shell_wrap;
+        if self.resolve_program && self.wsl.is_none() {
+            let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+            program = which::which_in(program.as_os_str(), path.as_deref(), self.cwd.as_deref())
+                .ok_or_else(|| which::not_found(&self.program, path.as_deref()))?;
+            shell_wrap = shell_wrap && which::needs_cmd_exe(&program);
+        } else if cfg!(windows) && shell_wrap && self.wsl.is_none() {
+            // only fall back to cmd.exe for batch files and builtins
+            let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+            if let Some(found) =
+                which::which_in(program.as_os_str(), path.as_deref(), self.cwd.as_deref())
+            {
+                shell_wrap = which::needs_cmd_exe(&found);
+                program = found;
+            }
+        }
+        // cmd.exe can't start in a UNC directory, so it has to `pushd` there
+        // from a script
+        let cwd_in_script = cfg!(windows) && self.unc_cwd().is_some() && self.wsl.is_none();
+        let temp_script = self.temp_script || (cwd_in_script && shell_wrap);
+        let cwd_in_script = cwd_in_script && temp_script;
+        let bwrap_args = self.bwrap_args();
+        #[cfg(target_os = "linux")]
+        if let Some(systemd_run) = &self.systemd_run {
+            let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+            let (unit, args) = systemd_run.unit(path);
+            // outside the other wrappers, so the scope covers them too
+            self.wrappers.insert(0, args);
+            self.systemd_unit = Some(unit);
+        }
+        let mut cmd = if let Some(distro) = &self.wsl {
+            let mut cmd = self.new_command("wsl.exe");
+            if let Some(distro) = distro {
+                cmd.args(["-d", distro]);
+            }
+            if let Some(cwd) = &self.cwd {
+                cmd.arg("--cd");
+                match wsl::to_wsl_path(cwd) {
+                    Some(cwd) => cmd.arg(cwd),
+                    None => cmd.arg(cwd),
+                };
+            }
+            cmd.arg("--exec").arg(&self.program);
+            cmd.args(self.args.iter().map(Arg::as_os_str));
+            cmd
+        } else if let Some(bwrap_args) = &bwrap_args {
+            let mut cmd = self.new_command("bwrap");
+            cmd.args(bwrap_args).arg(&program);
+            cmd.args(self.args.iter().map(Arg::as_os_str));
+            cmd
+        } else if let Some(cmd) = self.escalate_command(&program)? {
+            cmd
+        } else if temp_script {
+            let file = self.write_temp_script(&program)?;
+            let mut cmd = if is_powershell_script(&program) {
+                let mut cmd = self.new_command(Shell::PowerShell.program());
+                cmd.args(Shell::PowerShell.file_args());
+                cmd
+            } else if cfg!(windows) {
+                let mut cmd = self.new_command(Shell::Cmd.program());
+                cmd.args(Shell::Cmd.file_args());
+                cmd
+            } else {
+                self.new_command("sh")
+            };
+            cmd.arg(file.path());
+            self.temp_files.push(file);
+            cmd
+        } else if cfg!(windows) && shell_wrap {
+            self.cmd_exe_command(&program)
+        } else {
+            let mut cmd = self.new_command(&program);
+            for arg in &self.args {
+                match arg {
+                    #[cfg(windows)]
+                    Arg::Raw(arg) => {
+                        use std::os::windows::process::CommandExt;
+                        cmd.as_std_mut().raw_arg(arg);
+                    }
+                    arg => {
+                        cmd.arg(arg.as_os_str());
+                    }
+                }
+            }
+            cmd
+        };
+        if let Some(base) = &self.env_base {
+            cmd.env_clear();
+            cmd.envs(base.iter());
+        }
+        cmd.envs(&self.envs);
+        if self.wsl.is_some() {
+            if !self.envs.is_empty() {
+                let existing = env::child_var("WSLENV", self.env_base.as_ref(), &self.envs);
+                let keys = self.envs.keys().map(OsString::as_os_str);
+                cmd.env("WSLENV", wsl::wslenv(existing.as_deref(), keys));
+            }
+        } else if let Some(cwd) = self
+            .cwd
+            .as_deref()
+            .filter(|_| !cwd_in_script && bwrap_args.is_none())
+        {
+            #[cfg(unix)]
+            if let Some(chroot) = &mut self.pre_exec.chroot {
+                // changed to after entering the new root
+                chroot.set_cwd(cwd);
+            } else {
+                cmd.current_dir(cwd);
+            }
+            #[cfg(windows)]
+            cmd.current_dir(crate::winpath::child_cwd(cwd));
+        }
+        #[cfg(windows)]
+        {
+            let mut flags = self.creation_flags;
+            if self.grace_period.is_some() {
+                // CTRL_BREAK_EVENT can only target a process group
+                flags |= CREATE_NEW_PROCESS_GROUP;
+            }
+            if flags != 0 {
+                cmd.creation_flags(flags);
+            }
+        }
+        cmd.stdin(self.stdin_cfg.take().unwrap_or_else(Stdio::null));
+        cmd.stdout(self.stdout_cfg.take().unwrap_or_else(Stdio::piped));
+        cmd.stderr(self.stderr_cfg.take().unwrap_or_else(Stdio::piped));
+        Ok(cmd)
+    }
+
+    /// Wraps the command in `cmd.exe /d /s /c "..."`, quoting each arg for the
+    /// program and escaping `cmd.exe` metacharacters so args reach it intact.
+    fn cmd_exe_command(&self, program: &Path) -> Command {
+        #[cfg(windows)]
+        let program = &crate::winpath::strip_verbatim(program);
+        let program = program.to_string_lossy();
+        let mut line = if program.contains([' ', '\t']) {
+            format!("\"{program}\"")
+        } else {
+            shell_words::escape_cmd(&program).into_owned()
+        };
+        for arg in &self.args {
+            line.push(' ');
+            match arg {
+                Arg::Regular(arg) => line.push_str(&shell_words::quote_cmd(&arg.to_string_lossy())),
+                Arg::Raw(arg) => line.push_str(&arg.to_string_lossy()),
+            }
+        }
+        let mut cmd = self.new_command("cmd.exe");
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            cmd.as_std_mut().raw_arg(format!("/d /s /c \"{line}\""));
+        }
+        #[cfg(not(windows))]
+        cmd.args(["/d", "/s", "/c", &line]);
+        cmd
+    }
+
+    /// Writes the command line to a temp script for [`temp_script`](Self::temp_script).
+    fn write_temp_script(&self, program: &Path) -> Result<TempFile> {
+        #[cfg(windows)]
+        let program = &crate::winpath::strip_verbatim(program);
+        let ps1 = is_powershell_script(program);
+        let program = program.to_string_lossy();
+        if ps1 {
+            // Windows PowerShell reads scripts without a BOM as ANSI
+            let mut script = String::from("\u{feff}");
+            if let Some(cwd) = self.unc_cwd() {
+                let cwd = Shell::PowerShell.quote(&cwd.to_string_lossy()).into_owned();
+                script += &format!("Set-Location -LiteralPath {cwd}\r\n");
+            }
+            script += &format!("& {}", Shell::PowerShell.quote(&program));
+            for arg in &self.args {
+                script.push(' ');
+                match arg {
+                    Arg::Regular(arg) => {
+                        script += &Shell::PowerShell.quote(&arg.to_string_lossy());
+                    }
+                    Arg::Raw(arg) => script += &arg.to_string_lossy(),
+                }
+            }
+            script += "\r\nexit $LASTEXITCODE\r\n";
+            Ok(TempFile::create("ps1", script.as_bytes(), false)?)
+        } else if cfg!(windows) {
+            let line = self.batch_line(&program);
+            let script = match self.unc_cwd() {
+                Some(cwd) => format!(
+                    "@echo off\r\npushd {} || exit /b 1\r\n{line}\r\nset ENSEMBLER_EXIT=%ERRORLEVEL%\r\npopd\r\nexit /b %ENSEMBLER_EXIT%\r\n",
+                    shell_words::quote_batch(&cwd.to_string_lossy())
+                ),
+                None => format!("@echo off\r\n{line}\r\nexit /b %ERRORLEVEL%\r\n"),
+            };
+            Ok(TempFile::create("cmd", script.as_bytes(), false)?)
+        } else {
+            let mut line = format!("exec {}", shell_words::quote(&program));
+            for arg in &self.args {
+                line.push(' ');
+                line.push_str(&shell_words::quote(&arg.as_os_str().to_string_lossy()));
+            }
+            Ok(TempFile::create(
+                "sh",
+                format!("{line}\n").as_bytes(),
+                false,
+            )?)
+        }
+    }
+
+    /// Renders the command as a line in a batch (`.cmd`) file.
+    fn batch_line(&self, program: &str) -> String {
+        let mut line = shell_words::quote_batch(program).to_string();
+        for arg in &self.args {
+            line.push(' ');
+            match arg {
+                Arg::Regular(arg) => {
+                    line.push_str(&shell_words::quote_batch(&arg.to_string_lossy()))
+                }
+                Arg::Raw(arg) => line.push_str(&arg.to_string_lossy().replace('%', "%%")),
+            }
+        }
+        line
+    }
+
+    /// Runs the command elevated; see [`elevated`](Self::elevated).
+    #[cfg(windows)]
+    async fn execute_elevated(mut self, redactor: Option<&Redactor>) -> Result<CmdResult> {
+        use std::os::windows::process::ExitStatusExt;
+
+        if self.expand_env {
+            self.expand_env_vars();
+        }
+        let path = env::child_var("PATH", self.env_base.as_ref(), &self.envs);
+        let program = match which::which_in(
+            OsStr::new(&self.program),
+            path.as_deref(),
+            self.cwd.as_deref(),
+        ) {
+            Some(program) => crate::winpath::strip_verbatim(&program),
+            None if self.resolve_program => {
+                return Err(which::not_found(&self.program, path.as_deref()))
+            }
+            None => PathBuf::from(&self.program),
+        };
+        let quote = |path: &Path| shell_words::quote_batch(&path.to_string_lossy()).into_owned();
+        let stdin = TempFile::create(
+            "in",
+            self.stdin.take().unwrap_or_default().as_bytes(),
+            false,
+        )?;
+        let stdout = TempFile::create("out", b"", false)?;
+        let stderr = TempFile::create("err", b"", false)?;
+        let mut script = String::from("@echo off\r\n");
+        if let Some(cwd) = &self.cwd {
+            script += &format!("pushd {} || exit /b 1\r\n", quote(cwd.as_path()));
+        }
+        for (key, val) in &self.envs {
+            let escape = |s: &OsStr| s.to_string_lossy().replace('%', "%%");
+            script += &format!(
+                "set \"{}={}\"\r\n",
+                escape(key.as_os_str()),
+                escape(val.as_os_str())
+            );
+        }
+        script += &format!(
+            "{} < {} > {} 2> {}\r\nexit /b %ERRORLEVEL%\r\n",
+            self.batch_line(&program.to_string_lossy()),
+            quote(stdin.path()),
+            quote(stdout.path()),
+            quote(stderr.path()),
+        );
+        let script = TempFile::create("cmd", script.as_bytes(), false)?;
+
+        let process = crate::elevate::spawn(script.path())?;
+        let (started_at, start) = (SystemTime::now(), std::time::Instant::now());
+        if let Some(reporter) = &self.reporter {
+            reporter.prop("ensembler_cmd", &self.redacted_display(redactor.as_deref()));
+            reporter.set_status(ProgressState::Running);
+        }
+        let deadline = self.timeout.map(|t| tokio::time::Instant::now() + t);
+        let code = loop {
+            if let Some(code) = process.try_wait()? {
+                break code;
+            }
+            let error = if self.cancel.is_cancelled() {
+                Some(crate::Error::Cancelled)
+            } else if deadline.is_some_and(|d| tokio::time::Instant::now() >= d) {
+                let elapsed = start.elapsed();
+                // the output is only read once the command exits
+                let partial = CmdResult {
+                    status: ExitStatus::from_raw(1),
+                    started_at: Some(started_at),
+                    finished_at: Some(started_at + elapsed),
+                    duration: elapsed,
+                    labels: self.labels.clone(),
+                    ..Default::default()
+                };
+                Some(crate::Error::TimedOut {
+                    elapsed,
+                    partial: Box::new(partial),
+                })
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                if let Err(e) = process.kill() {
+                    debug!(target: &self.log_target(), "Failed to kill elevated process: {e}");
+                }
+                if let Some(reporter) = &self.reporter {
+                    reporter.set_status(ProgressState::Failed);
+                }
+                return Err(error);
+            }
+            select! {
+                _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+                _ = self.cancel.cancelled() => {}
+            }
+        };
+
+        let read = |file: &TempFile| -> Result<Vec<String>> {
+            let bytes = std::fs::read(file.path())?;
+            Ok(bytes
+                .split_inclusive(|b| *b == b'\n')
+                .map(|line| {
+                    let line = self.encoding.decode_line(line);
+                    match redactor {
+                        Some(r) => r.redact(&line),
+                        None => line,
+                    }
+                })
+                .collect())
+        };
+        let duration = start.elapsed();
+        let (out, err) = (read(&stdout)?, read(&stderr)?);
+        let join = |lines: &[String]| lines.iter().map(|l| format!("{l}\n")).collect::<String>();
+        let result = CmdResult {
+            stdout: join(&out),
+            stderr: join(&err),
+            combined_output: join(&[out.as_slice(), err.as_slice()].concat()),
+            status: ExitStatus::from_raw(code),
+            peak_memory: None,
+            up_to_date: false,
+            started_at: Some(started_at),
+            finished_at: Some(started_at + duration),
+            duration,
+            // the output is read from files once the command exits
+            first_output: None,
+            longest_stall: duration,
+            labels: self.labels.clone(),
+            resource_usage: None,
+        };
+        if self.is_success(&result.status) {
+            if let Some(reporter) = &self.reporter {
+                reporter.set_status(ProgressState::Done);
+            }
+        } else {
+            self.on_error([out, err].concat().join("\n"), result.clone())?;
+        }
+        Ok(result)
+    }
+
+    /// Returns a command that runs `program` inside any
+    /// [`wrap`](Self::wrap) wrappers.
+    fn new_command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut words = self.wrappers.iter().flatten();
+        match words.next() {
+            Some(wrapper) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.args(words).arg(program);
+                cmd
+            }
+            None => Command::new(program),
+        }
+    }
+
+    /// Returns the command wrapped in `sudo` or `doas`, if it's run with
+    /// [`escalate`](Self::escalate).
+    fn escalate_command(&mut self, program: &Path) -> Result<Option<Command>> {
+        #[cfg(unix)]
+        if let Some(escalation) = &self.escalation {
+            let escalated = escalation.command_args(&self.envs, |key| {
+                env::child_var(key, self.env_base.as_ref(), &IndexMap::new())
+            })?;
+            let mut cmd = self.new_command(escalation.program());
+            cmd.args(escalated.args).arg(program);
+            cmd.args(self.args.iter().map(Arg::as_os_str));
+            if let Some(askpass) = escalated.askpass {
+                cmd.env("SUDO_ASKPASS", askpass.path());
+                self.temp_files.push(askpass);
+            }
+            self.temp_files.extend(escalated.env_file);
+            return Ok(Some(cmd));
+        }
+        let _ = program;
+        Ok(None)
+    }
+
+    /// Returns the options set that each run the command through another
+    /// program, of which [`build_command`](Self::build_command) applies one.
+    fn launchers(&self) -> Vec<&'static str> {
+        let mut launchers = vec![];
+        if self.wsl.is_some() {
+            launchers.push("wsl");
+        }
+        #[cfg(target_os = "linux")]
+        if self.bwrap.is_some() {
+            launchers.push("bwrap");
+        }
+        #[cfg(unix)]
+        if self.escalation.is_some() {
+            launchers.push("escalate");
+        }
+        if self.temp_script {
+            launchers.push("temp_script");
+        }
+        launchers
+    }
+
+    /// Fails if more than one [`launcher`](Self::launchers) is set, rather
+    /// than running the command without all but one of them.
+    fn check_launchers(&self) -> std::io::Result<()> {
+        match self.launchers().as_slice() {
+            [first, second, ..] => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{first} and {second} can't be combined"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the options set that confine or change the identity of a
+    /// local process, which an [`executor`](Self::executor) can't apply.
+    fn local_confinement(&self) -> Vec<&'static str> {
+        #[allow(unused_mut)]
+        let mut options = vec![];
+        #[cfg(unix)]
+        {
+            let pre_exec = &self.pre_exec;
+            for (name, set) in [
+                ("uid", pre_exec.uid.is_some()),
+                ("gid", pre_exec.gid.is_some()),
+                ("groups", pre_exec.groups.is_some()),
+                ("chroot", pre_exec.chroot.is_some()),
+                ("umask", pre_exec.umask.is_some()),
+                ("limit_memory", pre_exec.memory_limit.is_some()),
+                ("limit_cpu_time", pre_exec.cpu_time_limit.is_some()),
+                ("limit_open_files", pre_exec.open_files_limit.is_some()),
+                ("escalate", self.escalation.is_some()),
+            ] {
+                if set {
+                    options.push(name);
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        for (name, set) in [
+            ("cgroup", self.cgroup.is_some()),
+            ("sandbox", self.sandbox.is_some()),
+            ("bwrap", self.bwrap.is_some()),
+            ("systemd_run", self.systemd_run.is_some()),
+        ] {
+            if set {
+                options.push(name);
+            }
+        }
+        options
+    }
+
+    /// Fails if an [`executor`](Self::executor) is set along with an option
+    /// that confines the local process, rather than running the command
+    /// without it.
+    fn check_executor(&self) -> std::io::Result<()> {
+        match self.local_confinement().first() {
+            Some(option) if self.executor.is_some() => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{option} can't be used with an executor"),
+            )),
+            _ => Ok(()),
         }
-        self
     }
 
-    /// Adds a single argument to the command.
-    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
-        self.cmd.arg(arg.as_ref());
-        self.args.push(arg.as_ref().to_string_lossy().to_string());
-        self
+    /// Returns the bwrap options to run the command with, if it's sandboxed
+    /// with [`bwrap`](Self::bwrap).
+    fn bwrap_args(&self) -> Option<Vec<OsString>> {
+        #[cfg(target_os = "linux")]
+        return self.bwrap.as_ref().map(|bwrap| {
+            let home = env::child_var("HOME", self.env_base.as_ref(), &self.envs);
+            bwrap.command_args(home.as_deref().map(Path::new), self.cwd.as_deref())
+        });
+        #[cfg(not(target_os = "linux"))]
+        None
     }
 
-    /// Append a raw, unescaped fragment to the command line on Windows.
-    ///
-    /// Rust's `Command::arg` applies MSVCRT-style argv escaping on Windows,
-    /// which collides with `cmd.exe`'s own parsing rules and mangles strings
-    /// that already contain cmd-appropriate quoting. This method uses
-    /// `CommandExt::raw_arg` to append the string verbatim so shell-quoted
-    /// payloads (like rendered `{{files}}` values) survive intact.
-    ///
-    /// On non-Windows platforms this falls back to a regular `arg`.
-    #[allow(unused_mut)]
-    pub fn raw_arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
-        let s = arg.as_ref().to_string_lossy().to_string();
+    /// Returns the working directory if it is a UNC path on Windows.
+    fn unc_cwd(&self) -> Option<PathBuf> {
         #[cfg(windows)]
-        {
-            use std::os::windows::process::CommandExt;
-            self.cmd.as_std_mut().raw_arg(&s);
-        }
+        return self
+            .cwd
+            .as_deref()
+            .filter(|cwd| crate::winpath::is_unc(cwd))
+            .map(crate::winpath::strip_verbatim);
         #[cfg(not(windows))]
-        {
-            self.cmd.arg(arg.as_ref());
+        None
+    }
+
+    /// Expands `${VAR}`/`%VAR%` references in args and the working directory
+    /// using the child's environment.
+    fn expand_env_vars(&mut self) {
+        (self.args, self.cwd) = self.expanded_env_vars();
+    }
+
+    /// Returns the args and working directory with `${VAR}`/`%VAR%`
+    /// references expanded, as [`expand_env_vars`](Self::expand_env_vars)
+    /// would leave them.
+    fn expanded_env_vars(&self) -> (Vec<Arg>, Option<PathBuf>) {
+        let lookup = |key: &str| {
+            env::child_var(key, self.env_base.as_ref(), &self.envs)
+                .map(|v| v.to_string_lossy().to_string())
+        };
+        let expand_os = |s: &OsStr| match s.to_str() {
+            Some(s) => OsString::from(env::expand(s, lookup)),
+            None => s.to_os_string(),
+        };
+        let args = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Arg::Regular(s) => Arg::Regular(expand_os(s)),
+                Arg::Raw(s) => Arg::Raw(expand_os(s)),
+            })
+            .collect();
+        let cwd = self
+            .cwd
+            .as_deref()
+            .map(|cwd| PathBuf::from(expand_os(cwd.as_os_str())));
+        (args, cwd)
+    }
+
+    /// Prints `line` above the progress display, or to stderr without one,
+    /// for [`verbosity`](Self::verbosity).
+    fn echo(&self, line: &str) {
+        echo(self.reporter.as_deref(), line);
+    }
+
+    /// Prints how the command exited, at [`Verbosity::Trace`], and emits it
+    /// as a `tracing` event.
+    fn trace_exit(&self, outcome: &str, stopwatch: &crate::timings::Stopwatch) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            outcome,
+            elapsed_ms = stopwatch.elapsed().as_millis() as u64,
+            "exited"
+        );
+        if self.verbosity >= Verbosity::Trace {
+            let (elapsed, _) = stopwatch.props();
+            self.echo(&format!("  {outcome} after {elapsed}"));
         }
-        self.args.push(s);
-        self
     }
 
-    /// Adds multiple arguments to the command.
-    pub fn args<I, S>(mut self, args: I) -> Self
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
-    {
-        let args = args
+    /// Describes how the child's environment differs from the parent's.
+    fn env_diff_lines(&self, redactor: Option<&Redactor>) -> Vec<String> {
+        let render = |key: &str, val: &str| {
+            if env::is_secret_key(key) {
+                self.style.redacted.clone()
+            } else if let Some(r) = redactor {
+                r.redact(val)
+            } else {
+                val.to_string()
+            }
+        };
+        env::diff(self.env_base.as_ref(), &self.envs)
             .into_iter()
-            .map(|s| s.as_ref().to_string_lossy().to_string())
-            .collect::<Vec<_>>();
-        self.cmd.args(&args);
-        self.args.extend(args);
-        self
+            .map(|change| match change {
+                env::EnvChange::Added(k, v) => format!("  env +{k}={}", render(&k, &v)),
+                env::EnvChange::Changed(k, old, new) => {
+                    format!("  env ~{k}={} (was {})", render(&k, &new), render(&k, &old))
+                }
+                env::EnvChange::Removed(k) => format!("  env -{k}"),
+            })
+            .collect()
     }
 
-    /// Pipes a string to the command's stdin.
-    ///
-    /// This automatically configures stdin to be piped.
-    pub fn stdin_string(mut self, input: impl Into<String>) -> Self {
-        self.cmd.stdin(Stdio::piped());
-        self.stdin = Some(input.into());
-        self
+    /// Returns whether the command succeeded with `status`, allowing for
+    /// [`allow_non_zero`](Self::allow_non_zero) and
+    /// [`success_codes`](Self::success_codes).
+    fn is_success(&self, status: &ExitStatus) -> bool {
+        match status.code() {
+            _ if self.allow_non_zero => true,
+            Some(code) if !self.success_codes.is_empty() => self.success_codes.contains(&code),
+            _ => status.success(),
+        }
     }
 
-    /// Executes the command and waits for it to complete.
-    ///
-    /// Returns [`CmdResult`] containing captured stdout, stderr, and exit status
-    /// on success. Returns an error if the command fails to start or exits with
-    /// a non-zero status.
-    ///
-    /// # Errors
-    ///
-    /// - [`Error::Io`] if the command fails to start
-    /// - [`Error::ScriptFailed`] if the command exits with a non-zero status
-    pub async fn execute(mut self) -> Result<CmdResult> {
-        debug!("$ {self}");
+    /// Returns the args as displayed, with redactions applied.
+    fn display_args(&self) -> Result<Vec<String>> {
+        let redactor = self.redactor()?;
+        Ok(self
+            .args
+            .iter()
+            .map(|arg| redact_lossy(redactor.as_deref(), arg.as_os_str()))
+            .collect())
+    }
 
-        // Build Aho-Corasick automaton for efficient multi-pattern redaction
-        // This is done before spawning to avoid orphan processes on build failure
-        let redactor: Option<Arc<Redactor>> = if self.redactions.is_empty() {
-            None
-        } else {
-            let automaton = AhoCorasick::new(self.redactions.iter()).map_err(|e| {
-                crate::Error::Internal(format!("failed to build redaction matcher: {e}"))
-            })?;
-            let replacements = vec!["[redacted]"; self.redactions.len()];
-            Some(Arc::new(Redactor {
-                automaton,
-                replacements,
-            }))
-        };
+    /// Joins the program and args, quoting anything the platform shell would
+    /// otherwise split or expand. Raw args are already command-line text and
+    /// are kept verbatim.
+    fn quoted_command_line(&self) -> String {
+        let shell = Shell::default();
+        let mut line = shell.quote(&self.program).into_owned();
+        for arg in &self.args {
+            line.push(' ');
+            match arg {
+                Arg::Regular(arg) => line.push_str(&shell.quote(&arg.to_string_lossy())),
+                Arg::Raw(arg) => line.push_str(&arg.to_string_lossy()),
+            }
+        }
+        line
+    }
 
-        // Put the child in its own process group so we can kill the entire
-        // tree on timeout/cancellation (not just the direct child).
-        #[cfg(unix)]
-        self.cmd.process_group(0);
+    /// Prints the output of a failed command under a header naming it, for
+    /// [`collapse_output`](Self::collapse_output).
+    fn print_expanded(&self, reporter: &dyn ProgressReporter, output: &str) -> Result<()> {
+        let header = self.style.header(&self.redacted_command_line()?);
+        reporter.println(&header);
+        reporter.println(output.trim());
+        Ok(())
+    }
 
-        let mut cp = self.cmd.spawn()?;
-        let id = match cp.id() {
-            Some(id) => id,
-            None => {
-                let _ = cp.kill().await;
-                return Err(crate::Error::Internal("process has no id".to_string()));
+    fn on_error(&self, output: String, result: CmdResult) -> Result<()> {
+        #[allow(unused_mut)]
+        let mut output = output.trim().to_string();
+        #[cfg(unix)]
+        if let Some(limit) = self.pre_exec.exceeded_limit(&result.status) {
+            if !output.is_empty() {
+                output.push('\n');
             }
+            output.push_str(&format!("{} exceeded its {limit}", self.program));
+        }
+        if let Some(reporter) = &self.reporter {
+            reporter.set_status(ProgressState::Failed);
+            match self.verbosity {
+                Verbosity::Quiet => {}
+                _ if self.collapse_output => self.print_expanded(reporter.as_ref(), &output)?,
+                // verbose output has already been printed
+                Verbosity::Normal if self.show_stderr_on_error => reporter.println(&output),
+                _ => {}
+            }
+        }
+        let annotate: Option<fn(&str, &str) -> String> = match self.ci_annotations {
+            true if env::is_github_actions() => Some(crate::github::error_annotation),
+            true if env::is_azure_pipelines() => Some(crate::azure::error_issue),
+            _ => None,
         };
-        if let Err(e) = RUNNING_PIDS
-            .lock()
-            .map(|mut pids| pids.insert(id))
-            .map_err(|e| e.to_string())
-        {
-            let _ = cp.kill().await;
-            return Err(crate::Error::Internal(format!(
-                "failed to lock RUNNING_PIDS: {e}"
-            )));
+        if let Some(annotate) = annotate {
+            let title = format!(
+                "{} failed with {}",
+                self.redacted_command_line()?,
+                crate::error::render_exit_status(&result.status)
+            );
+            let lines = output.lines().collect::<Vec<_>>();
+            let tail = lines[lines.len().saturating_sub(ANNOTATION_LINES)..].join("\n");
+            self.echo(&annotate(&title, &tail));
         }
-        trace!("Started process: {id} for {}", self.program);
-        #[cfg(feature = "progress")]
-        if let Some(pr) = &self.pr {
-            pr.prop("ensembler_cmd", &self.to_string());
-            pr.prop("ensembler_stdout", &"".to_string());
-            pr.set_status(progress::ProgressStatus::Running);
-        }
-        let result = Arc::new(Mutex::new(CmdResult::default()));
-        let combined_output = Arc::new(Mutex::new(Vec::new()));
-
-        let (stdout_flush, stdout_ready) = oneshot::channel();
-        if let Some(stdout) = cp.stdout.take() {
-            let result = result.clone();
-            let combined_output = combined_output.clone();
-            let redactor = redactor.clone();
-            #[cfg(feature = "progress")]
-            let pr = self.pr.clone();
-            tokio::spawn(async move {
-                let stdout = BufReader::new(stdout);
-                let mut lines = stdout.lines();
-                while let Ok(Some(line)) = lines.next_line().await {
-                    let line = match &redactor {
-                        Some(r) => r.automaton.replace_all(&line, &r.replacements),
-                        None => line,
-                    };
-                    let mut result = result.lock().await;
-                    result.stdout += &line;
-                    result.stdout += "\n";
-                    result.combined_output += &line;
-                    result.combined_output += "\n";
-                    #[cfg(feature = "progress")]
-                    if let Some(pr) = &pr {
-                        pr.prop("ensembler_stdout", &line);
-                        pr.update();
-                    }
-                    combined_output.lock().await.push(line);
-                }
-                let _ = stdout_flush.send(());
-            });
-        } else {
-            drop(stdout_flush);
+        Err(ScriptFailed(Box::new(ScriptFailure {
+            program: self.program.clone(),
+            args: self.display_args()?,
+            cwd: self.cwd.clone(),
+            output,
+            result,
+            context: self.context.clone(),
+            hint: self.hint.clone(),
+            message: self.failure_message.clone(),
+        })))?
+    }
+}
+
+/// Sets the position of `reporter` from `line`, if `parser` finds one in it.
+fn set_position(
+    reporter: &dyn ProgressReporter,
+    parser: Option<&crate::ProgressParser>,
+    line: &str,
+    stopwatch: &crate::timings::Stopwatch,
+) {
+    if let Some((current, total)) = parser.and_then(|p| p.parse(line)) {
+        stopwatch.set_position(current, total);
+        reporter.set_position(current, total);
+    }
+}
+
+/// Prints `line` through `reporter`, or to stderr without one.
+fn echo(reporter: Option<&dyn ProgressReporter>, line: &str) {
+    match reporter {
+        Some(reporter) => reporter.println(line),
+        None => eprintln!("{line}"),
+    }
+}
+
+/// Records how the command went on its `tracing` span.
+#[cfg(feature = "tracing")]
+fn record_outcome(span: &tracing::Span, result: &Result<CmdResult>, duration: Duration) {
+    let finished = match result {
+        Ok(result) => Some(result),
+        Err(ScriptFailed(failure)) => Some(failure.result()),
+        Err(_) => None,
+    };
+    span.record("duration_ms", duration.as_millis() as u64);
+    if let Some(finished) = finished {
+        span.record("exit_code", finished.status.code());
+        span.record("stdout_bytes", finished.stdout.len() as u64);
+        span.record("stderr_bytes", finished.stderr.len() as u64);
+    }
+    if result.is_err() {
+        span.record("otel.status_code", "ERROR");
+    }
+}
+
+/// Returns a reporter for the log format of the CI the process runs in, if
+/// it runs in one that has one.
+fn ci_reporter() -> Option<Arc<dyn ProgressReporter>> {
+    if env::is_github_actions() {
+        Some(Arc::new(crate::GithubGroups::new()))
+    } else if env::is_azure_pipelines() {
+        Some(Arc::new(crate::AzureGroups::new()))
+    } else if env::is_teamcity() {
+        Some(Arc::new(crate::TeamcityMessages::new()))
+    } else {
+        None
+    }
+}
+
+/// Whether `program` is a PowerShell script, which Windows can only run
+/// through PowerShell.
+fn is_powershell_script(program: &Path) -> bool {
+    cfg!(windows)
+        && program
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ps1"))
+}
+
+/// Prints `line` like [`echo`], after `prefix` if there is one.
+fn echo_prefixed(reporter: Option<&dyn ProgressReporter>, prefix: Option<&str>, line: &str) {
+    match prefix {
+        Some(prefix) => echo(reporter, &format!("{prefix}{line}")),
+        None => echo(reporter, line),
+    }
+}
+
+/// Sets the `ensembler_elapsed` and `ensembler_eta` props.
+fn report_elapsed(reporter: &dyn ProgressReporter, stopwatch: &crate::timings::Stopwatch) {
+    let (elapsed, eta) = stopwatch.props();
+    reporter.prop("ensembler_elapsed", &elapsed);
+    reporter.prop("ensembler_eta", &eta);
+}
+
+/// How many bytes of stdout and stderr are read at a time by default.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// One of a command's output streams, read a line at a time.
+struct LineReader {
+    reader: Option<BufReader<Pin<Box<dyn AsyncRead + Send>>>>,
+    /// The line being read, kept between reads so they can be cancelled.
+    buf: Vec<u8>,
+}
+
+impl LineReader {
+    /// Reads `reader` with a buffer of `capacity` bytes.
+    fn new(reader: Option<Pin<Box<dyn AsyncRead + Send>>>, capacity: usize) -> Self {
+        Self {
+            reader: reader.map(|r| BufReader::with_capacity(capacity, r)),
+            buf: vec![],
         }
-        let (stderr_flush, stderr_ready) = oneshot::channel();
-        if let Some(stderr) = cp.stderr.take() {
-            let result = result.clone();
-            let combined_output = combined_output.clone();
-            #[cfg(feature = "progress")]
-            let pr = self.pr.clone();
-            #[cfg(feature = "progress")]
-            let stderr_to_progress = self.stderr_to_progress;
-            tokio::spawn(async move {
-                let stderr = BufReader::new(stderr);
-                let mut lines = stderr.lines();
-                while let Ok(Some(line)) = lines.next_line().await {
-                    let line = match &redactor {
-                        Some(r) => r.automaton.replace_all(&line, &r.replacements),
-                        None => line,
-                    };
-                    let mut result = result.lock().await;
-                    result.stderr += &line;
-                    result.stderr += "\n";
-                    result.combined_output += &line;
-                    result.combined_output += "\n";
-                    #[cfg(feature = "progress")]
-                    if let Some(pr) = &pr {
-                        if stderr_to_progress {
-                            // Update progress bar like stdout does
-                            pr.prop("ensembler_stdout", &line);
-                            pr.update();
-                        } else {
-                            // Print above progress bars (current behavior)
-                            pr.println(&line);
-                        }
-                    }
-                    combined_output.lock().await.push(line);
-                }
-                let _ = stderr_flush.send(());
-            });
-        } else {
-            drop(stderr_flush);
-        }
-        let (stdin_flush, stdin_ready) = oneshot::channel();
-        if let Some(text) = self.stdin.take() {
-            let Some(mut stdin) = cp.stdin.take() else {
-                let _ = cp.kill().await;
-                if let Err(e) = RUNNING_PIDS
-                    .lock()
-                    .map(|mut pids| pids.remove(&id))
-                    .map_err(|e| e.to_string())
-                {
-                    debug!("Failed to lock RUNNING_PIDS to remove pid {id}: {e}");
-                }
-                #[cfg(feature = "progress")]
-                if let Some(pr) = &self.pr {
-                    pr.set_status(progress::ProgressStatus::Failed);
-                }
-                return Err(crate::Error::Internal(
-                    "stdin was requested but not available".to_string(),
-                ));
-            };
-            tokio::spawn(async move {
-                if let Err(e) = stdin.write_all(text.as_bytes()).await {
-                    debug!("Failed to write to stdin: {e}");
-                }
-                let _ = stdin_flush.send(());
-            });
-        } else {
-            drop(stdin_flush);
+    }
+
+    /// Reads and decodes the next line, or returns `None` once the stream
+    /// has ended. Cancel safe: a line that's partly read when the future is
+    /// dropped is finished by the next call.
+    async fn next_line(&mut self, encoding: OutputEncoding) -> Option<String> {
+        let reader = self.reader.as_mut()?;
+        let read = reader.read_until(b'\n', &mut self.buf).await;
+        if matches!(read, Ok(0) | Err(_)) && self.buf.is_empty() {
+            self.reader = None;
+            return None;
         }
+        let line = encoding.decode_line(&self.buf);
+        self.buf.clear();
+        Some(line)
+    }
+}
 
-        // Create timeout future that either sleeps or waits forever
-        let timeout_fut = async {
-            if let Some(duration) = self.timeout {
-                tokio::time::sleep(duration).await;
-            } else {
-                std::future::pending::<()>().await;
-            }
-        };
-        tokio::pin!(timeout_fut);
+/// The most captured output a chunk holds, unless one line is longer.
+const CHUNK_SIZE: usize = 64 * 1024;
 
-        let mut timed_out = false;
-        let mut was_cancelled = false;
-        let status = loop {
-            // Use biased select to prioritize process completion over timeout/cancellation.
-            // This prevents a race where if process completes at the same instant as timeout,
-            // we'd incorrectly report a timeout instead of success.
-            select! {
-                biased;
-                status = cp.wait() => {
-                    break status?;
-                }
-                _ = &mut timeout_fut => {
-                    timed_out = true;
-                    #[cfg(unix)]
-                    kill_process_group(id);
-                    let _ = cp.kill().await;
-                }
-                _ = self.cancel.cancelled() => {
-                    was_cancelled = true;
-                    #[cfg(unix)]
-                    kill_process_group(id);
-                    let _ = cp.kill().await;
-                }
+/// The output collected from a command, stored once: stdout and stderr are
+/// only split out of the combined output when it's moved into a
+/// [`CmdResult`].
+///
+/// The output is kept in chunks of up to [`CHUNK_SIZE`] bytes rather than
+/// one string, so commands printing hundreds of megabytes don't copy
+/// everything they've printed each time the buffer grows. Chunks are only
+/// joined once, when the command finishes.
+#[derive(Debug, Default, Clone)]
+struct Captured {
+    /// Every line in the order it was read, each followed by a newline. No
+    /// line is split across chunks.
+    chunks: Vec<String>,
+    /// The stream of each line, and its length with its newline.
+    lines: Vec<(crate::LogStream, usize)>,
+    first_output: Option<Duration>,
+}
+
+impl Captured {
+    /// Returns the combined output without its final newline.
+    fn output(&self) -> String {
+        let mut output = self.chunks.concat();
+        output.pop();
+        output
+    }
+
+    /// Adds `line` read from `stream`.
+    fn push(&mut self, stream: crate::LogStream, line: &str) {
+        let len = line.len() + 1;
+        let chunk = match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() + len <= CHUNK_SIZE => chunk,
+            _ => {
+                self.chunks.push(String::new());
+                self.chunks.last_mut().unwrap()
             }
         };
-        if let Err(e) = RUNNING_PIDS
-            .lock()
-            .map(|mut pids| pids.remove(&id))
-            .map_err(|e| e.to_string())
-        {
-            debug!("Failed to lock RUNNING_PIDS to remove pid {id}: {e}");
-        }
+        chunk.push_str(line);
+        chunk.push('\n');
+        self.lines.push((stream, len));
+    }
 
-        if was_cancelled {
-            #[cfg(feature = "progress")]
-            if let Some(pr) = &self.pr {
-                pr.set_status(progress::ProgressStatus::Failed);
+    /// Returns the lines read from `stream`, each followed by a newline.
+    fn stream(&self, stream: crate::LogStream) -> String {
+        let size = self
+            .lines
+            .iter()
+            .filter(|(s, _)| *s == stream)
+            .map(|(_, len)| len)
+            .sum();
+        let mut text = String::with_capacity(size);
+        let mut chunks = self.chunks.iter();
+        let mut chunk = "";
+        for &(s, len) in &self.lines {
+            if chunk.is_empty() {
+                chunk = chunks.next().map_or("", String::as_str);
             }
-            return Err(crate::Error::Cancelled);
+            let (line, rest) = chunk.split_at(len);
+            if s == stream {
+                text += line;
+            }
+            chunk = rest;
         }
+        text
+    }
 
-        if timed_out {
-            #[cfg(feature = "progress")]
-            if let Some(pr) = &self.pr {
-                pr.set_status(progress::ProgressStatus::Failed);
+    /// Moves the output into `result`.
+    fn fill(self, result: &mut CmdResult) {
+        result.stdout = self.stream(crate::LogStream::Stdout);
+        result.stderr = self.stream(crate::LogStream::Stderr);
+        result.combined_output = self.chunks.concat();
+        result.first_output = self.first_output;
+    }
+}
+
+/// Where each line of a command's output goes besides its [`Captured`]
+/// output: the reporter, the output tail, the log mux and the terminal.
+struct OutputSink {
+    redactor: Option<Arc<Redactor>>,
+    reporter: Option<Arc<dyn ProgressReporter>>,
+    #[cfg(feature = "progress")]
+    tail: Option<Arc<crate::tail::OutputTail>>,
+    parser: Option<crate::ProgressParser>,
+    stopwatch: Arc<crate::timings::Stopwatch>,
+    verbosity: Verbosity,
+    prefix: Option<String>,
+    stderr_to_progress: bool,
+    log_mux: Option<(crate::LogMux, String)>,
+}
+
+impl OutputSink {
+    /// Redacts `line`, collects it into `captured` and shows it.
+    fn line(&self, captured: &mut Captured, stream: crate::LogStream, line: String) {
+        let at = self.stopwatch.output();
+        let line = match &self.redactor {
+            Some(r) => r.redact(&line),
+            None => line,
+        };
+        #[cfg(feature = "tracing")]
+        match stream {
+            crate::LogStream::Stdout => tracing::trace!(stream = "stdout", line = %line),
+            _ => tracing::trace!(stream = "stderr", line = %line),
+        }
+        captured.first_output.get_or_insert(at);
+        captured.push(stream, &line);
+        if let Some((mux, label)) = &self.log_mux {
+            mux.push(label, stream, &line);
+        }
+        let reporter = self.reporter.as_deref();
+        let quiet = self.verbosity == Verbosity::Quiet;
+        // every line is printed
+        let verbose = self.verbosity >= Verbosity::Verbose || self.prefix.is_some();
+        #[cfg(feature = "progress")]
+        let tailed = self
+            .tail
+            .as_ref()
+            .filter(|_| !quiet)
+            .inspect(|tail| tail.push(&line))
+            .is_some();
+        #[cfg(not(feature = "progress"))]
+        let tailed = false;
+        if let Some(reporter) = reporter {
+            set_position(reporter, self.parser.as_ref(), &line, &self.stopwatch);
+            if quiet {
+                // nothing is shown
+            } else if stream == crate::LogStream::Stdout {
+                reporter.prop("ensembler_stdout", &line);
+                reporter.update();
+            } else if tailed {
+                // the tail shows it
+            } else if self.stderr_to_progress {
+                // Update progress bar like stdout does
+                reporter.prop("ensembler_stdout", &line);
+                reporter.update();
+            } else if !verbose {
+                // Print above progress bars
+                reporter.println(&line);
             }
-            return Err(crate::Error::TimedOut);
         }
+        if verbose {
+            echo_prefixed(reporter, self.prefix.as_deref(), &line);
+        }
+    }
+}
 
-        result.lock().await.status = status;
+/// A command started by [`CmdLineRunner::spawn_local`], tracked so
+/// [`CmdLineRunner::kill_all`] can reach it until it's dropped.
+struct LocalProcess {
+    child: Child,
+    id: u32,
+    usage: Option<crate::ResourceUsage>,
+    /// Whether [`kill`](Process::kill) has reaped the child through tokio.
+    killed: bool,
+    #[cfg(windows)]
+    job: Option<Arc<crate::job::Job>>,
+    #[cfg(target_os = "linux")]
+    unit: Option<crate::systemd::TransientUnit>,
+}
 
-        // these are sent when the process has flushed IO
-        let _ = stdout_ready.await;
-        let _ = stderr_ready.await;
-        let _ = stdin_ready.await;
+impl Process for LocalProcess {
+    fn id(&self) -> Option<u32> {
+        Some(self.id)
+    }
 
-        if status.success() || self.allow_non_zero {
-            #[cfg(feature = "progress")]
-            if let Some(pr) = &self.pr {
-                pr.set_status(progress::ProgressStatus::Done);
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.child.take_stdin()
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.child.take_stdout()
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.child.take_stderr()
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, std::io::Result<ExitStatus>> {
+        Box::pin(async move {
+            // once killed, tokio has reaped the child and has its status
+            #[cfg(unix)]
+            let status = match self.killed {
+                true => self.child.wait().await?,
+                false => match crate::rusage::wait(self.id).await {
+                    Ok((status, usage)) => {
+                        self.usage = Some(usage);
+                        status
+                    }
+                    Err(e) => {
+                        debug!("Failed to wait for pid {} with wait4: {e}", self.id);
+                        self.child.wait().await?
+                    }
+                },
+            };
+            #[cfg(windows)]
+            let status = self.child.wait().await?;
+            #[cfg(windows)]
+            if let Some(job) = &self.job {
+                match job.usage() {
+                    Ok(usage) => self.usage = Some(usage),
+                    Err(e) => debug!("Failed to read the job usage of pid {}: {e}", self.id),
+                }
+                // only a killed or dropped command takes its tree with it
+                if !self.killed {
+                    if let Err(e) = job.release() {
+                        debug!("Failed to release the job of pid {}: {e}", self.id);
+                    }
+                }
             }
-        } else {
-            let result = result.lock().await.to_owned();
-            self.on_error(combined_output.lock().await.join("\n"), result)?;
-        }
+            #[cfg(target_os = "linux")]
+            if let Some(unit) = &mut self.unit {
+                unit.stop().await;
+            }
+            Ok(status)
+        })
+    }
 
-        let result = result.lock().await.to_owned();
-        Ok(result)
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, std::io::Result<()>> {
+        Box::pin(async move {
+            stop_child(
+                &mut self.child,
+                self.id,
+                grace_period,
+                #[cfg(windows)]
+                self.job.as_deref(),
+            )
+            .await;
+            self.killed = true;
+            #[cfg(target_os = "linux")]
+            if let Some(unit) = &mut self.unit {
+                unit.stop().await;
+            }
+            Ok(())
+        })
     }
 
-    fn on_error(&self, output: String, result: CmdResult) -> Result<()> {
-        let output = output.trim().to_string();
-        #[cfg(feature = "progress")]
-        if let Some(pr) = &self.pr {
-            pr.set_status(progress::ProgressStatus::Failed);
-            if self.show_stderr_on_error {
-                pr.println(&output);
+    fn resource_usage(&self) -> Option<crate::ResourceUsage> {
+        self.usage
+    }
+}
+
+impl Drop for LocalProcess {
+    fn drop(&mut self) {
+        let id = self.id;
+        if let Err(e) = RUNNING_PIDS
+            .lock()
+            .map(|mut pids| pids.remove(&id))
+            .map_err(|e| e.to_string())
+        {
+            debug!("Failed to lock RUNNING_PIDS to remove pid {id}: {e}");
+        }
+        #[cfg(windows)]
+        crate::job::untrack(id);
+    }
+}
+
+/// Stops the child and its process tree.
+///
+/// With a grace period, the child is first asked to exit (`SIGTERM` to its
+/// process group on Unix, `CTRL_BREAK_EVENT` on Windows) and given that long
+/// to do so. Anything still running afterwards is killed.
+async fn stop_child(
+    cp: &mut Child,
+    id: u32,
+    grace_period: Option<Duration>,
+    #[cfg(windows)] job: Option<&crate::job::Job>,
+) {
+    if let Some(grace_period) = grace_period {
+        #[cfg(unix)]
+        let asked = signal_process_group(id, nix::sys::signal::Signal::SIGTERM);
+        #[cfg(windows)]
+        let asked = match crate::job::ctrl_break(id) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Failed to send CTRL_BREAK to pid {id}: {e}");
+                false
             }
+        };
+        if asked && tokio::time::timeout(grace_period, cp.wait()).await.is_err() {
+            debug!("pid {id} still running after {grace_period:?}, killing it");
         }
-        Err(ScriptFailed(Box::new((
-            self.program.clone(),
-            self.args.clone(),
-            output,
-            result,
-        ))))?
     }
+    #[cfg(unix)]
+    signal_process_group(id, nix::sys::signal::Signal::SIGKILL);
+    #[cfg(windows)]
+    if let Some(Err(e)) = job.map(|job| job.terminate()) {
+        debug!("Failed to terminate job for pid {id}: {e}");
+    }
+    let _ = cp.kill().await;
 }
 
-/// Kill an entire process group by PGID (which equals the child PID since
-/// we spawn with process_group(0)).
+/// Signals an entire process group by PGID (which equals the child PID since
+/// we spawn with process_group(0)). Returns `false` if the signal couldn't be
+/// sent, e.g. because the group already exited.
 #[cfg(unix)]
-fn kill_process_group(pid: u32) {
+fn signal_process_group(pid: u32, signal: nix::sys::signal::Signal) -> bool {
     let pgid = nix::unistd::Pid::from_raw(pid as i32);
-    if let Err(e) = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGKILL) {
-        debug!("Failed to kill process group {pid}: {e}");
+    match nix::sys::signal::killpg(pgid, signal) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Failed to send {signal} to process group {pid}: {e}");
+            false
+        }
     }
 }
 
 impl Display for CmdLineRunner {
+    /// Renders the command line so it can be pasted back into a shell:
+    /// arguments are quoted for the platform shell where needed, and scripts
+    /// run with [`shell`](CmdLineRunner::shell) show just the script.
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let args = self.args.join(" ");
-        let mut cmd = format!("{} {}", &self.program, args);
-        if cmd.starts_with("sh -o errexit -c ") {
-            cmd = cmd[17..].to_string();
+        if self.program == "sh" && self.args.len() == 4 {
+            let flags = self.args[..3].iter().map(Arg::as_os_str);
+            if flags.eq(["-o", "errexit", "-c"].map(OsStr::new)) {
+                return write!(f, "{}", self.args[3].as_os_str().to_string_lossy());
+            }
         }
-        write!(f, "{cmd}")
+        write!(f, "{}", self.quoted_command_line())
     }
 }
 
 impl Debug for CmdLineRunner {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let args = self.args.join(" ");
-        write!(f, "{} {args}", self.program)
+        write!(f, "{}", self.quoted_command_line())
     }
 }
 
 /// The result of executing a command.
 ///
-/// Contains the captured output streams and exit status.
+/// Contains the captured output streams and exit status. Fields are added
+/// as more is recorded about commands, so build one, for a test say, from
+/// [`CmdResult::default()`] and set the fields it needs.
 #[derive(Debug, Default, Clone)]
+#[non_exhaustive]
 pub struct CmdResult {
     /// The captured standard output.
     pub stdout: String,
@@ -710,4 +3963,39 @@ pub struct CmdResult {
     pub combined_output: String,
     /// The exit status of the process.
     pub status: ExitStatus,
+    /// The most memory, in bytes, used at once by the command and its
+    /// descendants. Only reported for commands run in a
+    /// [`cgroup`](CmdLineRunner::cgroup) on kernels that track it.
+    pub peak_memory: Option<u64>,
+    /// Whether the command was skipped because its outputs were up to
+    /// date, see [`CmdLineRunner::freshness`].
+    pub up_to_date: bool,
+    /// When the process was spawned, or `None` if it didn't run because
+    /// its result was cached or up to date.
+    pub started_at: Option<SystemTime>,
+    /// When the process exited, or `None` if it didn't run.
+    pub finished_at: Option<SystemTime>,
+    /// How long the process ran, from spawn to exit.
+    pub duration: Duration,
+    /// How long after spawning the process wrote its first line of output,
+    /// or `None` if it wrote none.
+    pub first_output: Option<Duration>,
+    /// The longest time the process went without writing output, counting
+    /// the time before its first line and after its last.
+    pub longest_stall: Duration,
+    /// The CPU time and memory the command used, if the executor reports
+    /// it. Commands run locally report it on Unix, unless dozens are already
+    /// running, and, when they're in a job object, on Windows.
+    pub resource_usage: Option<crate::ResourceUsage>,
+    /// The labels set with [`CmdLineRunner::label`].
+    pub labels: Vec<(String, String)>,
+}
+
+impl CmdResult {
+    /// Diffs the stdout of `previous` against this result's, for spotting
+    /// changes between runs of the same command. Use
+    /// [`Diff::new`](crate::Diff::new) to compare other streams.
+    pub fn diff(&self, previous: &CmdResult) -> crate::Diff {
+        crate::Diff::new(&previous.stdout, &self.stdout)
+    }
 }
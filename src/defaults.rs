@@ -0,0 +1,250 @@
+use crate::{
+    FailureMessage, History, JunitReport, Metrics, Policy, RunReport, Shell, Style, Summary,
+    Timings, Verbosity,
+};
+use indexmap::{IndexMap, IndexSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock as Lazy;
+use std::sync::RwLock;
+use std::time::Duration;
+
+static DEFAULTS: Lazy<RwLock<Defaults>> = Lazy::new(Default::default);
+
+/// Settings applied to every [`CmdLineRunner`](crate::CmdLineRunner) when it
+/// is created.
+///
+/// Each setting can still be overridden per command: calling
+/// [`env`](crate::CmdLineRunner::env), [`current_dir`](crate::CmdLineRunner::current_dir)
+/// or [`timeout`](crate::CmdLineRunner::timeout) on a runner replaces the
+/// default, [`env_clear`](crate::CmdLineRunner::env_clear) drops the default
+/// env vars, and redactions are added to the defaults.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults};
+/// use std::time::Duration;
+///
+/// ensembler::set_defaults(
+///     Defaults::new()
+///         .env("CI", "1")
+///         .timeout(Duration::from_secs(300))
+///         .redact(["hunter2".to_string()]),
+/// );
+///
+/// // picks up CI=1, the 5 minute timeout and the redaction
+/// let runner = CmdLineRunner::new("make");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Defaults {
+    pub(crate) envs: IndexMap<OsString, OsString>,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) redactions: IndexSet<String>,
+    pub(crate) shell: Option<Shell>,
+    pub(crate) wrappers: Vec<Vec<OsString>>,
+    pub(crate) history: Option<History>,
+    pub(crate) junit: Option<JunitReport>,
+    pub(crate) summary: Option<Summary>,
+    pub(crate) run_report: Option<RunReport>,
+    pub(crate) metrics: Option<Metrics>,
+    pub(crate) policy: Option<Policy>,
+    pub(crate) timings: Option<Timings>,
+    pub(crate) style: Style,
+    pub(crate) verbosity: Verbosity,
+    pub(crate) ci_groups: bool,
+    pub(crate) ci_annotations: bool,
+    pub(crate) failure_message: Option<FailureMessage>,
+}
+
+impl Defaults {
+    /// Creates an empty set of defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets an environment variable for every command.
+    pub fn env<K, V>(mut self, key: K, val: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.envs
+            .insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
+        self
+    }
+
+    /// Sets the working directory for every command.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets a timeout for every command.
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        self.timeout = Some(duration);
+        self
+    }
+
+    /// Adds strings to redact from every command's output.
+    pub fn redact(mut self, redactions: impl IntoIterator<Item = String>) -> Self {
+        self.redactions.extend(redactions);
+        self
+    }
+
+    /// Sets the shell used by [`CmdLineRunner::shell`](crate::CmdLineRunner::shell),
+    /// [`sh!`](crate::sh) and [`ScriptRunner`](crate::ScriptRunner) scripts
+    /// without a shebang.
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    /// Runs every command through `wrapper`, outside any wrappers added
+    /// with [`CmdLineRunner::wrap`](crate::CmdLineRunner::wrap).
+    pub fn wrap<I, S>(mut self, wrapper: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let wrapper: Vec<OsString> = wrapper
+            .into_iter()
+            .map(|a| a.as_ref().to_os_string())
+            .collect();
+        if !wrapper.is_empty() {
+            self.wrappers.push(wrapper);
+        }
+        self
+    }
+
+    /// Records every command in `history`.
+    pub fn history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Records every command as a test case in `report`.
+    pub fn junit(mut self, report: JunitReport) -> Self {
+        self.junit = Some(report);
+        self
+    }
+
+    /// Adds every command to `summary` when it finishes.
+    pub fn summary(mut self, summary: Summary) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    /// Records every command in `report`.
+    pub fn run_report(mut self, report: RunReport) -> Self {
+        self.run_report = Some(report);
+        self
+    }
+
+    /// Counts every command in `metrics`.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records how long every command takes in `timings`.
+    pub fn timings(mut self, timings: Timings) -> Self {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Checks every command against `policy` before running it.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Renders commands with `style`.
+    pub fn style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Sets how much of every command is shown.
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// When running in CI, reports every command in the CI's own log format
+    /// unless it has a reporter or progress job of its own, including in
+    /// place of the jobs [`with_progress`](crate::CmdLineRunner::with_progress)
+    /// creates: [`GithubGroups`](crate::GithubGroups) in GitHub Actions,
+    /// [`AzureGroups`](crate::AzureGroups) in Azure Pipelines and
+    /// [`TeamcityMessages`](crate::TeamcityMessages) in TeamCity. The CI is
+    /// detected from its environment, e.g. `TEAMCITY_VERSION`. Off by
+    /// default.
+    pub fn ci_groups(mut self, enable: bool) -> Self {
+        self.ci_groups = enable;
+        self
+    }
+
+    /// When running in GitHub Actions or Azure Pipelines, prints an error
+    /// annotation for every command that fails, with the command line and
+    /// the end of its output, so failures show up on the run and its pull
+    /// request.
+    pub fn ci_annotations(mut self, enable: bool) -> Self {
+        self.ci_annotations = enable;
+        self
+    }
+
+    /// Displays every command that fails with `message`.
+    pub fn failure_message(mut self, message: FailureMessage) -> Self {
+        self.failure_message = Some(message);
+        self
+    }
+
+    /// Returns the default environment variables.
+    pub fn get_envs(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.envs
+            .iter()
+            .map(|(k, v)| (k.as_os_str(), v.as_os_str()))
+    }
+
+    /// Returns the default working directory, if set.
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// Returns the default shell, if set.
+    pub fn get_shell(&self) -> Option<&Shell> {
+        self.shell.as_ref()
+    }
+
+    /// Returns the default timeout, if set.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// Replaces the defaults applied to newly created runners.
+///
+/// Runners that already exist are not affected.
+pub fn set_defaults(defaults: Defaults) {
+    match DEFAULTS.write() {
+        Ok(mut d) => *d = defaults,
+        Err(e) => *e.into_inner() = defaults,
+    }
+}
+
+/// Returns the default style, without copying the other defaults.
+pub(crate) fn style() -> Style {
+    match DEFAULTS.read() {
+        Ok(d) => d.style.clone(),
+        Err(e) => e.into_inner().style.clone(),
+    }
+}
+
+/// Returns a copy of the defaults applied to newly created runners.
+pub fn defaults() -> Defaults {
+    match DEFAULTS.read() {
+        Ok(d) => d.clone(),
+        Err(e) => e.into_inner().clone(),
+    }
+}
@@ -0,0 +1,269 @@
+//! Line diffs of command output.
+
+use std::fmt::{self, Display, Formatter};
+
+/// A line diff between two outputs, displayed in unified format.
+///
+/// Created with [`CmdResult::diff`](crate::CmdResult::diff),
+/// [`HistoryEntry::diff`](crate::HistoryEntry::diff), or [`new`](Self::new)
+/// for any two strings. Displays as nothing when they're the same.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let before = CmdLineRunner::new("terraform").arg("show").execute().await?;
+/// // ...
+/// let after = CmdLineRunner::new("terraform").arg("show").execute().await?;
+///
+/// let diff = after.diff(&before);
+/// if !diff.is_empty() {
+///     println!("drift detected:\n{diff}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Diff {
+    old: String,
+    new: String,
+    old_label: String,
+    new_label: String,
+    context: usize,
+    edits: Vec<Edit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Same(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+impl Diff {
+    /// Diffs `old` against `new` line by line.
+    pub fn new(old: impl Into<String>, new: impl Into<String>) -> Self {
+        let (old, new) = (old.into(), new.into());
+        let edits = edits(&lines(&old), &lines(&new));
+        Self {
+            old,
+            new,
+            old_label: "previous".into(),
+            new_label: "current".into(),
+            context: 3,
+            edits,
+        }
+    }
+
+    /// Sets the names shown in the `---` and `+++` header lines. Defaults to
+    /// `previous` and `current`.
+    pub fn labels(mut self, old: impl Into<String>, new: impl Into<String>) -> Self {
+        self.old_label = old.into();
+        self.new_label = new.into();
+        self
+    }
+
+    /// Sets how many unchanged lines to show around each change. Defaults
+    /// to 3.
+    pub fn context(mut self, lines: usize) -> Self {
+        self.context = lines;
+        self
+    }
+
+    /// Returns whether the outputs are the same.
+    pub fn is_empty(&self) -> bool {
+        self.edits.iter().all(|e| matches!(e, Edit::Same(..)))
+    }
+
+    /// Returns the number of lines removed and added.
+    pub fn stats(&self) -> (usize, usize) {
+        let removed = self.edits.iter().filter(|e| matches!(e, Edit::Delete(_)));
+        let added = self.edits.iter().filter(|e| matches!(e, Edit::Insert(_)));
+        (removed.count(), added.count())
+    }
+
+    /// Groups the edits into hunks of changes with their context, as ranges
+    /// of `edits`.
+    fn hunks(&self) -> Vec<std::ops::Range<usize>> {
+        let mut hunks: Vec<std::ops::Range<usize>> = vec![];
+        for (i, edit) in self.edits.iter().enumerate() {
+            if matches!(edit, Edit::Same(..)) {
+                continue;
+            }
+            let start = i.saturating_sub(self.context);
+            let end = (i + 1 + self.context).min(self.edits.len());
+            match hunks.last_mut() {
+                Some(last) if last.end >= start => last.end = end,
+                _ => hunks.push(start..end),
+            }
+        }
+        hunks
+    }
+
+    /// Returns how many old and new lines come before `edit`.
+    fn lines_before(&self, edit: Edit) -> (usize, usize) {
+        let at = self.edits.iter().position(|e| *e == edit).unwrap_or(0);
+        self.edits[..at]
+            .iter()
+            .fold((0, 0), |(old, new), edit| match edit {
+                Edit::Same(..) => (old + 1, new + 1),
+                Edit::Delete(_) => (old + 1, new),
+                Edit::Insert(_) => (old, new + 1),
+            })
+    }
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let (old, new) = (lines(&self.old), lines(&self.new));
+        writeln!(f, "--- {}", self.old_label)?;
+        writeln!(f, "+++ {}", self.new_label)?;
+        for hunk in self.hunks() {
+            let edits = &self.edits[hunk];
+            let (mut old_start, mut new_start) = (None, None);
+            let (mut old_len, mut new_len) = (0, 0);
+            for edit in edits {
+                if let Edit::Same(i, _) | Edit::Delete(i) = *edit {
+                    old_start.get_or_insert(i);
+                    old_len += 1;
+                }
+                if let Edit::Same(_, j) | Edit::Insert(j) = *edit {
+                    new_start.get_or_insert(j);
+                    new_len += 1;
+                }
+            }
+            // an empty side is numbered by the line before it
+            let (old_before, new_before) = self.lines_before(edits[0]);
+            writeln!(
+                f,
+                "@@ -{} +{} @@",
+                range(old_start, old_before, old_len),
+                range(new_start, new_before, new_len)
+            )?;
+            for edit in edits {
+                let (sign, line) = match *edit {
+                    Edit::Same(i, _) => (' ', old[i]),
+                    Edit::Delete(i) => ('-', old[i]),
+                    Edit::Insert(j) => ('+', new[j]),
+                };
+                write!(f, "{sign}{line}")?;
+                if !line.ends_with('\n') {
+                    writeln!(f, "\n\\ No newline at end of file")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats a hunk range as `start,len`, 1-based, leaving out a length of 1.
+fn range(start: Option<usize>, before: usize, len: usize) -> String {
+    match (start, len) {
+        (Some(start), 1) => format!("{}", start + 1),
+        (Some(start), len) => format!("{},{len}", start + 1),
+        (None, _) => format!("{before},0"),
+    }
+}
+
+fn lines(s: &str) -> Vec<&str> {
+    s.split_inclusive('\n').collect()
+}
+
+/// Finds a shortest edit script from `a` to `b` with Myers' algorithm.
+fn edits(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    // common ends are cheap to match and keep the search small
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let mut edits: Vec<Edit> = (0..prefix).map(|i| Edit::Same(i, i)).collect();
+    edits.extend(myers(a_mid, b_mid).into_iter().map(|edit| match edit {
+        Edit::Same(i, j) => Edit::Same(i + prefix, j + prefix),
+        Edit::Delete(i) => Edit::Delete(i + prefix),
+        Edit::Insert(j) => Edit::Insert(j + prefix),
+    }));
+    let (a_end, b_end) = (a.len() - suffix, b.len() - suffix);
+    edits.extend((0..suffix).map(|k| Edit::Same(a_end + k, b_end + k)));
+    edits
+}
+
+/// The most differences [`myers`] searches for before giving up, which
+/// bounds the memory its saved frontiers take.
+const MAX_EDITS: isize = 2000;
+
+/// Finds a shortest edit script with Myers' algorithm, or if more than
+/// [`MAX_EDITS`] lines differ, replaces all of `a` with all of `b`.
+fn myers(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m).min(MAX_EDITS);
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // trace[d] holds the frontier on diagonals -d-1..=d+1 before round d
+    let mut trace = vec![];
+    let mut found = false;
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                found = true;
+                break 'search;
+            }
+        }
+    }
+    if !found {
+        let deletes = (0..a.len()).map(Edit::Delete);
+        return deletes.chain((0..b.len()).map(Edit::Insert)).collect();
+    }
+    // walk back through the saved frontiers to recover the path
+    let (mut x, mut y) = (n, m);
+    let mut edits = vec![];
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Same(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(prev_y as usize));
+            } else {
+                edits.push(Edit::Delete(prev_x as usize));
+            }
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    edits
+}
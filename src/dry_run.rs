@@ -0,0 +1,116 @@
+//! Reviewing recorded commands against the current environment.
+
+use crate::shell_words::quote;
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+/// What a recorded command would do if run again now, without running it.
+///
+/// Returned by [`History::dry_run`](crate::History::dry_run) and
+/// [`audit::dry_run`](crate::audit::dry_run). Displays as the command line
+/// followed by anything that changed since it was recorded:
+///
+/// ```text
+/// $ cd /src/app && make deploy
+///   program make not found
+///   env DEPLOY_ENV: recorded "staging", now "prod"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DryRun {
+    /// The recorded program.
+    pub program: String,
+    /// The recorded args.
+    pub args: Vec<String>,
+    /// The recorded working directory.
+    pub cwd: Option<PathBuf>,
+    /// Where the program is found now, if anywhere, searching the recorded
+    /// `PATH` if one was set and the current one otherwise.
+    pub resolved: Option<PathBuf>,
+    /// Whether the working directory exists now.
+    pub cwd_exists: bool,
+    /// The recorded env vars whose current value in this process differs.
+    pub env: Vec<EnvDiff>,
+}
+
+/// An env var whose recorded value differs from the current one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EnvDiff {
+    /// The variable name.
+    pub key: String,
+    /// The value it had when recorded.
+    pub recorded: String,
+    /// Its value now, or `None` if unset.
+    pub current: Option<String>,
+}
+
+impl DryRun {
+    pub(crate) fn new<'a>(
+        program: &str,
+        args: &[String],
+        env: impl IntoIterator<Item = (&'a str, &'a str)>,
+        cwd: Option<PathBuf>,
+    ) -> Self {
+        let env: Vec<(&str, &str)> = env.into_iter().collect();
+        let path = env.iter().find(|(key, _)| *key == "PATH").map(|(_, v)| *v);
+        let resolved =
+            crate::which::which_in(OsStr::new(program), path.map(OsStr::new), cwd.as_deref());
+        let redacted = crate::defaults::style().redacted;
+        let env = env
+            .into_iter()
+            // values are stored redacted, so these can't be compared
+            .filter(|(_, recorded)| !recorded.contains(&redacted))
+            .filter_map(|(key, recorded)| {
+                let current = std::env::var_os(key).map(|v| v.to_string_lossy().into_owned());
+                (current.as_deref() != Some(recorded)).then(|| EnvDiff {
+                    key: key.to_string(),
+                    recorded: recorded.to_string(),
+                    current,
+                })
+            })
+            .collect();
+        Self {
+            program: program.to_string(),
+            args: args.to_vec(),
+            cwd_exists: cwd.as_ref().is_none_or(|cwd| cwd.is_dir()),
+            cwd,
+            resolved,
+            env,
+        }
+    }
+
+    /// Returns whether the command would run the same way as recorded, as
+    /// far as can be told without running it.
+    pub fn unchanged(&self) -> bool {
+        self.resolved.is_some() && self.cwd_exists && self.env.is_empty()
+    }
+}
+
+impl Display for DryRun {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "$ ")?;
+        if let Some(cwd) = &self.cwd {
+            write!(f, "cd {} && ", quote(&cwd.to_string_lossy()))?;
+        }
+        write!(f, "{}", quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(arg))?;
+        }
+        if self.resolved.is_none() {
+            write!(f, "\n  program {} not found", self.program)?;
+        }
+        if let Some(cwd) = self.cwd.as_ref().filter(|_| !self.cwd_exists) {
+            write!(f, "\n  directory {} does not exist", cwd.display())?;
+        }
+        for diff in &self.env {
+            write!(f, "\n  env {}: recorded {:?}, ", diff.key, diff.recorded)?;
+            match &diff.current {
+                Some(current) => write!(f, "now {current:?}")?,
+                None => write!(f, "now unset")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Running commands elevated through UAC (`ShellExecuteExW` with `runas`).
+//!
+//! An elevated process can't inherit our pipes, so the command runs from a
+//! batch script that redirects its stdio to temp files, which are read back
+//! once it exits.
+
+use crate::{Error, Result};
+use std::ffi::c_void;
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+
+type Handle = *mut c_void;
+
+const SEE_MASK_NOCLOSEPROCESS: u32 = 0x0000_0040;
+const SEE_MASK_NOASYNC: u32 = 0x0000_0100;
+const SEE_MASK_FLAG_NO_UI: u32 = 0x0000_0400;
+const SW_HIDE: i32 = 0;
+const WAIT_OBJECT_0: u32 = 0;
+const WAIT_TIMEOUT: u32 = 0x102;
+const ERROR_CANCELLED: i32 = 1223;
+
+#[repr(C)]
+struct ShellExecuteInfoW {
+    cb_size: u32,
+    f_mask: u32,
+    hwnd: Handle,
+    lp_verb: *const u16,
+    lp_file: *const u16,
+    lp_parameters: *const u16,
+    lp_directory: *const u16,
+    n_show: i32,
+    h_inst_app: Handle,
+    lp_id_list: *mut c_void,
+    lp_class: *const u16,
+    hkey_class: Handle,
+    dw_hot_key: u32,
+    h_icon_or_monitor: Handle,
+    h_process: Handle,
+}
+
+#[link(name = "shell32")]
+extern "system" {
+    fn ShellExecuteExW(info: *mut ShellExecuteInfoW) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn WaitForSingleObject(handle: Handle, millis: u32) -> u32;
+    fn GetExitCodeProcess(process: Handle, exit_code: *mut u32) -> i32;
+    fn TerminateProcess(process: Handle, exit_code: u32) -> i32;
+    fn CloseHandle(handle: Handle) -> i32;
+}
+
+/// A process started with elevation.
+pub(crate) struct ElevatedProcess(Handle);
+
+// SAFETY: process handles may be used and closed from any thread.
+unsafe impl Send for ElevatedProcess {}
+unsafe impl Sync for ElevatedProcess {}
+
+impl ElevatedProcess {
+    /// Returns the exit code if the process has exited.
+    pub(crate) fn try_wait(&self) -> io::Result<Option<u32>> {
+        // SAFETY: the handle is valid until `self` is dropped.
+        match unsafe { WaitForSingleObject(self.0, 0) } {
+            WAIT_OBJECT_0 => {
+                let mut code = 0;
+                // SAFETY: `code` is a valid out pointer.
+                if unsafe { GetExitCodeProcess(self.0, &mut code) } == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Some(code))
+            }
+            WAIT_TIMEOUT => Ok(None),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Terminates the process. This can fail with access denied, since the
+    /// handle UAC gives back may not carry terminate rights.
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        // SAFETY: the handle is valid until `self` is dropped.
+        if unsafe { TerminateProcess(self.0, 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ElevatedProcess {
+    fn drop(&mut self) {
+        // SAFETY: the handle is owned by `self` and closed exactly once.
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Runs `script` with `cmd.exe /d /c` in a hidden window after a UAC prompt.
+///
+/// Returns [`Error::ElevationCancelled`] if the user declines the prompt.
+pub(crate) fn spawn(script: &Path) -> Result<ElevatedProcess> {
+    let wide = |s: &std::ffi::OsStr| s.encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let verb = wide("runas".as_ref());
+    let file = wide("cmd.exe".as_ref());
+    let params = wide(format!("/d /c \"{}\"", script.display()).as_ref());
+    let mut info = ShellExecuteInfoW {
+        cb_size: std::mem::size_of::<ShellExecuteInfoW>() as u32,
+        f_mask: SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC | SEE_MASK_FLAG_NO_UI,
+        hwnd: std::ptr::null_mut(),
+        lp_verb: verb.as_ptr(),
+        lp_file: file.as_ptr(),
+        lp_parameters: params.as_ptr(),
+        lp_directory: std::ptr::null(),
+        n_show: SW_HIDE,
+        h_inst_app: std::ptr::null_mut(),
+        lp_id_list: std::ptr::null_mut(),
+        lp_class: std::ptr::null(),
+        hkey_class: std::ptr::null_mut(),
+        dw_hot_key: 0,
+        h_icon_or_monitor: std::ptr::null_mut(),
+        h_process: std::ptr::null_mut(),
+    };
+    // SAFETY: `info` and the strings it points to outlive the call.
+    if unsafe { ShellExecuteExW(&mut info) } == 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_CANCELLED) {
+            return Err(Error::ElevationCancelled);
+        }
+        return Err(err.into());
+    }
+    if info.h_process.is_null() {
+        return Err(Error::Internal(
+            "elevated process has no handle".to_string(),
+        ));
+    }
+    Ok(ElevatedProcess(info.h_process))
+}
@@ -0,0 +1,108 @@
+/// How captured stdout/stderr bytes are decoded into strings.
+///
+/// Many Windows tools write in the console's OEM codepage (e.g. CP437 or
+/// CP850) rather than UTF-8, which shows up as replacement characters when
+/// decoded as UTF-8. Set with
+/// [`CmdLineRunner::output_encoding`](crate::CmdLineRunner::output_encoding).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OutputEncoding {
+    /// Decode lines that are valid UTF-8 as UTF-8. On Windows, other lines
+    /// are decoded with the console's output codepage (or the OEM codepage
+    /// when there is no console); elsewhere invalid bytes are replaced.
+    #[default]
+    Auto,
+    /// Always decode as UTF-8, replacing invalid bytes.
+    Utf8,
+    /// Decode with the given Windows codepage (e.g. `850`). Only supported on
+    /// Windows; other platforms decode as UTF-8.
+    CodePage(u32),
+}
+
+impl OutputEncoding {
+    /// Decodes one line of output, without its trailing `\n` or `\r\n`.
+    pub(crate) fn decode_line(self, line: &[u8]) -> String {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match self {
+            OutputEncoding::Auto => match std::str::from_utf8(line) {
+                Ok(s) => s.to_string(),
+                #[cfg(windows)]
+                Err(_) => codepage::decode(codepage::console(), line),
+                #[cfg(not(windows))]
+                Err(_) => String::from_utf8_lossy(line).to_string(),
+            },
+            OutputEncoding::Utf8 => String::from_utf8_lossy(line).to_string(),
+            #[cfg(windows)]
+            OutputEncoding::CodePage(cp) => codepage::decode(cp, line),
+            #[cfg(not(windows))]
+            OutputEncoding::CodePage(_) => String::from_utf8_lossy(line).to_string(),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod codepage {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetConsoleOutputCP() -> u32;
+        fn GetOEMCP() -> u32;
+        fn MultiByteToWideChar(
+            codepage: u32,
+            flags: u32,
+            input: *const u8,
+            input_len: i32,
+            output: *mut u16,
+            output_len: i32,
+        ) -> i32;
+    }
+
+    /// Returns the console's output codepage, or the OEM codepage when the
+    /// process has no console.
+    pub(super) fn console() -> u32 {
+        // SAFETY: both functions take no arguments and have no preconditions.
+        match unsafe { GetConsoleOutputCP() } {
+            0 => unsafe { GetOEMCP() },
+            cp => cp,
+        }
+    }
+
+    pub(super) fn decode(codepage: u32, bytes: &[u8]) -> String {
+        if bytes.is_empty() {
+            return String::new();
+        }
+        let Ok(input_len) = i32::try_from(bytes.len()) else {
+            return String::from_utf8_lossy(bytes).to_string();
+        };
+        // SAFETY: a null output buffer asks for the required length.
+        let len = unsafe {
+            MultiByteToWideChar(
+                codepage,
+                0,
+                bytes.as_ptr(),
+                input_len,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if len <= 0 {
+            return String::from_utf8_lossy(bytes).to_string();
+        }
+        let mut wide = vec![0u16; len as usize];
+        // SAFETY: `wide` holds `len` UTF-16 units.
+        let len = unsafe {
+            MultiByteToWideChar(
+                codepage,
+                0,
+                bytes.as_ptr(),
+                input_len,
+                wide.as_mut_ptr(),
+                len,
+            )
+        };
+        if len <= 0 {
+            return String::from_utf8_lossy(bytes).to_string();
+        }
+        String::from_utf16_lossy(&wide[..len as usize])
+    }
+}
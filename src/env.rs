@@ -0,0 +1,352 @@
+//! Environment helpers.
+//!
+//! Besides [`EnvSnapshot`], this module exposes the checks ensembler uses to
+//! describe the environment it's running in, so applications can make the
+//! same decisions (e.g. whether to render interactive progress).
+
+use indexmap::IndexMap;
+use std::ffi::{OsStr, OsString};
+use std::io::IsTerminal;
+use std::sync::Arc;
+
+/// Environment variables set by common CI providers.
+const CI_VARS: &[&str] = &[
+    "CI",
+    "GITHUB_ACTIONS",
+    "GITLAB_CI",
+    "BUILDKITE",
+    "CIRCLECI",
+    "TF_BUILD",
+    "TEAMCITY_VERSION",
+    "JENKINS_URL",
+];
+
+/// Returns `true` when running under a CI system.
+///
+/// Checks `CI` and the variables set by common providers (GitHub Actions,
+/// GitLab, Buildkite, CircleCI, Azure Pipelines, TeamCity, Jenkins).
+/// `CI=false` and `CI=0` are treated as unset.
+pub fn is_ci() -> bool {
+    CI_VARS.iter().any(|var| var_is_truthy(var))
+}
+
+/// Returns `true` when running in GitHub Actions.
+pub fn is_github_actions() -> bool {
+    var_is_truthy("GITHUB_ACTIONS")
+}
+
+/// Returns `true` when running in Azure Pipelines.
+pub fn is_azure_pipelines() -> bool {
+    var_is_truthy("TF_BUILD")
+}
+
+/// Returns `true` when running in TeamCity.
+pub fn is_teamcity() -> bool {
+    std::env::var_os("TEAMCITY_VERSION").is_some()
+}
+
+/// Returns `true` if stderr is attached to a terminal.
+///
+/// Progress output is written to stderr, so this is the stream that decides
+/// whether interactive rendering makes sense.
+pub fn is_tty() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+/// Returns the width of the terminal attached to stderr, in columns.
+///
+/// Falls back to a numeric `COLUMNS` variable and returns `None` when neither
+/// is available.
+pub fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size_of(std::io::stderr())
+        .map(|(w, _)| w.0 as usize)
+        .or_else(|| std::env::var("COLUMNS").ok()?.parse().ok())
+}
+
+/// Returns `true` if `var` is set to something other than an empty string,
+/// `0`, or `false`.
+pub(crate) fn var_is_truthy(var: &str) -> bool {
+    match std::env::var(var) {
+        Ok(v) => !v.is_empty() && v != "0" && !v.eq_ignore_ascii_case("false"),
+        Err(_) => false,
+    }
+}
+
+/// A captured environment block that can be modified and applied to runners.
+///
+/// Snapshots are cheap to clone (the variables are shared until modified),
+/// so an environment can be computed once — for example by sourcing a shell
+/// profile and parsing `env -0` — and then reused for many commands via
+/// [`CmdLineRunner::env_snapshot`](crate::CmdLineRunner::env_snapshot).
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, EnvSnapshot};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let output = CmdLineRunner::new("bash")
+///     .args(["-c", "source ~/.profile && env -0"])
+///     .execute()
+///     .await?;
+/// let mut env = EnvSnapshot::from_bytes(output.stdout.as_bytes());
+/// env.set("RUST_LOG", "debug");
+///
+/// for crate_dir in ["a", "b", "c"] {
+///     CmdLineRunner::new("cargo")
+///         .arg("build")
+///         .current_dir(crate_dir)
+///         .env_snapshot(&env)
+///         .execute()
+///         .await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvSnapshot {
+    vars: Arc<IndexMap<OsString, OsString>>,
+}
+
+impl EnvSnapshot {
+    /// Captures the current process environment.
+    pub fn capture() -> Self {
+        std::env::vars_os().collect()
+    }
+
+    /// Returns the value of `key`, if set.
+    pub fn get<K: AsRef<OsStr>>(&self, key: K) -> Option<&OsStr> {
+        self.vars
+            .iter()
+            .find(|(k, _)| key_eq(k, key.as_ref()))
+            .map(|(_, v)| v.as_os_str())
+    }
+
+    /// Sets `key` to `val`, replacing any existing value.
+    pub fn set<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) {
+        let vars = Arc::make_mut(&mut self.vars);
+        match vars.keys().position(|k| key_eq(k, key.as_ref())) {
+            Some(i) => vars[i] = val.as_ref().to_os_string(),
+            None => {
+                vars.insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
+            }
+        }
+    }
+
+    /// Removes `key`, returning its previous value.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Option<OsString> {
+        let i = self.vars.keys().position(|k| key_eq(k, key.as_ref()))?;
+        Arc::make_mut(&mut self.vars)
+            .shift_remove_index(i)
+            .map(|(_, v)| v)
+    }
+
+    /// Iterates over the variables in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.vars
+            .iter()
+            .map(|(k, v)| (k.as_os_str(), v.as_os_str()))
+    }
+
+    /// Returns the number of variables.
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// Returns `true` if no variables are set.
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Serializes the snapshot as NUL-terminated `KEY=VALUE` entries, the
+    /// same format printed by `env -0`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for (k, v) in self.iter() {
+            out.extend_from_slice(&os_to_bytes(k));
+            out.push(b'=');
+            out.extend_from_slice(&os_to_bytes(v));
+            out.push(0);
+        }
+        out
+    }
+
+    /// Parses NUL-separated `KEY=VALUE` entries as produced by `env -0`
+    /// or [`to_bytes`](Self::to_bytes). Malformed entries are skipped.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        bytes
+            .split(|b| *b == 0)
+            .filter_map(|entry| {
+                // skip the first byte so Windows' hidden `=C:=C:\` entries parse
+                let eq = entry.iter().skip(1).position(|b| *b == b'=')? + 1;
+                Some((bytes_to_os(&entry[..eq]), bytes_to_os(&entry[eq + 1..])))
+            })
+            .collect()
+    }
+}
+
+impl<K: AsRef<OsStr>, V: AsRef<OsStr>> FromIterator<(K, V)> for EnvSnapshot {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut snapshot = Self::default();
+        for (k, v) in iter {
+            snapshot.set(k, v);
+        }
+        snapshot
+    }
+}
+
+#[cfg(unix)]
+fn os_to_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_to_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn bytes_to_os(b: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(b).to_os_string()
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os(b: &[u8]) -> OsString {
+    String::from_utf8_lossy(b).to_string().into()
+}
+
+/// Looks up `key` in the environment a child would see: the runner's own
+/// overrides first, then its base snapshot, or the parent's environment if
+/// no base was set.
+pub(crate) fn child_var(
+    key: &str,
+    base: Option<&EnvSnapshot>,
+    envs: &IndexMap<OsString, OsString>,
+) -> Option<OsString> {
+    let found = envs.iter().find(|(k, _)| key_eq(k, OsStr::new(key)));
+    match (found, base) {
+        (Some((_, v)), _) => Some(v.clone()),
+        (None, Some(base)) => base.get(key).map(|v| v.to_os_string()),
+        (None, None) => std::env::var_os(key),
+    }
+}
+
+/// Compares environment variable names the way the platform does
+/// (case-insensitively on Windows).
+pub(crate) fn key_eq(a: &OsStr, b: &OsStr) -> bool {
+    if cfg!(windows) {
+        a.to_string_lossy()
+            .eq_ignore_ascii_case(&b.to_string_lossy())
+    } else {
+        a == b
+    }
+}
+
+/// Expands `${VAR}` and `%VAR%` references in `input`.
+///
+/// Unset `${VAR}` references expand to an empty string (like `sh`), while
+/// unset `%VAR%` references are left untouched (like `cmd.exe`).
+pub(crate) fn expand(input: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(i) = rest.find(['$', '%']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(name) = rest
+            .strip_prefix("${")
+            .and_then(|r| r.split_once('}'))
+            .map(|(name, _)| name)
+            .filter(|name| is_var_name(name, false))
+        {
+            out.push_str(&lookup(name).unwrap_or_default());
+            rest = &rest[name.len() + 3..];
+        } else if let Some((name, value)) = rest
+            .strip_prefix('%')
+            .and_then(|r| r.split_once('%'))
+            .map(|(name, _)| name)
+            .filter(|name| is_var_name(name, true))
+            .and_then(|name| lookup(name).map(|value| (name, value)))
+        {
+            out.push_str(&value);
+            rest = &rest[name.len() + 2..];
+        } else {
+            out.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_var_name(name: &str, windows_style: bool) -> bool {
+    let mut chars = name.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    (first.is_ascii_alphabetic() || first == '_')
+        && chars
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || (windows_style && "()".contains(c)))
+}
+
+/// A single difference between the parent's environment and a child's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EnvChange {
+    Added(String, String),
+    Changed(String, String, String),
+    Removed(String),
+}
+
+/// Computes how the child's environment differs from the parent's.
+pub(crate) fn diff(
+    base: Option<&EnvSnapshot>,
+    envs: &IndexMap<OsString, OsString>,
+) -> Vec<EnvChange> {
+    let mut changes = vec![];
+    let mut child = envs.clone();
+    if let Some(base) = base {
+        let mut removed = std::env::vars_os()
+            .filter(|(k, _)| base.get(k).is_none() && !envs.keys().any(|e| key_eq(e, k)))
+            .map(|(k, _)| k.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        removed.sort();
+        changes.extend(removed.into_iter().map(EnvChange::Removed));
+        for (k, v) in base.iter() {
+            if !envs.keys().any(|e| key_eq(e, k)) {
+                child.insert(k.to_os_string(), v.to_os_string());
+            }
+        }
+    }
+    for (k, v) in &child {
+        let key = k.to_string_lossy().to_string();
+        let val = v.to_string_lossy().to_string();
+        match std::env::var_os(k) {
+            None => changes.push(EnvChange::Added(key, val)),
+            Some(old) if &old != v => changes.push(EnvChange::Changed(
+                key,
+                old.to_string_lossy().to_string(),
+                val,
+            )),
+            Some(_) => {}
+        }
+    }
+    changes
+}
+
+/// Returns `true` for variable names that conventionally hold credentials.
+pub(crate) fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    [
+        "TOKEN",
+        "SECRET",
+        "PASSWORD",
+        "PASSWD",
+        "API_KEY",
+        "PRIVATE_KEY",
+        "CREDENTIAL",
+    ]
+    .iter()
+    .any(|s| key.contains(s))
+}
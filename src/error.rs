@@ -1,6 +1,9 @@
 use thiserror::Error;
 
 use crate::cmd::CmdResult;
+use crate::FailureMessage;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Errors that can occur when executing commands.
 #[derive(Error, Debug)]
@@ -21,28 +24,328 @@ pub enum Error {
 
     /// The command exited with a non-zero status code.
     ///
-    /// Contains the program name, arguments, combined output, and result.
-    #[error("{} exited with non-zero status: {}\n{}", .0.0, render_exit_status(&.0.3), .0.2)]
-    ScriptFailed(Box<(String, Vec<String>, String, CmdResult)>),
+    /// The result's [`labels`](CmdResult::labels) are shown after the exit
+    /// status, the command's [`context`](crate::CmdLineRunner::context)
+    /// before the program, and its [`hint`](crate::CmdLineRunner::hint)
+    /// after the output. A [`FailureMessage`] replaces all of this.
+    #[error("{}", render_failure(.0))]
+    ScriptFailed(Box<ScriptFailure>),
+
+    /// The program could not be found on `PATH`.
+    ///
+    /// Returned when spawning fails because the program doesn't exist, or
+    /// before spawning when [`resolve_program`](crate::CmdLineRunner::resolve_program)
+    /// is enabled. Close matches on `PATH` are suggested after the program.
+    #[error("{}: command not found{}", .0.program, render_suggestions(&.0.suggestions))]
+    ProgramNotFound(Box<MissingProgram>),
+
+    /// The working directory set with
+    /// [`current_dir`](crate::CmdLineRunner::current_dir) doesn't exist or
+    /// isn't a directory, so the program couldn't start.
+    ///
+    /// Contains the program name and the working directory.
+    #[error("{}: working directory {} does not exist or is not a directory", .0, .1.display())]
+    CurrentDirNotFound(String, PathBuf),
+
+    /// The problems [`validate`](crate::CmdLineRunner::validate) found with a
+    /// command before running it.
+    #[error("{}", render_problems(.0))]
+    Preflight(Vec<Error>),
+
+    /// A command line string could not be parsed.
+    #[error("invalid command line: {0}")]
+    InvalidCommandLine(String),
+
+    /// The user declined the UAC prompt for an
+    /// [`elevated`](crate::CmdLineRunner::elevated) command.
+    #[error("elevation was cancelled")]
+    ElevationCancelled,
 
     /// The command was cancelled via a cancellation token.
     #[error("command was cancelled")]
     Cancelled,
 
-    /// The command timed out.
-    #[error("command timed out")]
-    TimedOut,
+    /// A [`Policy`](crate::Policy) rejected the command before it started.
+    ///
+    /// Contains the program name and the reason.
+    #[error("{0} denied by policy: {1}")]
+    PolicyDenied(String, String),
+
+    /// The command ran longer than its
+    /// [`timeout`](crate::CmdLineRunner::timeout) and was killed.
+    #[error("command timed out after {:.2}s", .elapsed.as_secs_f64())]
+    TimedOut {
+        /// How long the command ran before it was killed.
+        elapsed: Duration,
+        /// What the command printed before it was killed, and the status it
+        /// exited with once killed.
+        partial: Box<CmdResult>,
+    },
+
+    /// An error of the caller's own, returned in place of
+    /// [`Error::ScriptFailed`] when a mapping set with
+    /// [`map_exit_code`](crate::CmdLineRunner::map_exit_code) or
+    /// [`map_failure`](crate::CmdLineRunner::map_failure) matched.
+    ///
+    /// Get it back with [`downcast_ref`](Error::downcast_ref).
+    #[error(transparent)]
+    Custom(Box<dyn std::error::Error + Send + Sync>),
 
     #[error("internal error: {0}")]
     Internal(String),
 }
 
+impl Error {
+    /// Returns the error set with
+    /// [`map_exit_code`](crate::CmdLineRunner::map_exit_code) or
+    /// [`map_failure`](crate::CmdLineRunner::map_failure), if this is an
+    /// [`Error::Custom`] of type `E`.
+    pub fn downcast_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        match self {
+            Error::Custom(error) => error.downcast_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// An I/O error with the [`context`](crate::CmdLineRunner::context) of the
+/// command it came from, keeping the original as its source.
+#[derive(Debug)]
+pub(crate) struct IoContext {
+    context: String,
+    source: std::io::Error,
+}
+
+impl IoContext {
+    /// Wraps `source` in an I/O error of the same kind, shown after
+    /// `context`.
+    pub(crate) fn wrap(context: String, source: std::io::Error) -> std::io::Error {
+        std::io::Error::new(source.kind(), Self { context, source })
+    }
+}
+
+impl std::fmt::Display for IoContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl std::error::Error for IoContext {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// A command that exited with a non-zero status, returned in
+/// [`Error::ScriptFailed`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Error};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// match CmdLineRunner::new("cargo").arg("test").execute().await {
+///     Err(Error::ScriptFailed(failure)) if failure.exit_code() == Some(101) => {
+///         eprintln!("tests failed after {:?}", failure.duration());
+///     }
+///     _ => {}
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ScriptFailure {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) output: String,
+    pub(crate) result: CmdResult,
+    pub(crate) context: Option<String>,
+    pub(crate) hint: Option<String>,
+    pub(crate) message: Option<FailureMessage>,
+}
+
+impl ScriptFailure {
+    /// The program that was run.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Its arguments, as displayed, with redactions applied.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// The working directory, if one was set.
+    pub fn current_dir(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// The combined stdout and stderr, trimmed, followed by a note if the
+    /// command exceeded one of its resource limits.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// The captured stdout, for tools that print their errors there.
+    pub fn stdout(&self) -> &str {
+        &self.result.stdout
+    }
+
+    /// The captured stderr.
+    pub fn stderr(&self) -> &str {
+        &self.result.stderr
+    }
+
+    /// What the command was for, set with
+    /// [`CmdLineRunner::context`](crate::CmdLineRunner::context).
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_deref()
+    }
+
+    /// The advice set with [`CmdLineRunner::hint`](crate::CmdLineRunner::hint).
+    pub fn hint(&self) -> Option<&str> {
+        self.hint.as_deref()
+    }
+
+    /// The result of the command.
+    pub fn result(&self) -> &CmdResult {
+        &self.result
+    }
+
+    /// Returns the result of the command, dropping the rest.
+    pub fn into_result(self) -> CmdResult {
+        self.result
+    }
+
+    /// The exit code, or `None` if the command was killed by a signal.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.result.status.code()
+    }
+
+    /// How long the command ran.
+    pub fn duration(&self) -> Duration {
+        self.result.duration
+    }
+}
+
+/// A program that couldn't be found, returned in [`Error::ProgramNotFound`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Error};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// if let Err(Error::ProgramNotFound(missing)) = CmdLineRunner::new("kubctl").execute().await {
+///     for suggestion in missing.suggestions() {
+///         eprintln!("did you mean `{suggestion}`?");
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MissingProgram {
+    pub(crate) program: String,
+    pub(crate) searched: Vec<PathBuf>,
+    pub(crate) suggestions: Vec<String>,
+}
+
+impl MissingProgram {
+    /// The program that couldn't be found.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// The `PATH` directories that were searched, in order.
+    pub fn searched(&self) -> &[PathBuf] {
+        &self.searched
+    }
+
+    /// Programs on `PATH` with similar names, closest first.
+    pub fn suggestions(&self) -> &[String] {
+        &self.suggestions
+    }
+}
+
 /// A specialized Result type for ensembler operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
-fn render_exit_status(result: &CmdResult) -> String {
-    match result.status.code() {
+/// Renders labels as `key=value` pairs separated by commas.
+pub(crate) fn render_labels(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn render_suggestions(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [one] => format!(" (did you mean `{one}`?)"),
+        [rest @ .., last] => {
+            let rest = rest.iter().map(|s| format!("`{s}`")).collect::<Vec<_>>();
+            format!(" (did you mean {} or `{last}`?)", rest.join(", "))
+        }
+    }
+}
+
+fn render_problems(problems: &[Error]) -> String {
+    match problems {
+        [problem] => problem.to_string(),
+        problems => {
+            let list = problems
+                .iter()
+                .map(|p| format!("\n  {p}"))
+                .collect::<String>();
+            format!("{} problems found:{list}", problems.len())
+        }
+    }
+}
+
+fn render_failure(failure: &ScriptFailure) -> String {
+    if let Some(message) = &failure.message {
+        return message.render(failure);
+    }
+    let context = match &failure.context {
+        Some(context) => format!("{context}: "),
+        None => String::new(),
+    };
+    let mut rendered = format!(
+        "{context}{} exited with non-zero status: {}{}\n{}",
+        failure.program,
+        render_exit_status(&failure.result.status),
+        render_result_labels(&failure.result),
+        failure.output
+    );
+    if let Some(hint) = &failure.hint {
+        rendered += &format!("\nhint: {hint}");
+    }
+    rendered
+}
+
+fn render_result_labels(result: &CmdResult) -> String {
+    match result.labels.as_slice() {
+        [] => String::new(),
+        labels => format!(" ({})", render_labels(labels)),
+    }
+}
+
+pub(crate) fn render_exit_status(status: &std::process::ExitStatus) -> String {
+    match status.code() {
         Some(exit_status) => format!("exit code {exit_status}"),
+        #[cfg(unix)]
+        None => {
+            use std::os::unix::process::ExitStatusExt;
+            match status.signal().map(nix::sys::signal::Signal::try_from) {
+                Some(Ok(signal)) => format!("killed by {signal}"),
+                Some(Err(_)) | None => "no exit status".into(),
+            }
+        }
+        #[cfg(not(unix))]
         None => "no exit status".into(),
     }
 }
@@ -0,0 +1,229 @@
+//! Running commands as another user with `sudo` or `doas`.
+
+use crate::shell_words::quote;
+use crate::tempfile::TempFile;
+use std::ffi::OsString;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Sudo,
+    Doas,
+}
+
+/// Runs a command through `sudo` or `doas` (Unix only).
+///
+/// Set with [`CmdLineRunner::escalate`](crate::CmdLineRunner::escalate).
+/// The command runs as root unless a [`user`](Self::user) is set.
+///
+/// Both tools reset the environment, so env vars set on the runner with
+/// [`env`](crate::CmdLineRunner::env) are passed on without putting their
+/// values on a command line, where other users could see them. sudo keeps
+/// them with `--preserve-env`, which the sudoers policy must allow. doas
+/// has no such option, so the command runs through `sh`, which reads them
+/// from a temporary file readable only by the current user; this only works
+/// when running as root, and fails with [`Error::Io`](crate::Error::Io)
+/// with a [`user`](Self::user). Inherited vars are dropped unless listed in
+/// [`preserve_env`](Self::preserve_env).
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Escalation};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let password = std::env::var("SUDO_PASSWORD").unwrap();
+/// CmdLineRunner::new("systemctl")
+///     .args(["restart", "nginx"])
+///     .escalate(
+///         Escalation::sudo()
+///             .preserve_env(["HTTP_PROXY"])
+///             .password(password),
+///     )
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Escalation {
+    tool: Tool,
+    user: Option<String>,
+    preserve_env: Vec<String>,
+    password: Option<String>,
+}
+
+impl Escalation {
+    /// Escalates with `sudo`.
+    pub fn sudo() -> Self {
+        Self::new(Tool::Sudo)
+    }
+
+    /// Escalates with OpenBSD's `doas`.
+    pub fn doas() -> Self {
+        Self::new(Tool::Doas)
+    }
+
+    fn new(tool: Tool) -> Self {
+        Self {
+            tool,
+            user: None,
+            preserve_env: vec![],
+            password: None,
+        }
+    }
+
+    /// Runs the command as `user` instead of root (`-u`).
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Keeps these inherited env vars, e.g. `["HTTP_PROXY", "SSH_AUTH_SOCK"]`.
+    ///
+    /// With sudo they are passed with `--preserve-env`, which the sudoers
+    /// policy may refuse. doas has no such option, so their current values
+    /// are passed like the runner's own vars.
+    pub fn preserve_env<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.preserve_env.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Answers sudo's password prompt with `password` instead of asking on
+    /// the terminal.
+    ///
+    /// The password is given to sudo by a temporary askpass script
+    /// (`sudo -A`) readable only by the current user, and is redacted from
+    /// the command's output and errors. doas can only read passwords from a
+    /// terminal, so running with a password fails with
+    /// [`Error::Io`](crate::Error::Io).
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub(crate) fn password_str(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    /// The escalation program.
+    pub(crate) fn program(&self) -> &'static str {
+        match self.tool {
+            Tool::Sudo => "sudo",
+            Tool::Doas => "doas",
+        }
+    }
+
+    /// Returns the arguments to escalate with, up to the program, and the
+    /// temp files they refer to.
+    ///
+    /// `envs` are the runner's vars, which sudo must be started with, and
+    /// `lookup` finds the current value of a preserved var.
+    pub(crate) fn command_args<'a>(
+        &self,
+        envs: impl IntoIterator<Item = (&'a OsString, &'a OsString)>,
+        lookup: impl Fn(&str) -> Option<OsString>,
+    ) -> io::Result<Escalated> {
+        let mut args: Vec<OsString> = vec![];
+        let mut askpass = None;
+        let mut env_file = None;
+        if let Some(password) = &self.password {
+            if self.tool == Tool::Doas {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "doas can only read a password from a terminal",
+                ));
+            }
+            let script = format!("#!/bin/sh\nprintf '%s\\n' {}\n", quote(password));
+            askpass = Some(TempFile::create("sh", script.as_bytes(), true)?);
+            args.push("-A".into());
+        }
+        if let Some(user) = &self.user {
+            args.extend(["-u".into(), user.into()]);
+        }
+        match self.tool {
+            Tool::Sudo => {
+                let mut keys = self.preserve_env.clone();
+                keys.extend(
+                    envs.into_iter()
+                        .map(|(k, _)| k.to_string_lossy().into_owned()),
+                );
+                if !keys.is_empty() {
+                    args.push(format!("--preserve-env={}", keys.join(",")).into());
+                }
+                args.push("--".into());
+            }
+            Tool::Doas => {
+                let mut vars: Vec<(String, OsString)> = vec![];
+                for key in &self.preserve_env {
+                    if let Some(value) = lookup(key) {
+                        vars.push((key.clone(), value));
+                    }
+                }
+                vars.extend(
+                    envs.into_iter()
+                        .map(|(k, v)| (k.to_string_lossy().into_owned(), v.clone())),
+                );
+                args.push("--".into());
+                if !vars.is_empty() {
+                    if self.user.is_some() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "doas can only pass env vars to a command run as root",
+                        ));
+                    }
+                    let file = TempFile::create("sh", env_script(&vars)?.as_bytes(), false)?;
+                    args.extend([
+                        "sh".into(),
+                        "-c".into(),
+                        ". \"$0\" && exec \"$@\"".into(),
+                        file.path().into(),
+                    ]);
+                    env_file = Some(file);
+                }
+            }
+        }
+        Ok(Escalated {
+            args,
+            askpass,
+            env_file,
+        })
+    }
+}
+
+/// The arguments [`Escalation::command_args`] returns, with the temp files
+/// they refer to, which must outlive the command.
+pub(crate) struct Escalated {
+    /// The arguments to the escalation program, up to the command's program
+    pub(crate) args: Vec<OsString>,
+    /// The askpass script for `SUDO_ASKPASS`
+    pub(crate) askpass: Option<TempFile>,
+    /// The env vars for doas's `sh` to read
+    pub(crate) env_file: Option<TempFile>,
+}
+
+/// Renders `vars` as a script that exports them, failing on names `sh`
+/// can't assign.
+fn env_script(vars: &[(String, OsString)]) -> io::Result<String> {
+    let mut script = String::new();
+    for (key, value) in vars {
+        let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't pass env var {key:?} through doas"),
+            ));
+        }
+        script.push_str(&format!(
+            "export {key}={}\n",
+            quote(&value.to_string_lossy())
+        ));
+    }
+    Ok(script)
+}
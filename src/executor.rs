@@ -0,0 +1,234 @@
+//! Pluggable backends that start the processes [`CmdLineRunner`] runs.
+//!
+//! By default a runner starts its command as a local child process. Setting
+//! an [`Executor`] with [`CmdLineRunner::executor`] hands the configured
+//! program, args, environment and working directory to it instead, while the
+//! runner keeps handling output capture, redaction, stdin, timeouts,
+//! cancellation and progress.
+//!
+//! [`CmdLineRunner`]: crate::CmdLineRunner
+//! [`CmdLineRunner::executor`]: crate::CmdLineRunner::executor
+
+mod container;
+mod ssh;
+
+pub use container::{ContainerRunner, ContainerRuntime};
+pub use ssh::Ssh;
+
+use crate::EnvSnapshot;
+use indexmap::IndexMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A boxed future, as returned by [`Process`] methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Starts commands for a [`CmdLineRunner`](crate::CmdLineRunner).
+///
+/// # Example
+///
+/// An executor that runs every command under `nice`:
+///
+/// ```no_run
+/// use ensembler::executor::{Executor, Invocation, Process};
+/// use ensembler::CmdLineRunner;
+///
+/// #[derive(Debug)]
+/// struct Nice;
+///
+/// impl Executor for Nice {
+///     fn spawn(&self, invocation: &Invocation) -> ensembler::Result<Box<dyn Process>> {
+///         let mut cmd = invocation.command_with("nice", ["-n", "10"]);
+///         Ok(Box::new(cmd.spawn()?))
+///     }
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// CmdLineRunner::new("make").executor(Nice).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait Executor: Debug + Send + Sync {
+    /// Starts `invocation`, with stdout and stderr piped back.
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>>;
+}
+
+/// A command started by an [`Executor`].
+///
+/// Implemented for [`tokio::process::Child`], so executors that wrap the
+/// command in another local program can return the spawned child.
+pub trait Process: Send {
+    /// The process id, if the process is local.
+    fn id(&self) -> Option<u32>;
+
+    /// Takes the process's stdin, if it was piped.
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>>;
+
+    /// Takes the process's stdout, if it was piped.
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>>;
+
+    /// Takes the process's stderr, if it was piped.
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>>;
+
+    /// Waits for the process to exit.
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>>;
+
+    /// Stops the process on timeout or cancellation, asking it to exit first
+    /// if `grace_period` is set, and waits for it to exit.
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>>;
+
+    /// The CPU time and memory the process used, once [`wait`](Self::wait)
+    /// has returned, if it's known.
+    fn resource_usage(&self) -> Option<crate::ResourceUsage> {
+        None
+    }
+}
+
+impl Process for tokio::process::Child {
+    fn id(&self) -> Option<u32> {
+        tokio::process::Child::id(self)
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.stdin.take().map(|s| Box::pin(s) as _)
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.stdout.take().map(|s| Box::pin(s) as _)
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.stderr.take().map(|s| Box::pin(s) as _)
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(tokio::process::Child::wait(self))
+    }
+
+    fn kill(&mut self, _grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(tokio::process::Child::kill(self))
+    }
+}
+
+/// The command a runner asks an [`Executor`] to start.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub(crate) program: String,
+    pub(crate) args: Vec<OsString>,
+    pub(crate) env_base: Option<EnvSnapshot>,
+    pub(crate) envs: IndexMap<OsString, OsString>,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) stdin: bool,
+    pub(crate) redactor: Option<Arc<crate::cmd::Redactor>>,
+}
+
+impl Invocation {
+    /// The program to run.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// The arguments to pass to the program.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// The environment to start from, if the runner replaced the inherited
+    /// one (e.g. with [`env_clear`](crate::CmdLineRunner::env_clear)).
+    pub fn env_base(&self) -> Option<&EnvSnapshot> {
+        self.env_base.as_ref()
+    }
+
+    /// The environment variables set on the runner, applied on top of
+    /// [`env_base`](Self::env_base) or the inherited environment.
+    pub fn envs(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.envs
+            .iter()
+            .map(|(k, v)| (k.as_os_str(), v.as_os_str()))
+    }
+
+    /// The working directory, if set.
+    pub fn current_dir(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// Whether the runner writes to the process's stdin.
+    pub fn has_stdin(&self) -> bool {
+        self.stdin
+    }
+
+    /// Returns `s` with the runner's [`redact`](crate::CmdLineRunner::redact)
+    /// strings replaced, for executors that log or record the command.
+    pub fn redact(&self, s: &str) -> String {
+        match &self.redactor {
+            Some(redactor) => redactor.redact(s),
+            None => s.to_string(),
+        }
+    }
+
+    /// Returns a local command for the invocation, with its environment,
+    /// working directory and piped stdio.
+    pub fn command(&self) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new(&self.program);
+        cmd.args(&self.args);
+        self.configure(&mut cmd);
+        cmd
+    }
+
+    /// Like [`command`](Self::command), but runs the invocation through
+    /// `wrapper`, e.g. `command_with("nice", ["-n", "10"])` for
+    /// `nice -n 10 <program> <args>`.
+    pub fn command_with<I, S>(&self, wrapper: impl AsRef<OsStr>, args: I) -> tokio::process::Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut cmd = tokio::process::Command::new(wrapper);
+        cmd.args(args).arg(&self.program).args(&self.args);
+        self.configure(&mut cmd);
+        cmd
+    }
+
+    fn configure(&self, cmd: &mut tokio::process::Command) {
+        if let Some(base) = &self.env_base {
+            cmd.env_clear();
+            cmd.envs(base.iter());
+        }
+        cmd.envs(&self.envs);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.stdin(if self.stdin {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+    }
+}
+
+/// Builds the status of a process that exited with `code`, for results that
+/// don't come from a process that ran.
+pub(crate) fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw((code & 0xff) << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
@@ -0,0 +1,353 @@
+use super::{BoxFuture, Executor, Invocation, Process};
+use crate::tempfile::TempFile;
+use crate::CmdLineRunner;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock as Lazy;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::Child;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+static DETECTED: Lazy<Option<ContainerRuntime>> = Lazy::new(ContainerRuntime::detect);
+
+/// A container engine CLI that [`ContainerRunner`] can drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ContainerRuntime {
+    /// Docker, or a Docker-compatible CLI named `docker`.
+    Docker,
+    /// Podman, including rootless Podman.
+    Podman,
+    /// nerdctl for containerd.
+    Nerdctl,
+}
+
+impl ContainerRuntime {
+    /// Finds the first runtime on `PATH`, trying `docker`, `podman` and
+    /// `nerdctl` in that order.
+    ///
+    /// A `docker` that links to `podman` (as installed by `podman-docker`) is
+    /// detected as Podman.
+    pub fn detect() -> Option<Self> {
+        [Self::Docker, Self::Podman, Self::Nerdctl]
+            .into_iter()
+            .find_map(|runtime| {
+                let path = CmdLineRunner::which(runtime.program())?;
+                let target = std::fs::canonicalize(&path).unwrap_or(path);
+                match target.file_stem().and_then(|name| name.to_str()) {
+                    Some("podman") => Some(Self::Podman),
+                    _ => Some(runtime),
+                }
+            })
+    }
+
+    /// The runtime's CLI program.
+    pub fn program(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+            Self::Nerdctl => "nerdctl",
+        }
+    }
+}
+
+/// Runs commands in a container with the `docker`, `podman` or `nerdctl` CLI.
+///
+/// [`run`](Self::run) starts a fresh container from an image for each
+/// command and removes it afterwards; [`exec`](Self::exec) runs the command
+/// in an existing container. The runner's env vars, along with those of its
+/// [`env_snapshot`](crate::CmdLineRunner::env_snapshot) if set, are passed in a
+/// private `--env-file` so their values stay off the command line, and its
+/// working directory becomes the container's (`-w`), so it must be a path
+/// inside the container. The inherited environment is not forwarded, and the
+/// CLI itself runs with the caller's environment.
+///
+/// The runtime is detected with [`ContainerRuntime::detect`] unless set with
+/// [`runtime`](Self::runtime). With rootless Podman, `run` keeps the user's
+/// uid inside the container (`--userns=keep-id`) so files written to mounts
+/// stay owned by them, as they are with Docker.
+///
+/// On timeout or cancellation a `run` container is stopped with
+/// `<runtime> kill`, signalling it first when a grace period is set. For
+/// `exec`, only the CLI client is killed.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::executor::ContainerRunner;
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let result = CmdLineRunner::new("cargo")
+///     .arg("test")
+///     .env("RUST_BACKTRACE", "1")
+///     .current_dir("/src")
+///     .executor(ContainerRunner::run("rust:1.88").mount(".", "/src"))
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ContainerRunner {
+    runtime: Option<ContainerRuntime>,
+    program: Option<OsString>,
+    target: Target,
+    options: Vec<OsString>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Image(String),
+    Container(String),
+}
+
+impl ContainerRunner {
+    /// Runs each command in a new container from `image`.
+    pub fn run(image: impl Into<String>) -> Self {
+        Self {
+            runtime: None,
+            program: None,
+            target: Target::Image(image.into()),
+            options: vec![],
+        }
+    }
+
+    /// Runs each command in the running container `container`.
+    pub fn exec(container: impl Into<String>) -> Self {
+        Self {
+            runtime: None,
+            program: None,
+            target: Target::Container(container.into()),
+            options: vec![],
+        }
+    }
+
+    /// Uses `runtime` instead of detecting one.
+    pub fn runtime(mut self, runtime: ContainerRuntime) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Runs the runtime's CLI from `program` rather than from `PATH`.
+    pub fn program(mut self, program: impl Into<OsString>) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Bind mounts the host's `src` at `dest` in the container.
+    ///
+    /// Ignored by [`exec`](Self::exec).
+    pub fn mount(self, src: impl AsRef<Path>, dest: &str) -> Self {
+        self.volume(src.as_ref(), dest, "")
+    }
+
+    /// Bind mounts the host's `src` read-only at `dest` in the container.
+    ///
+    /// Ignored by [`exec`](Self::exec).
+    pub fn mount_ro(self, src: impl AsRef<Path>, dest: &str) -> Self {
+        self.volume(src.as_ref(), dest, ":ro")
+    }
+
+    /// Runs the command as `user` (`name`, `uid` or `uid:gid`).
+    pub fn user(self, user: &str) -> Self {
+        self.options(["--user", user])
+    }
+
+    /// Passes other options to `run`/`exec`, e.g.
+    /// `["--network", "none"]`.
+    pub fn options<I, S>(mut self, options: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.options.extend(options.into_iter().map(Into::into));
+        self
+    }
+
+    fn volume(mut self, src: &Path, dest: &str, mode: &str) -> Self {
+        if matches!(self.target, Target::Image(_)) {
+            // runtimes require absolute host paths
+            let src = std::path::absolute(src).unwrap_or_else(|_| src.to_path_buf());
+            let mut volume = src.into_os_string();
+            volume.push(format!(":{dest}{mode}"));
+            self.options.extend(["-v".into(), volume]);
+        }
+        self
+    }
+}
+
+impl Executor for ContainerRunner {
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>> {
+        let runtime = self
+            .runtime
+            .or(*DETECTED)
+            .ok_or_else(|| crate::which::not_found("docker, podman or nerdctl", None))?;
+        let program = self
+            .program
+            .clone()
+            .unwrap_or_else(|| runtime.program().into());
+        let mut cmd = tokio::process::Command::new(&program);
+        let name = match &self.target {
+            Target::Image(_) => {
+                let name = format!(
+                    "ensembler-{}-{}",
+                    std::process::id(),
+                    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+                );
+                cmd.args(["run", "--rm", "--name", &name]);
+                if runtime == ContainerRuntime::Podman && self.rootless() && !self.sets_user() {
+                    cmd.arg("--userns=keep-id");
+                }
+                Some(name)
+            }
+            Target::Container(_) => {
+                cmd.arg("exec");
+                None
+            }
+        };
+        if invocation.has_stdin() {
+            cmd.arg("-i");
+        }
+        if let Some(cwd) = invocation.current_dir() {
+            cmd.arg("-w").arg(cwd);
+        }
+        let env_file = env_file(invocation)?;
+        if let Some(file) = &env_file {
+            cmd.arg("--env-file").arg(file.path());
+        }
+        cmd.args(&self.options);
+        match &self.target {
+            Target::Image(image) => cmd.arg(image),
+            Target::Container(container) => cmd.arg(container),
+        };
+        cmd.arg(invocation.program()).args(invocation.args());
+        cmd.stdin(if invocation.has_stdin() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+        Ok(Box::new(ContainerProcess {
+            child: cmd.spawn()?,
+            program,
+            name,
+            _env_file: env_file,
+        }))
+    }
+}
+
+/// Writes the invocation's env vars to a file for `--env-file`, which takes
+/// one `KEY=value` per line with no quoting.
+fn env_file(invocation: &Invocation) -> io::Result<Option<TempFile>> {
+    let mut env = invocation.env_base().cloned().unwrap_or_default();
+    for (key, val) in invocation.envs() {
+        env.set(key, val);
+    }
+    if env.iter().next().is_none() {
+        return Ok(None);
+    }
+    let mut contents = vec![];
+    for (key, val) in env.iter() {
+        let valid = |s: &OsStr| {
+            !s.as_encoded_bytes()
+                .iter()
+                .any(|b| matches!(b, b'\n' | b'\r'))
+        };
+        if key.is_empty() || key.as_encoded_bytes().contains(&b'=') || !valid(key) || !valid(val) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't pass env var {key:?} to a container: --env-file has no quoting"),
+            ));
+        }
+        contents.extend_from_slice(key.as_encoded_bytes());
+        contents.push(b'=');
+        contents.extend_from_slice(val.as_encoded_bytes());
+        contents.push(b'\n');
+    }
+    TempFile::create("env", &contents, false).map(Some)
+}
+
+impl ContainerRunner {
+    fn rootless(&self) -> bool {
+        #[cfg(unix)]
+        return !nix::unistd::geteuid().is_root();
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// Whether the options already pick the container's user or namespace.
+    fn sets_user(&self) -> bool {
+        self.options.iter().any(|o| {
+            let o = o.to_string_lossy();
+            o == "--user" || o == "-u" || o.starts_with("--user=") || o.starts_with("--userns")
+        })
+    }
+}
+
+struct ContainerProcess {
+    child: Child,
+    program: OsString,
+    /// The container's name, for containers started by `run`.
+    name: Option<String>,
+    _env_file: Option<TempFile>,
+}
+
+impl ContainerProcess {
+    async fn kill_container(&self, name: &str, signal: &str) {
+        let status = tokio::process::Command::new(&self.program)
+            .args(["kill", "--signal", signal, name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if let Err(e) = status {
+            debug!("Failed to send {signal} to container {name}: {e}");
+        }
+    }
+}
+
+impl Process for ContainerProcess {
+    fn id(&self) -> Option<u32> {
+        None
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.child.take_stdin()
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.child.take_stdout()
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.child.take_stderr()
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(self.child.wait())
+    }
+
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(name) = self.name.clone() {
+                if let Some(grace_period) = grace_period {
+                    self.kill_container(&name, "TERM").await;
+                    let _ = tokio::time::timeout(grace_period, self.child.wait()).await;
+                }
+                self.kill_container(&name, "KILL").await;
+            }
+            self.child.kill().await
+        })
+    }
+}
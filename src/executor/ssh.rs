@@ -0,0 +1,260 @@
+use super::{BoxFuture, Executor, Invocation, Process};
+use crate::shell_words::quote;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::process::{Child, ChildStdout};
+
+/// Runs commands on a remote host with the `ssh` client.
+///
+/// The command line is quoted for the remote shell, so arguments, env vars
+/// and the working directory arrive intact. Stdin is forwarded, and on
+/// timeout or cancellation the remote process group is signalled over a
+/// second connection before the local client is killed.
+///
+/// `ssh` runs non-interactively (`BatchMode=yes`), so authentication must
+/// work without prompting, e.g. with an agent or key file. The remote login
+/// shell must accept POSIX quoting, and the command runs under `sh`.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::executor::Ssh;
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let result = CmdLineRunner::new("systemctl")
+///     .args(["restart", "my app"])
+///     .env("DEPLOY_ID", "42")
+///     .current_dir("/srv/app")
+///     .executor(Ssh::new("deploy@web1").port(2222))
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Ssh {
+    program: OsString,
+    destination: String,
+    options: Vec<OsString>,
+}
+
+impl Ssh {
+    /// Creates an executor for `destination`, e.g. `user@host`.
+    pub fn new(destination: impl Into<String>) -> Self {
+        Self {
+            program: "ssh".into(),
+            destination: destination.into(),
+            options: vec![],
+        }
+    }
+
+    /// Uses `program` as the ssh client instead of `ssh` from `PATH`.
+    pub fn program(mut self, program: impl Into<OsString>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Connects to `port` instead of the default.
+    pub fn port(self, port: u16) -> Self {
+        self.options(["-p".to_string(), port.to_string()])
+    }
+
+    /// Authenticates with the private key in `path`.
+    pub fn identity_file(self, path: impl AsRef<Path>) -> Self {
+        self.options([
+            OsString::from("-i"),
+            path.as_ref().as_os_str().to_os_string(),
+        ])
+    }
+
+    /// Sets an `ssh_config` option, e.g. `option("StrictHostKeyChecking", "no")`.
+    pub fn option(self, key: &str, value: &str) -> Self {
+        self.options(["-o".to_string(), format!("{key}={value}")])
+    }
+
+    /// Passes other options to `ssh`, e.g. `["-J", "bastion"]`.
+    pub fn options<I, S>(mut self, options: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.options.extend(options.into_iter().map(Into::into));
+        self
+    }
+
+    /// Returns an `ssh` command that runs `script` with `sh` on the host.
+    fn command(&self, script: &str) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new(&self.program);
+        cmd.args(["-T", "-o", "BatchMode=yes"])
+            .args(&self.options)
+            .arg("--")
+            .arg(&self.destination)
+            // exec so the script's shell leads the session's process group
+            .arg(format!("exec sh -c {}", quote(script)));
+        cmd
+    }
+}
+
+impl Executor for Ssh {
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>> {
+        let mut cmd = self.command(&remote_script(invocation));
+        cmd.stdin(if invocation.has_stdin() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+        Ok(Box::new(SshProcess {
+            child: cmd.spawn()?,
+            ssh: self.clone(),
+            pid: Arc::default(),
+        }))
+    }
+}
+
+/// Builds the remote `sh` script: it prints its pid on the first line of
+/// stdout for [`SshProcess::kill`], then runs the command with its env and
+/// cwd in its place, so the pid stays that of the process group's leader.
+fn remote_script(invocation: &Invocation) -> String {
+    let mut script = "echo $$".to_string();
+    if let Some(cwd) = invocation.current_dir() {
+        script.push_str(" && cd ");
+        script.push_str(&quote(&cwd.to_string_lossy()));
+    }
+    script.push_str(" && exec env");
+    if let Some(base) = invocation.env_base() {
+        script.push_str(" -i");
+        for (k, v) in base.iter() {
+            push_var(&mut script, &k.to_string_lossy(), &v.to_string_lossy());
+        }
+    }
+    for (k, v) in invocation.envs() {
+        push_var(&mut script, &k.to_string_lossy(), &v.to_string_lossy());
+    }
+    script.push(' ');
+    script.push_str(&quote(invocation.program()));
+    for arg in invocation.args() {
+        script.push(' ');
+        script.push_str(&quote(&arg.to_string_lossy()));
+    }
+    script
+}
+
+fn push_var(script: &mut String, key: &str, val: &str) {
+    script.push(' ');
+    script.push_str(&quote(&format!("{key}={val}")));
+}
+
+/// A command started by [`Ssh`].
+struct SshProcess {
+    child: Child,
+    ssh: Ssh,
+    /// The remote shell's pid, once [`PidReader`] has read it
+    pid: Arc<OnceLock<u32>>,
+}
+
+impl SshProcess {
+    /// Sends `signal` to the remote process group over a new connection.
+    async fn signal_remote(&self, signal: &str) {
+        let Some(pid) = self.pid.get() else {
+            debug!("No pid from {} to signal yet", self.ssh.destination);
+            return;
+        };
+        let script = format!("kill -{signal} -- -{pid} 2>/dev/null");
+        let mut cmd = self.ssh.command(&script);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Err(e) = cmd.status().await {
+            debug!("Failed to signal {}: {e}", self.ssh.destination);
+        }
+    }
+}
+
+impl Process for SshProcess {
+    fn id(&self) -> Option<u32> {
+        None
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.child.take_stdin()
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let stdout = self.child.stdout.take()?;
+        Some(Box::pin(PidReader {
+            inner: stdout,
+            pid: self.pid.clone(),
+            line: Some(vec![]),
+        }))
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        self.child.take_stderr()
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(self.child.wait())
+    }
+
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if let Some(grace_period) = grace_period {
+                self.signal_remote("TERM").await;
+                let _ = tokio::time::timeout(grace_period, self.child.wait()).await;
+            }
+            self.signal_remote("KILL").await;
+            self.child.kill().await
+        })
+    }
+}
+
+/// Takes the remote shell's pid from the first line of stdout, passing the
+/// rest through as the command's output.
+struct PidReader {
+    inner: ChildStdout,
+    pid: Arc<OnceLock<u32>>,
+    /// The first line so far, until it's complete
+    line: Option<Vec<u8>>,
+}
+
+impl AsyncRead for PidReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while let Some(line) = &mut this.line {
+            // a byte at a time, so none of the command's output is read
+            let mut byte = [0];
+            let mut byte_buf = ReadBuf::new(&mut byte);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut byte_buf))?;
+            match byte_buf.filled().first() {
+                None => return Poll::Ready(Ok(())),
+                Some(b'\n') => {
+                    if let Some(pid) = std::str::from_utf8(line)
+                        .ok()
+                        .and_then(|line| line.trim().parse().ok())
+                    {
+                        let _ = this.pid.set(pid);
+                    }
+                    this.line = None;
+                }
+                Some(&b) => line.push(b),
+            }
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
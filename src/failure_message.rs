@@ -0,0 +1,121 @@
+//! Custom messages for commands that fail.
+
+use crate::ScriptFailure;
+
+/// A template for how [`Error::ScriptFailed`](crate::Error::ScriptFailed)
+/// is displayed, in place of the default dump of the exit status and
+/// output, so a CLI's users see a message written for them.
+///
+/// Set for one command with
+/// [`CmdLineRunner::failure_message`](crate::CmdLineRunner::failure_message),
+/// or for every command with
+/// [`Defaults::failure_message`](crate::Defaults::failure_message). The
+/// template can contain these placeholders:
+///
+/// - `{program}`: the program
+/// - `{args}`: the arguments, separated by spaces, with redactions applied
+/// - `{command}`: the program and arguments
+/// - `{exit_code}`: the exit code, or nothing if the command was killed by a
+///   signal
+/// - `{status}`: the exit status, such as `exit code 2` or `killed by SIGKILL`
+/// - `{output}`: the combined stdout and stderr
+/// - `{tail}`: the last lines of output; see [`tail_lines`](Self::tail_lines)
+/// - `{hint}`: the text set with [`CmdLineRunner::hint`](crate::CmdLineRunner::hint)
+/// - `{context}`: the text set with [`CmdLineRunner::context`](crate::CmdLineRunner::context)
+/// - `{labels}`: the [labels](crate::CmdLineRunner::label), as `key=value`
+///   pairs separated by commas
+///
+/// Other text, including unknown placeholders, is kept as is.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, FailureMessage};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// ensembler::set_defaults(Defaults::new().failure_message(
+///     FailureMessage::new("`{command}` failed ({status}):\n{tail}\n{hint}").tail_lines(5),
+/// ));
+///
+/// CmdLineRunner::new("npm")
+///     .arg("ci")
+///     .hint("try deleting node_modules and running again")
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureMessage {
+    template: String,
+    tail_lines: usize,
+}
+
+impl FailureMessage {
+    /// Creates a message from `template`.
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+            tail_lines: 10,
+        }
+    }
+
+    /// Sets how many lines of output `{tail}` shows. Defaults to 10.
+    pub fn tail_lines(mut self, lines: usize) -> Self {
+        self.tail_lines = lines;
+        self
+    }
+
+    /// Renders the message for `failure`.
+    pub fn render(&self, failure: &ScriptFailure) -> String {
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            rendered += &rest[..start];
+            rest = &rest[start..];
+            let value = rest
+                .find('}')
+                .and_then(|end| Some((end, self.placeholder(&rest[1..end], failure)?)));
+            match value {
+                Some((end, value)) => {
+                    rendered += &value;
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered + rest
+    }
+
+    /// Returns the value of the placeholder called `name`, if there is one.
+    fn placeholder(&self, name: &str, failure: &ScriptFailure) -> Option<String> {
+        let value = match name {
+            "program" => failure.program().to_string(),
+            "args" => failure.args().join(" "),
+            "command" => [failure.program()]
+                .into_iter()
+                .chain(failure.args().iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "exit_code" => failure
+                .exit_code()
+                .map(|code| code.to_string())
+                .unwrap_or_default(),
+            "status" => crate::error::render_exit_status(&failure.result().status),
+            "output" => failure.output().to_string(),
+            "tail" => {
+                let lines = failure.output().lines().collect::<Vec<_>>();
+                lines[lines.len().saturating_sub(self.tail_lines)..].join("\n")
+            }
+            "hint" => failure.hint().unwrap_or_default().to_string(),
+            "context" => failure.context().unwrap_or_default().to_string(),
+            "labels" => crate::error::render_labels(&failure.result().labels),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
@@ -0,0 +1,171 @@
+//! Skipping commands whose outputs are newer than their inputs.
+
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Skips a command when its declared outputs are up to date with its
+/// declared inputs, like a `make` rule.
+///
+/// By default a command is up to date when every output exists and none is
+/// older than the newest input. With [`stamp`](Self::stamp), the inputs'
+/// contents and the command line are hashed instead, so touching an input
+/// without changing it doesn't rerun the command, and changing the args
+/// does. Directories count as everything in them. A command with no outputs
+/// or a missing input is never up to date.
+///
+/// When the command is skipped, [`execute`](crate::CmdLineRunner::execute)
+/// returns a successful [`CmdResult`](crate::CmdResult) with no output and
+/// [`up_to_date`](crate::CmdResult::up_to_date) set. Set with
+/// [`CmdLineRunner::freshness`](crate::CmdLineRunner::freshness).
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Freshness};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let result = CmdLineRunner::new("cc")
+///     .args(["-o", "hello", "hello.c"])
+///     .freshness(Freshness::new().input("hello.c").output("hello"))
+///     .execute()
+///     .await?;
+/// if result.up_to_date {
+///     println!("hello is up to date");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Freshness {
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    stamp: Option<PathBuf>,
+}
+
+impl Freshness {
+    /// Creates a check with no inputs or outputs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file or directory the command reads. Relative paths are
+    /// resolved from the command's working directory.
+    pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+        self.inputs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a file or directory the command writes. Relative paths are
+    /// resolved from the command's working directory.
+    pub fn output(mut self, path: impl AsRef<Path>) -> Self {
+        self.outputs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Compares content hashes instead of modification times, keeping the
+    /// hash from the last successful run in the file at `path`, which is
+    /// resolved like the inputs.
+    pub fn stamp(mut self, path: impl AsRef<Path>) -> Self {
+        self.stamp = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Hashes the command and its inputs, if this compares hashes.
+    pub(crate) fn key<'a>(
+        &self,
+        command: impl IntoIterator<Item = &'a OsStr>,
+        cwd: &Path,
+    ) -> io::Result<Option<String>> {
+        if self.stamp.is_none() {
+            return Ok(None);
+        }
+        let mut hasher = Sha256::new();
+        let mut field = |tag: &str, value: &[u8]| {
+            hasher.update(tag.as_bytes());
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        };
+        for word in command {
+            field("arg", word.as_encoded_bytes());
+        }
+        for input in &self.inputs {
+            let path = cwd.join(input);
+            field("input", path.as_os_str().as_encoded_bytes());
+            crate::cache::hash_path(&path, &mut field)?;
+        }
+        let digest = hasher.finalize();
+        Ok(Some(digest.iter().map(|b| format!("{b:02x}")).collect()))
+    }
+
+    /// Returns whether the outputs are up to date, given the current
+    /// [`key`](Self::key).
+    pub(crate) fn is_fresh(&self, cwd: &Path, key: Option<&str>) -> io::Result<bool> {
+        if self.outputs.is_empty() {
+            return Ok(false);
+        }
+        let mut newest_input = None;
+        for input in &self.inputs {
+            match mtime(&cwd.join(input), true)? {
+                Some(time) => newest_input = newest_input.max(Some(time)),
+                None => return Ok(false),
+            }
+        }
+        let mut oldest_output = None;
+        for output in &self.outputs {
+            match mtime(&cwd.join(output), false)? {
+                Some(time) => oldest_output = Some(oldest_output.unwrap_or(time).min(time)),
+                None => return Ok(false),
+            }
+        }
+        match (&self.stamp, key) {
+            (Some(stamp), Some(key)) => match std::fs::read_to_string(cwd.join(stamp)) {
+                Ok(stored) => Ok(stored.trim() == key),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            },
+            _ => Ok(newest_input <= oldest_output),
+        }
+    }
+
+    /// Stores `key` as the hash of the last successful run.
+    pub(crate) fn record(&self, cwd: &Path, key: Option<&str>) -> io::Result<()> {
+        let (Some(stamp), Some(key)) = (&self.stamp, key) else {
+            return Ok(());
+        };
+        let stamp = cwd.join(stamp);
+        if let Some(dir) = stamp.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(stamp, key)
+    }
+}
+
+/// Returns the modification time of `path`, or if it's a directory the
+/// newest or oldest time of the files in it. A directory's own time counts
+/// towards the newest, as it changes when files are added or removed.
+/// Returns `None` if the path is missing.
+fn mtime(path: &Path, newest: bool) -> io::Result<Option<SystemTime>> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        return Ok(Some(metadata.modified()?));
+    }
+    let mut times = vec![];
+    for entry in std::fs::read_dir(path)? {
+        times.extend(mtime(&entry?.path(), newest)?);
+    }
+    if newest || times.is_empty() {
+        times.push(metadata.modified()?);
+    }
+    Ok(match newest {
+        true => times.into_iter().max(),
+        false => times.into_iter().min(),
+    })
+}
@@ -0,0 +1,154 @@
+//! Grouping command output in GitHub Actions logs.
+
+use crate::reporter::{ProgressReporter, ProgressState};
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// The group whose output is being printed as it comes, and the finished
+/// groups waiting for it to end.
+static LOG: Mutex<Log> = Mutex::new(Log {
+    streaming: false,
+    pending: vec![],
+});
+
+struct Log {
+    streaming: bool,
+    pending: Vec<String>,
+}
+
+const WAITING: u8 = 0;
+const STREAMING: u8 = 1;
+const BUFFERING: u8 = 2;
+const FINISHED: u8 = 3;
+
+/// A [`ProgressReporter`] that wraps each command's output in a collapsible
+/// `::group::` in GitHub Actions logs, titled with the command line, in
+/// place of interactive progress rendering.
+///
+/// Output is printed to stderr as it comes. When commands run at the same
+/// time only one group is streamed, and the others are printed whole when
+/// they finish, so groups never interleave. The output of a failed command
+/// is printed again after its group so it isn't hidden.
+///
+/// Attach one with [`CmdLineRunner::reporter`](crate::CmdLineRunner::reporter),
+/// or use [`Defaults::ci_groups`](crate::Defaults::ci_groups) to attach
+/// one to every command when running in GitHub Actions.
+#[derive(Debug)]
+pub struct GithubGroups {
+    open: &'static str,
+    close: &'static str,
+    title: Mutex<String>,
+    lines: Mutex<Vec<String>>,
+    state: AtomicU8,
+}
+
+impl GithubGroups {
+    /// Creates a reporter for one command.
+    pub fn new() -> Self {
+        Self::with_markers("::group::", "::endgroup::")
+    }
+
+    /// Creates a reporter that opens groups with `open` followed by the
+    /// title, and closes them with `close`, for CIs with the same grouping.
+    pub(crate) fn with_markers(open: &'static str, close: &'static str) -> Self {
+        Self {
+            open,
+            close,
+            title: Mutex::new(String::new()),
+            lines: Mutex::new(vec![]),
+            state: AtomicU8::new(WAITING),
+        }
+    }
+
+    fn line(&self, line: &str) {
+        let mut log = lock(&LOG);
+        match self.state.load(Ordering::Relaxed) {
+            STREAMING => print(line),
+            // printed after the streaming group ends
+            FINISHED if log.streaming => log.pending.push(line.to_string()),
+            FINISHED => print(line),
+            _ => lock(&self.lines).push(line.to_string()),
+        }
+    }
+}
+
+impl Default for GithubGroups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressReporter for GithubGroups {
+    fn set_status(&self, status: ProgressState) {
+        let title = lock(&self.title).replace(['\r', '\n'], " ");
+        let mut log = lock(&LOG);
+        match (status, self.state.load(Ordering::Relaxed)) {
+            (ProgressState::Running, WAITING) if !log.streaming => {
+                log.streaming = true;
+                self.state.store(STREAMING, Ordering::Relaxed);
+                print(&format!("{}{title}", self.open));
+            }
+            (ProgressState::Running, WAITING) => self.state.store(BUFFERING, Ordering::Relaxed),
+            (ProgressState::Running, _) => {}
+            (_, STREAMING) => {
+                self.state.store(FINISHED, Ordering::Relaxed);
+                print(self.close);
+                log.streaming = false;
+                for text in log.pending.drain(..) {
+                    print(&text);
+                }
+            }
+            (_, FINISHED) => {}
+            // finished without starting, e.g. because it was up to date
+            (_, WAITING) => self.state.store(FINISHED, Ordering::Relaxed),
+            (_, _) => {
+                self.state.store(FINISHED, Ordering::Relaxed);
+                let lines = std::mem::take(&mut *lock(&self.lines));
+                let group = [format!("{}{title}", self.open)]
+                    .into_iter()
+                    .chain(lines)
+                    .chain([self.close.to_string()]);
+                let text = group.collect::<Vec<_>>().join("\n");
+                match log.streaming {
+                    true => log.pending.push(text),
+                    false => print(&text),
+                }
+            }
+        }
+    }
+
+    fn prop(&self, key: &str, value: &str) {
+        match key {
+            "ensembler_cmd" => *lock(&self.title) = value.to_string(),
+            // set to an empty string before the command starts
+            "ensembler_stdout" if self.state.load(Ordering::Relaxed) != WAITING => self.line(value),
+            _ => {}
+        }
+    }
+
+    fn println(&self, line: &str) {
+        self.line(line);
+    }
+}
+
+fn print(text: &str) {
+    let _ = writeln!(std::io::stderr().lock(), "{text}");
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Renders an `::error` workflow command, which GitHub shows as an
+/// annotation on the run and its pull request.
+pub(crate) fn error_annotation(title: &str, message: &str) -> String {
+    let title = escape(title).replace(':', "%3A").replace(',', "%2C");
+    format!("::error title={title}::{}", escape(message))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
@@ -0,0 +1,300 @@
+//! A local log of executed commands.
+
+use crate::DryRun;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Records every command run to a JSON Lines file, and reads it back.
+///
+/// Set for one command with [`CmdLineRunner::history`](crate::CmdLineRunner::history),
+/// or for every command with [`Defaults::history`](crate::Defaults::history).
+/// Each run appends one [`HistoryEntry`] when it finishes, whether it
+/// succeeded, failed, timed out or couldn't start. Args, env vars and output
+/// are stored with redactions applied.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, History};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let history = History::new("/home/me/.local/state/mytool/history.jsonl");
+/// ensembler::set_defaults(Defaults::new().history(history.clone()));
+///
+/// CmdLineRunner::new("cargo").arg("test").execute().await?;
+///
+/// for entry in history.query().program("cargo").failed().limit(10).entries()? {
+///     println!("{} exited with {:?}", entry.command_line(), entry.exit_code);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    max_output: usize,
+}
+
+/// One command recorded by a [`History`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    /// The program that was run.
+    pub program: String,
+    /// Its arguments.
+    pub args: Vec<String>,
+    /// The env vars set on the runner (not the inherited ones).
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// The working directory, if one was set.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// When the command started.
+    #[serde(with = "millis_since_epoch")]
+    pub started_at: SystemTime,
+    /// How long it ran.
+    #[serde(with = "millis")]
+    pub duration: Duration,
+    /// The exit code, or `None` if it was killed by a signal or didn't run.
+    pub exit_code: Option<i32>,
+    /// The error returned instead of a result, e.g. for a timeout.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// The end of the combined stdout and stderr, up to
+    /// [`History::max_output`] bytes.
+    #[serde(default)]
+    pub output: String,
+    /// How long after starting it wrote its first line of output, or `None`
+    /// if it wrote none or didn't finish.
+    #[serde(default, with = "opt_millis")]
+    pub first_output: Option<Duration>,
+    /// The longest time it went without writing output, or `None` if it
+    /// didn't finish.
+    #[serde(default, with = "opt_millis")]
+    pub longest_stall: Option<Duration>,
+    /// The labels set with [`CmdLineRunner::label`](crate::CmdLineRunner::label).
+    #[serde(default)]
+    pub labels: Vec<(String, String)>,
+}
+
+impl HistoryEntry {
+    /// Returns whether the command ran and exited with code 0.
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0) && self.error.is_none()
+    }
+
+    /// Renders the program and args as a shell command line.
+    pub fn command_line(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(&self.args)
+            .map(|word| crate::shell_words::quote(word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Diffs the recorded output of `previous` against this entry's, for
+    /// comparing the latest run of a command with an earlier one.
+    ///
+    /// Both outputs are the recorded tails, so set
+    /// [`History::max_output`] high enough to hold the whole output.
+    pub fn diff(&self, previous: &HistoryEntry) -> crate::Diff {
+        crate::Diff::new(&previous.output, &self.output)
+    }
+
+    /// Checks what running this command again would do, without running it.
+    pub fn dry_run(&self) -> DryRun {
+        DryRun::new(
+            &self.program,
+            &self.args,
+            self.env.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            self.cwd.clone(),
+        )
+    }
+}
+
+impl History {
+    /// Creates a history stored at `path`, created on the first write.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_output: 4096,
+        }
+    }
+
+    /// Sets how many bytes of output to keep per command, from the end.
+    /// Defaults to 4 KiB.
+    pub fn max_output(mut self, bytes: usize) -> Self {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Returns the path of the history file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `entry` to the file.
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry).map_err(io::Error::other)?;
+        line.push('\n');
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        // one write per line keeps concurrent appends from interleaving
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// Returns every entry, oldest first. A missing file has no entries, and
+    /// lines that can't be parsed are skipped.
+    pub fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => debug!("Skipping invalid history entry: {e}"),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Checks every entry against the current environment without running
+    /// anything, for reviewing what replaying the history would do.
+    pub fn dry_run(&self) -> io::Result<Vec<DryRun>> {
+        Ok(self.entries()?.iter().map(HistoryEntry::dry_run).collect())
+    }
+
+    /// Starts a query over the entries.
+    pub fn query(&self) -> HistoryQuery<'_> {
+        HistoryQuery {
+            history: self,
+            program: None,
+            failed: false,
+            since: None,
+            limit: None,
+        }
+    }
+
+    /// Returns the end of `output`, up to [`max_output`](Self::max_output)
+    /// bytes.
+    pub(crate) fn truncate_output(&self, output: &str) -> String {
+        tail(output, self.max_output)
+    }
+}
+
+/// Returns the end of `output`, up to `max` bytes.
+pub(crate) fn tail(output: &str, max: usize) -> String {
+    let mut start = output.len().saturating_sub(max);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    output[start..].to_string()
+}
+
+/// A filter over [`History`] entries, built with [`History::query`].
+#[derive(Debug)]
+pub struct HistoryQuery<'a> {
+    history: &'a History,
+    program: Option<String>,
+    failed: bool,
+    since: Option<SystemTime>,
+    limit: Option<usize>,
+}
+
+impl HistoryQuery<'_> {
+    /// Only entries for `program`.
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Only entries that didn't succeed.
+    pub fn failed(mut self) -> Self {
+        self.failed = true;
+        self
+    }
+
+    /// Only entries started at or after `time`.
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Only the most recent `n` matching entries.
+    pub fn limit(mut self, n: usize) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Returns the matching entries, oldest first.
+    pub fn entries(self) -> io::Result<Vec<HistoryEntry>> {
+        let mut entries: Vec<HistoryEntry> = self
+            .history
+            .entries()?
+            .into_iter()
+            .filter(|e| self.program.as_ref().is_none_or(|p| &e.program == p))
+            .filter(|e| !self.failed || !e.success())
+            .filter(|e| self.since.is_none_or(|t| e.started_at >= t))
+            .collect();
+        if let Some(limit) = self.limit {
+            entries.drain(..entries.len().saturating_sub(limit));
+        }
+        Ok(entries)
+    }
+}
+
+pub(crate) mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+mod opt_millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => s.serialize_some(&(d.as_millis() as u64)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_millis))
+    }
+}
+
+pub(crate) mod millis_since_epoch {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        s.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        Ok(UNIX_EPOCH + Duration::from_millis(u64::deserialize(d)?))
+    }
+}
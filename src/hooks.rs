@@ -0,0 +1,115 @@
+//! Hooks called before and after every command the process runs.
+//!
+//! Once [`register`]ed, a pair of hooks is called for every
+//! [`CmdLineRunner::execute`](crate::CmdLineRunner::execute) in the process,
+//! so concerns like logging and metrics can be handled in one place rather
+//! than at every call site. Hooks run on the task executing the command and
+//! should return quickly.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use ensembler::{hooks, CmdLineRunner};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> ensembler::Result<()> {
+//! hooks::register(
+//!     |spec| eprintln!("starting {}", spec.command_line()),
+//!     |spec, result| eprintln!("{} exited with {}", spec.command_line(), result.status),
+//! );
+//!
+//! CmdLineRunner::new("git").arg("fetch").execute().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::CmdResult;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+type Before = dyn Fn(&CmdSpec) + Send + Sync;
+type After = dyn Fn(&CmdSpec, &CmdResult) + Send + Sync;
+
+static HOOKS: RwLock<Vec<(Arc<Before>, Arc<After>)>> = RwLock::new(Vec::new());
+
+/// A command about to run, as passed to hooks, with redactions applied.
+#[derive(Debug, Clone)]
+pub struct CmdSpec {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) command_line: String,
+    pub(crate) cwd: Option<PathBuf>,
+    pub(crate) labels: Vec<(String, String)>,
+}
+
+impl CmdSpec {
+    /// The program to run.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// The arguments, with redactions applied.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// The command line as it's displayed, with redactions applied.
+    pub fn command_line(&self) -> &str {
+        &self.command_line
+    }
+
+    /// The working directory, if set.
+    pub fn current_dir(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// The labels set with [`CmdLineRunner::label`](crate::CmdLineRunner::label).
+    pub fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+}
+
+/// Registers hooks called for every command: `before` just before it
+/// starts, and `after` once it has exited, whatever its exit status.
+/// Commands that don't exit, such as ones that time out or can't be
+/// started, only call `before`.
+///
+/// Hooks are called in the order they were registered, and can't be
+/// unregistered.
+pub fn register(
+    before: impl Fn(&CmdSpec) + Send + Sync + 'static,
+    after: impl Fn(&CmdSpec, &CmdResult) + Send + Sync + 'static,
+) {
+    let mut hooks = HOOKS.write().unwrap_or_else(|e| e.into_inner());
+    hooks.push((Arc::new(before), Arc::new(after)));
+}
+
+/// Returns whether any hooks are registered.
+pub(crate) fn registered() -> bool {
+    !hooks().is_empty()
+}
+
+/// Calls the `before` hooks.
+pub(crate) fn before(spec: &CmdSpec) {
+    for (before, _) in hooks() {
+        before(spec);
+    }
+}
+
+/// Calls the `after` hooks with `result`, if the command exited.
+pub(crate) fn after(spec: &CmdSpec, result: &crate::Result<CmdResult>) {
+    let result = match result {
+        Ok(result) => result,
+        Err(crate::Error::ScriptFailed(failure)) => failure.result(),
+        Err(_) => return,
+    };
+    for (_, after) in hooks() {
+        after(spec, result);
+    }
+}
+
+/// Returns a copy of the hooks, so hooks can register more without
+/// deadlocking.
+fn hooks() -> Vec<(Arc<Before>, Arc<After>)> {
+    HOOKS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
@@ -0,0 +1,143 @@
+//! Showing progress with indicatif.
+
+use crate::reporter::{ProgressReporter, ProgressState};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const SPINNER: &str = "{spinner:.COLOR} {prefix} {wide_msg:.dim}";
+const BAR: &str = "{spinner:.COLOR} {prefix} [{wide_bar}] {percent:>3}%";
+const FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// A [`ProgressReporter`] that shows a command as an indicatif
+/// [`ProgressBar`], for CLIs that already draw with a [`MultiProgress`].
+///
+/// The bar's prefix is the command line, unless it already has one, and its
+/// message is the latest line of output. stderr and the output of failed
+/// commands are printed above the bars. [`add`](Self::add) styles the bar
+/// as a spinner that turns into a bar when a
+/// [`ProgressParser`](crate::ProgressParser) finds a position, while
+/// [`from_bar`](Self::from_bar) leaves styling to the caller.
+///
+/// This type is only available when the `indicatif` feature is enabled.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, IndicatifReporter};
+/// use indicatif::MultiProgress;
+/// use std::sync::Arc;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let multi = MultiProgress::new();
+/// let build = |dir: &str| {
+///     CmdLineRunner::new("make")
+///         .current_dir(dir)
+///         .reporter(Arc::new(IndicatifReporter::add(&multi)))
+///         .execute()
+/// };
+/// let (api, web) = tokio::join!(build("api"), build("web"));
+/// api?;
+/// web?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct IndicatifReporter {
+    bar: ProgressBar,
+    styled: bool,
+    determinate: AtomicBool,
+    success: String,
+    failure: String,
+}
+
+impl IndicatifReporter {
+    /// Adds a spinner to `multi` for the command.
+    pub fn add(multi: &MultiProgress) -> Self {
+        let bar = multi.add(ProgressBar::new_spinner());
+        bar.set_style(style(SPINNER, "blue", "✔"));
+        Self {
+            styled: true,
+            ..Self::from_bar(bar)
+        }
+    }
+
+    /// Reports to `bar` without changing its style.
+    pub fn from_bar(bar: ProgressBar) -> Self {
+        let style = crate::defaults::style();
+        Self {
+            bar,
+            styled: false,
+            determinate: AtomicBool::new(false),
+            success: style.success.unwrap_or_else(|| "✔".into()),
+            failure: style.failure.unwrap_or_else(|| "✗".into()),
+        }
+    }
+
+    /// Returns the bar this reports to.
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+
+    /// Shows `done` in `color` in place of the spinner, if this styles the
+    /// bar.
+    fn restyle(&self, color: &str, done: &str) {
+        if self.styled {
+            let template = match self.determinate.load(Ordering::Relaxed) {
+                true => BAR,
+                false => SPINNER,
+            };
+            self.bar.set_style(style(template, color, done));
+        }
+    }
+}
+
+impl ProgressReporter for IndicatifReporter {
+    fn set_status(&self, status: ProgressState) {
+        match status {
+            ProgressState::Running => self.bar.enable_steady_tick(Duration::from_millis(100)),
+            ProgressState::Done => {
+                self.restyle("green", &self.success);
+                self.bar.finish();
+            }
+            ProgressState::Failed => {
+                self.restyle("red", &self.failure);
+                self.bar.abandon();
+            }
+        }
+    }
+
+    fn prop(&self, key: &str, value: &str) {
+        match key {
+            "ensembler_cmd" if self.bar.prefix().is_empty() => {
+                self.bar.set_prefix(value.to_string())
+            }
+            "ensembler_stdout" => self.bar.set_message(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn println(&self, line: &str) {
+        // unlike ProgressBar::println, this still prints when the bar is hidden
+        self.bar.suspend(|| eprintln!("{line}"));
+    }
+
+    fn set_position(&self, current: usize, total: usize) {
+        if self.styled && !self.determinate.swap(true, Ordering::Relaxed) {
+            self.bar.set_style(style(BAR, "blue", "✔"));
+        }
+        self.bar.set_length(total as u64);
+        self.bar.set_position(current as u64);
+    }
+}
+
+/// Builds a style from `template` with the spinner in `color`, showing
+/// `done` in place of the spinner once the bar is finished.
+fn style(template: &str, color: &str, done: &str) -> ProgressStyle {
+    let mut frames = FRAMES.to_vec();
+    frames.push(done);
+    ProgressStyle::with_template(&template.replace("COLOR", color))
+        .expect("valid template")
+        .tick_strings(&frames)
+}
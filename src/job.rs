@@ -0,0 +1,280 @@
+//! Windows Job Objects used to terminate a child's entire process tree.
+//!
+//! Each child is assigned to its own job created with
+//! `JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE`. Terminating the job kills every
+//! process in the tree without spawning `taskkill`, and because the handle
+//! is closed when the parent exits, the tree also dies if the parent
+//! crashes or the command is dropped while running. Once the child exits on
+//! its own the limit is lifted, so processes it left running on purpose
+//! (e.g. daemons) outlive the job. Children can also be asked to exit first
+//! with a console `CTRL_BREAK_EVENT`.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io;
+use std::sync::LazyLock as Lazy;
+use std::sync::{Arc, Mutex};
+
+type Handle = *mut c_void;
+
+const JOB_OBJECT_BASIC_ACCOUNTING_INFORMATION_CLASS: i32 = 1;
+const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: i32 = 9;
+const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+const PROCESS_TERMINATE: u32 = 0x0001;
+const CTRL_BREAK_EVENT: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct BasicAccountingInformation {
+    total_user_time: i64,
+    total_kernel_time: i64,
+    this_period_total_user_time: i64,
+    this_period_total_kernel_time: i64,
+    total_page_fault_count: u32,
+    total_processes: u32,
+    active_processes: u32,
+    total_terminated_processes: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct BasicLimitInformation {
+    per_process_user_time_limit: i64,
+    per_job_user_time_limit: i64,
+    limit_flags: u32,
+    minimum_working_set_size: usize,
+    maximum_working_set_size: usize,
+    active_process_limit: u32,
+    affinity: usize,
+    priority_class: u32,
+    scheduling_class: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IoCounters {
+    read_operation_count: u64,
+    write_operation_count: u64,
+    other_operation_count: u64,
+    read_transfer_count: u64,
+    write_transfer_count: u64,
+    other_transfer_count: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ExtendedLimitInformation {
+    basic_limit_information: BasicLimitInformation,
+    io_info: IoCounters,
+    process_memory_limit: usize,
+    job_memory_limit: usize,
+    peak_process_memory_used: usize,
+    peak_job_memory_used: usize,
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+    fn SetInformationJobObject(job: Handle, class: i32, info: *const c_void, len: u32) -> i32;
+    fn QueryInformationJobObject(
+        job: Handle,
+        class: i32,
+        info: *mut c_void,
+        len: u32,
+        returned: *mut u32,
+    ) -> i32;
+    fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+    fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+    fn OpenProcess(access: u32, inherit: i32, pid: u32) -> Handle;
+    fn TerminateProcess(process: Handle, exit_code: u32) -> i32;
+    fn CloseHandle(handle: Handle) -> i32;
+    fn GenerateConsoleCtrlEvent(event: u32, process_group: u32) -> i32;
+    fn SetProcessAffinityMask(process: Handle, mask: usize) -> i32;
+}
+
+/// Jobs of running children, keyed by the child's PID.
+static RUNNING_JOBS: Lazy<Mutex<HashMap<u32, Arc<Job>>>> = Lazy::new(Default::default);
+
+/// An owned job object handle. Closing it kills any processes still in the
+/// job, unless it has been [`release`](Job::release)d.
+pub(crate) struct Job(Handle);
+
+// SAFETY: job handles may be used and closed from any thread.
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+impl Job {
+    /// Creates an anonymous job that kills its processes when closed.
+    fn new() -> io::Result<Self> {
+        // SAFETY: null attributes and name create an unnamed job with default security.
+        let handle = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let job = Job(handle);
+        job.set_limit_flags(JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE)?;
+        Ok(job)
+    }
+
+    /// Lets the processes in the job outlive it, for when the child has
+    /// exited normally and anything it left running was meant to keep
+    /// running.
+    pub(crate) fn release(&self) -> io::Result<()> {
+        self.set_limit_flags(0)
+    }
+
+    fn set_limit_flags(&self, flags: u32) -> io::Result<()> {
+        let mut info = ExtendedLimitInformation::default();
+        info.basic_limit_information.limit_flags = flags;
+        // SAFETY: `info` is a valid JOBOBJECT_EXTENDED_LIMIT_INFORMATION for the duration of the call.
+        let ok = unsafe {
+            SetInformationJobObject(
+                self.0,
+                JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS,
+                &info as *const _ as *const c_void,
+                std::mem::size_of::<ExtendedLimitInformation>() as u32,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn assign(&self, process: Handle) -> io::Result<()> {
+        // SAFETY: both handles are valid; the process handle is owned by the child.
+        if unsafe { AssignProcessToJobObject(self.0, process) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the CPU time and peak memory used by every process that has
+    /// been in the job.
+    pub(crate) fn usage(&self) -> io::Result<crate::ResourceUsage> {
+        let mut accounting = BasicAccountingInformation::default();
+        self.query(
+            JOB_OBJECT_BASIC_ACCOUNTING_INFORMATION_CLASS,
+            &mut accounting,
+        )?;
+        let mut limits = ExtendedLimitInformation::default();
+        self.query(JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS, &mut limits)?;
+        Ok(crate::ResourceUsage {
+            user_time: crate::rusage::from_filetime(accounting.total_user_time),
+            system_time: crate::rusage::from_filetime(accounting.total_kernel_time),
+            max_rss: limits.peak_process_memory_used as u64,
+        })
+    }
+
+    fn query<T>(&self, class: i32, info: &mut T) -> io::Result<()> {
+        // SAFETY: `info` is the structure for `class` and valid for writes
+        // for the duration of the call.
+        let ok = unsafe {
+            QueryInformationJobObject(
+                self.0,
+                class,
+                info as *mut T as *mut c_void,
+                std::mem::size_of::<T>() as u32,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Kills every process in the job.
+    pub(crate) fn terminate(&self) -> io::Result<()> {
+        // SAFETY: the handle stays valid until `self` is dropped.
+        if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        // SAFETY: the handle is owned by `self` and closed exactly once.
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Puts `child` in a new job and registers it under `pid`.
+///
+/// Processes the child starts before it is assigned are not part of the job.
+/// Returns `None` (after logging) if the job can't be created or assigned,
+/// e.g. when the parent's own job forbids nesting on older Windows versions.
+pub(crate) fn track(child: &tokio::process::Child, pid: u32) -> Option<Arc<Job>> {
+    let process = child.raw_handle()?;
+    let job = Job::new().and_then(|job| job.assign(process).map(|_| job));
+    let job = match job {
+        Ok(job) => Arc::new(job),
+        Err(e) => {
+            debug!("Failed to assign pid {pid} to a job object: {e}");
+            return None;
+        }
+    };
+    if let Ok(mut jobs) = RUNNING_JOBS.lock() {
+        jobs.insert(pid, job.clone());
+    }
+    Some(job)
+}
+
+/// Removes the job registered for `pid`. Unless the job was released, the
+/// tree is killed once the last reference is dropped.
+pub(crate) fn untrack(pid: u32) {
+    if let Ok(mut jobs) = RUNNING_JOBS.lock() {
+        jobs.remove(&pid);
+    }
+}
+
+/// Terminates the job registered for `pid`, or just the process itself with
+/// `TerminateProcess` if it isn't in a job.
+pub(crate) fn terminate(pid: u32) -> io::Result<()> {
+    let job = match RUNNING_JOBS.lock() {
+        Ok(jobs) => jobs.get(&pid).cloned(),
+        Err(_) => None,
+    };
+    if let Some(job) = job {
+        return job.terminate();
+    }
+    // SAFETY: OpenProcess has no preconditions; the handle is closed below.
+    let process = unsafe { OpenProcess(PROCESS_TERMINATE, 0, pid) };
+    if process.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `process` was opened with PROCESS_TERMINATE access.
+    let result = if unsafe { TerminateProcess(process, 1) } == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    // SAFETY: `process` is a valid handle owned here.
+    unsafe { CloseHandle(process) };
+    result
+}
+
+/// Sends `CTRL_BREAK_EVENT` to the process group led by `pid`, the Windows
+/// counterpart of `SIGTERM`. The child must have been created with
+/// `CREATE_NEW_PROCESS_GROUP` and share this process's console.
+pub(crate) fn ctrl_break(pid: u32) -> io::Result<()> {
+    // SAFETY: GenerateConsoleCtrlEvent has no memory-safety preconditions.
+    if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restricts `child` to the CPUs set in `mask`.
+pub(crate) fn set_affinity(child: &tokio::process::Child, mask: u64) -> io::Result<()> {
+    let Some(process) = child.raw_handle() else {
+        return Ok(());
+    };
+    // SAFETY: the handle is owned by `child`, which outlives the call.
+    if unsafe { SetProcessAffinityMask(process, mask as usize) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! JUnit XML reports of executed commands.
+
+use crate::HistoryEntry;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Records commands as JUnit test cases, so CI systems can show which
+/// commands of a run passed and failed.
+///
+/// Set for one command with [`CmdLineRunner::junit`](crate::CmdLineRunner::junit),
+/// or for every command with [`Defaults::junit`](crate::Defaults::junit).
+/// Clones share their cases. Each command becomes a test case named after
+/// its redacted command line, with its duration, and with its output in a
+/// `<failure>` if it failed or an `<error>` if it didn't finish, such as
+/// when it timed out. Its [labels](crate::CmdLineRunner::label) become
+/// `<properties>`.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, JunitReport};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let report = JunitReport::new("build");
+/// ensembler::set_defaults(Defaults::new().junit(report.clone()));
+///
+/// let result = CmdLineRunner::new("cargo").arg("test").execute().await;
+/// report.write("target/junit.xml")?;
+/// result?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct JunitReport {
+    name: String,
+    cases: Arc<Mutex<Vec<HistoryEntry>>>,
+}
+
+impl JunitReport {
+    /// Creates an empty report whose test suite is called `name`.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            cases: Default::default(),
+        }
+    }
+
+    /// Adds `entry` as a test case, such as one read back from a
+    /// [`History`](crate::History).
+    pub fn record(&self, entry: HistoryEntry) {
+        match self.cases.lock() {
+            Ok(mut cases) => cases.push(entry),
+            Err(e) => e.into_inner().push(entry),
+        }
+    }
+
+    /// Renders the report as JUnit XML.
+    pub fn to_xml(&self) -> String {
+        let cases = match self.cases.lock() {
+            Ok(cases) => cases.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        let errors = cases.iter().filter(|c| c.error.is_some()).count();
+        let failures = cases.iter().filter(|c| !c.success()).count() - errors;
+        let time: f64 = cases.iter().map(|c| c.duration.as_secs_f64()).sum();
+        let name = escape(&self.name);
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        xml += &format!(
+            "  <testsuite name=\"{name}\" tests=\"{}\" failures=\"{failures}\" errors=\"{errors}\" time=\"{time:.3}\">\n",
+            cases.len()
+        );
+        for case in &cases {
+            xml += &format!(
+                "    <testcase name=\"{}\" classname=\"{name}\" time=\"{:.3}\"",
+                escape(&case.command_line()),
+                case.duration.as_secs_f64()
+            );
+            let failure = match (&case.error, case.exit_code) {
+                (Some(error), _) => Some(("error", error.clone())),
+                (None, Some(0)) => None,
+                (None, Some(code)) => Some(("failure", format!("exit code {code}"))),
+                (None, None) => Some(("failure", "no exit status".to_string())),
+            };
+            if failure.is_none() && case.labels.is_empty() {
+                xml += "/>\n";
+                continue;
+            }
+            xml += ">\n";
+            if !case.labels.is_empty() {
+                xml += "      <properties>\n";
+                for (key, value) in &case.labels {
+                    xml += &format!(
+                        "        <property name=\"{}\" value=\"{}\"/>\n",
+                        escape(key),
+                        escape(value)
+                    );
+                }
+                xml += "      </properties>\n";
+            }
+            if let Some((tag, message)) = failure {
+                xml += &format!(
+                    "      <{tag} message=\"{}\">{}</{tag}>\n",
+                    escape(&message),
+                    escape(&case.output)
+                );
+            }
+            xml += "    </testcase>\n";
+        }
+        xml + "  </testsuite>\n</testsuites>\n"
+    }
+
+    /// Writes the report to `path`, creating its directory if needed.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_xml())
+    }
+}
+
+/// Escapes `text` for XML text and attributes, dropping control characters
+/// XML can't contain.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\0'..='\u{1f}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
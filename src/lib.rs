@@ -107,8 +107,111 @@
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+mod macros;
+pub mod audit;
+mod azure;
+#[cfg(target_os = "linux")]
+mod bwrap;
+mod cache;
+#[cfg(target_os = "linux")]
+mod cgroup;
 mod cmd;
+mod defaults;
+mod diff;
+mod dry_run;
+#[cfg(windows)]
+mod elevate;
+mod encoding;
+pub mod env;
 mod error;
+#[cfg(unix)]
+mod escalate;
+pub mod executor;
+mod failure_message;
+mod freshness;
+mod github;
+mod history;
+pub mod hooks;
+#[cfg(feature = "indicatif")]
+mod indicatif_reporter;
+#[cfg(windows)]
+mod job;
+mod junit;
+mod log_mux;
+mod metrics;
+mod policy;
+#[cfg(unix)]
+mod pre_exec;
+mod progress_parser;
+mod reporter;
+mod run_report;
+mod rusage;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod script;
+mod shell;
+pub mod shell_words;
+mod stats;
+mod style;
+mod summary;
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(feature = "progress")]
+mod tail;
+mod teamcity;
+mod tempfile;
+mod template;
+pub mod testing;
+mod timings;
+mod verbosity;
+mod which;
+#[cfg(windows)]
+mod winpath;
+pub mod wsl;
 
-pub use cmd::{CmdLineRunner, CmdResult};
-pub use error::{Error, Result};
+pub use azure::AzureGroups;
+#[cfg(target_os = "linux")]
+pub use bwrap::Bwrap;
+pub use cache::Cache;
+#[cfg(target_os = "linux")]
+pub use cgroup::Cgroup;
+pub use cmd::{CmdLineRunner, CmdResult, PriorityClass};
+pub use defaults::{defaults, set_defaults, Defaults};
+pub use diff::Diff;
+pub use dry_run::{DryRun, EnvDiff};
+pub use encoding::OutputEncoding;
+pub use env::EnvSnapshot;
+pub use error::{Error, MissingProgram, Result, ScriptFailure};
+#[cfg(unix)]
+pub use escalate::Escalation;
+pub use executor::Executor;
+pub use failure_message::FailureMessage;
+pub use freshness::Freshness;
+pub use github::GithubGroups;
+pub use history::{History, HistoryEntry, HistoryQuery};
+#[cfg(feature = "indicatif")]
+pub use indicatif_reporter::IndicatifReporter;
+pub use junit::JunitReport;
+pub use log_mux::{LogFollower, LogLine, LogMux, LogStream};
+pub use metrics::Metrics;
+pub use policy::Policy;
+#[cfg(target_os = "linux")]
+pub use pre_exec::IoPriority;
+pub use progress_parser::ProgressParser;
+pub use reporter::{ProgressReporter, ProgressState};
+pub use run_report::{RunRecord, RunReport};
+pub use rusage::ResourceUsage;
+#[cfg(target_os = "linux")]
+pub use sandbox::Sandbox;
+pub use script::ScriptRunner;
+pub use shell::Shell;
+pub use stats::{stats, Stats};
+pub use style::Style;
+pub use summary::Summary;
+#[cfg(target_os = "linux")]
+pub use systemd::SystemdRun;
+pub use teamcity::TeamcityMessages;
+pub use template::CmdTemplate;
+pub use timings::Timings;
+pub use verbosity::Verbosity;
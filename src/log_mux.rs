@@ -0,0 +1,215 @@
+//! Merging the output of many commands into one stream.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Merges the output of many running commands into one ordered stream of
+/// labeled lines, the backend for a `mytool logs` style subcommand.
+///
+/// Add a command with [`CmdLineRunner::log_mux`](crate::CmdLineRunner::log_mux).
+/// Clones share their lines. The most recent lines are kept, 1000 by default,
+/// for [`lines`](Self::lines) and for followers to start with.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, LogMux, LogStream};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let mux = LogMux::new();
+/// for (label, script) in [("web", "dev"), ("worker", "worker")] {
+///     let runner = CmdLineRunner::new("npm")
+///         .args(["run", script])
+///         .log_mux(mux.clone(), label);
+///     tokio::spawn(runner.execute());
+/// }
+///
+/// // like `mytool logs --follow --tail 10 worker`
+/// let mut follower = mux.follow().tail(10).label("worker");
+/// while let Some(line) = follower.next().await {
+///     let marker = if line.stream == LogStream::Stderr { "!" } else { " " };
+///     println!("{}{marker}| {}", line.label, line.text);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LogMux {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    state: Mutex<State>,
+    sender: broadcast::Sender<LogLine>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_seq: u64,
+    lines: VecDeque<LogLine>,
+}
+
+/// Which stream of a command a [`LogLine`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LogStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+/// One line of output in a [`LogMux`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LogLine {
+    /// The position of the line in the mux, counting from 0.
+    pub seq: u64,
+    /// The label of the command it came from.
+    pub label: String,
+    /// The stream it came from.
+    pub stream: LogStream,
+    /// The line, with redactions applied and without its newline.
+    pub text: String,
+    /// When it was received.
+    pub time: SystemTime,
+}
+
+impl Default for LogMux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogMux {
+    /// Creates an empty mux that keeps the last 1000 lines.
+    pub fn new() -> Self {
+        Self::with_capacity(1000)
+    }
+
+    /// Creates an empty mux that keeps the last `lines` lines.
+    pub fn with_capacity(lines: usize) -> Self {
+        // followers that fall this far behind skip ahead
+        let (sender, _) = broadcast::channel(lines.max(1));
+        Self {
+            inner: Arc::new(Inner {
+                capacity: lines,
+                state: Default::default(),
+                sender,
+            }),
+        }
+    }
+
+    /// Adds a line, such as a message of the tool's own.
+    pub fn push(&self, label: &str, stream: LogStream, text: &str) {
+        let mut state = self.state();
+        let line = LogLine {
+            seq: state.next_seq,
+            label: label.to_string(),
+            stream,
+            text: text.to_string(),
+            time: SystemTime::now(),
+        };
+        state.next_seq += 1;
+        if self.inner.capacity > 0 {
+            if state.lines.len() == self.inner.capacity {
+                state.lines.pop_front();
+            }
+            state.lines.push_back(line.clone());
+        }
+        // sent under the lock so followers see lines in order
+        let _ = self.inner.sender.send(line);
+    }
+
+    /// Returns the lines that are kept, oldest first.
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.state().lines.iter().cloned().collect()
+    }
+
+    /// Starts following the mux: the kept lines, then new lines as they
+    /// arrive.
+    pub fn follow(&self) -> LogFollower {
+        let state = self.state();
+        LogFollower {
+            backlog: state.lines.clone(),
+            receiver: self.inner.sender.subscribe(),
+            tail: None,
+            labels: vec![],
+            stream: None,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Lines of a [`LogMux`], as they arrive, created with
+/// [`LogMux::follow`].
+#[derive(Debug)]
+pub struct LogFollower {
+    backlog: VecDeque<LogLine>,
+    receiver: broadcast::Receiver<LogLine>,
+    tail: Option<usize>,
+    labels: Vec<String>,
+    stream: Option<LogStream>,
+}
+
+impl LogFollower {
+    /// Starts with at most the last `lines` matching kept lines, or only
+    /// new lines with 0.
+    pub fn tail(mut self, lines: usize) -> Self {
+        self.tail = Some(lines);
+        self
+    }
+
+    /// Only lines from commands labeled `label`. Can be called more than
+    /// once to follow several commands.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.labels.push(label.into());
+        self
+    }
+
+    /// Only lines from `stream`.
+    pub fn stream(mut self, stream: LogStream) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+
+    /// Waits for the next matching line, or returns `None` once every
+    /// [`LogMux`] clone has been dropped. Lines this follower fell too far
+    /// behind on are skipped.
+    pub async fn next(&mut self) -> Option<LogLine> {
+        if let Some(tail) = self.tail.take() {
+            let (labels, stream) = (&self.labels, self.stream);
+            self.backlog.retain(|line| matches(labels, stream, line));
+            self.backlog
+                .drain(..self.backlog.len().saturating_sub(tail));
+        }
+        while let Some(line) = self.backlog.pop_front() {
+            if self.matches(&line) {
+                return Some(line);
+            }
+        }
+        loop {
+            match self.receiver.recv().await {
+                Ok(line) if self.matches(&line) => return Some(line),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn matches(&self, line: &LogLine) -> bool {
+        matches(&self.labels, self.stream, line)
+    }
+}
+
+fn matches(labels: &[String], stream: Option<LogStream>, line: &LogLine) -> bool {
+    (labels.is_empty() || labels.contains(&line.label)) && stream.is_none_or(|s| s == line.stream)
+}
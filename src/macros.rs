@@ -0,0 +1,53 @@
+/// Builds a [`CmdLineRunner`](crate::CmdLineRunner) from a program and arguments.
+///
+/// Each argument is passed with [`arg`](crate::CmdLineRunner::arg), so values
+/// are never interpreted by a shell.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::cmd;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let msg = "fix: don't break on spaces";
+/// cmd!("git", "commit", "-m", msg).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! cmd {
+    ($program:expr $(, $arg:expr)* $(,)?) => {
+        $crate::CmdLineRunner::new($program)$(.arg($arg))*
+    };
+}
+
+/// Builds a [`CmdLineRunner::shell`](crate::CmdLineRunner::shell) runner from
+/// a format string, quoting every interpolated argument.
+///
+/// Only positional arguments passed to the macro are quoted; variables
+/// captured inline (`{name}`) are inserted verbatim, so pass untrusted values
+/// as arguments.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::sh;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let file = "my file; rm -rf ~";
+/// // runs: sh -o errexit -c "wc -l 'my file; rm -rf ~' | tr -d ' '"
+/// sh!("wc -l {} | tr -d ' '", file).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! sh {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {
+        $crate::CmdLineRunner::shell(::std::format!(
+            $fmt
+            $(, $crate::shell_words::__sh_quote(&::std::string::ToString::to_string(&$arg)))*
+        ))
+    };
+}
@@ -0,0 +1,161 @@
+//! Prometheus metrics for executed commands.
+
+use crate::HistoryEntry;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The upper bounds, in seconds, of the duration histogram's buckets.
+const BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0];
+
+/// Counts commands started, succeeded and failed, their durations and their
+/// bytes of output, per program, for a long-running process to expose to
+/// Prometheus.
+///
+/// Set for one command with [`CmdLineRunner::metrics`](crate::CmdLineRunner::metrics),
+/// or for every command with [`Defaults::metrics`](crate::Defaults::metrics).
+/// Clones share their counts. Serve [`render`](Self::render) from the
+/// process's metrics endpoint:
+///
+/// ```text
+/// ensembler_commands_started_total{program="git"} 3
+/// ensembler_commands_succeeded_total{program="git"} 2
+/// ensembler_commands_failed_total{program="git"} 1
+/// ensembler_command_output_bytes_total{program="git"} 5120
+/// ensembler_command_duration_seconds_bucket{program="git",le="0.1"} 1
+/// ...
+/// ensembler_command_duration_seconds_sum{program="git"} 1.25
+/// ensembler_command_duration_seconds_count{program="git"} 3
+/// ```
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, Metrics};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let metrics = Metrics::new();
+/// ensembler::set_defaults(Defaults::new().metrics(metrics.clone()));
+///
+/// CmdLineRunner::new("git").arg("fetch").execute().await?;
+///
+/// // in the handler for GET /metrics
+/// let body = metrics.render();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    programs: Arc<Mutex<BTreeMap<String, ProgramMetrics>>>,
+}
+
+#[derive(Debug, Default)]
+struct ProgramMetrics {
+    started: u64,
+    succeeded: u64,
+    failed: u64,
+    output_bytes: u64,
+    buckets: [u64; BUCKETS.len()],
+    duration_sum: f64,
+    duration_count: u64,
+}
+
+impl Metrics {
+    /// Creates a registry with no commands counted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let programs = self.lock();
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: fn(&ProgramMetrics) -> u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (program, metrics) in programs.iter() {
+                let program = escape(program);
+                let _ = writeln!(out, "{name}{{program=\"{program}\"}} {}", value(metrics));
+            }
+        };
+        counter(
+            "ensembler_commands_started_total",
+            "Commands started.",
+            |m| m.started,
+        );
+        counter(
+            "ensembler_commands_succeeded_total",
+            "Commands that exited successfully.",
+            |m| m.succeeded,
+        );
+        counter(
+            "ensembler_commands_failed_total",
+            "Commands that failed, timed out or couldn't start.",
+            |m| m.failed,
+        );
+        counter(
+            "ensembler_command_output_bytes_total",
+            "Bytes of stdout and stderr.",
+            |m| m.output_bytes,
+        );
+        let name = "ensembler_command_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} How long commands ran.\n# TYPE {name} histogram"
+        );
+        for (program, metrics) in programs.iter() {
+            let program = escape(program);
+            for (le, count) in BUCKETS.iter().zip(metrics.buckets) {
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{program=\"{program}\",le=\"{le}\"}} {count}"
+                );
+            }
+            let (sum, count) = (metrics.duration_sum, metrics.duration_count);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{program=\"{program}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(out, "{name}_sum{{program=\"{program}\"}} {sum}");
+            let _ = writeln!(out, "{name}_count{{program=\"{program}\"}} {count}");
+        }
+        out
+    }
+
+    /// Counts a command of `program` as started.
+    pub(crate) fn started(&self, program: &str) {
+        self.lock().entry(program.to_string()).or_default().started += 1;
+    }
+
+    /// Counts the command `entry` describes as finished.
+    pub(crate) fn finished(&self, entry: &HistoryEntry) {
+        let mut programs = self.lock();
+        let metrics = programs.entry(entry.program.clone()).or_default();
+        match entry.success() {
+            true => metrics.succeeded += 1,
+            false => metrics.failed += 1,
+        }
+        metrics.output_bytes += entry.output.len() as u64;
+        let secs = entry.duration.as_secs_f64();
+        // buckets are cumulative
+        for (le, count) in BUCKETS.iter().zip(&mut metrics.buckets) {
+            if secs <= *le {
+                *count += 1;
+            }
+        }
+        metrics.duration_sum += secs;
+        metrics.duration_count += 1;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, ProgramMetrics>> {
+        self.programs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,367 @@
+//! Allowing and denying commands before they run.
+
+use crate::executor::Invocation;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+type Rule = Arc<dyn Fn(&Invocation) -> Option<String> + Send + Sync>;
+
+/// Rules that decide whether a command may run, for tools that run commands
+/// from untrusted config.
+///
+/// Set for every command with [`Defaults::policy`](crate::Defaults::policy).
+/// [`execute`](crate::CmdLineRunner::execute) and
+/// [`exec`](crate::CmdLineRunner::exec) check the program, args and working
+/// directory before anything is started, and fail with
+/// [`Error::PolicyDenied`](crate::Error::PolicyDenied) if a rule rejects
+/// them. Wrappers added with [`wrap`](crate::CmdLineRunner::wrap) are not
+/// checked.
+///
+/// A command is denied if any deny rule matches, or if an allow list is set
+/// and the command isn't on it.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, Policy};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// ensembler::set_defaults(
+///     Defaults::new().policy(
+///         Policy::new()
+///             .allow_programs(["git", "make", "rm"])
+///             .deny_args("rm", ["-*r*", "/"])
+///             .allow_cwd("/srv/builds"),
+///     ),
+/// );
+///
+/// let err = CmdLineRunner::new("curl").arg("example.com").execute().await;
+/// assert!(matches!(err, Err(ensembler::Error::PolicyDenied(..))));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct Policy {
+    allowed_programs: Option<Vec<String>>,
+    denied_programs: Vec<String>,
+    denied_args: Vec<(String, Vec<String>)>,
+    allowed_cwds: Option<Vec<PathBuf>>,
+    deny_control_chars: bool,
+    deny_shell_newlines: bool,
+    path_roots: Option<Vec<PathBuf>>,
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Creates a policy that allows everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allows these programs. A bare name like `git` allows the program
+    /// found on `PATH`, while a path allows only that path.
+    pub fn allow_programs<I, S>(mut self, programs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_programs
+            .get_or_insert_default()
+            .extend(programs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Denies these programs, by name wherever they are, so `rm` also
+    /// denies `/bin/rm`.
+    pub fn deny_programs<I, S>(mut self, programs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.denied_programs
+            .extend(programs.into_iter().map(Into::into));
+        self
+    }
+
+    /// Denies `program` (matched by name) when every one of `patterns`
+    /// matches one of its args, in any order. Patterns match whole args,
+    /// with `*` matching any run of characters and `?` any one character.
+    pub fn deny_args<I, S>(mut self, program: impl Into<String>, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let patterns = patterns.into_iter().map(Into::into).collect();
+        self.denied_args.push((program.into(), patterns));
+        self
+    }
+
+    /// Only allows commands whose working directory is `dir` or inside it.
+    /// Can be called more than once to allow several directories.
+    pub fn allow_cwd(mut self, dir: impl AsRef<Path>) -> Self {
+        self.allowed_cwds
+            .get_or_insert_default()
+            .push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Denies args containing control characters other than tab and
+    /// newline, such as NUL, carriage returns and terminal escape sequences.
+    pub fn deny_control_chars(mut self) -> Self {
+        self.deny_control_chars = true;
+        self
+    }
+
+    /// Denies newlines and carriage returns in the args of shells (`sh`,
+    /// `bash`, `cmd`, `powershell` and so on) and of batch files, which run
+    /// through `cmd.exe`. A shell runs text after a newline as another
+    /// command, so this stops a value spliced into a script from adding
+    /// commands of its own. Multi-line scripts are denied too, so use
+    /// [`CmdLineRunner::shell_stdin`](crate::CmdLineRunner::shell_stdin) for
+    /// trusted ones.
+    pub fn deny_shell_newlines(mut self) -> Self {
+        self.deny_shell_newlines = true;
+        self
+    }
+
+    /// Denies args naming a path outside `root`. Can be called more than
+    /// once to allow several roots.
+    ///
+    /// Args that are absolute paths, start with `~`, or contain a `..`
+    /// component are checked, including the value of `--flag=value` args,
+    /// after resolving them from the working directory and following
+    /// symlinks. Other relative args stay inside the working directory, so
+    /// combine this with [`allow_cwd`](Self::allow_cwd).
+    pub fn confine_paths(mut self, root: impl AsRef<Path>) -> Self {
+        self.path_roots
+            .get_or_insert_default()
+            .push(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a rule that denies a command by returning the reason.
+    ///
+    /// The rule sees the command without wrappers, with its working
+    /// directory made absolute.
+    pub fn rule<F>(mut self, rule: F) -> Self
+    where
+        F: Fn(&Invocation) -> Option<String> + Send + Sync + 'static,
+    {
+        self.rules.push(Arc::new(rule));
+        self
+    }
+
+    /// Returns why `invocation` is denied, if it is. Its working directory
+    /// must be absolute.
+    pub(crate) fn check(&self, invocation: &Invocation) -> Option<String> {
+        let program = invocation.program();
+        let name = program_name(program);
+        if self.denied_programs.iter().any(|p| program_name(p) == name) {
+            return Some("program is denied".into());
+        }
+        let args: Vec<_> = invocation
+            .args()
+            .iter()
+            .map(|a| a.to_string_lossy())
+            .collect();
+        for (denied, patterns) in &self.denied_args {
+            if program_name(denied) == name
+                && patterns
+                    .iter()
+                    .all(|pat| args.iter().any(|arg| glob_match(pat, arg)))
+            {
+                return Some(format!("args match `{}`", patterns.join(" ")));
+            }
+        }
+        if let Some(allowed) = &self.allowed_programs {
+            let has_separator = program.contains(['/', std::path::MAIN_SEPARATOR]);
+            let ok = allowed.iter().any(|p| match has_separator {
+                true => Path::new(p) == Path::new(program),
+                false => program_name(p) == name,
+            });
+            if !ok {
+                return Some("program is not allowed".into());
+            }
+        }
+        if let (Some(allowed), Some(cwd)) = (&self.allowed_cwds, invocation.current_dir()) {
+            let cwd = canonical(cwd);
+            if !allowed.iter().any(|dir| cwd.starts_with(canonical(dir))) {
+                return Some(format!(
+                    "working directory {} is not allowed",
+                    cwd.display()
+                ));
+            }
+        }
+        if let Some(reason) = self.check_args(invocation, &name) {
+            return Some(reason);
+        }
+        self.rules.iter().find_map(|rule| rule(invocation))
+    }
+
+    /// Applies the argument sanitization rules. Reasons name the arg by
+    /// position rather than quoting it, as it may hold a secret.
+    fn check_args(&self, invocation: &Invocation, name: &str) -> Option<String> {
+        let shell = self.deny_shell_newlines && is_shell(invocation.program(), name);
+        let cwd = invocation.current_dir().unwrap_or(Path::new(""));
+        for (i, arg) in invocation.args().iter().enumerate() {
+            let n = i + 1;
+            let arg = arg.to_string_lossy();
+            if self.deny_control_chars
+                && arg
+                    .chars()
+                    .any(|c| c.is_control() && c != '\t' && c != '\n')
+            {
+                return Some(format!("arg {n} contains a control character"));
+            }
+            if shell && arg.contains(['\n', '\r']) {
+                return Some(format!("arg {n} contains a newline"));
+            }
+            let Some(roots) = &self.path_roots else {
+                continue;
+            };
+            let value = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with('-') => value,
+                _ => &arg,
+            };
+            for candidate in [&*arg, value] {
+                if !looks_like_path(candidate) {
+                    continue;
+                }
+                let path = match candidate.strip_prefix('~') {
+                    Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+                        home_dir().join(&rest[rest.len().min(1)..])
+                    }
+                    // another user's home, which this can't resolve
+                    Some(_) => PathBuf::new(),
+                    None => cwd.join(candidate),
+                };
+                let path = canonical(&path);
+                let inside =
+                    |root: &PathBuf| path.is_absolute() && path.starts_with(canonical(root));
+                if !roots.iter().any(inside) {
+                    return Some(format!("arg {n} is a path outside the allowed roots"));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("allowed_programs", &self.allowed_programs)
+            .field("denied_programs", &self.denied_programs)
+            .field("denied_args", &self.denied_args)
+            .field("allowed_cwds", &self.allowed_cwds)
+            .field("deny_control_chars", &self.deny_control_chars)
+            .field("deny_shell_newlines", &self.deny_shell_newlines)
+            .field("path_roots", &self.path_roots)
+            .field("rules", &self.rules.len())
+            .finish()
+    }
+}
+
+/// Returns the file name of a program, without `.exe` and case-folded on
+/// Windows.
+fn program_name(program: &str) -> String {
+    let name = Path::new(program)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if cfg!(windows) {
+        let name = name.to_lowercase();
+        match name.strip_suffix(".exe") {
+            Some(stem) => stem.to_string(),
+            None => name,
+        }
+    } else {
+        name
+    }
+}
+
+/// Returns whether a program is a shell that would run newlines in its args
+/// as separate commands.
+fn is_shell(program: &str, name: &str) -> bool {
+    const SHELLS: &[&str] = &[
+        "sh",
+        "bash",
+        "dash",
+        "zsh",
+        "ksh",
+        "fish",
+        "cmd",
+        "powershell",
+        "pwsh",
+    ];
+    let name = name.strip_suffix(".exe").unwrap_or(name);
+    SHELLS.contains(&name) || crate::which::needs_cmd_exe(Path::new(program))
+}
+
+/// Returns whether an arg names a path that could leave the working
+/// directory.
+fn looks_like_path(arg: &str) -> bool {
+    let path = Path::new(arg);
+    path.is_absolute()
+        || arg.starts_with('~')
+        || arg.starts_with(['/', '\\'])
+        || path.components().any(|c| c == Component::ParentDir)
+}
+
+fn home_dir() -> PathBuf {
+    std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// Resolves symlinks and `..`, so a path can't be used to step outside an
+/// allowed directory. Parts that don't exist yet are resolved lexically.
+fn canonical(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+        if let Ok(real) = resolved.canonicalize() {
+            resolved = real;
+        }
+    }
+    resolved
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` matches one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*` if the rest fails to match
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
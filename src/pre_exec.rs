@@ -0,0 +1,223 @@
+//! Process attributes applied in the forked child just before `exec`.
+//!
+//! Everything here runs between `fork` and `exec`, so [`PreExec::apply`]
+//! must stay async-signal-safe: no allocation, locking, or logging. Any
+//! data it needs is prepared when the runner is configured.
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::Signal;
+use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Uid};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+/// Attributes applied to the child by a single `pre_exec` hook.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PreExec {
+    pub(crate) groups: Option<Vec<Gid>>,
+    pub(crate) gid: Option<Gid>,
+    pub(crate) uid: Option<Uid>,
+    pub(crate) umask: Option<Mode>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) memory_limit: Option<u64>,
+    pub(crate) cpu_time_limit: Option<u64>,
+    pub(crate) open_files_limit: Option<u64>,
+    pub(crate) chroot: Option<Chroot>,
+    #[cfg(target_os = "linux")]
+    pub(crate) io_priority: Option<IoPriority>,
+    #[cfg(target_os = "linux")]
+    pub(crate) cpu_affinity: Option<nix::sched::CpuSet>,
+    /// `cgroup.procs` of the cgroup to join.
+    #[cfg(target_os = "linux")]
+    pub(crate) cgroup_procs: Option<std::ffi::CString>,
+    #[cfg(target_os = "linux")]
+    pub(crate) sandbox: Option<crate::sandbox::Prepared>,
+}
+
+/// A root directory to confine the child to, and where to start inside it.
+#[derive(Debug, Clone)]
+pub(crate) struct Chroot {
+    pub(crate) root: PathBuf,
+    root_c: CString,
+    cwd: CString,
+}
+
+impl Chroot {
+    pub(crate) fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            root_c: c_path(root),
+            cwd: c_path(Path::new("/")),
+        }
+    }
+
+    /// Sets the working directory, as seen from inside the new root.
+    pub(crate) fn set_cwd(&mut self, cwd: &Path) {
+        self.cwd = c_path(&Path::new("/").join(cwd));
+    }
+}
+
+fn c_path(path: &Path) -> CString {
+    // paths with NUL bytes can't be passed to the kernel and fail in exec
+    CString::new(path.as_os_str().as_bytes()).unwrap_or_default()
+}
+
+/// Linux I/O scheduling class and priority, as set by `ionice`.
+///
+/// Set with [`CmdLineRunner::io_priority`](crate::CmdLineRunner::io_priority).
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only gets disk time when no other process needs it.
+    Idle,
+    /// The default class, with a priority from 0 (highest) to 7 (lowest).
+    BestEffort(u8),
+    /// Always gets disk access first, with a priority from 0 to 7. Requires
+    /// `CAP_SYS_ADMIN`.
+    Realtime(u8),
+}
+
+#[cfg(target_os = "linux")]
+impl IoPriority {
+    /// Encodes the priority for `ioprio_set(2)`.
+    fn to_raw(self) -> i32 {
+        const CLASS_SHIFT: i32 = 13;
+        let (class, data) = match self {
+            IoPriority::Realtime(level) => (1, level.min(7)),
+            IoPriority::BestEffort(level) => (2, level.min(7)),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << CLASS_SHIFT) | data as i32
+    }
+}
+
+impl PreExec {
+    /// Returns `true` if nothing needs to be applied.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.groups.is_none()
+            && self.gid.is_none()
+            && self.uid.is_none()
+            && self.umask.is_none()
+            && self.nice.is_none()
+            && self.memory_limit.is_none()
+            && self.cpu_time_limit.is_none()
+            && self.open_files_limit.is_none()
+            && self.chroot.is_none()
+            && self.linux_is_empty()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn linux_is_empty(&self) -> bool {
+        self.io_priority.is_none()
+            && self.cpu_affinity.is_none()
+            && self.cgroup_procs.is_none()
+            && self.sandbox.is_none()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn linux_is_empty(&self) -> bool {
+        true
+    }
+
+    /// Applies the attributes to the current (child) process.
+    ///
+    /// Supplementary groups and the gid are set before the uid, since
+    /// changing them requires privileges that `setuid` gives up.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        // join the cgroup before exec so the program never runs outside it
+        #[cfg(target_os = "linux")]
+        if let Some(procs) = &self.cgroup_procs {
+            use nix::fcntl::{open, OFlag};
+            let fd = open(
+                procs.as_c_str(),
+                OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )?;
+            nix::unistd::write(&fd, b"0")?;
+        }
+        // lower priority first, while raising it back is still permitted
+        if let Some(nice) = self.nice {
+            // SAFETY: setpriority has no memory-safety preconditions.
+            let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) };
+            Errno::result(ret)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(io_priority) = self.io_priority {
+            const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+            // SAFETY: ioprio_set takes only integer arguments.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    io_priority.to_raw(),
+                )
+            };
+            Errno::result(ret)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cpus) = &self.cpu_affinity {
+            nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), cpus)?;
+        }
+        // hard limits can only be raised while still privileged
+        if let Some(bytes) = self.memory_limit {
+            setrlimit(Resource::RLIMIT_AS, bytes, bytes)?;
+        }
+        if let Some(secs) = self.cpu_time_limit {
+            // SIGXCPU at the soft limit, SIGKILL a second later
+            setrlimit(Resource::RLIMIT_CPU, secs, secs.saturating_add(1))?;
+        }
+        if let Some(n) = self.open_files_limit {
+            setrlimit(Resource::RLIMIT_NOFILE, n, n)?;
+        }
+        if let Some(chroot) = &self.chroot {
+            nix::unistd::chroot(chroot.root_c.as_c_str())?;
+            nix::unistd::chdir(chroot.cwd.as_c_str())?;
+        }
+        if let Some(groups) = &self.groups {
+            nix::unistd::setgroups(groups)?;
+        }
+        if let Some(gid) = self.gid {
+            nix::unistd::setgid(gid)?;
+        }
+        if let Some(uid) = self.uid {
+            nix::unistd::setuid(uid)?;
+        }
+        if let Some(umask) = self.umask {
+            nix::sys::stat::umask(umask);
+        }
+        // last, since the seccomp filter can deny the syscalls above
+        #[cfg(target_os = "linux")]
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.apply()?;
+        }
+        Ok(())
+    }
+
+    /// Describes the resource limit the child most likely hit, given how it
+    /// exited.
+    ///
+    /// Exceeding `RLIMIT_AS` shows up as failed allocations, which most
+    /// programs turn into an abort or crash, so this is a best guess.
+    pub(crate) fn exceeded_limit(&self, status: &ExitStatus) -> Option<&'static str> {
+        let signal = Signal::try_from(status.signal()?).ok()?;
+        if self.cpu_time_limit.is_some() && matches!(signal, Signal::SIGXCPU | Signal::SIGKILL) {
+            return Some("CPU time limit (RLIMIT_CPU)");
+        }
+        if self.memory_limit.is_some()
+            && matches!(
+                signal,
+                Signal::SIGABRT | Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGKILL
+            )
+        {
+            return Some("memory limit (RLIMIT_AS)");
+        }
+        None
+    }
+}
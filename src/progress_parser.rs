@@ -0,0 +1,146 @@
+//! Reading progress out of command output.
+
+use std::fmt;
+use std::sync::Arc;
+
+type Parse = Arc<dyn Fn(&str) -> Option<(usize, usize)> + Send + Sync>;
+
+/// Pulls a position out of output lines, such as `45%` or `12/80`, to drive
+/// a determinate progress bar instead of a spinner.
+///
+/// Set with [`CmdLineRunner::progress_parser`](crate::CmdLineRunner::progress_parser).
+/// Each line of stdout and stderr is passed in, and a match sets the
+/// progress job's current and total values. Lines redrawn with carriage
+/// returns, as `curl` and `wget` do, are parsed from the text after the
+/// last `\r`.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, ProgressParser};
+///
+/// # #[cfg(feature = "progress")]
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// CmdLineRunner::new("wget")
+///     .arg("https://example.com/big.iso")
+///     .progress_message("Downloading")
+///     .progress_parser(ProgressParser::percent())
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "progress"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone)]
+pub struct ProgressParser {
+    parse: Parse,
+}
+
+impl ProgressParser {
+    /// Creates a parser from a function returning the current and total
+    /// values for a line, or `None` if the line has no progress in it.
+    ///
+    /// ```
+    /// use ensembler::ProgressParser;
+    ///
+    /// // docker pull: "abc123: Downloading  12MB/50MB"
+    /// let parser = ProgressParser::new(|line| {
+    ///     let (_, sizes) = line.split_once("Downloading")?;
+    ///     let (cur, total) = sizes.trim().split_once('/')?;
+    ///     let mb = |s: &str| s.strip_suffix("MB")?.parse().ok();
+    ///     Some((mb(cur)?, mb(total)?))
+    /// });
+    /// assert_eq!(parser.parse("abc123: Downloading  12MB/50MB"), Some((12, 50)));
+    /// ```
+    pub fn new<F>(parse: F) -> Self
+    where
+        F: Fn(&str) -> Option<(usize, usize)> + Send + Sync + 'static,
+    {
+        Self {
+            parse: Arc::new(parse),
+        }
+    }
+
+    /// Matches the last percentage on a line, like `45%` or `45.3%`, as a
+    /// position out of 1000.
+    ///
+    /// ```
+    /// use ensembler::ProgressParser;
+    ///
+    /// let parser = ProgressParser::percent();
+    /// assert_eq!(parser.parse(" 45% [=====>      ] 1.2M 3s"), Some((450, 1000)));
+    /// assert_eq!(parser.parse("no progress here"), None);
+    /// ```
+    pub fn percent() -> Self {
+        Self::new(percent)
+    }
+
+    /// Matches the first `x/y` counter on a line, like cargo's `45/120`,
+    /// where `x` is at most `y`.
+    ///
+    /// ```
+    /// use ensembler::ProgressParser;
+    ///
+    /// let parser = ProgressParser::count();
+    /// assert_eq!(parser.parse("Building [=====>  ] 45/120: serde"), Some((45, 120)));
+    /// assert_eq!(parser.parse("see docs/v2/index.html"), None);
+    /// ```
+    pub fn count() -> Self {
+        Self::new(count)
+    }
+
+    /// Matches a percentage, or failing that an `x/y` counter.
+    pub fn any() -> Self {
+        Self::new(|line| percent(line).or_else(|| count(line)))
+    }
+
+    /// Returns the current and total values for `line`, if it has any.
+    pub fn parse(&self, line: &str) -> Option<(usize, usize)> {
+        let line = line.rsplit('\r').find(|s| !s.is_empty()).unwrap_or(line);
+        (self.parse)(line)
+    }
+}
+
+impl fmt::Debug for ProgressParser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressParser").finish_non_exhaustive()
+    }
+}
+
+fn percent(line: &str) -> Option<(usize, usize)> {
+    line.match_indices('%').rev().find_map(|(end, _)| {
+        let number = &line[..end];
+        let start = number
+            .rfind(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or(0, |i| i + 1);
+        let value: f64 = number[start..].parse().ok()?;
+        (0.0..=100.0)
+            .contains(&value)
+            .then(|| ((value * 10.0).round() as usize, 1000))
+    })
+}
+
+fn count(line: &str) -> Option<(usize, usize)> {
+    let bytes = line.as_bytes();
+    line.match_indices('/').find_map(|(slash, _)| {
+        let start = bytes[..slash]
+            .iter()
+            .rposition(|b| !b.is_ascii_digit())
+            .map_or(0, |i| i + 1);
+        let end = bytes[slash + 1..]
+            .iter()
+            .position(|b| !b.is_ascii_digit())
+            .map_or(bytes.len(), |i| slash + 1 + i);
+        // skip paths, versions and dates like `a/1/2`, `1.2/3` or `10/2024-01`
+        let bounded =
+            |b: Option<&u8>| b.is_none_or(|b| !b.is_ascii_alphanumeric() && !b"/.-_".contains(b));
+        if !bounded(start.checked_sub(1).and_then(|i| bytes.get(i))) || !bounded(bytes.get(end)) {
+            return None;
+        }
+        let current: usize = line[start..slash].parse().ok()?;
+        let total: usize = line[slash + 1..end].parse().ok()?;
+        (total > 0 && current <= total).then_some((current, total))
+    })
+}
@@ -0,0 +1,124 @@
+//! Reporting command progress to a UI.
+
+/// The state of a command, as passed to
+/// [`ProgressReporter::set_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProgressState {
+    /// The command has started.
+    Running,
+    /// The command finished successfully.
+    Done,
+    /// The command failed, timed out or was cancelled.
+    Failed,
+}
+
+/// Receives a command's status and output as it runs, so it can be shown
+/// with any UI library.
+///
+/// Attach one with [`CmdLineRunner::reporter`](crate::CmdLineRunner::reporter).
+/// With the `progress` feature, clx's `ProgressJob` implements this and is
+/// what [`with_pr`](crate::CmdLineRunner::with_pr) attaches.
+///
+/// The runner sets these props:
+///
+/// - `ensembler_cmd`: the command line, when it starts
+/// - `ensembler_stdout`: the latest line of output
+/// - `ensembler_expected`: how long the command usually takes, with
+///   [`timings`](crate::CmdLineRunner::timings)
+/// - `ensembler_elapsed`: how long the command has been running, such as
+///   `1m23s`, updated every second
+/// - `ensembler_eta`: roughly how long is left, projected from the position
+///   a [`ProgressParser`](crate::ProgressParser) found or else from
+///   `ensembler_expected`, and empty when neither is known
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, ProgressReporter, ProgressState};
+/// use std::sync::Arc;
+///
+/// struct Log;
+///
+/// impl ProgressReporter for Log {
+///     fn set_status(&self, status: ProgressState) {
+///         eprintln!("status: {status:?}");
+///     }
+///     fn prop(&self, key: &str, value: &str) {
+///         if key == "ensembler_stdout" {
+///             eprintln!("> {value}");
+///         }
+///     }
+///     fn println(&self, line: &str) {
+///         eprintln!("{line}");
+///     }
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// CmdLineRunner::new("make")
+///     .reporter(Arc::new(Log))
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait ProgressReporter: Send + Sync {
+    /// Called when the command starts and when it finishes.
+    fn set_status(&self, status: ProgressState);
+
+    /// Sets a named value for display, such as the latest output line.
+    fn prop(&self, key: &str, value: &str);
+
+    /// Prints a line above the progress display, such as stderr or the
+    /// output of a failed command.
+    fn println(&self, line: &str);
+
+    /// Called after a batch of props has been set, to redraw.
+    fn update(&self) {}
+
+    /// Sets how far through its work the command is, as found by a
+    /// [`ProgressParser`](crate::ProgressParser).
+    fn set_position(&self, current: usize, total: usize) {
+        let _ = (current, total);
+    }
+}
+
+#[cfg(feature = "progress")]
+impl ProgressReporter for clx::progress::ProgressJob {
+    fn set_status(&self, status: ProgressState) {
+        use clx::progress::ProgressStatus;
+        let marker = |success| crate::defaults::style().marker(success);
+        self.set_status(match status {
+            ProgressState::Running => ProgressStatus::Running,
+            ProgressState::Done => {
+                marker(true).map_or(ProgressStatus::Done, ProgressStatus::DoneCustom)
+            }
+            ProgressState::Failed => {
+                // clx has no custom failed status, but a custom done one
+                // looks the same
+                marker(false).map_or(ProgressStatus::Failed, ProgressStatus::DoneCustom)
+            }
+        });
+    }
+
+    fn prop(&self, key: &str, value: &str) {
+        self.prop(key, value);
+    }
+
+    fn println(&self, line: &str) {
+        self.println(line);
+    }
+
+    fn update(&self) {
+        self.update();
+    }
+
+    fn set_position(&self, current: usize, total: usize) {
+        // clx clamps each value against the other, so set the current value
+        // on both sides of the total to allow it to go down between phases
+        self.progress_current(current);
+        self.progress_total(total);
+        self.progress_current(current);
+    }
+}
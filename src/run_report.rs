@@ -0,0 +1,157 @@
+//! Machine-readable JSON reports of executed commands.
+
+use crate::HistoryEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// Records commands for a JSON report that other tools can read, rather
+/// than parsing logs.
+///
+/// Set for one command with [`CmdLineRunner::run_report`](crate::CmdLineRunner::run_report),
+/// or for every command with [`Defaults::run_report`](crate::Defaults::run_report).
+/// Clones share their records. Each command gets a [`RunRecord`] with
+/// redactions applied; only the names of env vars are recorded, not their
+/// values.
+///
+/// The report is an object with a `commands` array:
+///
+/// ```json
+/// {
+///   "commands": [
+///     {
+///       "argv": ["cargo", "test"],
+///       "env": ["RUST_LOG"],
+///       "cwd": null,
+///       "started_at": 1767225600000,
+///       "duration_ms": 5230,
+///       "exit_code": 101,
+///       "error": null,
+///       "output": "test result: FAILED. 41 passed; 1 failed\n",
+///       "labels": {"package": "core"}
+///     }
+///   ]
+/// }
+/// ```
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, RunReport};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let report = RunReport::new();
+/// ensembler::set_defaults(Defaults::new().run_report(report.clone()));
+///
+/// let result = CmdLineRunner::new("cargo").arg("test").execute().await;
+/// report.write("target/run-report.json")?;
+/// result?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    max_output: usize,
+    records: Arc<Mutex<Vec<RunRecord>>>,
+}
+
+/// One command recorded by a [`RunReport`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RunRecord {
+    /// The program and its arguments as run, after any wrappers.
+    pub argv: Vec<String>,
+    /// The names of the env vars set on the runner (not the inherited ones).
+    pub env: Vec<String>,
+    /// The working directory, if one was set.
+    pub cwd: Option<PathBuf>,
+    /// When the command started.
+    #[serde(with = "crate::history::millis_since_epoch")]
+    pub started_at: SystemTime,
+    /// How long it ran.
+    #[serde(rename = "duration_ms", with = "crate::history::millis")]
+    pub duration: Duration,
+    /// The exit code, or `None` if it was killed by a signal or didn't run.
+    pub exit_code: Option<i32>,
+    /// The error returned instead of a result, e.g. for a timeout.
+    pub error: Option<String>,
+    /// The end of the combined stdout and stderr, up to
+    /// [`RunReport::max_output`] bytes.
+    pub output: String,
+    /// The labels set with [`CmdLineRunner::label`](crate::CmdLineRunner::label).
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    commands: &'a [RunRecord],
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self {
+            max_output: 4096,
+            records: Default::default(),
+        }
+    }
+
+    /// Sets how many bytes of output to keep per command, from the end.
+    /// Defaults to 4 KiB.
+    pub fn max_output(mut self, bytes: usize) -> Self {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Returns the recorded commands in the order they finished.
+    pub fn records(&self) -> Vec<RunRecord> {
+        self.lock().clone()
+    }
+
+    /// Renders the report as pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        let records = self.lock();
+        let report = Report { commands: &records };
+        serde_json::to_string_pretty(&report).expect("a report is always serializable")
+    }
+
+    /// Writes the report to `path`, creating its directory if needed.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_json() + "\n")
+    }
+
+    /// Records the command `entry` describes, run as `argv`.
+    pub(crate) fn record(&self, argv: Vec<String>, entry: &HistoryEntry) {
+        let record = RunRecord {
+            argv,
+            env: entry.env.iter().map(|(k, _)| k.clone()).collect(),
+            cwd: entry.cwd.clone(),
+            started_at: entry.started_at,
+            duration: entry.duration,
+            exit_code: entry.exit_code,
+            error: entry.error.clone(),
+            output: crate::history::tail(&entry.output, self.max_output),
+            labels: entry.labels.iter().cloned().collect(),
+        };
+        self.lock().push(record);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<RunRecord>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,126 @@
+//! CPU time and memory used by a command.
+
+use std::time::Duration;
+
+/// How many commands [`wait`] parks a blocking thread for at once. Past this,
+/// it fails so the caller falls back to tokio's wait, without usage, rather
+/// than taking every thread in tokio's blocking pool from `tokio::fs` and
+/// `spawn_blocking` work.
+#[cfg(unix)]
+const MAX_BLOCKED_WAITS: usize = 64;
+
+#[cfg(unix)]
+static BLOCKED_WAITS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// The CPU time and memory a command used, from `wait4` on Unix and the
+/// child's job object on Windows. Returned as
+/// [`CmdResult::resource_usage`](crate::CmdResult::resource_usage).
+///
+/// On Unix this covers the command and the descendants it waited for; on
+/// Windows it covers every process in the command's tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ResourceUsage {
+    /// CPU time spent in user mode.
+    pub user_time: Duration,
+    /// CPU time spent in the kernel.
+    pub system_time: Duration,
+    /// The largest resident set size, in bytes, of any one process. On
+    /// Windows, the most memory any one process committed.
+    pub max_rss: u64,
+}
+
+impl ResourceUsage {
+    /// Returns the total CPU time, user and system.
+    pub fn cpu_time(&self) -> Duration {
+        self.user_time + self.system_time
+    }
+}
+
+/// Waits for `pid` to exit and reaps it, returning its status and usage.
+///
+/// The wait parks a thread from tokio's blocking pool in `waitid` until the
+/// child exits, so each running command holds one. At most
+/// [`MAX_BLOCKED_WAITS`] are parked at once; past that this fails and the
+/// caller should wait through tokio instead.
+///
+/// `waitid` is called with `WNOWAIT`, so it leaves the child to be reaped.
+/// Only once it has exited is it reaped here with `wait4`, and tokio's own
+/// handle then finds it gone when it's dropped. This can't race with tokio:
+/// tokio only reaps a child when its `wait` or `try_wait` is polled, or after
+/// its `Child` is dropped, and the caller must do neither while this future
+/// is pending. If this future is dropped first, nothing is reaped here and
+/// the child is left to tokio; the parked thread returns once the child
+/// exits or is reaped.
+#[cfg(unix)]
+pub(crate) async fn wait(pid: u32) -> std::io::Result<(std::process::ExitStatus, ResourceUsage)> {
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::atomic::Ordering;
+
+    if BLOCKED_WAITS.fetch_add(1, Ordering::Relaxed) >= MAX_BLOCKED_WAITS {
+        BLOCKED_WAITS.fetch_sub(1, Ordering::Relaxed);
+        return Err(io::Error::other(format!(
+            "more than {MAX_BLOCKED_WAITS} commands are already waiting"
+        )));
+    }
+    tokio::task::spawn_blocking(move || {
+        let ret = loop {
+            // SAFETY: siginfo_t is plain data that waitid fills in.
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            // SAFETY: `info` is valid for writes for the duration of the call.
+            let ret = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if ret == 0 {
+                break Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                break Err(e);
+            }
+        };
+        BLOCKED_WAITS.fetch_sub(1, Ordering::Relaxed);
+        ret
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    let mut status = 0;
+    // SAFETY: rusage is plain data that wait4 fills in.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `status` and `usage` are valid for writes for the duration of
+    // the call. The child has exited, so WNOHANG reaps it without blocking.
+    let ret = unsafe { libc::wait4(pid as libc::pid_t, &mut status, libc::WNOHANG, &mut usage) };
+    match ret {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => return Err(io::Error::other(format!("pid {pid} has not exited"))),
+        _ => {}
+    }
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    // kilobytes on Linux, bytes on macOS
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let max_rss = usage.ru_maxrss as u64;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let max_rss = usage.ru_maxrss as u64 * 1024;
+    let usage = ResourceUsage {
+        user_time: time(usage.ru_utime),
+        system_time: time(usage.ru_stime),
+        max_rss,
+    };
+    Ok((std::process::ExitStatus::from_raw(status), usage))
+}
+
+/// Converts a count of 100 nanosecond intervals, as Windows reports CPU
+/// time, to a duration.
+#[cfg(windows)]
+pub(crate) fn from_filetime(intervals: i64) -> Duration {
+    Duration::from_nanos(intervals.max(0) as u64 * 100)
+}
@@ -0,0 +1,355 @@
+//! Landlock filesystem and seccomp syscall restrictions for a single command.
+//!
+//! The Landlock ruleset and the seccomp filter are built in the parent, so
+//! the `pre_exec` hook only has to make the syscalls that enforce them.
+
+use nix::errno::Errno;
+use nix::libc;
+use std::fmt;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A least-privilege policy for a command (Linux only).
+///
+/// Filesystem access is restricted with [Landlock] once any path is allowed:
+/// the command can then only read, or read and write, beneath the allowed
+/// paths. Syscalls are restricted with a seccomp filter that fails the
+/// denied ones with `EPERM`. Both restrictions are inherited by every
+/// process the command starts and can't be lifted. Set with
+/// [`CmdLineRunner::sandbox`](crate::CmdLineRunner::sandbox).
+///
+/// Running the command fails if the kernel lacks Landlock (5.13+) or, for
+/// syscall rules, the architecture isn't x86_64 or aarch64, rather than
+/// running it unconfined.
+///
+/// [Landlock]: https://docs.kernel.org/userspace-api/landlock.html
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Sandbox};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let sandbox = Sandbox::new()
+///     .allow_read(["/usr", "/lib", "/lib64", "/bin", "/etc"])
+///     .allow_write(["/tmp/plugin-work"])
+///     .deny_network();
+///
+/// CmdLineRunner::new("/usr/bin/plugin")
+///     .sandbox(sandbox)
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    deny_syscalls: Vec<i64>,
+    deny_network: bool,
+}
+
+impl Sandbox {
+    /// Creates a policy that restricts nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows reading and executing files beneath `paths`.
+    pub fn allow_read<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.read
+            .extend(paths.into_iter().map(|p| p.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Allows reading, writing, creating and removing files beneath `paths`.
+    pub fn allow_write<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        self.write
+            .extend(paths.into_iter().map(|p| p.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Makes the given syscalls fail with `EPERM`, e.g.
+    /// `[libc::SYS_ptrace, libc::SYS_mount]`.
+    pub fn deny_syscalls(mut self, syscalls: impl IntoIterator<Item = i64>) -> Self {
+        self.deny_syscalls.extend(syscalls);
+        self
+    }
+
+    /// Blocks creating sockets other than Unix domain sockets, cutting the
+    /// command off from the network.
+    pub fn deny_network(mut self) -> Self {
+        self.deny_network = true;
+        self
+    }
+
+    /// Builds the Landlock ruleset and seccomp filter.
+    pub(crate) fn prepare(&self) -> io::Result<Prepared> {
+        let ruleset = if self.read.is_empty() && self.write.is_empty() {
+            None
+        } else {
+            Some(Arc::new(self.landlock_ruleset()?))
+        };
+        let filter = if self.deny_syscalls.is_empty() && !self.deny_network {
+            None
+        } else {
+            Some(Filter(self.seccomp_filter()?))
+        };
+        Ok(Prepared { ruleset, filter })
+    }
+
+    fn landlock_ruleset(&self) -> io::Result<OwnedFd> {
+        // SAFETY: querying the ABI version takes no pointer.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Landlock is not available: {}", io::Error::last_os_error()),
+            ));
+        }
+        let mut write_access = ACCESS_FS_WRITE;
+        if abi >= 2 {
+            write_access |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            write_access |= ACCESS_FS_TRUNCATE;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_READ | write_access,
+        };
+        // SAFETY: `attr` is a valid ruleset attribute of the given size.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        let fd = Errno::result(fd)?;
+        // SAFETY: the syscall returned a new file descriptor that we own.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        let rules = self.read.iter().map(|p| (p, ACCESS_FS_READ)).chain(
+            self.write
+                .iter()
+                .map(|p| (p, ACCESS_FS_READ | write_access)),
+        );
+        for (path, access) in rules {
+            let parent = nix::fcntl::open(
+                path,
+                nix::fcntl::OFlag::O_PATH | nix::fcntl::OFlag::O_CLOEXEC,
+                nix::sys::stat::Mode::empty(),
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    io::Error::from(e).kind(),
+                    format!("failed to open {} for the sandbox: {e}", path.display()),
+                )
+            })?;
+            let is_dir = nix::sys::stat::fstat(&parent)
+                .map(|st| st.st_mode & libc::S_IFMT == libc::S_IFDIR)
+                .unwrap_or(false);
+            let rule = PathBeneathAttr {
+                // directory-only rights are rejected on files
+                allowed_access: if is_dir {
+                    access
+                } else {
+                    access & ACCESS_FS_FILE
+                },
+                parent_fd: parent.as_raw_fd(),
+            };
+            // SAFETY: `rule` is a valid path-beneath rule for this ruleset.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+            Errno::result(ret)?;
+        }
+        Ok(ruleset)
+    }
+
+    fn seccomp_filter(&self) -> io::Result<Vec<libc::sock_filter>> {
+        let Some(arch) = AUDIT_ARCH else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "seccomp syscall rules are not supported on this architecture",
+            ));
+        };
+        let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+        let mut filter = vec![
+            // syscall numbers differ between architectures
+            stmt(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                SECCOMP_DATA_ARCH,
+            ),
+            jump(arch, 1, 0),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            // x32 syscalls would get around the deny list
+            libc::sock_filter {
+                code: (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16,
+                jt: 0,
+                jf: 1,
+                k: X32_SYSCALL_BIT,
+            },
+            stmt(libc::BPF_RET | libc::BPF_K, deny),
+        ]);
+        for nr in &self.deny_syscalls {
+            filter.push(jump(*nr as u32, 0, 1));
+            filter.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+        }
+        if self.deny_network {
+            filter.extend([
+                jump(libc::SYS_socket as u32, 0, 3),
+                stmt(
+                    libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                    SECCOMP_DATA_ARG0,
+                ),
+                jump(libc::AF_UNIX as u32, 1, 0),
+                stmt(libc::BPF_RET | libc::BPF_K, deny),
+            ]);
+        }
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        Ok(filter)
+    }
+}
+
+/// A [`Sandbox`] ready to be applied in the child.
+#[derive(Debug, Clone)]
+pub(crate) struct Prepared {
+    ruleset: Option<Arc<OwnedFd>>,
+    filter: Option<Filter>,
+}
+
+impl Prepared {
+    /// Restricts the current (child) process. Must run after everything else
+    /// in the `pre_exec` hook, since the seccomp filter may deny syscalls it
+    /// makes.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        // SAFETY: prctl with integer arguments.
+        Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        if let Some(ruleset) = &self.ruleset {
+            // SAFETY: `ruleset` is an open Landlock ruleset.
+            let ret =
+                unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) };
+            Errno::result(ret)?;
+        }
+        if let Some(Filter(filter)) = &self.filter {
+            let prog = libc::sock_fprog {
+                len: filter.len() as u16,
+                filter: filter.as_ptr() as *mut _,
+            };
+            // SAFETY: `prog` points to a valid filter that outlives the call.
+            let ret = unsafe {
+                libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &prog as *const libc::sock_fprog,
+                )
+            };
+            Errno::result(ret)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Filter(Vec<libc::sock_filter>);
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Filter({} instructions)", self.0.len())
+    }
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Compares the accumulator with `k`, skipping `jt` or `jf` instructions.
+fn jump(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: i32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Remove dir/file and make char/dir/reg/sock/fifo/block/sym.
+const ACCESS_FS_MODIFY_DIR: u64 = 0b1_1111_1111 << 4;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const ACCESS_FS_WRITE: u64 = ACCESS_FS_WRITE_FILE | ACCESS_FS_MODIFY_DIR;
+/// Rights that apply to a single file.
+const ACCESS_FS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+#[cfg(target_endian = "little")]
+const SECCOMP_DATA_ARG0: u32 = 16;
+#[cfg(target_endian = "big")]
+const SECCOMP_DATA_ARG0: u32 = 20;
+
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
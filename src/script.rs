@@ -0,0 +1,162 @@
+use crate::tempfile::TempFile;
+use crate::{CmdLineRunner, CmdResult, Result, Shell};
+use std::ffi::{OsStr, OsString};
+
+/// Runs a multi-line script by writing it to a temporary file.
+///
+/// Passing scripts through `sh -c` breaks down with complex quoting and on
+/// Windows. `ScriptRunner` instead writes the body to a file that only the
+/// current user can access, runs it, and deletes it once the command finishes.
+///
+/// The interpreter is chosen in this order:
+///
+/// 1. one set with [`interpreter`](Self::interpreter)
+/// 2. the script's shebang line (`#!/usr/bin/env python3`)
+/// 3. the [`Shell`] set with [`shell`](Self::shell), or else the default
+///    one (see [`Defaults::shell`](crate::Defaults::shell)), run with its
+///    error-exit flags
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::ScriptRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let result = ScriptRunner::new(
+///     r#"#!/usr/bin/env python3
+/// import sys
+/// print("hello from", sys.argv[1])
+/// "#,
+/// )
+/// .runner()?
+/// .arg("python")
+/// .execute()
+/// .await?;
+///
+/// assert_eq!(result.stdout.trim(), "hello from python");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ScriptRunner {
+    body: String,
+    interpreter: Option<Vec<OsString>>,
+    shell: Option<Shell>,
+    extension: Option<String>,
+}
+
+impl ScriptRunner {
+    /// Creates a runner for the given script body.
+    pub fn new(body: impl Into<String>) -> Self {
+        Self {
+            body: body.into(),
+            interpreter: None,
+            shell: None,
+            extension: None,
+        }
+    }
+
+    /// Runs the script with `shell` when it has no shebang, e.g.
+    /// [`Shell::PowerShell`] for a `.ps1` script.
+    pub fn shell(mut self, shell: Shell) -> Self {
+        self.shell = Some(shell);
+        self
+    }
+
+    /// Runs the script with `program`, ignoring any shebang.
+    ///
+    /// `args` are passed before the script path, e.g.
+    /// `.interpreter("bash", ["-eu"])`.
+    pub fn interpreter<P, I, S>(mut self, program: P, args: I) -> Self
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut interpreter = vec![program.as_ref().to_os_string()];
+        interpreter.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self.interpreter = Some(interpreter);
+        self
+    }
+
+    /// Sets the temp file's extension (without the dot).
+    ///
+    /// Some interpreters require one, e.g. `ps1` for PowerShell. Defaults to
+    /// the one the shell needs (`cmd` or `ps1`) when the script runs with a
+    /// [`Shell`], and none otherwise.
+    pub fn extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = Some(extension.into());
+        self
+    }
+
+    /// Writes the script and returns a runner that executes it.
+    ///
+    /// Further arguments added to the runner are passed to the script. The
+    /// temp file is deleted when the runner is dropped, which happens when
+    /// [`execute`](CmdLineRunner::execute) completes.
+    pub fn runner(self) -> Result<CmdLineRunner> {
+        let mut default_ext = "";
+        let interpreter = match self
+            .interpreter
+            .clone()
+            .or_else(|| parse_shebang(&self.body))
+        {
+            Some(interpreter) => interpreter,
+            None => {
+                let shell = self
+                    .shell
+                    .clone()
+                    .or_else(|| crate::defaults().shell)
+                    .unwrap_or_default();
+                default_ext = shell.file_extension();
+                let mut interpreter = vec![OsString::from(shell.program())];
+                interpreter.extend(shell.file_args().into_iter().map(OsString::from));
+                interpreter
+            }
+        };
+        let ext = self.extension.as_deref().unwrap_or(default_ext);
+        let body = if cfg!(windows) && ext.eq_ignore_ascii_case("cmd") {
+            self.body.replace("\r\n", "\n").replace('\n', "\r\n")
+        } else {
+            self.body
+        };
+        let file = TempFile::create(ext, body.as_bytes(), true)?;
+        let runner = CmdLineRunner::new_direct(&interpreter[0])
+            .args(&interpreter[1..])
+            .arg(file.path());
+        Ok(runner.with_temp_file(file))
+    }
+
+    /// Writes the script, executes it, and cleans up.
+    pub async fn execute(self) -> Result<CmdResult> {
+        self.runner()?.execute().await
+    }
+}
+
+/// Parses a `#!` line into an interpreter command.
+///
+/// On Windows, `/usr/bin/env prog` becomes `prog` and absolute interpreter
+/// paths like `/bin/bash` are reduced to their file name so they can be
+/// found on `PATH`.
+fn parse_shebang(body: &str) -> Option<Vec<OsString>> {
+    let line = body.strip_prefix("#!")?.lines().next()?.trim();
+    let mut words = line
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        return None;
+    }
+    if cfg!(windows) {
+        if words[0].ends_with("/env") && words.len() > 1 {
+            words.remove(0);
+            if words[0] == "-S" && words.len() > 1 {
+                words.remove(0);
+            }
+        } else if let Some((_, name)) = words[0].rsplit_once('/') {
+            words[0] = name.to_string();
+        }
+    }
+    Some(words.into_iter().map(OsString::from).collect())
+}
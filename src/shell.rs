@@ -0,0 +1,316 @@
+use crate::shell_words;
+use crate::{CmdLineRunner, Result, ScriptRunner};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::LazyLock as Lazy;
+
+/// A shell that scripts can be run with.
+///
+/// Each variant knows its program, the flags that make it stop on the first
+/// error, how a script is passed to it, and how to quote a literal argument
+/// for it. This lets task runners honor a user's configured shell:
+///
+/// ```no_run
+/// use ensembler::Shell;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let shell: Shell = "bash".parse()?;
+/// let script = format!("echo {}", shell.quote("it's $HOME"));
+/// shell.runner(script).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Shell {
+    /// POSIX `sh`, run as `sh -o errexit -c <script>`.
+    Sh,
+    /// `bash -o errexit -o pipefail -c <script>`.
+    Bash,
+    /// `zsh -o errexit -o pipefail -c <script>`.
+    Zsh,
+    /// `fish -c <script>` (fish has no errexit mode).
+    Fish,
+    /// `cmd.exe /d /s /c "<script>"`.
+    Cmd,
+    /// PowerShell; see [`CmdLineRunner::powershell`].
+    PowerShell,
+    /// Any other shell: `program args... <script>`.
+    ///
+    /// Scripts are quoted with POSIX rules.
+    Custom {
+        /// The shell executable.
+        program: String,
+        /// Arguments passed before the script, e.g. `["-c"]`.
+        args: Vec<String>,
+    },
+}
+
+impl Default for Shell {
+    /// `Sh` on Unix and `Cmd` on Windows.
+    fn default() -> Self {
+        if cfg!(windows) {
+            Shell::Cmd
+        } else {
+            Shell::Sh
+        }
+    }
+}
+
+impl Shell {
+    /// Returns the shell's executable.
+    pub fn program(&self) -> &str {
+        match self {
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+            Shell::Cmd => "cmd.exe",
+            Shell::PowerShell => powershell_program(),
+            Shell::Custom { program, .. } => program,
+        }
+    }
+
+    /// Returns the arguments passed before a script given on the command line.
+    pub fn script_args(&self) -> Vec<&str> {
+        match self {
+            Shell::Sh => vec!["-o", "errexit", "-c"],
+            Shell::Bash | Shell::Zsh => vec!["-o", "errexit", "-o", "pipefail", "-c"],
+            Shell::Fish => vec!["-c"],
+            Shell::Cmd => vec!["/d", "/s", "/c"],
+            Shell::PowerShell => vec!["-NoProfile", "-NonInteractive", "-Command"],
+            Shell::Custom { args, .. } => args.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Quotes `s` so this shell reads it back as a single literal argument.
+    pub fn quote<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh | Shell::Custom { .. } => shell_words::quote(s),
+            Shell::Fish => {
+                if !s.is_empty()
+                    && !s
+                        .contains(|c: char| !c.is_ascii_alphanumeric() && !"_@%+=:,./-".contains(c))
+                {
+                    Cow::Borrowed(s)
+                } else {
+                    Cow::Owned(format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'")))
+                }
+            }
+            Shell::Cmd => shell_words::quote_cmd(s),
+            Shell::PowerShell => {
+                // typographic single quotes end a string too, and are
+                // escaped the same way, by doubling
+                let mut quoted = String::with_capacity(s.len() + 2);
+                quoted.push('\'');
+                for c in s.chars() {
+                    if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+                        quoted.push(c);
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('\'');
+                Cow::Owned(quoted)
+            }
+        }
+    }
+
+    /// Returns the arguments passed before the path of a script file.
+    pub(crate) fn file_args(&self) -> Vec<&str> {
+        match self {
+            Shell::Sh => vec!["-o", "errexit"],
+            Shell::Bash | Shell::Zsh => vec!["-o", "errexit", "-o", "pipefail"],
+            Shell::Fish => vec![],
+            Shell::Cmd => vec!["/d", "/c"],
+            Shell::PowerShell => vec![
+                "-NoProfile",
+                "-NonInteractive",
+                "-ExecutionPolicy",
+                "Bypass",
+                "-File",
+            ],
+            Shell::Custom { args, .. } => custom_args(args).iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Returns the extension a script file needs to run with this shell, if
+    /// any.
+    pub(crate) fn file_extension(&self) -> &'static str {
+        match self {
+            Shell::Cmd => "cmd",
+            Shell::PowerShell => "ps1",
+            _ => "",
+        }
+    }
+
+    /// Creates a runner that executes `script` with this shell.
+    pub fn runner(&self, script: impl AsRef<str>) -> CmdLineRunner {
+        let script = script.as_ref();
+        match self {
+            Shell::Cmd => {
+                CmdLineRunner::new_direct(self.program()).raw_arg(format!("/d /s /c \"{script}\""))
+            }
+            Shell::PowerShell => powershell_runner(script),
+            _ => CmdLineRunner::new_direct(self.program())
+                .args(self.script_args())
+                .arg(script),
+        }
+    }
+
+    /// Creates a runner that feeds `script` to this shell on stdin instead of
+    /// passing it as an argument.
+    ///
+    /// POSIX shells run as `sh -o errexit -s` (with `pipefail` for bash and
+    /// zsh), fish reads the script from stdin, and PowerShell reads it in
+    /// full before running it as a script block. `cmd.exe` can't run a
+    /// script from stdin without echoing prompts, so the script is written
+    /// to a temporary `.cmd` file instead. [`Shell::Custom`] shells are run
+    /// with their args minus a trailing `-c`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`](crate::Error::Io) if the `cmd.exe` temp file
+    /// can't be written.
+    pub fn stdin_runner(&self, script: impl Into<String>) -> Result<CmdLineRunner> {
+        let script = script.into();
+        let runner = CmdLineRunner::new_direct(self.program());
+        let runner = match self {
+            Shell::Sh => runner.args(["-o", "errexit", "-s"]),
+            Shell::Bash | Shell::Zsh => runner.args(["-o", "errexit", "-o", "pipefail", "-s"]),
+            Shell::Fish => runner,
+            Shell::Cmd => return ScriptRunner::new(script).shell(Shell::Cmd).runner(),
+            Shell::PowerShell => {
+                return Ok(runner
+                    .args(["-NoProfile", "-NonInteractive", "-Command"])
+                    .arg(POWERSHELL_STDIN)
+                    .stdin_string(powershell_script(&script)));
+            }
+            Shell::Custom { args, .. } => runner.args(custom_args(args)),
+        };
+        Ok(runner.stdin_string(script))
+    }
+}
+
+impl FromStr for Shell {
+    type Err = crate::Error;
+
+    /// Parses a shell from a user-supplied name or command line.
+    ///
+    /// A bare name or path whose file name is a known shell (`bash`,
+    /// `/usr/bin/zsh`, `pwsh.exe`, ...) maps to that variant. Anything else,
+    /// including a program with arguments like `bash -l -c`, becomes
+    /// [`Shell::Custom`]; a lone unknown program gets `-c` as its argument.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = shell_words::split(s)?;
+        if words.is_empty() {
+            return Err(crate::Error::InvalidCommandLine(format!(
+                "no shell in `{s}`"
+            )));
+        }
+        let program = words.remove(0);
+        if !words.is_empty() {
+            return Ok(Shell::Custom {
+                program,
+                args: words,
+            });
+        }
+        let name = Path::new(&program)
+            .file_stem()
+            .unwrap_or(OsStr::new(""))
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        Ok(match name.as_str() {
+            "sh" => Shell::Sh,
+            "bash" => Shell::Bash,
+            "zsh" => Shell::Zsh,
+            "fish" => Shell::Fish,
+            "cmd" => Shell::Cmd,
+            "pwsh" | "powershell" => Shell::PowerShell,
+            _ => Shell::Custom {
+                program,
+                args: vec!["-c".into()],
+            },
+        })
+    }
+}
+
+impl Display for Shell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+/// Returns a custom shell's args without a trailing `-c`, for scripts that
+/// aren't passed as an argument.
+fn custom_args(args: &[String]) -> &[String] {
+    match args.split_last() {
+        Some((last, rest)) if last == "-c" => rest,
+        _ => args,
+    }
+}
+
+/// Reads a whole script from stdin and runs it, so multi-line constructs
+/// aren't parsed line by line as `-Command -` would.
+const POWERSHELL_STDIN: &str =
+    "& ([scriptblock]::Create([Console]::In.ReadToEnd())); exit $LASTEXITCODE";
+
+/// Wraps `script` so errors stop it and the exit code of the last native
+/// command is propagated.
+fn powershell_script(script: &str) -> String {
+    format!(
+        "$ErrorActionPreference = 'Stop'\n{script}\nif ($LASTEXITCODE) {{ exit $LASTEXITCODE }}"
+    )
+}
+
+/// Builds the PowerShell runner for [`powershell_script`].
+fn powershell_runner(script: &str) -> CmdLineRunner {
+    let script = powershell_script(script);
+    let runner =
+        CmdLineRunner::new_direct(powershell_program()).args(["-NoProfile", "-NonInteractive"]);
+    if cfg!(windows) {
+        let utf16 = script
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<_>>();
+        runner.args(["-EncodedCommand", &base64(&utf16)])
+    } else {
+        runner.args(["-Command", &script])
+    }
+}
+
+/// Returns the PowerShell executable to use: `pwsh` if installed, otherwise
+/// Windows PowerShell on Windows.
+fn powershell_program() -> &'static str {
+    static PROGRAM: Lazy<&'static str> = Lazy::new(|| {
+        if CmdLineRunner::which("pwsh").is_some() || !cfg!(windows) {
+            "pwsh"
+        } else {
+            "powershell.exe"
+        }
+    });
+    *PROGRAM
+}
+
+/// Standard base64 encoding (used for PowerShell's `-EncodedCommand`).
+fn base64(bytes: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,229 @@
+//! Shell-style quoting helpers.
+
+use crate::{Error, Result};
+use std::borrow::Cow;
+
+/// Quotes `s` so a POSIX shell reads it back as a single literal word.
+///
+/// Strings made only of characters that are never special to the shell are
+/// returned unchanged; anything else is wrapped in single quotes.
+///
+/// # Example
+///
+/// ```
+/// use ensembler::shell_words::quote;
+///
+/// assert_eq!(quote("hello"), "hello");
+/// assert_eq!(quote("hello world"), "'hello world'");
+/// assert_eq!(quote("it's"), r"'it'\''s'");
+/// ```
+pub fn quote(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && s.chars().all(is_safe_char) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(format!("'{}'", s.replace('\'', r"'\''")))
+}
+
+fn is_safe_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c)
+}
+
+/// Splits a command line into words using POSIX shell quoting rules.
+///
+/// Words are separated by whitespace. Single quotes preserve their contents
+/// literally, double quotes allow `\"`, `\\`, `\$` and `` \` `` escapes, and a
+/// backslash outside quotes escapes the next character. A `#` at the start
+/// of a word begins a comment that runs to the end of the line. No expansion
+/// (variables, globs, command substitution) is performed.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidCommandLine`] for unterminated quotes or a
+/// trailing backslash.
+///
+/// # Example
+///
+/// ```
+/// use ensembler::shell_words::split;
+///
+/// let words = split(r#"docker run -e "GREETING=hello world" alpine echo 'a b'"#).unwrap();
+/// assert_eq!(words, ["docker", "run", "-e", "GREETING=hello world", "alpine", "echo", "a b"]);
+/// ```
+pub fn split(s: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(word.take());
+            }
+            '#' if word.is_none() => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(invalid(s, "trailing backslash")),
+            },
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(invalid(s, "unterminated single quote")),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(invalid(s, "unterminated double quote")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(invalid(s, "unterminated double quote")),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn invalid(s: &str, reason: &str) -> Error {
+    Error::InvalidCommandLine(format!("{reason} in `{s}`"))
+}
+
+/// Quotes `s` so a Windows program reads it back as a single argument.
+///
+/// Follows the `CommandLineToArgvW`/MSVCRT rules most programs use to split
+/// their command line: arguments with whitespace or quotes are wrapped in
+/// double quotes, embedded quotes are backslash-escaped, and backslashes
+/// are doubled only where they precede a quote.
+///
+/// This is only correct for programs started directly; when the command
+/// line also goes through `cmd.exe`, pass the result through
+/// [`escape_cmd`].
+///
+/// # Example
+///
+/// ```
+/// use ensembler::shell_words::quote_windows;
+///
+/// assert_eq!(quote_windows("hello"), "hello");
+/// assert_eq!(quote_windows("hello world"), r#""hello world""#);
+/// assert_eq!(quote_windows(r#"say "hi""#), r#""say \"hi\"""#);
+/// assert_eq!(quote_windows(r"C:\dir\"), r"C:\dir\");
+/// assert_eq!(quote_windows(r"C:\my dir\"), r#""C:\my dir\\""#);
+/// ```
+pub fn quote_windows(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && !s.contains([' ', '\t', '\n', '\x0b', '"']) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut backslashes = 0;
+    for c in s.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                out.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                out.push('"');
+                backslashes = 0;
+            }
+            c => {
+                out.extend(std::iter::repeat_n('\\', backslashes));
+                out.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    out.extend(std::iter::repeat_n('\\', backslashes * 2));
+    out.push('"');
+    Cow::Owned(out)
+}
+
+/// Escapes `cmd.exe` metacharacters in `s` with `^` so `cmd.exe` passes it
+/// through to the program unchanged.
+///
+/// Every `(`, `)`, `%`, `!`, `^`, `"`, `<`, `>`, `&` and `|` is escaped,
+/// including quotes, so the result is safe regardless of `cmd.exe`'s quote
+/// state. Use it on an argument already quoted with [`quote_windows`].
+///
+/// # Example
+///
+/// ```
+/// use ensembler::shell_words::{escape_cmd, quote_windows};
+///
+/// assert_eq!(escape_cmd(&quote_windows("a & b")), r#"^"a ^& b^""#);
+/// assert_eq!(escape_cmd("100%"), "100^%");
+/// ```
+pub fn escape_cmd(s: &str) -> Cow<'_, str> {
+    escape_cmd_with(s, "^%")
+}
+
+fn escape_cmd_with<'a>(s: &'a str, percent: &str) -> Cow<'a, str> {
+    if !s.contains(is_cmd_meta) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() * 2);
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str(percent),
+            c if is_cmd_meta(c) => {
+                out.push('^');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+fn is_cmd_meta(c: char) -> bool {
+    "()%!^\"<>&|".contains(c)
+}
+
+/// Quotes `s` for a program run through `cmd.exe`.
+pub(crate) fn quote_cmd(s: &str) -> Cow<'_, str> {
+    match quote_windows(s) {
+        Cow::Borrowed(s) => escape_cmd(s),
+        Cow::Owned(s) => Cow::Owned(escape_cmd(&s).into_owned()),
+    }
+}
+
+/// Quotes `s` for a program run from a batch (`.cmd`) file, where `%` must
+/// be doubled rather than escaped with `^`.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) fn quote_batch(s: &str) -> Cow<'_, str> {
+    match quote_windows(s) {
+        Cow::Borrowed(s) => escape_cmd_with(s, "%%"),
+        Cow::Owned(s) => Cow::Owned(escape_cmd_with(&s, "%%").into_owned()),
+    }
+}
+
+/// Quotes `s` for the shell used by [`CmdLineRunner::shell`](crate::CmdLineRunner::shell).
+#[doc(hidden)]
+pub fn __sh_quote(s: &str) -> String {
+    crate::defaults()
+        .shell
+        .unwrap_or_default()
+        .quote(s)
+        .into_owned()
+}
@@ -0,0 +1,123 @@
+//! Counting every command the process runs.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+static STATS: Mutex<Stats> = Mutex::new(Stats {
+    commands: 0,
+    running: 0,
+    wall_time: Duration::ZERO,
+    failures: BTreeMap::new(),
+});
+
+/// Counts of the commands this process has run, returned by [`stats`].
+///
+/// Its `Display` is a one-line summary for a `--debug` message at exit:
+///
+/// ```text
+/// 12 commands, 2 failed (cargo: 2), 34.51s total
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// How many commands have finished, including any that failed or were
+    /// cancelled.
+    pub commands: u64,
+    /// How many commands are running now.
+    pub running: u64,
+    /// The total time commands have run for, added up, so it's more than
+    /// the elapsed time when commands run at the same time.
+    pub wall_time: Duration,
+    /// How many commands returned an error, by program.
+    pub failures: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    /// Returns how many commands returned an error.
+    pub fn failed(&self) -> u64 {
+        self.failures.values().sum()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = if self.commands == 1 { "" } else { "s" };
+        write!(f, "{} command{s}", self.commands)?;
+        if !self.failures.is_empty() {
+            let programs = self
+                .failures
+                .iter()
+                .map(|(program, n)| format!("{program}: {n}"))
+                .collect::<Vec<_>>();
+            write!(f, ", {} failed ({})", self.failed(), programs.join(", "))?;
+        }
+        if self.running > 0 {
+            write!(f, ", {} running", self.running)?;
+        }
+        write!(f, ", {:.2}s total", self.wall_time.as_secs_f64())
+    }
+}
+
+/// Returns counts of the commands this process has run with
+/// [`CmdLineRunner::execute`](crate::CmdLineRunner::execute).
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// CmdLineRunner::new("cargo").arg("build").execute().await?;
+/// eprintln!("debug: {}", ensembler::stats());
+/// # Ok(())
+/// # }
+/// ```
+pub fn stats() -> Stats {
+    lock().clone()
+}
+
+/// A running command, counted as finished when dropped.
+pub(crate) struct Running {
+    program: String,
+    start: Instant,
+    failed: bool,
+}
+
+impl Running {
+    /// Counts a command of `program` as running.
+    pub(crate) fn start(program: &str) -> Self {
+        lock().running += 1;
+        Self {
+            program: program.to_string(),
+            start: Instant::now(),
+            failed: false,
+        }
+    }
+
+    /// Counts the command as failed when it finishes.
+    pub(crate) fn fail(&mut self) {
+        self.failed = true;
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut stats = lock();
+        stats.running -= 1;
+        stats.commands += 1;
+        stats.wall_time += self.start.elapsed();
+        if self.failed {
+            *stats
+                .failures
+                .entry(std::mem::take(&mut self.program))
+                .or_default() += 1;
+        }
+    }
+}
+
+fn lock() -> MutexGuard<'static, Stats> {
+    STATS.lock().unwrap_or_else(|e| e.into_inner())
+}
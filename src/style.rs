@@ -0,0 +1,106 @@
+//! How commands are rendered in progress and error output.
+
+use std::borrow::Cow;
+
+/// How commands are rendered: the `$ command` header printed for failed
+/// commands, the markers shown when a command finishes, the text secrets
+/// are replaced with, and how long command lines may get.
+///
+/// Set for every command with [`Defaults::style`](crate::Defaults::style).
+///
+/// # Example
+///
+/// ```
+/// use ensembler::{Defaults, Style};
+///
+/// ensembler::set_defaults(
+///     Defaults::new().style(
+///         Style::new()
+///             .command(console::Style::new().cyan().bold())
+///             .markers("ok", "FAIL")
+///             .redacted("***")
+///             .max_width(60),
+///     ),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Style {
+    pub(crate) command: console::Style,
+    pub(crate) success: Option<String>,
+    pub(crate) failure: Option<String>,
+    pub(crate) redacted: String,
+    pub(crate) max_width: Option<usize>,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            command: console::Style::new().bold(),
+            success: None,
+            failure: None,
+            redacted: "[redacted]".into(),
+            max_width: None,
+        }
+    }
+}
+
+impl Style {
+    /// Creates the default style: a bold `$ command` header, the progress
+    /// backend's own markers, `[redacted]`, and no truncation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the style of the `$ command` header printed above the output of
+    /// a failed command.
+    pub fn command(mut self, style: console::Style) -> Self {
+        self.command = style;
+        self
+    }
+
+    /// Sets the markers shown in place of the spinner when a command
+    /// succeeds or fails, in green and red.
+    pub fn markers(mut self, success: impl Into<String>, failure: impl Into<String>) -> Self {
+        self.success = Some(success.into());
+        self.failure = Some(failure.into());
+        self
+    }
+
+    /// Sets the text that redacted strings are replaced with. Defaults to
+    /// `[redacted]`.
+    pub fn redacted(mut self, text: impl Into<String>) -> Self {
+        self.redacted = text.into();
+        self
+    }
+
+    /// Shortens command lines longer than `width` columns, ending them with
+    /// `…`, in progress jobs and the `$ command` header.
+    pub fn max_width(mut self, width: usize) -> Self {
+        self.max_width = Some(width);
+        self
+    }
+
+    /// Shortens `line` to the maximum width.
+    pub(crate) fn truncate<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        match self.max_width {
+            Some(width) => console::truncate_str(line, width, "…"),
+            None => Cow::Borrowed(line),
+        }
+    }
+
+    /// Renders the `$ command` header.
+    pub(crate) fn header(&self, line: &str) -> String {
+        let header = format!("$ {line}");
+        self.command.apply_to(self.truncate(&header)).to_string()
+    }
+
+    /// Returns the marker for a finished command in its color, if one is
+    /// set.
+    #[cfg_attr(not(feature = "progress"), allow(dead_code))]
+    pub(crate) fn marker(&self, success: bool) -> Option<String> {
+        match success {
+            true => Some(console::style(self.success.as_ref()?).green().to_string()),
+            false => Some(console::style(self.failure.as_ref()?).red().to_string()),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! End-of-run summaries of executed commands.
+
+use crate::HistoryEntry;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Collects the commands of a run, for a summary of how each went once
+/// they've all finished.
+///
+/// Set for one command with [`CmdLineRunner::summary`](crate::CmdLineRunner::summary),
+/// or for every command with [`Defaults::summary`](crate::Defaults::summary).
+/// Clones share their entries. Read them back with
+/// [`entries`](Self::entries), or print the summary as a table with
+/// `Display`:
+///
+/// ```text
+/// STATUS  DURATION  EXIT    OUTPUT  COMMAND
+/// ok          0.4s     0   1.2 KiB  cargo build
+/// failed     1m05s   101  38.0 KiB  cargo test
+/// error       5.0s     -       0 B  cargo doc
+/// 1 passed, 1 failed, 1 error in 1m10s
+/// ```
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Defaults, Summary};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let summary = Summary::new();
+/// ensembler::set_defaults(Defaults::new().summary(summary.clone()));
+///
+/// let (build, lint) = tokio::join!(
+///     CmdLineRunner::new("cargo").arg("build").execute(),
+///     CmdLineRunner::new("cargo").arg("clippy").execute(),
+/// );
+/// eprint!("{summary}");
+/// build?;
+/// lint?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    entries: Arc<Mutex<Vec<HistoryEntry>>>,
+}
+
+impl Summary {
+    /// Creates an empty summary.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `entry`, such as one read back from a [`History`](crate::History).
+    pub fn record(&self, entry: HistoryEntry) {
+        match self.entries.lock() {
+            Ok(mut entries) => entries.push(entry),
+            Err(e) => e.into_inner().push(entry),
+        }
+    }
+
+    /// Returns the commands in the order they finished, with their full
+    /// redacted output.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        match self.entries.lock() {
+            Ok(entries) => entries.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Returns whether every command succeeded.
+    pub fn success(&self) -> bool {
+        self.entries().iter().all(HistoryEntry::success)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.entries();
+        let mut rows = vec![[
+            "STATUS".to_string(),
+            "DURATION".to_string(),
+            "EXIT".to_string(),
+            "OUTPUT".to_string(),
+        ]];
+        for entry in &entries {
+            rows.push([
+                status(entry).to_string(),
+                format_duration(entry.duration),
+                entry.exit_code.map_or("-".into(), |code| code.to_string()),
+                format_size(entry.output.len()),
+            ]);
+        }
+        let mut widths = [0; 4];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let commands = std::iter::once("COMMAND".to_string())
+            .chain(entries.iter().map(HistoryEntry::command_line));
+        for (row, command) in rows.iter().zip(commands) {
+            // the status is left-aligned, the numbers right-aligned
+            writeln!(
+                f,
+                "{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {command}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+            )?;
+        }
+        let count = |s| entries.iter().filter(|e| status(e) == s).count();
+        let total = entries.iter().map(|e| e.duration).sum();
+        writeln!(
+            f,
+            "{} passed, {} failed, {} error{} in {}",
+            count("ok"),
+            count("failed"),
+            count("error"),
+            if count("error") == 1 { "" } else { "s" },
+            format_duration(total)
+        )
+    }
+}
+
+fn status(entry: &HistoryEntry) -> &'static str {
+    match (&entry.error, entry.success()) {
+        (Some(_), _) => "error",
+        (None, true) => "ok",
+        (None, false) => "failed",
+    }
+}
+
+/// Formats a duration to a tenth of a second below a minute, since most
+/// commands in a summary are quick.
+fn format_duration(d: Duration) -> String {
+    match d.as_secs() {
+        0..60 => format!("{:.1}s", d.as_secs_f64()),
+        _ => crate::timings::format_duration(d),
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    if size < 1024.0 {
+        return format!("{bytes} B");
+    }
+    let mut unit = 0;
+    size /= 1024.0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
@@ -0,0 +1,164 @@
+//! Running commands in transient systemd scopes.
+
+use std::ffi::OsString;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs a command in a transient systemd scope with `systemd-run --scope`
+/// (Linux only).
+///
+/// The scope gives the command and everything it starts their own cgroup,
+/// so systemd accounts for their resources (`systemctl status`), applies
+/// any [`property`](Self::property) limits, and tags their journal entries
+/// with the unit. The scope is stopped when the command exits or is killed,
+/// taking down any processes left behind, even ones that left the process
+/// group. Set with [`CmdLineRunner::systemd_run`](crate::CmdLineRunner::systemd_run).
+///
+/// Unlike [`Cgroup`](crate::Cgroup), this works without write access to the
+/// cgroup tree, as systemd creates the cgroup. Unprivileged callers need
+/// [`user`](Self::user) to use their own service manager.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, SystemdRun};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// CmdLineRunner::new("make")
+///     .arg("-j8")
+///     .systemd_run(
+///         SystemdRun::new()
+///             .user()
+///             .property("MemoryMax", "4G")
+///             .property("CPUQuota", "200%"),
+///     )
+///     .execute()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SystemdRun {
+    user: bool,
+    slice: Option<String>,
+    properties: Vec<String>,
+}
+
+impl SystemdRun {
+    /// Creates a scope in the system service manager.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the calling user's service manager (`--user`).
+    pub fn user(mut self) -> Self {
+        self.user = true;
+        self
+    }
+
+    /// Places the scope in `slice`, e.g. `builds.slice`.
+    pub fn slice(mut self, slice: impl Into<String>) -> Self {
+        self.slice = Some(slice.into());
+        self
+    }
+
+    /// Sets a unit property (`-p key=value`), e.g. `("MemoryMax", "1G")`.
+    pub fn property(mut self, key: &str, value: impl AsRef<str>) -> Self {
+        self.properties.push(format!("{key}={}", value.as_ref()));
+        self
+    }
+
+    /// Returns a new unit for one command, and the `systemd-run` arguments
+    /// that come before the command to start it in that unit. `path` is the
+    /// `PATH` used to find `systemctl` when stopping the unit.
+    pub(crate) fn unit(&self, path: Option<OsString>) -> (TransientUnit, Vec<OsString>) {
+        let name = format!(
+            "ensembler-{}-{}.scope",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut args: Vec<OsString> = vec!["systemd-run".into()];
+        if self.user {
+            args.push("--user".into());
+        }
+        args.extend([
+            "--scope".into(),
+            "--quiet".into(),
+            "--collect".into(),
+            format!("--unit={name}").into(),
+        ]);
+        if let Some(slice) = &self.slice {
+            args.push(format!("--slice={slice}").into());
+        }
+        for property in &self.properties {
+            args.extend(["-p".into(), property.into()]);
+        }
+        args.push("--".into());
+        let unit = TransientUnit {
+            name,
+            user: self.user,
+            path,
+            stopped: false,
+        };
+        (unit, args)
+    }
+}
+
+/// A scope started for one command, stopped when dropped.
+#[derive(Debug)]
+pub(crate) struct TransientUnit {
+    name: String,
+    user: bool,
+    path: Option<OsString>,
+    stopped: bool,
+}
+
+impl TransientUnit {
+    fn systemctl(&self) -> std::process::Command {
+        let mut cmd = std::process::Command::new("systemctl");
+        if let Some(path) = &self.path {
+            cmd.env("PATH", path);
+        }
+        if self.user {
+            cmd.arg("--user");
+        }
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        cmd
+    }
+
+    /// Stops the scope, killing anything still running in it.
+    pub(crate) async fn stop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        self.stopped = true;
+        let mut cmd = tokio::process::Command::from(self.systemctl());
+        // the scope is usually already gone once its last process exits
+        match cmd.args(["stop", "--quiet", &self.name]).status().await {
+            Ok(status) if !status.success() => {
+                trace!("systemctl stop {} exited with {status}", self.name)
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Failed to stop {}: {e}", self.name),
+        }
+    }
+}
+
+impl Drop for TransientUnit {
+    fn drop(&mut self) {
+        if !self.stopped {
+            let mut cmd = self.systemctl();
+            if let Err(e) = cmd
+                .args(["stop", "--no-block", "--quiet", &self.name])
+                .status()
+            {
+                debug!("Failed to stop {}: {e}", self.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! A scrolling tail of command output under a progress job.
+
+use clx::progress::{self, ProgressJob, ProgressJobBuilder};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// A child job showing the last lines of output, removed when dropped so
+/// the tail collapses once the command finishes.
+pub(crate) struct OutputTail {
+    job: Arc<ProgressJob>,
+    lines: Mutex<VecDeque<String>>,
+    height: usize,
+}
+
+impl OutputTail {
+    /// Adds a tail of `height` lines under `parent`. Returns `None` in text
+    /// mode, where every update would print the whole tail again.
+    pub(crate) fn start(parent: &Arc<ProgressJob>, height: usize) -> Option<Self> {
+        if height == 0 || progress::output() == progress::ProgressOutput::Text {
+            return None;
+        }
+        let job = ProgressJobBuilder::new()
+            .body("{% for line in ensembler_tail %}{{ line | flex | dim }}\n{% endfor %}")
+            .prop("ensembler_tail", &Vec::<String>::new())
+            .build();
+        Some(Self {
+            job: parent.add(job),
+            lines: Mutex::new(VecDeque::with_capacity(height)),
+            height,
+        })
+    }
+
+    pub(crate) fn push(&self, line: &str) {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.height {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+        self.job.prop("ensembler_tail", &*lines);
+    }
+
+    /// Removes the tail from the display.
+    pub(crate) fn finish(&self) {
+        self.job.remove();
+    }
+}
+
+impl Drop for OutputTail {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,163 @@
+//! TeamCity service messages for commands.
+
+use crate::reporter::{ProgressReporter, ProgressState};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static FLOW_IDS: AtomicU64 = AtomicU64::new(1);
+
+/// How many lines of output a `testFailed` message's details end with.
+const DETAIL_LINES: usize = 20;
+
+/// A [`ProgressReporter`] that reports each command to TeamCity with
+/// service messages, in place of interactive progress rendering.
+///
+/// Each command is a block titled with the command line, holding its
+/// output, and a test that fails with the end of its output if the command
+/// fails. Every message has a `flowId` of its own, so TeamCity keeps the
+/// output of commands that run at the same time apart.
+///
+/// Messages are printed to stderr unless a writer is given with
+/// [`with_writer`](Self::with_writer).
+///
+/// Attach one with [`CmdLineRunner::reporter`](crate::CmdLineRunner::reporter).
+/// It isn't used otherwise: with [`Defaults::ci_groups`](crate::Defaults::ci_groups)
+/// enabled, which it isn't by default, one is attached to every command
+/// when `TEAMCITY_VERSION` is set.
+pub struct TeamcityMessages {
+    flow_id: u64,
+    name: Mutex<String>,
+    started: Mutex<Option<Instant>>,
+    tail: Mutex<VecDeque<String>>,
+    out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl TeamcityMessages {
+    /// Creates a reporter for one command.
+    pub fn new() -> Self {
+        Self {
+            flow_id: FLOW_IDS.fetch_add(1, Ordering::Relaxed),
+            name: Mutex::new(String::new()),
+            started: Mutex::new(None),
+            tail: Mutex::new(VecDeque::new()),
+            out: None,
+        }
+    }
+
+    /// Creates a reporter for one command that writes its messages to
+    /// `writer` instead of stderr.
+    pub fn with_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Some(Mutex::new(Box::new(writer))),
+            ..Self::new()
+        }
+    }
+
+    fn message(&self, name: &str, attrs: &[(&str, &str)]) {
+        let mut message = format!("##teamcity[{name}");
+        for (key, value) in attrs {
+            message += &format!(" {key}='{}'", escape(value));
+        }
+        message += &format!(" flowId='{}']", self.flow_id);
+        let _ = match &self.out {
+            Some(out) => writeln!(lock(out), "{message}"),
+            None => writeln!(std::io::stderr().lock(), "{message}"),
+        };
+    }
+
+    fn line(&self, line: &str) {
+        let mut tail = lock(&self.tail);
+        if tail.len() == DETAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_string());
+        self.message("message", &[("text", line)]);
+    }
+}
+
+impl Default for TeamcityMessages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for TeamcityMessages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TeamcityMessages")
+            .field("flow_id", &self.flow_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProgressReporter for TeamcityMessages {
+    fn set_status(&self, status: ProgressState) {
+        let name = lock(&self.name).clone();
+        let mut started = lock(&self.started);
+        let start = match (status, *started) {
+            (ProgressState::Running, None) => {
+                *started = Some(Instant::now());
+                self.message("blockOpened", &[("name", &name)]);
+                self.message("testStarted", &[("name", &name)]);
+                return;
+            }
+            (ProgressState::Running, Some(_)) => return,
+            // finished without starting, e.g. because it was up to date, or
+            // already finished
+            (_, None) => return,
+            (_, Some(start)) => start,
+        };
+        *started = None;
+        if status == ProgressState::Failed {
+            let details = lock(&self.tail).iter().cloned().collect::<Vec<_>>();
+            self.message(
+                "testFailed",
+                &[
+                    ("name", &name),
+                    ("message", "command failed"),
+                    ("details", &details.join("\n")),
+                ],
+            );
+        }
+        let duration = start.elapsed().as_millis().to_string();
+        self.message("testFinished", &[("name", &name), ("duration", &duration)]);
+        self.message("blockClosed", &[("name", &name)]);
+    }
+
+    fn prop(&self, key: &str, value: &str) {
+        match key {
+            "ensembler_cmd" => *lock(&self.name) = value.to_string(),
+            // set to an empty string before the command starts
+            "ensembler_stdout" if lock(&self.started).is_some() => self.line(value),
+            _ => {}
+        }
+    }
+
+    fn println(&self, line: &str) {
+        self.line(line);
+    }
+}
+
+/// Escapes `value` for a service message attribute.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '|' => escaped += "||",
+            '\'' => escaped += "|'",
+            '\n' => escaped += "|n",
+            '\r' => escaped += "|r",
+            '[' => escaped += "|[",
+            ']' => escaped += "|]",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,76 @@
+use std::collections::hash_map::RandomState;
+use std::fs::OpenOptions;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A file in the system temp dir that is removed when dropped.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates a new file readable and writable only by the current user
+    /// (and executable if `executable` is set) containing `contents`.
+    ///
+    /// The name is randomized and the file is created with `create_new`, so
+    /// an existing file or symlink is never reused.
+    pub(crate) fn create(
+        extension: &str,
+        contents: &[u8],
+        executable: bool,
+    ) -> std::io::Result<Self> {
+        let dir = std::env::temp_dir();
+        let mut attempts = 0;
+        loop {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.write_u128(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos(),
+            );
+            let mut name = format!("ensembler-{:016x}", hasher.finish());
+            if !extension.is_empty() {
+                name.push('.');
+                name.push_str(extension);
+            }
+            let path = dir.join(name);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(if executable { 0o700 } else { 0o600 });
+            }
+            #[cfg(not(unix))]
+            let _ = executable;
+            match options.open(&path) {
+                Ok(mut file) => {
+                    let temp = Self { path };
+                    file.write_all(contents)?;
+                    file.sync_all()?;
+                    return Ok(temp);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < 16 => {
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("Failed to remove temp file {}: {e}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use crate::CmdLineRunner;
+use indexmap::IndexMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// A reusable preset from which [`CmdLineRunner`]s are created.
+///
+/// A template holds the program, base arguments, environment, and working
+/// directory shared by many call sites. Each call to [`runner`](Self::runner)
+/// returns a fresh runner with those settings applied, ready for
+/// command-specific arguments.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::CmdTemplate;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let git = CmdTemplate::new("git")
+///     .args(["-c", "color.ui=never"])
+///     .env("GIT_TERMINAL_PROMPT", "0")
+///     .current_dir("/path/to/repo");
+///
+/// let status = git.runner().arg("status").execute().await?;
+/// let log = git.runner().args(["log", "-1"]).execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CmdTemplate {
+    program: OsString,
+    direct: bool,
+    args: Vec<OsString>,
+    envs: IndexMap<OsString, OsString>,
+    cwd: Option<PathBuf>,
+}
+
+impl CmdTemplate {
+    /// Creates a template for `program`, instantiated with [`CmdLineRunner::new`].
+    pub fn new<P: AsRef<OsStr>>(program: P) -> Self {
+        Self {
+            program: program.as_ref().to_os_string(),
+            direct: false,
+            args: vec![],
+            envs: Default::default(),
+            cwd: None,
+        }
+    }
+
+    /// Creates a template for `program`, instantiated with [`CmdLineRunner::new_direct`].
+    pub fn new_direct<P: AsRef<OsStr>>(program: P) -> Self {
+        Self {
+            direct: true,
+            ..Self::new(program)
+        }
+    }
+
+    /// Adds a base argument passed before any per-runner arguments.
+    pub fn arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds multiple base arguments.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    /// Sets an environment variable for every runner.
+    pub fn env<K, V>(mut self, key: K, val: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.envs
+            .insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
+        self
+    }
+
+    /// Sets multiple environment variables for every runner.
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, val) in vars {
+            self = self.env(key, val);
+        }
+        self
+    }
+
+    /// Sets the working directory for every runner.
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Creates a runner with the template's settings applied.
+    pub fn runner(&self) -> CmdLineRunner {
+        let runner = if self.direct {
+            CmdLineRunner::new_direct(&self.program)
+        } else {
+            CmdLineRunner::new(&self.program)
+        };
+        let runner = runner.args(&self.args).envs(&self.envs);
+        match &self.cwd {
+            Some(cwd) => runner.current_dir(cwd),
+            None => runner,
+        }
+    }
+}
+
+impl From<&CmdTemplate> for CmdLineRunner {
+    fn from(template: &CmdTemplate) -> Self {
+        template.runner()
+    }
+}
@@ -0,0 +1,304 @@
+//! Test helpers for code that runs commands with [`CmdLineRunner`].
+//!
+//! [`CmdLineRunner`]: crate::CmdLineRunner
+
+mod fault;
+mod fixture;
+
+pub use fault::FaultInjector;
+pub use fixture::Recorder;
+
+use crate::executor::{exit_status, BoxFuture, Executor, Invocation, Process};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// An [`Executor`] that returns canned results instead of running anything.
+///
+/// Register the commands a test expects with [`expect`](Self::expect) and
+/// pass a clone to [`CmdLineRunner::executor`](crate::CmdLineRunner::executor).
+/// Running a command that wasn't expected panics, failing the test, and
+/// [`verify`](Self::verify) checks that every expectation was used.
+///
+/// # Example
+///
+/// ```
+/// use ensembler::testing::{MockExecutor, MockResult};
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let mock = MockExecutor::new();
+/// mock.expect("git", ["rev-parse", "HEAD"])
+///     .returns(MockResult::new().stdout("abc123\n"));
+///
+/// let result = CmdLineRunner::new("git")
+///     .args(["rev-parse", "HEAD"])
+///     .executor(mock.clone())
+///     .execute()
+///     .await?;
+///
+/// assert_eq!(result.stdout, "abc123\n");
+/// mock.verify();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockExecutor {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    expectations: Vec<Expectation>,
+    invocations: Vec<Invocation>,
+}
+
+#[derive(Debug)]
+struct Expectation {
+    program: String,
+    args: Vec<OsString>,
+    result: MockResult,
+    times: Option<usize>,
+    calls: usize,
+    /// Whether to match the command with its redactions applied
+    redacted: bool,
+}
+
+impl MockExecutor {
+    /// Creates an executor that expects no commands.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects `program` to be run with exactly `args`.
+    ///
+    /// By default the command succeeds with no output and may run any
+    /// number of times. Expectations are matched in the order they were
+    /// added, skipping ones that have run [`times`](Expect::times) already.
+    pub fn expect<I, S>(&self, program: &str, args: I) -> Expect<'_>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let mut state = self.lock();
+        state.expectations.push(Expectation {
+            program: program.to_string(),
+            args: args.into_iter().map(Into::into).collect(),
+            result: MockResult::new(),
+            times: None,
+            calls: 0,
+            redacted: false,
+        });
+        Expect {
+            mock: self,
+            index: state.expectations.len() - 1,
+        }
+    }
+
+    /// Returns the commands run so far, in order.
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.lock().invocations.clone()
+    }
+
+    /// Panics unless every expectation with a [`times`](Expect::times) count
+    /// ran that many times, and every other one ran at least once.
+    pub fn verify(&self) {
+        for e in &self.lock().expectations {
+            match e.times {
+                Some(times) if e.calls != times => panic!(
+                    "expected `{}` to run {times} times, but it ran {} times",
+                    e.describe(),
+                    e.calls
+                ),
+                None if e.calls == 0 => panic!("expected `{}` to run", e.describe()),
+                _ => {}
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+impl Executor for MockExecutor {
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>> {
+        let mut state = self.lock();
+        state.invocations.push(invocation.clone());
+        let expectation = state
+            .expectations
+            .iter_mut()
+            .find(|e| e.matches(invocation) && e.times.is_none_or(|times| e.calls < times));
+        let Some(expectation) = expectation else {
+            let args = invocation
+                .args()
+                .iter()
+                .map(|a| a.to_string_lossy())
+                .collect::<Vec<_>>();
+            panic!(
+                "unexpected command: `{} {}`",
+                invocation.program(),
+                args.join(" ")
+            );
+        };
+        expectation.calls += 1;
+        Ok(Box::new(MockProcess {
+            result: expectation.result.clone(),
+            killed: false,
+        }))
+    }
+}
+
+impl Expectation {
+    fn matches(&self, invocation: &Invocation) -> bool {
+        if !self.redacted {
+            return self.program == invocation.program() && self.args == invocation.args();
+        }
+        let redact = |s: &OsStr| OsString::from(invocation.redact(&s.to_string_lossy()));
+        self.program == invocation.redact(invocation.program())
+            && self.args.len() == invocation.args().len()
+            && self
+                .args
+                .iter()
+                .zip(invocation.args())
+                .all(|(expected, arg)| *expected == redact(arg))
+    }
+
+    fn describe(&self) -> String {
+        let mut line = self.program.clone();
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(&arg.to_string_lossy());
+        }
+        line
+    }
+}
+
+/// An expectation added with [`MockExecutor::expect`].
+#[derive(Debug)]
+pub struct Expect<'a> {
+    mock: &'a MockExecutor,
+    index: usize,
+}
+
+impl Expect<'_> {
+    /// Sets what the command returns.
+    pub fn returns(self, result: MockResult) -> Self {
+        self.mock.lock().expectations[self.index].result = result;
+        self
+    }
+
+    /// Expects the command to run exactly `times` times.
+    pub fn times(self, times: usize) -> Self {
+        self.mock.lock().expectations[self.index].times = Some(times);
+        self
+    }
+
+    /// Matches the command with its redactions applied, as [`Recorder`]
+    /// writes it.
+    pub(crate) fn redacted(self) -> Self {
+        self.mock.lock().expectations[self.index].redacted = true;
+        self
+    }
+}
+
+/// The output and exit code a [`MockExecutor`] returns for a command.
+#[derive(Debug, Clone, Default)]
+pub struct MockResult {
+    stdout: String,
+    stderr: String,
+    exit_code: i32,
+    delay: Duration,
+}
+
+impl MockResult {
+    /// Creates a result with no output and exit code 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the command's stdout.
+    pub fn stdout(mut self, stdout: impl Into<String>) -> Self {
+        self.stdout = stdout.into();
+        self
+    }
+
+    /// Sets the command's stderr.
+    pub fn stderr(mut self, stderr: impl Into<String>) -> Self {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Sets the command's exit code.
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.exit_code = code;
+        self
+    }
+
+    /// Makes the command take `delay` to exit, e.g. to test timeouts.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+struct MockProcess {
+    result: MockResult,
+    killed: bool,
+}
+
+impl Process for MockProcess {
+    fn id(&self) -> Option<u32> {
+        None
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        Some(Box::pin(tokio::io::sink()))
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let stdout = self.result.stdout.clone().into_bytes();
+        Some(Box::pin(io::Cursor::new(stdout)))
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let stderr = self.result.stderr.clone().into_bytes();
+        Some(Box::pin(io::Cursor::new(stderr)))
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(async move {
+            if self.killed {
+                return Ok(killed_status());
+            }
+            tokio::time::sleep(self.result.delay).await;
+            Ok(exit_status(self.result.exit_code))
+        })
+    }
+
+    fn kill(&mut self, _grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        self.killed = true;
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Builds the status of a process that was killed.
+fn killed_status() -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(nix::libc::SIGKILL)
+    }
+    #[cfg(windows)]
+    {
+        exit_status(1)
+    }
+}
@@ -0,0 +1,270 @@
+use crate::executor::{BoxFuture, Executor, Invocation, Process};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// An [`Executor`] that runs commands but randomly injects failures, for
+/// testing how callers handle them.
+///
+/// Each fault has a probability between 0 and 1 and is rolled separately
+/// for every command, so a command can get several at once. Set a
+/// [`seed`](Self::seed) to make a failing run reproducible.
+///
+/// Commands run locally through [`Invocation::command`] unless another
+/// [`executor`](Self::executor) is set.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::testing::FaultInjector;
+/// use ensembler::CmdLineRunner;
+/// use std::time::Duration;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let chaos = FaultInjector::new()
+///     .seed(7)
+///     .exit(0.2, 1)
+///     .kill(0.1, Duration::from_millis(50));
+///
+/// for _ in 0..10 {
+///     let result = CmdLineRunner::new("make")
+///         .executor(chaos.clone())
+///         .execute()
+///         .await;
+///     // check that failures are retried, reported, ...
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    executor: Option<Arc<dyn Executor>>,
+    rng: Arc<Mutex<u64>>,
+    exit: Option<(f64, i32)>,
+    delay: Option<(f64, Duration)>,
+    truncate: Option<(f64, usize)>,
+    kill: Option<(f64, Duration)>,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self {
+            executor: None,
+            rng: Arc::new(Mutex::new(seed)),
+            exit: None,
+            delay: None,
+            truncate: None,
+            kill: None,
+        }
+    }
+}
+
+impl FaultInjector {
+    /// Creates an injector with no faults and a random seed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs commands with `executor` instead of as local processes.
+    pub fn executor(mut self, executor: impl Executor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Seeds the random choice of faults.
+    pub fn seed(self, seed: u64) -> Self {
+        *self.rng.lock().unwrap_or_else(|e| e.into_inner()) = seed;
+        self
+    }
+
+    /// With `probability`, reports that the command exited with `code`
+    /// after it really exits.
+    pub fn exit(mut self, probability: f64, code: i32) -> Self {
+        self.exit = Some((probability, code));
+        self
+    }
+
+    /// With `probability`, holds back the command's stdout and stderr for
+    /// `delay`.
+    pub fn delay_output(mut self, probability: f64, delay: Duration) -> Self {
+        self.delay = Some((probability, delay));
+        self
+    }
+
+    /// With `probability`, ends the command's stdout and stderr after
+    /// `bytes` bytes each. The rest is read and thrown away so the command
+    /// doesn't block on a full pipe.
+    pub fn truncate_output(mut self, probability: f64, bytes: usize) -> Self {
+        self.truncate = Some((probability, bytes));
+        self
+    }
+
+    /// With `probability`, kills the command (with `SIGKILL` for local
+    /// processes) `after` it starts, if it is still running.
+    pub fn kill(mut self, probability: f64, after: Duration) -> Self {
+        self.kill = Some((probability, after));
+        self
+    }
+
+    /// Returns the value of a fault if it is set and its roll hits.
+    fn roll<T: Copy>(&self, fault: Option<(f64, T)>) -> Option<T> {
+        let (probability, value) = fault?;
+        // splitmix64
+        let mut state = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        let sample = (z >> 11) as f64 / (1u64 << 53) as f64;
+        (sample < probability).then_some(value)
+    }
+}
+
+impl Executor for FaultInjector {
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>> {
+        let inner: Box<dyn Process> = match &self.executor {
+            Some(executor) => executor.spawn(invocation)?,
+            None => Box::new(invocation.command().spawn()?),
+        };
+        let process = FaultyProcess {
+            inner,
+            exit: self.roll(self.exit),
+            delay: self.roll(self.delay),
+            truncate: self.roll(self.truncate),
+            kill_at: self.roll(self.kill).map(|after| Instant::now() + after),
+        };
+        if process.exit.is_some()
+            || process.delay.is_some()
+            || process.truncate.is_some()
+            || process.kill_at.is_some()
+        {
+            debug!(
+                "injecting faults into {}: exit={:?} delay={:?} truncate={:?} kill={}",
+                invocation.program(),
+                process.exit,
+                process.delay,
+                process.truncate,
+                process.kill_at.is_some()
+            );
+        }
+        Ok(Box::new(process))
+    }
+}
+
+struct FaultyProcess {
+    inner: Box<dyn Process>,
+    exit: Option<i32>,
+    delay: Option<Duration>,
+    truncate: Option<usize>,
+    kill_at: Option<Instant>,
+}
+
+impl FaultyProcess {
+    fn faulty_stream(
+        &self,
+        stream: Option<Pin<Box<dyn AsyncRead + Send>>>,
+    ) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        Some(Box::pin(FaultyStream {
+            inner: stream?,
+            delay: self.delay.map(|d| Box::pin(tokio::time::sleep(d))),
+            remaining: self.truncate,
+        }))
+    }
+}
+
+impl Process for FaultyProcess {
+    fn id(&self) -> Option<u32> {
+        self.inner.id()
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.inner.take_stdin()
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let stdout = self.inner.take_stdout();
+        self.faulty_stream(stdout)
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let stderr = self.inner.take_stderr();
+        self.faulty_stream(stderr)
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(async move {
+            if let Some(kill_at) = self.kill_at {
+                let exited = tokio::select! {
+                    status = self.inner.wait() => Some(status),
+                    _ = tokio::time::sleep_until(kill_at) => None,
+                };
+                self.kill_at = None;
+                if exited.is_none() {
+                    self.inner.kill(None).await?;
+                }
+            }
+            let status = self.inner.wait().await?;
+            match self.exit {
+                Some(code) if status.success() => Ok(crate::executor::exit_status(code)),
+                _ => Ok(status),
+            }
+        })
+    }
+
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.kill(grace_period)
+    }
+}
+
+/// A stream that starts after a delay and ends after a number of bytes.
+struct FaultyStream {
+    inner: Pin<Box<dyn AsyncRead + Send>>,
+    delay: Option<Pin<Box<Sleep>>>,
+    remaining: Option<usize>,
+}
+
+impl AsyncRead for FaultyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        match self.remaining {
+            None => self.inner.as_mut().poll_read(cx, buf),
+            Some(0) => {
+                let mut scratch = [0; 8192];
+                loop {
+                    let mut discard = ReadBuf::new(&mut scratch);
+                    ready!(self.inner.as_mut().poll_read(cx, &mut discard))?;
+                    if discard.filled().is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            }
+            Some(remaining) => {
+                let mut limited = vec![0; remaining.min(buf.remaining())];
+                let mut read = ReadBuf::new(&mut limited);
+                ready!(self.inner.as_mut().poll_read(cx, &mut read))?;
+                let read = read.filled();
+                buf.put_slice(read);
+                self.remaining = Some(remaining - read.len());
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
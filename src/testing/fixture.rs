@@ -0,0 +1,303 @@
+use super::{MockExecutor, MockResult};
+use crate::executor::{BoxFuture, Executor, Invocation, Process};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+
+/// The contents of a fixture file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Fixture {
+    commands: Vec<Recorded>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Recorded {
+    program: String,
+    args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<PathBuf>,
+    stdout: String,
+    stderr: String,
+    exit_code: i32,
+}
+
+/// An [`Executor`] that runs commands and records them to a fixture file.
+///
+/// Every command's program, args, working directory, output and exit code
+/// are written to `path` as JSON when it exits, replacing the file each
+/// time, with the runner's [`redact`](crate::CmdLineRunner::redact) strings
+/// replaced so fixtures can be committed. Load the fixture with
+/// [`MockExecutor::replay`] to serve the same results without running
+/// anything.
+///
+/// Commands run locally through [`Invocation::command`], so runner options
+/// that only apply to local children (e.g. `uid` or `cgroup`) are ignored;
+/// set [`executor`](Self::executor) to record through another backend.
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::testing::{MockExecutor, Recorder};
+/// use ensembler::CmdLineRunner;
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let fixture = "tests/fixtures/git.json";
+/// let runner = CmdLineRunner::new("git").args(["rev-parse", "HEAD"]);
+/// let runner = if std::env::var_os("RECORD").is_some() {
+///     runner.executor(Recorder::new(fixture))
+/// } else {
+///     runner.executor(MockExecutor::replay(fixture)?)
+/// };
+/// let result = runner.execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    executor: Option<Arc<dyn Executor>>,
+    fixture: Arc<Mutex<Fixture>>,
+}
+
+impl Recorder {
+    /// Creates a recorder that writes to `path`, starting an empty fixture.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            executor: None,
+            fixture: Default::default(),
+        }
+    }
+
+    /// Runs commands with `executor` instead of as local processes.
+    pub fn executor(mut self, executor: impl Executor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+}
+
+impl Executor for Recorder {
+    fn spawn(&self, invocation: &Invocation) -> crate::Result<Box<dyn Process>> {
+        let inner: Box<dyn Process> = match &self.executor {
+            Some(executor) => executor.spawn(invocation)?,
+            None => Box::new(invocation.command().spawn()?),
+        };
+        Ok(Box::new(RecordingProcess {
+            inner,
+            recorder: self.clone(),
+            invocation: invocation.clone(),
+            stdout: None,
+            stderr: None,
+            recorded: false,
+        }))
+    }
+}
+
+impl MockExecutor {
+    /// Creates an executor that serves the commands in a fixture written by
+    /// [`Recorder`].
+    ///
+    /// Each recorded command is expected once, matched on its program and
+    /// args, so a command recorded twice replays its results in order.
+    /// Commands are matched with the runner's redactions applied, as they
+    /// were recorded, and replay their redacted output.
+    pub fn replay(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let fixture = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read fixture {}: {e}", path.display()),
+            )
+        })?;
+        let fixture: Fixture = serde_json::from_str(&fixture).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid fixture {}: {e}", path.display()),
+            )
+        })?;
+        let mock = MockExecutor::new();
+        for command in fixture.commands {
+            mock.expect(&command.program, command.args)
+                .redacted()
+                .returns(
+                    MockResult::new()
+                        .stdout(command.stdout)
+                        .stderr(command.stderr)
+                        .exit_code(command.exit_code),
+                )
+                .times(1);
+        }
+        Ok(mock)
+    }
+}
+
+struct RecordingProcess {
+    inner: Box<dyn Process>,
+    recorder: Recorder,
+    invocation: Invocation,
+    stdout: Option<oneshot::Receiver<Vec<u8>>>,
+    stderr: Option<oneshot::Receiver<Vec<u8>>>,
+    recorded: bool,
+}
+
+impl RecordingProcess {
+    async fn record(&mut self, status: ExitStatus) -> io::Result<()> {
+        let mut output = [String::new(), String::new()];
+        for (stream, out) in [self.stdout.take(), self.stderr.take()]
+            .into_iter()
+            .zip(&mut output)
+        {
+            if let Some(stream) = stream {
+                *out = String::from_utf8_lossy(&stream.await.unwrap_or_default()).into_owned();
+            }
+        }
+        let [stdout, stderr] = output;
+        let redact = |s: &str| self.invocation.redact(s);
+        let recorded = Recorded {
+            program: redact(self.invocation.program()),
+            args: self
+                .invocation
+                .args()
+                .iter()
+                .map(|a| redact(&a.to_string_lossy()))
+                .collect(),
+            cwd: self
+                .invocation
+                .current_dir()
+                .map(|cwd| redact(&cwd.to_string_lossy()).into()),
+            stdout: redact(&stdout),
+            stderr: redact(&stderr),
+            exit_code: exit_code(status),
+        };
+        let json = {
+            let mut fixture = self
+                .recorder
+                .fixture
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            fixture.commands.push(recorded);
+            serde_json::to_string_pretty(&*fixture).map_err(io::Error::other)?
+        };
+        let path = &self.recorder.path;
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, json + "\n").map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to write fixture {}: {e}", path.display()),
+            )
+        })
+    }
+}
+
+impl Process for RecordingProcess {
+    fn id(&self) -> Option<u32> {
+        self.inner.id()
+    }
+
+    fn take_stdin(&mut self) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+        self.inner.take_stdin()
+    }
+
+    fn take_stdout(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let (tee, rx) = Tee::new(self.inner.take_stdout()?);
+        self.stdout = Some(rx);
+        Some(Box::pin(tee))
+    }
+
+    fn take_stderr(&mut self) -> Option<Pin<Box<dyn AsyncRead + Send>>> {
+        let (tee, rx) = Tee::new(self.inner.take_stderr()?);
+        self.stderr = Some(rx);
+        Some(Box::pin(tee))
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Box::pin(async move {
+            let status = self.inner.wait().await?;
+            // the runner waits again after killing the process
+            if !self.recorded {
+                self.recorded = true;
+                self.record(status).await?;
+            }
+            Ok(status)
+        })
+    }
+
+    fn kill(&mut self, grace_period: Option<Duration>) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.kill(grace_period)
+    }
+}
+
+/// Passes a stream through while keeping a copy, which is sent when the
+/// stream ends or the reader is dropped.
+struct Tee {
+    inner: Pin<Box<dyn AsyncRead + Send>>,
+    buf: Vec<u8>,
+    tx: Option<oneshot::Sender<Vec<u8>>>,
+}
+
+impl Tee {
+    fn new(inner: Pin<Box<dyn AsyncRead + Send>>) -> (Self, oneshot::Receiver<Vec<u8>>) {
+        let (tx, rx) = oneshot::channel();
+        let tee = Self {
+            inner,
+            buf: Vec::new(),
+            tx: Some(tx),
+        };
+        (tee, rx)
+    }
+
+    fn finish(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl AsyncRead for Tee {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = self.inner.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[before..];
+            if read.is_empty() {
+                self.finish();
+            } else {
+                self.buf.extend_from_slice(read);
+            }
+        }
+        poll
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The exit code to record for `status`, using the shell's `128 + signal`
+/// for processes killed by a signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
@@ -0,0 +1,254 @@
+//! Remembering how long commands take.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How much each new run moves the estimate, between 0 and 1.
+const WEIGHT: f64 = 0.3;
+
+/// A file of how long commands usually take, for showing an expected
+/// duration before they finish.
+///
+/// Set for one command with [`CmdLineRunner::timings`](crate::CmdLineRunner::timings),
+/// or for every command with [`Defaults::timings`](crate::Defaults::timings).
+/// Each successful run updates the estimate for its signature, the command
+/// line with redactions applied, as a moving average that favors recent
+/// runs. [`CmdLineRunner::expected_duration`](crate::CmdLineRunner::expected_duration)
+/// reads it back, and with the `progress` feature it's shown to progress
+/// templates as the `ensembler_expected` prop.
+///
+/// Other signatures, such as a task name in an orchestration layer, can be
+/// tracked with [`record`](Self::record) and [`expected`](Self::expected).
+///
+/// # Example
+///
+/// ```no_run
+/// use ensembler::{CmdLineRunner, Timings};
+///
+/// # #[tokio::main]
+/// # async fn main() -> ensembler::Result<()> {
+/// let timings = Timings::new("/home/me/.cache/mytool/timings.json");
+/// let runner = CmdLineRunner::new("cargo").arg("build").timings(timings);
+/// if let Some(expected) = runner.expected_duration() {
+///     println!("this usually takes ~{}s", expected.as_secs());
+/// }
+/// runner.execute().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Timings {
+    path: PathBuf,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Db {
+    commands: BTreeMap<String, Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    runs: u64,
+    expected_ms: f64,
+    last_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_output_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stall_ms: Option<u64>,
+}
+
+impl Timings {
+    /// Creates a timing database stored at `path`, created on the first
+    /// write.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the path of the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns how long `signature` is expected to take, if it has been
+    /// recorded.
+    pub fn expected(&self, signature: &str) -> Option<Duration> {
+        let db = match self.load() {
+            Ok(db) => db,
+            Err(e) => {
+                debug!("Failed to read timings: {e}");
+                return None;
+            }
+        };
+        let entry = db.commands.get(signature)?;
+        Some(Duration::from_millis(entry.expected_ms.round() as u64))
+    }
+
+    /// Returns how many runs of `signature` have been recorded.
+    pub fn runs(&self, signature: &str) -> u64 {
+        let db = self.load().unwrap_or_default();
+        db.commands.get(signature).map_or(0, |e| e.runs)
+    }
+
+    /// Returns how long after starting the last recorded run of
+    /// `signature` wrote its first output, if it wrote any.
+    pub fn first_output(&self, signature: &str) -> Option<Duration> {
+        let db = self.load().unwrap_or_default();
+        let ms = db.commands.get(signature)?.first_output_ms?;
+        Some(Duration::from_millis(ms))
+    }
+
+    /// Returns the longest time the last recorded run of `signature` went
+    /// without writing output.
+    pub fn longest_stall(&self, signature: &str) -> Option<Duration> {
+        let db = self.load().unwrap_or_default();
+        let ms = db.commands.get(signature)?.stall_ms?;
+        Some(Duration::from_millis(ms))
+    }
+
+    /// Records that `signature` took `duration`.
+    pub fn record(&self, signature: &str, duration: Duration) -> io::Result<()> {
+        self.update(signature, duration, |_| {})
+    }
+
+    /// Records a run of `signature` along with when its output started and
+    /// how long it stalled.
+    pub(crate) fn record_result(
+        &self,
+        signature: &str,
+        result: &crate::CmdResult,
+    ) -> io::Result<()> {
+        self.update(signature, result.duration, |e| {
+            e.first_output_ms = result.first_output.map(|d| d.as_millis() as u64);
+            e.stall_ms = Some(result.longest_stall.as_millis() as u64);
+        })
+    }
+
+    fn update(
+        &self,
+        signature: &str,
+        duration: Duration,
+        f: impl FnOnce(&mut Entry),
+    ) -> io::Result<()> {
+        let mut db = self.load()?;
+        let ms = duration.as_millis() as u64;
+        let entry = db
+            .commands
+            .entry(signature.to_string())
+            .and_modify(|e| {
+                e.runs += 1;
+                e.expected_ms += WEIGHT * (ms as f64 - e.expected_ms);
+                e.last_ms = ms;
+            })
+            .or_insert(Entry {
+                runs: 1,
+                expected_ms: ms as f64,
+                last_ms: ms,
+                first_output_ms: None,
+                stall_ms: None,
+            });
+        f(entry);
+        let json = serde_json::to_vec_pretty(&db).map_err(io::Error::other)?;
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        // written to a temp file first so readers never see a partial file
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    fn load(&self) -> io::Result<Db> {
+        match std::fs::read(&self.path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Db::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Formats a duration for display, e.g. `45s` or `2m05s`.
+pub(crate) fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs_f64().round() as u64;
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Tracks how long a running command has taken and how long it has left,
+/// for the `ensembler_elapsed` and `ensembler_eta` props.
+pub(crate) struct Stopwatch {
+    start: Instant,
+    expected: Option<Duration>,
+    position: Mutex<Option<(usize, usize)>>,
+    /// When the last output arrived and the longest gap so far.
+    silence: Mutex<(Duration, Duration)>,
+}
+
+impl Stopwatch {
+    pub(crate) fn start(expected: Option<Duration>) -> Self {
+        Self {
+            start: Instant::now(),
+            expected,
+            position: Mutex::new(None),
+            silence: Mutex::new((Duration::ZERO, Duration::ZERO)),
+        }
+    }
+
+    /// Returns how long the command has been running.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Records that the command wrote a line, returning how long after the
+    /// start it did.
+    pub(crate) fn output(&self) -> Duration {
+        let now = self.start.elapsed();
+        let mut silence = self.silence.lock().unwrap_or_else(|e| e.into_inner());
+        let (last, longest) = &mut *silence;
+        *longest = (*longest).max(now.saturating_sub(*last));
+        *last = now;
+        now
+    }
+
+    /// Returns the longest time the command went without output, counting
+    /// from the start and up to `end`.
+    pub(crate) fn longest_silence(&self, end: Duration) -> Duration {
+        let (last, longest) = *self.silence.lock().unwrap_or_else(|e| e.into_inner());
+        longest.max(end.saturating_sub(last))
+    }
+
+    /// Records how far through its work the command is.
+    pub(crate) fn set_position(&self, current: usize, total: usize) {
+        *self.position.lock().unwrap_or_else(|e| e.into_inner()) = Some((current, total));
+    }
+
+    /// Returns the elapsed time and the estimated time left, which is
+    /// projected from the position if there is one and otherwise from the
+    /// expected duration, or empty when neither is known.
+    pub(crate) fn props(&self) -> (String, String) {
+        let elapsed = self.start.elapsed();
+        let position = *self.position.lock().unwrap_or_else(|e| e.into_inner());
+        let remaining = match position {
+            Some((current, total)) if current > 0 => {
+                let left = total.saturating_sub(current) as f64 / current as f64;
+                Some(elapsed.mul_f64(left))
+            }
+            _ => self
+                .expected
+                .and_then(|expected| expected.checked_sub(elapsed)),
+        };
+        let eta = remaining.map(format_duration).unwrap_or_default();
+        (format_duration(elapsed), eta)
+    }
+}
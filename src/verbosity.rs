@@ -0,0 +1,42 @@
+//! How much of each command is shown.
+
+/// How much of each command ensembler shows, so a CLI's `-q` and `-v` flags
+/// can be applied in one place.
+///
+/// Set for every command with [`Defaults::verbosity`](crate::Defaults::verbosity)
+/// or for one with [`CmdLineRunner::verbosity`](crate::CmdLineRunner::verbosity).
+/// Lines are printed through the command's
+/// [`ProgressReporter`](crate::ProgressReporter), or to stderr without one.
+///
+/// # Example
+///
+/// ```
+/// use ensembler::{Defaults, Verbosity};
+///
+/// let (verbose, quiet) = (2, false); // from the CLI's flags
+/// let verbosity = match (quiet, verbose) {
+///     (true, _) => Verbosity::Quiet,
+///     (false, 0) => Verbosity::Normal,
+///     (false, 1) => Verbosity::Verbose,
+///     (false, _) => Verbosity::Trace,
+/// };
+/// ensembler::set_defaults(Defaults::new().verbosity(verbosity));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Verbosity {
+    /// Only progress status: output doesn't update progress jobs, and
+    /// stderr isn't printed, even when the command fails.
+    Quiet,
+    /// The latest line of output updates progress jobs, stderr is printed
+    /// as configured with [`stderr_to_progress`](crate::CmdLineRunner::stderr_to_progress),
+    /// and failures print their output as configured with
+    /// [`show_stderr_on_error`](crate::CmdLineRunner::show_stderr_on_error).
+    #[default]
+    Normal,
+    /// Like `Normal`, and also prints each command line before it runs and
+    /// every line of its output.
+    Verbose,
+    /// Like `Verbose`, and also prints the working directory, environment
+    /// changes, and how each command exited and how long it took.
+    Trace,
+}
@@ -0,0 +1,252 @@
+use std::ffi::OsStr;
+#[cfg(windows)]
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Default `PATHEXT` used on Windows when the variable is not set.
+#[cfg(windows)]
+const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+/// Resolves `program` to an executable path.
+///
+/// `path` is the `PATH` value to search (falling back to the parent's `PATH`
+/// when `None`) and `cwd` is used to resolve relative program paths like
+/// `./bin/tool`. On Windows, extensions from `PATHEXT` are tried when the
+/// program has none, and programs not on `PATH` are looked up in the
+/// registry's App Paths the way the Run dialog and `start` do.
+pub(crate) fn which_in(
+    program: &OsStr,
+    path: Option<&OsStr>,
+    cwd: Option<&Path>,
+) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.as_os_str().is_empty() {
+        return None;
+    }
+    if has_separator(program) {
+        let candidate = match cwd {
+            Some(cwd) if program.is_relative() => cwd.join(program),
+            _ => program.to_path_buf(),
+        };
+        return find_executable(&candidate);
+    }
+    let path = match path {
+        Some(path) => path.to_os_string(),
+        None => std::env::var_os("PATH").unwrap_or_default(),
+    };
+    let found = std::env::split_paths(&path)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .find_map(|dir| find_executable(&dir.join(program)));
+    #[cfg(windows)]
+    let found = found.or_else(|| app_paths::lookup(program));
+    found
+}
+
+/// Returns [`Error::ProgramNotFound`](crate::Error::ProgramNotFound) for
+/// `program`, listing the directories of `path` (or the parent's `PATH`)
+/// and any executables in them with similar names.
+pub(crate) fn not_found(program: &str, path: Option<&OsStr>) -> crate::Error {
+    let path = match path {
+        Some(path) => path.to_os_string(),
+        None => std::env::var_os("PATH").unwrap_or_default(),
+    };
+    let searched = std::env::split_paths(&path)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect::<Vec<_>>();
+    let suggestions = match has_separator(Path::new(program)) {
+        true => vec![],
+        false => suggest(program, &searched),
+    };
+    crate::Error::ProgramNotFound(Box::new(crate::MissingProgram {
+        program: program.to_string(),
+        searched,
+        suggestions,
+    }))
+}
+
+/// Returns up to three executables in `dirs` whose names are a few edits
+/// away from `program`, closest first.
+fn suggest(program: &str, dirs: &[PathBuf]) -> Vec<String> {
+    let max = (program.chars().count() / 3).max(1);
+    let mut matches = vec![];
+    for dir in dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file = entry.file_name();
+            let file = file.to_string_lossy();
+            #[cfg(windows)]
+            let file = match Path::new(&*file).file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let distance = edit_distance(program, &file);
+            if distance <= max && find_executable(&entry.path()).is_some() {
+                matches.push((distance, file.to_string()));
+            }
+        }
+    }
+    matches.sort();
+    matches.dedup_by(|a, b| a.1 == b.1);
+    matches.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// Returns the edit distance between `a` and `b`, counting swapping two
+/// adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    if a.len().abs_diff(b.len()) > 3 {
+        return usize::MAX;
+    }
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1)
+                .min(row[j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+fn has_separator(program: &Path) -> bool {
+    program.components().count() > 1 || program.is_absolute()
+}
+
+#[cfg(unix)]
+fn find_executable(candidate: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta = candidate.metadata().ok()?;
+    if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+        Some(candidate.to_path_buf())
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+fn find_executable(candidate: &Path) -> Option<PathBuf> {
+    if candidate.extension().is_some() && candidate.is_file() {
+        return Some(candidate.to_path_buf());
+    }
+    let pathext = std::env::var_os("PATHEXT").unwrap_or_else(|| DEFAULT_PATHEXT.into());
+    pathext
+        .to_string_lossy()
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| {
+            let mut file: OsString = candidate.as_os_str().to_os_string();
+            file.push(ext.to_ascii_lowercase());
+            PathBuf::from(file)
+        })
+        .find(|p| p.is_file())
+}
+
+/// Returns `true` if the resolved program must be run through `cmd.exe`
+/// (batch files cannot be spawned directly).
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) fn needs_cmd_exe(program: &Path) -> bool {
+    program
+        .extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy().to_ascii_lowercase();
+            ext == "bat" || ext == "cmd"
+        })
+        .unwrap_or(false)
+}
+
+/// Looks up programs in `HKCU`/`HKLM\SOFTWARE\Microsoft\Windows\CurrentVersion\App Paths`,
+/// where installers register executables that aren't on `PATH`.
+#[cfg(windows)]
+mod app_paths {
+    use std::ffi::{c_void, OsStr, OsString};
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    type Hkey = *mut c_void;
+
+    const HKEY_CURRENT_USER: isize = -2147483647;
+    const HKEY_LOCAL_MACHINE: isize = -2147483646;
+    const RRF_RT_REG_SZ: u32 = 0x2;
+    const RRF_RT_REG_EXPAND_SZ: u32 = 0x4;
+    const ERROR_SUCCESS: i32 = 0;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn RegGetValueW(
+            key: Hkey,
+            sub_key: *const u16,
+            value: *const u16,
+            flags: u32,
+            kind: *mut u32,
+            data: *mut c_void,
+            len: *mut u32,
+        ) -> i32;
+    }
+
+    pub(super) fn lookup(program: &Path) -> Option<PathBuf> {
+        let mut name = program.as_os_str().to_os_string();
+        if program.extension().is_none() {
+            name.push(".exe");
+        }
+        let mut sub_key = OsString::from(r"SOFTWARE\Microsoft\Windows\CurrentVersion\App Paths\");
+        sub_key.push(&name);
+        [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE]
+            .into_iter()
+            .find_map(|root| default_value(root as Hkey, &sub_key))
+            .map(|value| PathBuf::from(value.trim_matches('"')))
+            .filter(|path| path.is_file())
+    }
+
+    /// Reads the key's default value, expanding `%VAR%` references.
+    fn default_value(root: Hkey, sub_key: &OsStr) -> Option<String> {
+        let sub_key = sub_key.encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+        let flags = RRF_RT_REG_SZ | RRF_RT_REG_EXPAND_SZ;
+        let mut len = 0u32;
+        // SAFETY: a null data pointer asks for the required size in bytes.
+        let status = unsafe {
+            RegGetValueW(
+                root,
+                sub_key.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut len,
+            )
+        };
+        if status != ERROR_SUCCESS || len == 0 {
+            return None;
+        }
+        let mut buf = vec![0u16; (len as usize).div_ceil(2)];
+        // SAFETY: `buf` holds `len` bytes.
+        let status = unsafe {
+            RegGetValueW(
+                root,
+                sub_key.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null_mut(),
+                buf.as_mut_ptr() as *mut c_void,
+                &mut len,
+            )
+        };
+        if status != ERROR_SUCCESS {
+            return None;
+        }
+        let end = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+        Some(
+            OsString::from_wide(&buf[..end])
+                .to_string_lossy()
+                .to_string(),
+        )
+    }
+}
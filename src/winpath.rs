@@ -0,0 +1,85 @@
+//! Windows path normalization for working directories and program paths.
+//!
+//! `cmd.exe` can't use UNC paths (`\\server\share`) as its working directory
+//! and neither `cmd.exe` nor `CreateProcess` accept verbatim (`\\?\`) paths
+//! or working directories longer than `MAX_PATH`.
+
+use std::ffi::OsString;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Longest working directory `CreateProcess` accepts, leaving room for the
+/// trailing backslash and NUL.
+const MAX_CWD_LEN: usize = 258;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetShortPathNameW(long_path: *const u16, short_path: *mut u16, len: u32) -> u32;
+}
+
+/// Removes a `\\?\` or `\\?\UNC\` prefix: `\\?\C:\x` becomes `C:\x` and
+/// `\\?\UNC\server\share` becomes `\\server\share`.
+pub(crate) fn strip_verbatim(path: &Path) -> PathBuf {
+    let s = path.to_string_lossy();
+    if let Some(rest) = s.strip_prefix(r"\\?\UNC\") {
+        PathBuf::from(format!(r"\\{rest}"))
+    } else if let Some(rest) = s.strip_prefix(r"\\?\") {
+        PathBuf::from(rest)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Returns `true` for `\\server\share` paths, including their verbatim form.
+pub(crate) fn is_unc(path: &Path) -> bool {
+    let path = strip_verbatim(path);
+    let s = path.to_string_lossy();
+    s.starts_with(r"\\") && !s.starts_with(r"\\.\")
+}
+
+/// Returns a working directory `CreateProcess` will accept: verbatim
+/// prefixes are removed and directories longer than `MAX_PATH` are replaced
+/// with their 8.3 short name when the volume has one.
+pub(crate) fn child_cwd(cwd: &Path) -> PathBuf {
+    let stripped = strip_verbatim(cwd);
+    if stripped.as_os_str().len() <= MAX_CWD_LEN {
+        return stripped;
+    }
+    match short_path(&verbatim(&stripped)) {
+        Some(short) => strip_verbatim(&short),
+        None => {
+            debug!("no short name for {}", stripped.display());
+            stripped
+        }
+    }
+}
+
+/// Adds the `\\?\` prefix needed to pass long paths to Win32 APIs.
+fn verbatim(path: &Path) -> PathBuf {
+    let s = path.to_string_lossy();
+    match s.strip_prefix(r"\\") {
+        Some(rest) => PathBuf::from(format!(r"\\?\UNC\{rest}")),
+        None => PathBuf::from(format!(r"\\?\{s}")),
+    }
+}
+
+fn short_path(path: &Path) -> Option<PathBuf> {
+    let wide = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    // SAFETY: a zero-length buffer asks for the required length, including the NUL.
+    let len = unsafe { GetShortPathNameW(wide.as_ptr(), std::ptr::null_mut(), 0) };
+    if len == 0 {
+        return None;
+    }
+    let mut buf = vec![0u16; len as usize];
+    // SAFETY: `buf` holds `len` UTF-16 units.
+    let written = unsafe { GetShortPathNameW(wide.as_ptr(), buf.as_mut_ptr(), len) };
+    if written == 0 || written >= len {
+        return None;
+    }
+    buf.truncate(written as usize);
+    Some(PathBuf::from(OsString::from_wide(&buf)))
+}
@@ -0,0 +1,111 @@
+//! Helpers for running commands inside the Windows Subsystem for Linux.
+//!
+//! [`CmdLineRunner::wsl`](crate::CmdLineRunner::wsl) runs a command in a WSL
+//! distribution; the functions here translate paths between the Windows and
+//! Linux views of the filesystem so they can be passed as arguments.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Translates a Windows path to the path WSL sees it at.
+///
+/// Drive paths map to their DrvFs mount (`C:\Users\me` becomes
+/// `/mnt/c/Users/me`) and `\\wsl$\<distro>\...` or
+/// `\\wsl.localhost\<distro>\...` paths map to the path inside the
+/// distribution. Returns `None` for relative paths and other UNC shares.
+///
+/// ```
+/// use ensembler::wsl::to_wsl_path;
+///
+/// assert_eq!(to_wsl_path(r"C:\Users\me").as_deref(), Some("/mnt/c/Users/me"));
+/// assert_eq!(to_wsl_path(r"\\wsl$\Ubuntu\home\me").as_deref(), Some("/home/me"));
+/// ```
+pub fn to_wsl_path<P: AsRef<Path>>(path: P) -> Option<String> {
+    let path = path.as_ref().to_string_lossy().replace('/', "\\");
+    let path = path
+        .strip_prefix(r"\\?\UNC\")
+        .map(|p| format!(r"\\{p}"))
+        .unwrap_or_else(|| path.strip_prefix(r"\\?\").unwrap_or(&path).to_string());
+    let rest = ["\\\\wsl$\\", "\\\\wsl.localhost\\"]
+        .iter()
+        .find_map(|prefix| strip_prefix_ignore_case(&path, prefix));
+    if let Some(rest) = rest {
+        let inside = rest.split_once('\\').map(|(_, p)| p).unwrap_or("");
+        return Some(format!("/{}", inside.replace('\\', "/")));
+    }
+    let mut chars = path.chars();
+    let drive = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+    let rest = chars.as_str();
+    if !rest.is_empty() && !rest.starts_with('\\') {
+        return None;
+    }
+    let rest = rest.trim_end_matches('\\').replace('\\', "/");
+    Some(format!("/mnt/{}{rest}", drive.to_ascii_lowercase()))
+}
+
+/// Translates a Linux path under a DrvFs mount back to a Windows path.
+///
+/// `/mnt/c/Users/me` becomes `C:\Users\me`. Returns `None` for paths that
+/// aren't under `/mnt/<drive>`.
+///
+/// ```
+/// use ensembler::wsl::to_windows_path;
+///
+/// assert_eq!(
+///     to_windows_path("/mnt/d/src/app"),
+///     Some(r"D:\src\app".into())
+/// );
+/// ```
+pub fn to_windows_path(path: &str) -> Option<PathBuf> {
+    let rest = path.strip_prefix("/mnt/")?;
+    let (drive, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let mut drive_chars = drive.chars();
+    let letter = drive_chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if drive_chars.next().is_some() {
+        return None;
+    }
+    let rest = rest.trim_end_matches('/').replace('/', "\\");
+    Some(PathBuf::from(format!(
+        "{}:\\{rest}",
+        letter.to_ascii_uppercase()
+    )))
+}
+
+/// Builds a `WSLENV` value that forwards `keys` into WSL, keeping any
+/// entries already in `existing`. `PATH` is never forwarded since it would
+/// replace the distribution's own.
+pub(crate) fn wslenv<'a>(
+    existing: Option<&OsStr>,
+    keys: impl IntoIterator<Item = &'a OsStr>,
+) -> OsString {
+    let existing = existing
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut entries = existing
+        .split(':')
+        .filter(|e| !e.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    for key in keys {
+        let key = key.to_string_lossy();
+        if key.eq_ignore_ascii_case("WSLENV") || key.eq_ignore_ascii_case("PATH") {
+            continue;
+        }
+        if !entries
+            .iter()
+            .any(|e| e.split('/').next() == Some(key.as_ref()))
+        {
+            entries.push(format!("{key}/u"));
+        }
+    }
+    entries.join(":").into()
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &s[prefix.len()..])
+}
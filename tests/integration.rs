@@ -1,4 +1,4 @@
-use ensembler::{CmdLineRunner, CmdResult, Error};
+use ensembler::{CmdLineRunner, CmdResult, EnvSnapshot, Error, FailureMessage, OutputEncoding};
 use std::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
@@ -14,6 +14,199 @@ async fn test_basic_execution() {
     assert_eq!(result.stdout.trim(), "hello");
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn test_result_timing() {
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "sleep 0.2; echo done"])
+        .execute()
+        .await
+        .unwrap();
+
+    assert!(result.duration >= Duration::from_millis(200));
+    assert!(result.first_output.unwrap() >= Duration::from_millis(200));
+    let (started_at, finished_at) = (result.started_at.unwrap(), result.finished_at.unwrap());
+    assert_eq!(
+        finished_at.duration_since(started_at).unwrap(),
+        result.duration
+    );
+
+    let result = CmdLineRunner::new("true").execute().await.unwrap();
+    assert_eq!(result.first_output, None);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_resource_usage() {
+    // burns a little CPU and memory
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done"])
+        .execute()
+        .await
+        .unwrap();
+
+    let usage = result.resource_usage.unwrap();
+    assert!(usage.cpu_time() > Duration::ZERO);
+    assert!(usage.max_rss > 0);
+
+    // a failed exit status is still reported
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "exit 7"])
+        .execute()
+        .await
+        .unwrap_err();
+    let Error::ScriptFailed(failure) = err else {
+        panic!("unexpected error: {err}");
+    };
+    assert_eq!(failure.exit_code(), Some(7));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_script_failure_streams() {
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "echo out; echo err >&2; exit 1"])
+        .execute()
+        .await
+        .unwrap_err();
+
+    let Error::ScriptFailed(failure) = err else {
+        panic!("unexpected error: {err}");
+    };
+    assert_eq!(failure.stdout(), "out\n");
+    assert_eq!(failure.stderr(), "err\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_success_codes() {
+    let runner = |code: i32| {
+        CmdLineRunner::new("sh")
+            .args(["-c", &format!("exit {code}")])
+            .success_codes([0, 3])
+    };
+    assert!(runner(0).execute().await.is_ok());
+    let result = runner(3).execute().await.unwrap();
+    assert_eq!(result.status.code(), Some(3));
+    let err = runner(1).execute().await.unwrap_err();
+    assert!(matches!(err, Error::ScriptFailed(f) if f.exit_code() == Some(1)));
+
+    let err = CmdLineRunner::new("true")
+        .success_codes([1])
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::ScriptFailed(f) if f.exit_code() == Some(0)));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_context() {
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "exit 2"])
+        .context("installing node 20")
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("installing node 20: sh exited with non-zero status: exit code 2"),
+        "{err}"
+    );
+    assert!(matches!(&err, Error::ScriptFailed(f) if f.context() == Some("installing node 20")));
+
+    let dir = test_dir("context");
+    std::fs::write(dir.join("not-executable"), "").unwrap();
+    let err = CmdLineRunner::new(dir.join("not-executable"))
+        .context("running the tool")
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied),
+        "{err:?}"
+    );
+    assert!(err.to_string().starts_with("running the tool: "), "{err}");
+    let source = std::error::Error::source(&err)
+        .and_then(|e| e.downcast_ref::<std::io::Error>())
+        .unwrap();
+    assert_eq!(source.raw_os_error(), Some(13));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_failure_message() {
+    let runner = || {
+        CmdLineRunner::new("sh")
+            .args(["-c", "printf 'one\\ntwo\\nthree\\n'; exit 3"])
+            .hint("run it again")
+    };
+    let err = runner().execute().await.unwrap_err();
+    assert!(
+        err.to_string().ends_with("three\nhint: run it again"),
+        "{err}"
+    );
+
+    let message = FailureMessage::new(
+        "`{program}` failed with {exit_code} ({status}) {unknown}:\n{tail}\n{hint}",
+    )
+    .tail_lines(2);
+    let err = runner()
+        .failure_message(message)
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`sh` failed with 3 (exit code 3) {unknown}:\ntwo\nthree\nrun it again"
+    );
+
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "exit 1", "hunter2"])
+        .redact(["hunter2".to_string()])
+        .failure_message(FailureMessage::new("{command} | {args}"))
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "sh -c exit 1 [redacted] | -c exit 1 [redacted]"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_map_exit_code() {
+    #[derive(Debug, Clone, PartialEq, thiserror::Error)]
+    enum ToolError {
+        #[error("usage error")]
+        Usage,
+        #[error("conflict: {0}")]
+        Conflict(String),
+    }
+
+    let runner = |code: i32| {
+        CmdLineRunner::new("sh")
+            .args(["-c", &format!("echo busy; exit {code}")])
+            .map_exit_code(2, ToolError::Usage)
+            .map_failure(|f| {
+                (f.exit_code() == Some(3)).then(|| ToolError::Conflict(f.stdout().trim().into()))
+            })
+    };
+    let err = runner(2).execute().await.unwrap_err();
+    assert_eq!(err.downcast_ref::<ToolError>(), Some(&ToolError::Usage));
+    assert_eq!(err.to_string(), "usage error");
+    let err = runner(3).execute().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<ToolError>(),
+        Some(&ToolError::Conflict("busy".into()))
+    );
+    let err = runner(1).execute().await.unwrap_err();
+    assert!(matches!(err, Error::ScriptFailed(_)));
+    assert!(err.downcast_ref::<ToolError>().is_none());
+}
+
 #[tokio::test]
 async fn test_multiple_args() {
     let result = CmdLineRunner::new("echo")
@@ -79,10 +272,11 @@ async fn test_exit_code_failure() {
         .execute()
         .await;
 
-    if let Err(Error::ScriptFailed(details)) = result {
-        let (program, _args, _output, cmd_result) = *details;
-        assert_eq!(program, "bash");
-        assert_eq!(cmd_result.status.code(), Some(42));
+    if let Err(Error::ScriptFailed(failure)) = result {
+        assert_eq!(failure.program(), "bash");
+        assert_eq!(failure.args(), ["-c", "exit 42"]);
+        assert_eq!(failure.exit_code(), Some(42));
+        assert_eq!(failure.into_result().status.code(), Some(42));
     } else {
         panic!("Expected ScriptFailed error, got {:?}", result);
     }
@@ -132,6 +326,21 @@ async fn test_redaction_stderr() {
     assert!(!result.stderr.contains("my-api-key"));
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn test_redaction_failure_args() {
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "exit 1", "hunter2"])
+        .redact(["hunter2".to_string()])
+        .execute()
+        .await
+        .unwrap_err();
+    let Error::ScriptFailed(failure) = &err else {
+        panic!("expected ScriptFailed, got {err:?}");
+    };
+    assert_eq!(failure.args(), ["-c", "exit 1", "[redacted]"]);
+}
+
 #[tokio::test]
 #[cfg(unix)]
 async fn test_environment_variable() {
@@ -268,10 +477,13 @@ async fn test_command_not_found() {
         .execute()
         .await;
 
-    // On Windows with cmd.exe wrapping, this may be ScriptFailed instead of Io
+    // On Windows with cmd.exe wrapping, this may be ScriptFailed instead
     assert!(
-        matches!(result, Err(Error::Io(_)) | Err(Error::ScriptFailed(_))),
-        "Expected Io or ScriptFailed error, got {:?}",
+        matches!(
+            result,
+            Err(Error::ProgramNotFound(_)) | Err(Error::ScriptFailed(_))
+        ),
+        "Expected ProgramNotFound or ScriptFailed error, got {:?}",
         result
     );
 }
@@ -302,6 +514,29 @@ async fn test_large_output() {
     assert_eq!(line_count, 1000);
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn test_large_output_both_streams() {
+    // several hundred kilobytes of stdout and stderr
+    let result = CmdLineRunner::new("awk")
+        .arg(r#"BEGIN { for (i = 0; i < 20000; i++) { print "out " i; print "err " i > "/dev/stderr" } }"#)
+        .execute()
+        .await
+        .unwrap();
+
+    let expected = |stream: &str| {
+        (0..20000)
+            .map(|i| format!("{stream} {i}\n"))
+            .collect::<String>()
+    };
+    assert_eq!(result.stdout, expected("out"));
+    assert_eq!(result.stderr, expected("err"));
+    assert_eq!(
+        result.combined_output.len(),
+        result.stdout.len() + result.stderr.len()
+    );
+}
+
 #[tokio::test]
 async fn test_display_format() {
     let runner = CmdLineRunner::new("echo").arg("hello").arg("world");
@@ -410,7 +645,7 @@ async fn test_timeout() {
 
     // Should have timed out with specific error type
     assert!(
-        matches!(result, Err(Error::TimedOut)),
+        matches!(result, Err(Error::TimedOut { .. })),
         "Expected TimedOut error, got {:?}",
         result
     );
@@ -419,6 +654,26 @@ async fn test_timeout() {
     assert!(elapsed < Duration::from_secs(1));
 }
 
+#[tokio::test]
+#[cfg(unix)]
+async fn test_timeout_partial_result() {
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "echo started; sleep 10"])
+        .timeout(Duration::from_millis(500))
+        .execute()
+        .await
+        .unwrap_err();
+
+    let Error::TimedOut { elapsed, partial } = &err else {
+        panic!("unexpected error: {err:?}");
+    };
+    assert!(*elapsed >= Duration::from_millis(500));
+    assert_eq!(partial.stdout, "started\n");
+    assert!(!partial.status.success());
+    assert_eq!(partial.duration, *elapsed);
+    assert!(err.to_string().starts_with("command timed out after 0."));
+}
+
 #[tokio::test]
 async fn test_timeout_not_reached() {
     // Command completes before timeout
@@ -432,3 +687,3327 @@ async fn test_timeout_not_reached() {
     assert!(result.status.success());
     assert_eq!(result.stdout.trim(), "fast");
 }
+
+/// Creates a fresh, empty directory under the system temp dir for a test.
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("ensembler-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(unix)]
+fn write_script(path: &std::path::Path, body: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::write(path, body).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_which() {
+    let sh = CmdLineRunner::which("sh").unwrap();
+    assert!(sh.is_absolute());
+    assert!(CmdLineRunner::which("nonexistent_command_xyz123").is_none());
+}
+
+#[tokio::test]
+async fn test_resolve_program_not_found() {
+    let result = CmdLineRunner::new("nonexistent_command_xyz123")
+        .resolve_program(true)
+        .execute()
+        .await;
+
+    assert!(
+        matches!(&result, Err(Error::ProgramNotFound(p)) if p.program() == "nonexistent_command_xyz123"),
+        "Expected ProgramNotFound error, got {:?}",
+        result
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_resolve_program_custom_path() {
+    let dir = test_dir("resolve-path");
+    write_script(&dir.join("my-tool"), "#!/bin/sh\necho from my-tool\n");
+
+    let result = CmdLineRunner::new("my-tool")
+        .env("PATH", &dir)
+        .resolve_program(true)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout.trim(), "from my-tool");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_program_not_found_suggestions() {
+    let dir = test_dir("not-found-suggestions");
+    write_script(&dir.join("my-tool"), "#!/bin/sh\n");
+    write_script(&dir.join("unrelated"), "#!/bin/sh\n");
+
+    let err = CmdLineRunner::new("my-tol")
+        .env("PATH", &dir)
+        .execute()
+        .await
+        .unwrap_err();
+    let Error::ProgramNotFound(missing) = &err else {
+        panic!("unexpected error: {err:?}");
+    };
+    assert_eq!(missing.program(), "my-tol");
+    assert_eq!(missing.searched(), std::slice::from_ref(&dir));
+    assert_eq!(missing.suggestions(), ["my-tool"]);
+    assert_eq!(
+        err.to_string(),
+        "my-tol: command not found (did you mean `my-tool`?)"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_validate() {
+    assert!(CmdLineRunner::new("sh").arg("-c").validate().is_ok());
+
+    let err = CmdLineRunner::new("nonexistent_command_xyz123")
+        .current_dir("/nonexistent/dir")
+        .env("BAD=NAME", "1")
+        .env("NUL", "a\0b")
+        .stdin_string("input")
+        .stdin(std::process::Stdio::null())
+        .validate()
+        .unwrap_err();
+    let Error::Preflight(problems) = &err else {
+        panic!("unexpected error: {err:?}");
+    };
+    assert_eq!(problems.len(), 5, "{err}");
+    assert!(matches!(problems[0], Error::CurrentDirNotFound(..)));
+    assert!(matches!(problems[1], Error::ProgramNotFound(_)));
+    assert!(err.to_string().starts_with("5 problems found:\n  "));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_current_dir_not_found() {
+    let dir = test_dir("current-dir-not-found");
+    write_script(&dir.join("my-tool"), "#!/bin/sh\n");
+
+    for cwd in [dir.join("missing"), dir.join("my-tool")] {
+        let err = CmdLineRunner::new("my-tool")
+            .env("PATH", &dir)
+            .current_dir(&cwd)
+            .execute()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::CurrentDirNotFound(p, d) if p == "my-tool" && *d == cwd),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains(&*cwd.to_string_lossy()));
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+#[cfg(unix)] // Windows echo includes quotes around args with spaces
+async fn test_expand_env() {
+    let result = CmdLineRunner::new("echo")
+        .arg("${GREETING}, %NAME%! ${UNSET_VAR_XYZ}%UNSET_VAR_XYZ% 100%")
+        .env("GREETING", "hello")
+        .env("NAME", "world")
+        .expand_env(true)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout.trim(), "hello, world! %UNSET_VAR_XYZ% 100%");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_expand_env_uses_child_env() {
+    let dir = test_dir("expand-env");
+    let result = CmdLineRunner::new("pwd")
+        .env_clear()
+        .env("HOME", &dir)
+        .current_dir("${HOME}")
+        .expand_env(true)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout.trim(), dir.to_str().unwrap());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_expand_env_disabled_by_default() {
+    let runner = CmdLineRunner::new("echo").arg("${HOME}");
+    assert_eq!(runner.to_string(), "echo '${HOME}'");
+}
+
+/// Installs a process-wide logger (once) and returns the messages it has
+/// captured, each as `target: message`.
+fn captured_logs() -> Vec<String> {
+    use std::sync::{Mutex, OnceLock};
+    struct Capture(Mutex<Vec<String>>);
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            let line = format!("{}: {}", record.target(), record.args());
+            self.0.lock().unwrap().push(line);
+        }
+        fn flush(&self) {}
+    }
+    static LOGGER: OnceLock<&'static Capture> = OnceLock::new();
+    let logger = LOGGER.get_or_init(|| {
+        let logger = Box::leak(Box::new(Capture(Mutex::new(vec![]))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        logger
+    });
+    logger.0.lock().unwrap().clone()
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_log_env_diff() {
+    captured_logs();
+    CmdLineRunner::new("true")
+        .env("ENSEMBLER_DIFF_ADDED", "visible-value")
+        .env("ENSEMBLER_DIFF_TOKEN", "hidden-value")
+        .env("ENSEMBLER_DIFF_REDACTED", "sekrit")
+        .redact(vec!["sekrit".to_string()])
+        .log_env_diff(true)
+        .execute()
+        .await
+        .unwrap();
+
+    let logs = captured_logs().join("\n");
+    assert!(logs.contains("env +ENSEMBLER_DIFF_ADDED=visible-value"));
+    assert!(logs.contains("env +ENSEMBLER_DIFF_TOKEN=[redacted]"));
+    assert!(logs.contains("env +ENSEMBLER_DIFF_REDACTED=[redacted]"));
+    assert!(!logs.contains("hidden-value"));
+    assert!(!logs.contains("sekrit"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_log_target() {
+    captured_logs();
+    CmdLineRunner::new("/bin/echo")
+        .arg("ensembler-log-target")
+        .execute()
+        .await
+        .unwrap();
+
+    let logs = captured_logs();
+    assert!(logs.contains(&"ensembler::cmd::echo: $ /bin/echo ensembler-log-target".to_string()));
+}
+
+#[test]
+fn test_env_snapshot_roundtrip() {
+    let mut env: EnvSnapshot = [("A", "1"), ("B", "two=2")].into_iter().collect();
+    env.set("C", "3");
+    env.set("A", "one");
+    assert_eq!(env.remove("C").unwrap(), "3");
+
+    let parsed = EnvSnapshot::from_bytes(&env.to_bytes());
+    assert_eq!(parsed, env);
+    assert_eq!(parsed.get("A").unwrap(), "one");
+    assert_eq!(parsed.get("B").unwrap(), "two=2");
+    assert_eq!(parsed.len(), 2);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_env_snapshot_applied() {
+    let mut env = EnvSnapshot::capture();
+    env.set("SNAPSHOT_VAR", "from-snapshot");
+    env.remove("HOME");
+
+    let result = CmdLineRunner::new("bash")
+        .arg("-c")
+        .arg("echo $SNAPSHOT_VAR $OVERRIDE_VAR ${HOME:-no-home}")
+        .env("IGNORED_VAR", "discarded")
+        .env_snapshot(&env)
+        .env("OVERRIDE_VAR", "override")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout.trim(), "from-snapshot override no-home");
+}
+
+#[test]
+fn test_env_helpers() {
+    // Just make sure these are callable and consistent with the environment.
+    let _ = ensembler::env::is_tty();
+    if let Some(width) = ensembler::env::terminal_width() {
+        assert!(width > 0);
+    }
+    if std::env::var("GITHUB_ACTIONS").as_deref() == Ok("true") {
+        assert!(ensembler::env::is_ci());
+    }
+}
+
+/// Serializes tests that modify crate-wide state such as [`ensembler::set_defaults`].
+static GLOBAL_STATE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_global_defaults() {
+    let _guard = GLOBAL_STATE.lock().await;
+    ensembler::set_defaults(
+        ensembler::Defaults::new()
+            .env("ENSEMBLER_DEFAULT_VAR", "default")
+            .timeout(Duration::from_secs(60))
+            .redact(["default-secret".to_string()]),
+    );
+    let defaults_applied = CmdLineRunner::new("bash")
+        .arg("-c")
+        .arg("echo $ENSEMBLER_DEFAULT_VAR default-secret")
+        .execute()
+        .await;
+    let overridden = CmdLineRunner::new("bash")
+        .arg("-c")
+        .arg("echo $ENSEMBLER_DEFAULT_VAR")
+        .env("ENSEMBLER_DEFAULT_VAR", "override")
+        .execute()
+        .await;
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    assert_eq!(
+        defaults_applied.unwrap().stdout.trim(),
+        "default [redacted]"
+    );
+    assert_eq!(overridden.unwrap().stdout.trim(), "override");
+    assert!(ensembler::defaults().get_envs().next().is_none());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_style() {
+    use ensembler::{ProgressReporter, ProgressState, Style};
+
+    #[derive(Default)]
+    struct Lines(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressReporter for Lines {
+        fn set_status(&self, _: ProgressState) {}
+        fn prop(&self, key: &str, value: &str) {
+            if key == "ensembler_cmd" {
+                self.0.lock().unwrap().push(value.to_string());
+            }
+        }
+        fn println(&self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    let _guard = GLOBAL_STATE.lock().await;
+    let style = Style::new()
+        .command(console::Style::new())
+        .redacted("***")
+        .max_width(16);
+    ensembler::set_defaults(ensembler::Defaults::new().style(style));
+    let lines = std::sync::Arc::new(Lines::default());
+    let runner = CmdLineRunner::new("sh")
+        .args(["-c", "echo hunter2; exit 1"])
+        .redact(["hunter2".to_string()])
+        .reporter(lines.clone())
+        .collapse_output(true);
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    let err = runner.execute().await.unwrap_err();
+    assert!(err.to_string().ends_with("\n***"), "{err}");
+    assert_eq!(
+        *lines.0.lock().unwrap(),
+        ["sh -c 'echo ***…", "$ sh -c 'echo *…", "***"]
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_wrap() {
+    let _guard = GLOBAL_STATE.lock().await;
+    ensembler::set_defaults(ensembler::Defaults::new().wrap(["env", "OUTER=1"]));
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo $OUTER $INNER $0"])
+        .wrap(["sh", "-c", "echo wrapped; exec \"$@\"", "sh"])
+        .wrap(["env", "INNER=2"])
+        .execute()
+        .await;
+    ensembler::set_defaults(ensembler::Defaults::new());
+    assert_eq!(result.unwrap().stdout, "wrapped\n1 2 sh\n");
+
+    let mock = ensembler::testing::MockExecutor::new();
+    mock.expect("nice", ["-n", "19", "make", "-j8"]);
+    CmdLineRunner::new("make")
+        .arg("-j8")
+        .wrap(["nice", "-n", "19"])
+        .executor(mock.clone())
+        .execute()
+        .await
+        .unwrap();
+    mock.verify();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_policy() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let dir = test_dir("policy");
+    let policy = ensembler::Policy::new()
+        .allow_programs(["echo", "rm", "/bin/true"])
+        .deny_args("rm", ["-*r*", "/"])
+        .allow_cwd(&dir)
+        .rule(|invocation| {
+            let secret = invocation.args().iter().any(|a| a == "hunter2");
+            secret.then(|| "hunter2 is not allowed".to_string())
+        });
+    ensembler::set_defaults(ensembler::Defaults::new().policy(policy));
+    let run = |program: &str, args: &[&str]| {
+        CmdLineRunner::new(program)
+            .args(args)
+            .current_dir(&dir)
+            .redact(["hunter2".to_string()])
+            .execute()
+    };
+    let allowed = run("echo", &["hi"]).await;
+    let reasons = [
+        run("rm", &["-fr", "/"]).await,
+        run("sh", &["-c", "true"]).await,
+        run("/tmp/echo", &[]).await,
+        run("echo", &["hunter2"]).await,
+        CmdLineRunner::new("echo").current_dir("/").execute().await,
+    ];
+    let outside = CmdLineRunner::new("echo")
+        .current_dir(dir.join(".."))
+        .execute()
+        .await;
+    let expanded = CmdLineRunner::new("echo")
+        .current_dir("${D}")
+        .env("D", "/")
+        .expand_env(true)
+        .execute()
+        .await;
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    assert_eq!(allowed.unwrap().stdout, "hi\n");
+    let reasons: Vec<_> = reasons
+        .into_iter()
+        .map(|r| match r {
+            Err(ensembler::Error::PolicyDenied(_, reason)) => reason,
+            r => panic!("expected a policy denial, got {r:?}"),
+        })
+        .collect();
+    assert_eq!(reasons[0], "args match `-*r* /`");
+    assert_eq!(reasons[1], "program is not allowed");
+    assert_eq!(reasons[2], "program is not allowed");
+    assert_eq!(reasons[3], "[redacted] is not allowed");
+    assert_eq!(reasons[4], "working directory / is not allowed");
+    assert!(matches!(outside, Err(ensembler::Error::PolicyDenied(..))));
+    assert!(
+        matches!(&expanded, Err(ensembler::Error::PolicyDenied(_, reason)) if reason == "working directory / is not allowed"),
+        "{expanded:?}"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_policy_args() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let dir = test_dir("policy_args");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::os::unix::fs::symlink("/etc", dir.join("etc")).unwrap();
+    let policy = ensembler::Policy::new()
+        .deny_control_chars()
+        .deny_shell_newlines()
+        .confine_paths(&dir);
+    ensembler::set_defaults(ensembler::Defaults::new().policy(policy));
+    let run = |program: &str, args: &[&str]| {
+        CmdLineRunner::new(program)
+            .args(args)
+            .current_dir(dir.join("sub"))
+            .execute()
+    };
+    let allowed = [
+        run("echo", &["a\tb", "multi\nline", "file.txt"]).await,
+        run("echo", &["../sub/./x", "--out=../new/file"]).await,
+        run("sh", &["-c", "echo ok"]).await,
+    ];
+    let reasons = [
+        run("echo", &["\x1b[2J"]).await,
+        run("sh", &["-c", "echo ok\nrm -rf ~"]).await,
+        run("echo", &["ok", "../../escape"]).await,
+        run("echo", &["--file=/etc/passwd"]).await,
+        run("cat", &["../etc/passwd"]).await,
+        run("echo", &["~/x"]).await,
+        CmdLineRunner::new("ls")
+            .arg("${P}/hostname")
+            .env("P", "/etc")
+            .expand_env(true)
+            .current_dir(dir.join("sub"))
+            .execute()
+            .await,
+    ];
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    for result in allowed {
+        result.unwrap();
+    }
+    let reasons: Vec<_> = reasons
+        .into_iter()
+        .map(|r| match r {
+            Err(ensembler::Error::PolicyDenied(_, reason)) => reason,
+            r => panic!("expected a policy denial, got {r:?}"),
+        })
+        .collect();
+    assert_eq!(reasons[0], "arg 1 contains a control character");
+    assert_eq!(reasons[1], "arg 2 contains a newline");
+    assert_eq!(reasons[2], "arg 2 is a path outside the allowed roots");
+    assert_eq!(reasons[3], "arg 1 is a path outside the allowed roots");
+    assert_eq!(reasons[4], "arg 1 is a path outside the allowed roots");
+    assert_eq!(reasons[5], "arg 1 is a path outside the allowed roots");
+    assert_eq!(reasons[6], "arg 1 is a path outside the allowed roots");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_policy_cached() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let dir = test_dir("policy_cached");
+    std::fs::write(dir.join("in.txt"), "v1").unwrap();
+    let cached = || {
+        CmdLineRunner::new("cat")
+            .arg("in.txt")
+            .current_dir(&dir)
+            .cache(ensembler::Cache::new(dir.join("cache")))
+            .execute()
+    };
+    let fresh = || {
+        CmdLineRunner::new("cp")
+            .args(["in.txt", "out.txt"])
+            .current_dir(&dir)
+            .freshness(
+                ensembler::Freshness::new()
+                    .input("in.txt")
+                    .output("out.txt"),
+            )
+            .execute()
+    };
+    cached().await.unwrap();
+    fresh().await.unwrap();
+    assert!(fresh().await.unwrap().up_to_date);
+
+    let policy = ensembler::Policy::new().deny_programs(["cat", "cp"]);
+    ensembler::set_defaults(ensembler::Defaults::new().policy(policy));
+    let results = [cached().await, fresh().await];
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    for result in results {
+        assert!(
+            matches!(result, Err(ensembler::Error::PolicyDenied(..))),
+            "{result:?}"
+        );
+    }
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_cache() {
+    let dir = test_dir("cache");
+    let input = dir.join("input.txt");
+    std::fs::write(&input, "v1").unwrap();
+    let cache = ensembler::Cache::new(dir.join("cache")).input(&input);
+    let run = |arg: &str| {
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo run >> runs; cat input.txt; echo \" $0\"", arg])
+            .current_dir(&dir)
+            .cache(cache.clone())
+    };
+    let runs = || {
+        std::fs::read_to_string(dir.join("runs"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    assert_eq!(run("a").execute().await.unwrap().stdout, "v1 a\n");
+    let cached = run("a").execute().await.unwrap();
+    assert_eq!(cached.stdout, "v1 a\n");
+    assert!(cached.status.success());
+    assert_eq!(runs(), 1);
+
+    run("b").execute().await.unwrap();
+    assert_eq!(runs(), 2);
+    run("a").env("EXTRA", "1").execute().await.unwrap();
+    assert_eq!(runs(), 3);
+
+    std::fs::write(&input, "v2").unwrap();
+    assert_eq!(run("a").execute().await.unwrap().stdout, "v2 a\n");
+    assert_eq!(runs(), 4);
+
+    // a replaced environment is part of the key
+    let mut snapshot = ensembler::EnvSnapshot::capture();
+    run("a").env_snapshot(&snapshot).execute().await.unwrap();
+    run("a").env_snapshot(&snapshot).execute().await.unwrap();
+    assert_eq!(runs(), 5);
+    snapshot.set("EXTRA", "1");
+    run("a").env_snapshot(&snapshot).execute().await.unwrap();
+    assert_eq!(runs(), 6);
+
+    // symlinks are hashed by their target, so loops don't recurse
+    let tree = dir.join("tree");
+    std::fs::create_dir(&tree).unwrap();
+    std::os::unix::fs::symlink(".", tree.join("loop")).unwrap();
+    let linked = || {
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo run >> runs"])
+            .current_dir(&dir)
+            .cache(cache.clone().input("tree"))
+    };
+    linked().execute().await.unwrap();
+    linked().execute().await.unwrap();
+    assert_eq!(runs(), 7);
+    std::fs::remove_file(tree.join("loop")).unwrap();
+    std::os::unix::fs::symlink("..", tree.join("loop")).unwrap();
+    linked().execute().await.unwrap();
+    assert_eq!(runs(), 8);
+
+    let failing = || {
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo run >> runs; exit 1"])
+            .current_dir(&dir)
+            .cache(cache.clone())
+    };
+    assert!(failing().execute().await.is_err());
+    assert!(failing().execute().await.is_err());
+    assert_eq!(runs(), 10);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_freshness() {
+    let dir = test_dir("freshness");
+    std::fs::write(dir.join("in.txt"), "v1").unwrap();
+    let touch = |name: &str, secs: u64| {
+        let time = std::time::SystemTime::now() + Duration::from_secs(secs);
+        let file = std::fs::File::options()
+            .write(true)
+            .open(dir.join(name))
+            .unwrap();
+        file.set_modified(time).unwrap();
+    };
+    let run = |freshness: ensembler::Freshness, suffix: &str| {
+        CmdLineRunner::new("sh")
+            .args([
+                "-c",
+                "echo run; cp in.txt out.txt; echo \"$0\" >> out.txt",
+                suffix,
+            ])
+            .current_dir(&dir)
+            .freshness(freshness.input("in.txt").output("out.txt"))
+            .execute()
+    };
+    let mtimes = ensembler::Freshness::new;
+    let first = run(mtimes(), "a").await.unwrap();
+    assert!(!first.up_to_date);
+    assert_eq!(first.stdout, "run\n");
+    let second = run(mtimes(), "a").await.unwrap();
+    assert!(second.up_to_date && second.status.success() && second.stdout.is_empty());
+    touch("in.txt", 10);
+    assert!(!run(mtimes(), "a").await.unwrap().up_to_date);
+
+    let hashes = || ensembler::Freshness::new().stamp(".stamps/out");
+    assert!(!run(hashes(), "a").await.unwrap().up_to_date);
+    assert!(run(hashes(), "a").await.unwrap().up_to_date);
+    touch("in.txt", 20);
+    assert!(run(hashes(), "a").await.unwrap().up_to_date);
+    assert!(!run(hashes(), "b").await.unwrap().up_to_date);
+    std::fs::write(dir.join("in.txt"), "v2").unwrap();
+    assert!(!run(hashes(), "b").await.unwrap().up_to_date);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+        "v2b\n"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_diff() {
+    let diff = ensembler::Diff::new(
+        "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n",
+        "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nl\nm",
+    )
+    .labels("o", "n");
+    assert_eq!(diff.stats(), (2, 2));
+    assert_eq!(
+        diff.to_string(),
+        "--- o\n+++ n\n\
+         @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+         @@ -8,5 +8,5 @@\n h\n i\n j\n-k\n l\n+m\n\\ No newline at end of file\n"
+    );
+    let inserted = ensembler::Diff::new("a\nb\n", "a\nx\nb\n").context(0);
+    assert_eq!(
+        inserted.to_string(),
+        "--- previous\n+++ current\n@@ -1,0 +2 @@\n+x\n"
+    );
+
+    let run = |out: &str| CmdLineRunner::new("printf").arg(out).execute();
+    let before = run("one\ntwo\n").await.unwrap();
+    let same = run("one\ntwo\n").await.unwrap();
+    let after = run("one\n2\n").await.unwrap();
+    assert!(same.diff(&before).is_empty());
+    assert_eq!(same.diff(&before).to_string(), "");
+    assert_eq!(
+        after.diff(&before).to_string(),
+        "--- previous\n+++ current\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_timings() {
+    let dir = test_dir("timings");
+    let timings = ensembler::Timings::new(dir.join("timings.json"));
+    let runner = || {
+        CmdLineRunner::new("sleep")
+            .arg("0.1")
+            .env("TOKEN", "hunter2")
+            .timings(timings.clone())
+    };
+    assert_eq!(runner().expected_duration(), None);
+    runner().execute().await.unwrap();
+    runner().execute().await.unwrap();
+    let expected = runner().expected_duration().unwrap();
+    assert!(expected >= Duration::from_millis(100) && expected < Duration::from_secs(5));
+    assert_eq!(timings.runs("sleep 0.1"), 2);
+    CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(50))
+        .timings(timings.clone())
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(timings.runs("sleep 10"), 0);
+
+    CmdLineRunner::new("echo")
+        .arg("hunter2")
+        .redact(["hunter2".to_string()])
+        .timings(timings.clone())
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(timings.runs("echo [redacted]"), 1);
+    let db = std::fs::read_to_string(timings.path()).unwrap();
+    assert!(!db.contains("hunter2"));
+
+    timings.record("deploy", Duration::from_secs(1)).unwrap();
+    timings.record("deploy", Duration::from_secs(2)).unwrap();
+    assert_eq!(
+        timings.expected("deploy"),
+        Some(Duration::from_millis(1300))
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_output_stall() {
+    let dir = test_dir("output-stall");
+    let timings = ensembler::Timings::new(dir.join("timings.json"));
+    let history = ensembler::History::new(dir.join("history.jsonl"));
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo starting; sleep 1; echo done"])
+        .timings(timings.clone())
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap();
+
+    // well below the sleep, as a loaded runner can read the first line late
+    assert!(result.longest_stall >= Duration::from_millis(300));
+    let first_output = result.first_output.unwrap();
+
+    let signature = "sh -c 'echo starting; sleep 1; echo done'";
+    let ms = |d: Duration| Duration::from_millis(d.as_millis() as u64);
+    assert_eq!(timings.first_output(signature), Some(ms(first_output)));
+    assert_eq!(
+        timings.longest_stall(signature),
+        Some(ms(result.longest_stall))
+    );
+    let entry = &history.entries().unwrap()[0];
+    assert_eq!(entry.first_output, Some(ms(first_output)));
+    assert_eq!(entry.longest_stall, Some(ms(result.longest_stall)));
+}
+
+#[tokio::test]
+#[cfg(all(unix, feature = "progress"))]
+async fn test_with_progress() {
+    let result = CmdLineRunner::new("echo")
+        .arg("installed")
+        .progress_message("Installing")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "installed\n");
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "exit 2"])
+        .with_progress(true)
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ensembler::Error::ScriptFailed(_)));
+
+    let result = CmdLineRunner::new("sh")
+        .args([
+            "-c",
+            "for i in 1 2 3 4 5; do echo out $i; echo err $i >&2; done",
+        ])
+        .with_progress(true)
+        .progress_tail(3)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.lines().count(), 5);
+    assert_eq!(result.stderr.lines().last(), Some("err 5"));
+
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "echo building; echo boom >&2; exit 3"])
+        .with_progress(true)
+        .collapse_output(true)
+        .execute()
+        .await
+        .unwrap_err();
+    match err {
+        ensembler::Error::ScriptFailed(failure) => assert_eq!(failure.output(), "building\nboom"),
+        e => panic!("expected ScriptFailed, got {e:?}"),
+    }
+
+    let parent = clx::progress::ProgressJobBuilder::new()
+        .prop("message", "group")
+        .start();
+    for step in ["one", "two"] {
+        CmdLineRunner::new("echo")
+            .arg(step)
+            .progress_parent(parent.clone())
+            .execute()
+            .await
+            .unwrap();
+    }
+    assert_eq!(parent.children().len(), 2);
+    assert!(parent.is_running());
+    CmdLineRunner::new("false")
+        .progress_parent(parent.clone())
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(!parent.is_running());
+}
+
+#[tokio::test]
+#[cfg(all(unix, feature = "progress"))]
+async fn test_progress_parser() {
+    use ensembler::ProgressParser;
+
+    let parser = ProgressParser::any();
+    assert_eq!(
+        parser.parse("Receiving objects:  12% (3/25)"),
+        Some((120, 1000))
+    );
+    assert_eq!(
+        parser.parse("  Building [===>   ] 3/25: serde"),
+        Some((3, 25))
+    );
+    assert_eq!(parser.parse(" 10%\r 55.5%\r"), Some((555, 1000)));
+    assert_eq!(parser.parse("released 2024/10/01"), None);
+    assert_eq!(parser.parse("fetched 9/3 things"), None);
+
+    let result = CmdLineRunner::new("sh")
+        .args([
+            "-c",
+            "for i in 1 2 3; do echo step $i/3; done; echo 50% >&2",
+        ])
+        .progress_message("Stepping")
+        .progress_parser(parser)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.lines().last(), Some("step 3/3"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_reporter() {
+    use ensembler::{ProgressParser, ProgressReporter, ProgressState};
+
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressReporter for Recorder {
+        fn set_status(&self, status: ProgressState) {
+            self.0.lock().unwrap().push(format!("{status:?}"));
+        }
+        fn prop(&self, key: &str, value: &str) {
+            if key == "ensembler_stdout" && !value.is_empty() {
+                self.0.lock().unwrap().push(format!("out {value}"));
+            }
+        }
+        fn println(&self, line: &str) {
+            self.0.lock().unwrap().push(format!("println {line}"));
+        }
+        fn set_position(&self, current: usize, total: usize) {
+            self.0.lock().unwrap().push(format!("{current}/{total}"));
+        }
+    }
+
+    let recorder = std::sync::Arc::new(Recorder::default());
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo step 1/2; sleep 0.1; echo warning >&2"])
+        .reporter(recorder.clone())
+        .progress_parser(ProgressParser::count())
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        ["Running", "1/2", "out step 1/2", "println warning", "Done"]
+    );
+
+    let recorder = std::sync::Arc::new(Recorder::default());
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo oops >&2; exit 1"])
+        .reporter(recorder.clone())
+        .show_stderr_on_error(false)
+        .execute()
+        .await
+        .unwrap_err();
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        ["Running", "println oops", "Failed"]
+    );
+}
+
+#[tokio::test]
+#[cfg(all(unix, feature = "indicatif"))]
+async fn test_indicatif_reporter() {
+    use ensembler::{IndicatifReporter, ProgressParser};
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    let reporter = std::sync::Arc::new(IndicatifReporter::add(&multi));
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo 1/4; echo 3/4"])
+        .reporter(reporter.clone())
+        .progress_parser(ProgressParser::count())
+        .execute()
+        .await
+        .unwrap();
+    let bar = reporter.bar();
+    assert!(bar.is_finished());
+    assert_eq!(bar.length(), Some(4));
+    assert_eq!(bar.message(), "3/4");
+    assert!(bar.prefix().starts_with("sh -c"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_elapsed_props() {
+    use ensembler::{ProgressReporter, ProgressState, Timings};
+
+    #[derive(Default)]
+    struct Props(std::sync::Mutex<Vec<(String, String)>>);
+
+    impl ProgressReporter for Props {
+        fn set_status(&self, _: ProgressState) {}
+        fn prop(&self, key: &str, value: &str) {
+            let prop = (key.to_string(), value.to_string());
+            self.0.lock().unwrap().push(prop);
+        }
+        fn println(&self, _: &str) {}
+    }
+
+    let timings = Timings::new(test_dir("elapsed_props").join("timings.json"));
+    timings.record("sleep 1", Duration::from_secs(10)).unwrap();
+    let props = std::sync::Arc::new(Props::default());
+    CmdLineRunner::new("sleep")
+        .arg("1")
+        .reporter(props.clone())
+        .timings(timings)
+        .execute()
+        .await
+        .unwrap();
+    let props = props.0.lock().unwrap();
+    let values = |key: &str| -> Vec<String> {
+        let props = props.iter().filter(|(k, _)| k == key);
+        props.map(|(_, v)| v.clone()).collect()
+    };
+    assert_eq!(values("ensembler_expected"), ["10s"]);
+    assert_eq!(values("ensembler_eta").first().unwrap(), "10s");
+    assert_eq!(values("ensembler_elapsed").first().unwrap(), "0s");
+    assert_ne!(values("ensembler_elapsed").last().unwrap(), "0s");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_verbosity() {
+    use ensembler::{ProgressReporter, ProgressState, Verbosity};
+
+    #[derive(Default)]
+    struct Printed(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressReporter for Printed {
+        fn set_status(&self, _: ProgressState) {}
+        fn prop(&self, key: &str, value: &str) {
+            if key == "ensembler_stdout" && !value.is_empty() {
+                self.0.lock().unwrap().push(format!("prop {value}"));
+            }
+        }
+        fn println(&self, line: &str) {
+            let line = console::strip_ansi_codes(line).into_owned();
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    let run = |verbosity| async move {
+        let printed = std::sync::Arc::new(Printed::default());
+        let _ = CmdLineRunner::new("sh")
+            .args(["-c", "echo out; sleep 0.1; echo err >&2; exit 1"])
+            .current_dir("/")
+            .reporter(printed.clone())
+            .verbosity(verbosity)
+            .execute()
+            .await
+            .unwrap_err();
+        let printed = printed.0.lock().unwrap();
+        printed.clone()
+    };
+    assert!(run(Verbosity::Quiet).await.is_empty());
+    assert_eq!(
+        run(Verbosity::Normal).await,
+        ["prop out", "err", "out\nerr"]
+    );
+    assert_eq!(
+        run(Verbosity::Verbose).await,
+        [
+            "$ sh -c 'echo out; sleep 0.1; echo err >&2; exit 1'",
+            "prop out",
+            "out",
+            "err"
+        ]
+    );
+    let trace = run(Verbosity::Trace).await;
+    assert_eq!(trace[1], "  in /");
+    assert!(trace.last().unwrap().starts_with("  exit code 1 after "));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_history() {
+    let dir = test_dir("history");
+    let history = ensembler::History::new(dir.join("history.jsonl")).max_output(6);
+    let start = std::time::SystemTime::now();
+    CmdLineRunner::new("echo")
+        .args(["hello", "hunter2"])
+        .env("TOKEN", "hunter2")
+        .redact(["hunter2".to_string()])
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo failing; exit 3"])
+        .current_dir(&dir)
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap_err();
+    CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(50))
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap_err();
+
+    let entries = history.entries().unwrap();
+    assert_eq!(entries.len(), 3);
+    let echo = &entries[0];
+    assert_eq!(echo.command_line(), "echo hello \'[redacted]\'");
+    assert_eq!(echo.env, [("TOKEN".to_string(), "[redacted]".to_string())]);
+    assert_eq!(echo.output, "cted]\n");
+    assert!(echo.success() && echo.started_at >= start - Duration::from_secs(1));
+    assert_eq!(entries[1].exit_code, Some(3));
+    assert_eq!(entries[1].cwd.as_deref(), Some(dir.as_path()));
+    assert_eq!(entries[2].exit_code, None);
+    assert!(entries[2].error.as_deref().unwrap().contains("timed out"));
+    assert!(entries[2].duration >= Duration::from_millis(50));
+
+    let failed = history.query().failed().entries().unwrap();
+    assert_eq!(failed.len(), 2);
+    let last = history.query().failed().limit(1).entries().unwrap();
+    assert_eq!(last[0].program, "sleep");
+    assert_eq!(history.query().program("echo").entries().unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_history_dry_run() {
+    let dir = test_dir("history_dry_run");
+    let work = dir.join("work");
+    std::fs::create_dir_all(&work).unwrap();
+    let tool = dir.join("tool");
+    write_script(&tool, "#!/bin/sh\nexit 0\n");
+    let history = ensembler::History::new(dir.join("history.jsonl"));
+    CmdLineRunner::new("true")
+        .env("ENSEMBLER_DRY_RUN_VAR", "recorded")
+        .env("TOKEN", "hunter2")
+        .redact(["hunter2".to_string()])
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new(&tool)
+        .current_dir(&work)
+        .history(history.clone())
+        .execute()
+        .await
+        .unwrap();
+    std::fs::remove_file(&tool).unwrap();
+    std::fs::remove_dir(&work).unwrap();
+
+    let runs = history.dry_run().unwrap();
+    assert_eq!(runs.len(), 2);
+    assert!(runs[0].resolved.is_some() && runs[0].cwd_exists);
+    assert_eq!(runs[0].env.len(), 1);
+    assert_eq!(runs[0].env[0].key, "ENSEMBLER_DRY_RUN_VAR");
+    assert_eq!(runs[0].env[0].current, None);
+    assert!(!runs[0].unchanged());
+    assert!(runs[1].resolved.is_none() && !runs[1].cwd_exists);
+    let shown = runs[1].to_string();
+    assert!(shown.starts_with(&format!("$ cd {} && {}", work.display(), tool.display())));
+    assert!(shown.contains("not found") && shown.contains("does not exist"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_cmd_template() {
+    let template = ensembler::CmdTemplate::new("bash")
+        .args(["-c", "echo $TEMPLATE_VAR $0 $1 $(pwd)"])
+        .env("TEMPLATE_VAR", "from-template")
+        .current_dir("/");
+
+    let first = template.runner().arg("a").execute().await.unwrap();
+    let second = template.runner().args(["b", "c"]).execute().await.unwrap();
+
+    assert_eq!(first.stdout.trim(), "from-template a /");
+    assert_eq!(second.stdout.trim(), "from-template b c /");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_shell() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let result = CmdLineRunner::shell("echo 'hello world' && echo $((1 + 2))")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "hello world\n3\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_shell_errexit() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let result = CmdLineRunner::shell("false; echo unreachable")
+        .execute()
+        .await;
+
+    assert!(matches!(result, Err(Error::ScriptFailed(_))));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_shell_display() {
+    let _guard = GLOBAL_STATE.blocking_lock();
+    let runner = CmdLineRunner::shell("echo hi");
+    assert_eq!(runner.to_string(), "echo hi");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_default_shell() {
+    let _guard = GLOBAL_STATE.lock().await;
+    ensembler::set_defaults(ensembler::Defaults::new().shell(ensembler::Shell::Bash));
+    let result = CmdLineRunner::shell("echo $BASH_VERSION").execute().await;
+    ensembler::set_defaults(ensembler::Defaults::new());
+
+    assert!(!result.unwrap().stdout.trim().is_empty());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_cmd_macro() {
+    let msg = "hello world";
+    let result = ensembler::cmd!("printf", "%s|%s", msg, "x")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "hello world|x\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_sh_macro_quotes_args() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let evil = "it's $(echo pwned); echo injected";
+    let result = ensembler::sh!("echo {} {}", evil, 42)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "it's $(echo pwned); echo injected 42\n");
+}
+
+#[test]
+fn test_shell_words_split() {
+    use ensembler::shell_words::split;
+
+    assert_eq!(
+        split(r#"a "b c" 'd "e"' f\ g "h\"i" j''k # comment"#).unwrap(),
+        ["a", "b c", "d \"e\"", "f g", "h\"i", "jk"]
+    );
+    assert_eq!(split("  ").unwrap(), Vec::<String>::new());
+    assert_eq!(split("''").unwrap(), [""]);
+    assert!(matches!(
+        split("echo 'oops"),
+        Err(Error::InvalidCommandLine(_))
+    ));
+    assert!(matches!(
+        split("echo \\"),
+        Err(Error::InvalidCommandLine(_))
+    ));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_parse() {
+    let runner = CmdLineRunner::parse("printf '%s|%s' \"hello world\" x").unwrap();
+    assert_eq!(runner.to_string(), "printf '%s|%s' 'hello world' x");
+
+    let result = runner.execute().await.unwrap();
+    assert_eq!(result.stdout, "hello world|x\n");
+
+    assert!(matches!(
+        CmdLineRunner::parse("   "),
+        Err(Error::InvalidCommandLine(_))
+    ));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_script_runner_shebang() {
+    let runner = ensembler::ScriptRunner::new(
+        "#!/usr/bin/env bash\nset -eu\nname=\"$1\"\necho \"hello ${name}\"\necho \"it's quoted\"\n",
+    )
+    .runner()
+    .unwrap()
+    .arg("script");
+    let script_path = runner.to_string().split(' ').nth(2).unwrap().to_string();
+    assert!(std::path::Path::new(&script_path).exists());
+
+    let result = runner.execute().await.unwrap();
+
+    assert_eq!(result.stdout, "hello script\nit's quoted\n");
+    assert!(!std::path::Path::new(&script_path).exists());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_script_runner_interpreter() {
+    let result = ensembler::ScriptRunner::new("#!/bin/false\necho $0 | grep -c ensembler-")
+        .interpreter("sh", ["-e"])
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "1\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_script_runner_default_shell() {
+    let _guard = GLOBAL_STATE.lock().await;
+    let result = ensembler::ScriptRunner::new("false\necho unreachable")
+        .interpreter("sh", ["-e"])
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::ScriptFailed(_))));
+
+    // the default shell stops on errors, like `CmdLineRunner::shell`
+    let result = ensembler::ScriptRunner::new("false\necho unreachable")
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::ScriptFailed(_))));
+
+    let bash = || ensembler::ScriptRunner::new("echo \"${BASH_VERSION:+bash}\"");
+    ensembler::set_defaults(ensembler::Defaults::new().shell(ensembler::Shell::Bash));
+    let result = bash().execute().await;
+    ensembler::set_defaults(ensembler::Defaults::new());
+    assert_eq!(result.unwrap().stdout, "bash\n");
+    let result = bash().shell(ensembler::Shell::Bash).execute().await;
+    assert_eq!(result.unwrap().stdout, "bash\n");
+
+    let result = ensembler::ScriptRunner::new("echo one\necho two")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "one\ntwo\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_temp_script() {
+    let long_arg = "x".repeat(10_000);
+    let result = CmdLineRunner::new("printf")
+        .arg("%s|%s|%s\n")
+        .arg("it's $HOME")
+        .arg("a b")
+        .arg(&long_arg)
+        .temp_script(true)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, format!("it's $HOME|a b|{long_arg}\n"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_temp_script_exit_code() {
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "exit 3"])
+        .temp_script(true)
+        .execute()
+        .await;
+
+    match result {
+        Err(Error::ScriptFailed(failure)) => assert_eq!(failure.exit_code(), Some(3)),
+        other => panic!("Expected ScriptFailed error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_powershell() {
+    if CmdLineRunner::which("pwsh").is_none() && !cfg!(windows) {
+        return;
+    }
+    let result = CmdLineRunner::powershell("Write-Output \"hello $(1 + 2)\"")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim(), "hello 3");
+
+    let result = CmdLineRunner::powershell("cmd /c exit 7")
+        .allow_non_zero(true)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.status.code(), Some(7));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_powershell_args() {
+    let runner = CmdLineRunner::powershell("Get-Date");
+    assert!(runner
+        .to_string()
+        .starts_with("pwsh -NoProfile -NonInteractive -Command "));
+}
+
+#[test]
+fn test_shell_from_str() {
+    use ensembler::Shell;
+
+    assert_eq!("bash".parse::<Shell>().unwrap(), Shell::Bash);
+    assert_eq!("/usr/bin/zsh".parse::<Shell>().unwrap(), Shell::Zsh);
+    assert_eq!("pwsh.exe".parse::<Shell>().unwrap(), Shell::PowerShell);
+    assert_eq!("CMD.EXE".parse::<Shell>().unwrap(), Shell::Cmd);
+    assert_eq!(
+        "nu".parse::<Shell>().unwrap(),
+        Shell::Custom {
+            program: "nu".into(),
+            args: vec!["-c".into()],
+        }
+    );
+    assert_eq!(
+        "bash -l -c".parse::<Shell>().unwrap(),
+        Shell::Custom {
+            program: "bash".into(),
+            args: vec!["-l".into(), "-c".into()],
+        }
+    );
+    assert!("".parse::<Shell>().is_err());
+}
+
+#[test]
+fn test_shell_quote() {
+    use ensembler::Shell;
+
+    assert_eq!(Shell::Sh.quote("it's"), r"'it'\''s'");
+    assert_eq!(Shell::Fish.quote(r"it's \o/"), r"'it\'s \\o/'");
+    assert_eq!(Shell::PowerShell.quote("it's"), "'it''s'");
+    assert_eq!(
+        Shell::PowerShell.quote("it\u{2019}s \u{2018}x\u{2019}"),
+        "'it\u{2019}\u{2019}s \u{2018}\u{2018}x\u{2019}\u{2019}'"
+    );
+    assert_eq!(Shell::Cmd.quote("a & b"), r#"^"a ^& b^""#);
+    assert_eq!(Shell::Bash.quote("plain"), "plain");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_with_shell_bash_pipefail() {
+    let result =
+        CmdLineRunner::with_shell(&ensembler::Shell::Bash, "false | true; echo unreachable")
+            .execute()
+            .await;
+    assert!(matches!(result, Err(Error::ScriptFailed(_))));
+
+    let custom = "sh -c".parse().unwrap();
+    let result = CmdLineRunner::with_shell(&custom, "false | true; echo reached")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "reached\n");
+}
+
+#[test]
+fn test_wsl_paths() {
+    use ensembler::wsl::{to_windows_path, to_wsl_path};
+
+    assert_eq!(to_wsl_path(r"C:\").as_deref(), Some("/mnt/c"));
+    assert_eq!(
+        to_wsl_path(r"D:\src\my app\").as_deref(),
+        Some("/mnt/d/src/my app")
+    );
+    assert_eq!(
+        to_wsl_path(r"\\?\C:\long\path").as_deref(),
+        Some("/mnt/c/long/path")
+    );
+    assert_eq!(
+        to_wsl_path(r"\\WSL.localhost\Debian\etc\hosts").as_deref(),
+        Some("/etc/hosts")
+    );
+    assert_eq!(to_wsl_path(r"relative\path"), None);
+    assert_eq!(to_wsl_path(r"\\server\share"), None);
+
+    assert_eq!(to_windows_path("/mnt/c"), Some(r"C:\".into()));
+    assert_eq!(to_windows_path("/home/me"), None);
+    assert_eq!(to_windows_path("/mnt/wsl/x"), None);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_wsl_command_line() {
+    let dir = test_dir("wsl");
+    write_script(
+        &dir.join("wsl.exe"),
+        "#!/bin/sh\necho \"$@\"\necho \"$WSLENV\"\n",
+    );
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let result = CmdLineRunner::new("make")
+        .args(["-j", "4"])
+        .current_dir(r"C:\src")
+        .env("PATH", &path)
+        .env("CC", "clang")
+        .wsl(Some("Ubuntu"))
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        result.stdout,
+        "-d Ubuntu --cd /mnt/c/src --exec make -j 4\nCC/u\n"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_shell_stdin() {
+    let _guard = GLOBAL_STATE.lock().await;
+    // a single argument over MAX_ARG_STRLEN (128KiB) can't be passed via argv
+    let mut script = format!(": '{}'\n", "x".repeat(200 * 1024));
+    script.push_str("echo \"it's\" 'multi-line'\nfalse\necho unreachable\n");
+    let result = CmdLineRunner::shell_stdin(script)
+        .unwrap()
+        .allow_non_zero(true)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "it's multi-line\n");
+    assert_eq!(result.status.code(), Some(1));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_shell_stdin_custom() {
+    let shell: ensembler::Shell = "sh -c".parse().unwrap();
+    let result = shell
+        .stdin_runner("echo \"from $0\"\n")
+        .unwrap()
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout, "from sh\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_display_quotes_args() {
+    let runner = CmdLineRunner::new("echo").args(["hello world", "it's", "", "plain"]);
+    assert_eq!(runner.to_string(), r"echo 'hello world' 'it'\''s' '' plain");
+    assert_eq!(format!("{runner:?}"), runner.to_string());
+
+    let runner = CmdLineRunner::new("sh").args(["-o", "errexit", "-c", "echo $HOME"]);
+    assert_eq!(runner.to_string(), "echo $HOME");
+    assert_eq!(format!("{runner:?}"), "sh -o errexit -c 'echo $HOME'");
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_raw() {
+    // `ver` is a cmd.exe builtin, so it only runs through the wrapper
+    let result = CmdLineRunner::new("ver").execute().await.unwrap();
+    assert!(result.stdout.contains("Windows"), "{}", result.stdout);
+    let result = CmdLineRunner::new("ver").raw(true).execute().await;
+    assert!(
+        matches!(result, Err(Error::ProgramNotFound(_))),
+        "{result:?}"
+    );
+
+    let result = CmdLineRunner::new("whoami.exe")
+        .raw(true)
+        .execute()
+        .await
+        .unwrap();
+    assert!(result.status.success());
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_timeout_kills_job_tree() {
+    let start = Instant::now();
+    // the background ping inherits stdout, so output only finishes once the
+    // whole tree is gone
+    let result = CmdLineRunner::shell("start /b ping -n 30 127.0.0.1 & ping -n 30 127.0.0.1")
+        .timeout(Duration::from_millis(500))
+        .execute()
+        .await;
+
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_temp_script_ps1() {
+    let dir = test_dir("ps1");
+    let script = dir.join("greet.ps1");
+    std::fs::write(
+        &script,
+        "param($a, $b)\r\nWrite-Output \"$a|$b\"\r\nexit 4\r\n",
+    )
+    .unwrap();
+    let result = CmdLineRunner::new(&script)
+        .args(["it's", "a b"])
+        .temp_script(true)
+        .allow_non_zero(true)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim_end(), "it's|a b");
+    assert_eq!(result.status.code(), Some(4));
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_windows_resolves_batch_files() {
+    let dir = test_dir("pathext");
+    std::fs::write(dir.join("greet.cmd"), "@echo hello %~1\r\n").unwrap();
+    let path = format!("{};{}", dir.display(), std::env::var("PATH").unwrap());
+    let result = CmdLineRunner::new("greet")
+        .arg("a & b")
+        .env("PATH", path)
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(result.stdout.trim(), "hello a & b");
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_priority_class_and_creation_flags() {
+    use ensembler::PriorityClass;
+
+    let result = CmdLineRunner::new("powershell.exe")
+        .args([
+            "-NoProfile",
+            "-Command",
+            "(Get-Process -Id $PID).PriorityClass",
+        ])
+        .priority_class(PriorityClass::Idle)
+        .priority_class(PriorityClass::BelowNormal)
+        .no_window(true)
+        .new_process_group(true)
+        .execute()
+        .await
+        .unwrap();
+    // the last priority class replaces the first
+    assert_eq!(result.stdout.trim(), "BelowNormal");
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_windows_verbatim_and_unc_cwd() {
+    let dir = test_dir("unc");
+    let verbatim = format!(r"\\?\{}", dir.display());
+    let result = CmdLineRunner::new("cd")
+        .current_dir(&verbatim)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim(), dir.display().to_string());
+
+    // the administrative share maps back to the same directory
+    let s = dir.display().to_string();
+    let unc = format!(r"\\localhost\{}${}", &s[..1], &s[2..]);
+    if std::path::Path::new(&unc).is_dir() {
+        let result = CmdLineRunner::new("cd")
+            .current_dir(&unc)
+            .execute()
+            .await
+            .unwrap();
+        assert!(!result.stdout.trim().is_empty());
+    }
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_kill_all_signals_each_pid() {
+    let dir = test_dir("kill-all");
+    let pid_file = dir.join("pid");
+    let handle = tokio::spawn(
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo $$ > \"$0\"; exec sleep 30"])
+            .arg(&pid_file)
+            .execute(),
+    );
+    let pid = loop {
+        match std::fs::read_to_string(&pid_file) {
+            Ok(pid) if pid.ends_with('\n') => break pid.trim().parse::<u32>().unwrap(),
+            _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+
+    // SIGCONT leaves the children of tests running alongside alone
+    let results = CmdLineRunner::kill_all(nix::sys::signal::Signal::SIGCONT);
+    let (_, result) = results.iter().find(|(p, _)| *p == pid).unwrap();
+    assert!(result.is_ok(), "{result:?}");
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL).unwrap();
+    assert!(handle.await.unwrap().is_err());
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_kill_all_reports_each_pid() {
+    let handle = tokio::spawn(
+        CmdLineRunner::new("ping")
+            .args(["-n", "30", "127.0.0.1"])
+            .execute(),
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let results = CmdLineRunner::kill_all();
+    assert!(!results.is_empty());
+    assert!(results.iter().all(|(_, r)| r.is_ok()));
+    assert!(handle.await.unwrap().is_err());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_non_utf8_output() {
+    // "café" in CP850/Latin-1 followed by a valid UTF-8 line
+    let result = CmdLineRunner::new("printf")
+        .arg(r"caf\202\r\nna\303\257ve\n")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "caf\u{FFFD}\nnaïve\n");
+
+    let result = CmdLineRunner::new("printf")
+        .arg(r"caf\202")
+        .output_encoding(OutputEncoding::Utf8)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "caf\u{FFFD}\n");
+}
+
+#[tokio::test]
+#[cfg(windows)]
+async fn test_codepage_output() {
+    let dir = test_dir("codepage");
+    std::fs::write(dir.join("out.txt"), b"caf\x82\r\n").unwrap();
+    let result = CmdLineRunner::new("type")
+        .arg(dir.join("out.txt"))
+        .output_encoding(OutputEncoding::CodePage(850))
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "café\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_grace_period() {
+    let dir = test_dir("grace");
+    let marker = dir.join("cleaned-up");
+    let script = format!(
+        "trap 'echo > {}; exit 0' TERM; sleep 10 & wait",
+        marker.display()
+    );
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", &script])
+        .timeout(Duration::from_millis(200))
+        .grace_period(Duration::from_secs(5))
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+    assert!(marker.exists());
+
+    // children that ignore SIGTERM are killed once the grace period ends
+    let start = Instant::now();
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "trap '' TERM; sleep 10"])
+        .timeout(Duration::from_millis(100))
+        .grace_period(Duration::from_millis(200))
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_uid_gid_groups() {
+    if !nix::unistd::geteuid().is_root() {
+        // switching to our own ids is always allowed
+        let uid = nix::unistd::getuid().as_raw();
+        let result = CmdLineRunner::new("id")
+            .arg("-u")
+            .uid(uid)
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(result.stdout.trim(), uid.to_string());
+        return;
+    }
+    let result = CmdLineRunner::new("id")
+        .groups([65533])
+        .gid(65534)
+        .uid(65534)
+        .execute()
+        .await
+        .unwrap();
+    let out = result.stdout.trim();
+    assert!(out.starts_with("uid=65534"), "{out}");
+    assert!(out.contains("gid=65534"), "{out}");
+    assert!(out.contains(",65533"), "{out}");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_umask() {
+    let dir = test_dir("umask");
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "umask; touch file; ls -l file"])
+        .current_dir(&dir)
+        .umask(0o027)
+        .execute()
+        .await
+        .unwrap();
+    let mut lines = result.stdout.lines();
+    assert_eq!(lines.next(), Some("0027"));
+    assert!(lines.next().unwrap().starts_with("-rw-r-----"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_nice() {
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "ps -o nice= -p $$"])
+        .nice(7)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim(), "7");
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_io_priority() {
+    if CmdLineRunner::which("ionice").is_none() {
+        return;
+    }
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "ionice -p $$"])
+        .io_priority(ensembler::IoPriority::BestEffort(6))
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim(), "best-effort: prio 6");
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_cpu_affinity() {
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "grep Cpus_allowed_list /proc/self/status"])
+        .cpu_affinity(0b1)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout.trim(), "Cpus_allowed_list:\t0");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_resource_limits() {
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "ulimit -n; ulimit -v"])
+        .limit_open_files(64)
+        .limit_memory(512 * 1024 * 1024)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "64\n524288\n");
+
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "while :; do :; done"])
+        .limit_cpu_time(1)
+        .execute()
+        .await
+        .unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("killed by SIGXCPU"), "{msg}");
+    assert!(
+        msg.contains("sh exceeded its CPU time limit (RLIMIT_CPU)"),
+        "{msg}"
+    );
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_cgroup() {
+    use ensembler::Cgroup;
+    use std::path::Path;
+
+    let run = |cgroup: Cgroup| {
+        CmdLineRunner::new("cat")
+            .arg("/proc/self/cgroup")
+            .cgroup(cgroup)
+            .execute()
+    };
+    let own = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+    // by default the cgroup goes under this process's, which can't hand
+    // controllers to it while the process is in it
+    let result = run(Cgroup::new().pids_max(100)).await;
+    if !Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("cgroup v2 isn't mounted at /sys/fs/cgroup"),
+            "{err}"
+        );
+    } else if !own.lines().any(|l| l == "0::/") {
+        let err = result.unwrap_err().to_string();
+        assert!(
+            err.contains("which has processes of its own") || err.contains("isn't available"),
+            "{err}"
+        );
+    }
+
+    // the root of the v2 hierarchy, on pure or hybrid hosts
+    let Some(root) = ["/sys/fs/cgroup", "/sys/fs/cgroup/unified"]
+        .into_iter()
+        .map(Path::new)
+        .find(|dir| dir.join("cgroup.controllers").exists())
+    else {
+        let err = run(Cgroup::new().parent("/sys/fs/cgroup"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("is not a cgroup v2 directory"), "{err}");
+        return;
+    };
+    let controllers = std::fs::read_to_string(root.join("cgroup.controllers")).unwrap();
+    if !controllers.split_whitespace().any(|c| c == "pids") {
+        let err = run(Cgroup::new().parent(root).pids_max(100))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("the pids controller isn't available in"),
+            "{err}"
+        );
+    }
+    let result = run(Cgroup::new().parent(root)).await;
+    if nix::unistd::access(root, nix::unistd::AccessFlags::W_OK).is_err() {
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("failed to create"), "{err}");
+        return;
+    }
+    let stdout = result.unwrap().stdout;
+    let line = stdout.lines().find(|l| l.starts_with("0::")).unwrap();
+    let name = line.strip_prefix("0::/").unwrap();
+    assert!(name.starts_with("ensembler-"), "{line}");
+    assert!(!root.join(name).exists());
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_chroot() {
+    let root = test_dir("chroot");
+    if !nix::unistd::geteuid().is_root() {
+        let err = CmdLineRunner::new("/bin/sh")
+            .chroot(&root)
+            .execute()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("failed to chroot to"), "{err}");
+        return;
+    }
+    // copy sh and the libraries it links into the new root
+    let ldd = CmdLineRunner::new("ldd")
+        .arg("/bin/sh")
+        .execute()
+        .await
+        .unwrap();
+    let files = ldd
+        .stdout
+        .split_whitespace()
+        .filter(|w| w.starts_with('/'))
+        .chain(["/bin/sh"]);
+    for file in files {
+        let dest = root.join(file.trim_start_matches('/'));
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        std::fs::copy(file, dest).unwrap();
+    }
+    std::fs::create_dir(root.join("work")).unwrap();
+    std::fs::write(root.join("work/marker"), "").unwrap();
+
+    let result = CmdLineRunner::new("/bin/sh")
+        .args(["-c", "cd . && pwd -P && test -e marker && test ! -e /etc"])
+        .chroot(&root)
+        .current_dir("/work")
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "/work\n");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_sandbox() {
+    use ensembler::Sandbox;
+    use nix::libc;
+
+    let dir = test_dir("sandbox");
+    let result = CmdLineRunner::new("mkdir")
+        .arg(dir.join("denied"))
+        .sandbox(Sandbox::new().deny_syscalls([libc::SYS_mkdir, libc::SYS_mkdirat]))
+        .allow_non_zero(true)
+        .execute()
+        .await
+        .unwrap();
+    assert!(!result.status.success());
+    assert!(
+        result.stderr.contains("Operation not permitted"),
+        "{}",
+        result.stderr
+    );
+    assert!(!dir.join("denied").exists());
+
+    if CmdLineRunner::which("bash").is_some() {
+        let result = CmdLineRunner::new("bash")
+            .args(["-c", "exec 3<>/dev/tcp/127.0.0.1/9"])
+            .sandbox(Sandbox::new().deny_network())
+            .allow_non_zero(true)
+            .execute()
+            .await
+            .unwrap();
+        assert!(
+            result.stderr.contains("Operation not permitted"),
+            "{}",
+            result.stderr
+        );
+    }
+
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "touch inside && ! touch ../ensembler-sandbox-outside"])
+        .current_dir(&dir)
+        .sandbox(Sandbox::new().allow_read(["/"]).allow_write([&dir]))
+        .execute()
+        .await;
+    match result {
+        Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::Unsupported => {}
+        result => {
+            result.unwrap();
+            assert!(dir.join("inside").exists());
+            assert!(!dir.join("../ensembler-sandbox-outside").exists());
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_bwrap_command_line() {
+    let dir = test_dir("bwrap");
+    write_script(&dir.join("bwrap"), "#!/bin/sh\necho \"$@\"\n");
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let result = CmdLineRunner::new("make")
+        .args(["-j", "4"])
+        .current_dir("/src")
+        .env("PATH", &path)
+        .env("HOME", "/home/me")
+        .bwrap(
+            ensembler::Bwrap::new()
+                .ro_bind("/usr")
+                .bind_to("/tmp/out", "/out")
+                .tmpfs_home()
+                .no_network(),
+        )
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        result.stdout,
+        "--die-with-parent --proc /proc --dev /dev --ro-bind /usr /usr --bind /tmp/out /out \
+         --unshare-net --tmpfs /home/me --chdir /src -- make -j 4\n"
+    );
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_systemd_run() {
+    let dir = test_dir("systemd-run");
+    let log = dir.join("log");
+    write_script(
+        &dir.join("systemd-run"),
+        &format!(
+            "#!/bin/sh\necho \"systemd-run $*\" >> {log}\n\
+             while [ \"$1\" != -- ]; do shift; done\nshift\nexec \"$@\"\n",
+            log = log.display()
+        ),
+    );
+    write_script(
+        &dir.join("systemctl"),
+        &format!("#!/bin/sh\necho \"systemctl $*\" >> {}\n", log.display()),
+    );
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let run = |script: &str| {
+        CmdLineRunner::new("sh")
+            .args(["-c", script])
+            .env("PATH", &path)
+            .systemd_run(
+                ensembler::SystemdRun::new()
+                    .user()
+                    .slice("test.slice")
+                    .property("MemoryMax", "1G"),
+            )
+    };
+
+    let result = run("echo hi").execute().await.unwrap();
+    assert_eq!(result.stdout, "hi\n");
+    let result = run("exec sleep 10")
+        .timeout(Duration::from_millis(100))
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+
+    let log = std::fs::read_to_string(&log).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 4, "{log}");
+    for (run, stop) in [(lines[0], lines[1]), (lines[2], lines[3])] {
+        let unit = run
+            .split(' ')
+            .find_map(|arg| arg.strip_prefix("--unit="))
+            .unwrap();
+        assert!(unit.starts_with("ensembler-") && unit.ends_with(".scope"));
+        assert!(run.starts_with("systemd-run --user --scope --quiet --collect --unit="));
+        assert!(
+            run.ends_with("--slice=test.slice -p MemoryMax=1G -- sh -c echo hi")
+                || run.ends_with("--slice=test.slice -p MemoryMax=1G -- sh -c exec sleep 10")
+        );
+        assert_eq!(stop, format!("systemctl --user stop --quiet {unit}"));
+    }
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_escalate() {
+    use ensembler::Escalation;
+
+    let dir = test_dir("escalate");
+    write_script(
+        &dir.join("sudo"),
+        "#!/bin/sh\necho \"$@\"\n[ \"$1\" = -A ] && echo \"password: $(\"$SUDO_ASKPASS\")\"\nexit 0\n",
+    );
+    // logs its args and runs the command as the current user
+    write_script(
+        &dir.join("doas"),
+        "#!/bin/sh\necho \"$@\"\nwhile [ \"$1\" != -- ]; do shift; done\nshift\nexec \"$@\"\n",
+    );
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+
+    let result = CmdLineRunner::new("systemctl")
+        .args(["restart", "nginx"])
+        .env("PATH", &path)
+        .escalate(
+            Escalation::sudo()
+                .user("deploy")
+                .preserve_env(["HTTP_PROXY", "NO_PROXY"])
+                .password("hunter2"),
+        )
+        .execute()
+        .await
+        .unwrap();
+    // the runner's vars are preserved by name, not passed on the command line
+    assert_eq!(
+        result.stdout,
+        "-A -u deploy --preserve-env=HTTP_PROXY,NO_PROXY,PATH -- systemctl restart nginx\n\
+         password: [redacted]\n"
+    );
+
+    let mut inherited = EnvSnapshot::default();
+    inherited.set("PATH", &path);
+    inherited.set("HTTP_PROXY", "http://proxy");
+    let doas = |escalation: Escalation| {
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo \"$HTTP_PROXY $DEPLOY\""])
+            .env_snapshot(&inherited)
+            .env("DEPLOY", "secret-1")
+            .escalate(escalation.preserve_env(["HTTP_PROXY", "UNSET"]))
+            .execute()
+    };
+    let result = doas(Escalation::doas()).await.unwrap();
+    let (argv, output) = result.stdout.split_once('\n').unwrap();
+    assert!(
+        argv.starts_with("-- sh -c . \"$0\" && exec \"$@\" /"),
+        "{argv}"
+    );
+    assert!(
+        argv.ends_with(" sh -c echo \"$HTTP_PROXY $DEPLOY\""),
+        "{argv}"
+    );
+    assert!(!argv.contains("secret-1") && !argv.contains("http://proxy"));
+    assert_eq!(output, "http://proxy secret-1\n");
+    let err = doas(Escalation::doas().user("deploy")).await.unwrap_err();
+    assert!(matches!(err, Error::Io(_)), "{err:?}");
+
+    let err = CmdLineRunner::new("id")
+        .escalate(Escalation::doas().password("hunter2"))
+        .execute()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Io(_)), "{err:?}");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_launchers_conflict() {
+    let dir = test_dir("launchers_conflict");
+    write_script(
+        &dir.join("sudo"),
+        "#!/bin/sh
+echo \"$@\"\n",
+    );
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let runner = || {
+        CmdLineRunner::new("id")
+            .env("PATH", &path)
+            .escalate(ensembler::Escalation::sudo())
+            .temp_script(true)
+    };
+    let err = runner().execute().await.unwrap_err();
+    assert!(
+        matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::InvalidInput),
+        "{err:?}"
+    );
+    assert_eq!(
+        err.to_string(),
+        "escalate and temp_script can't be combined"
+    );
+    assert!(matches!(runner().validate(), Err(Error::Preflight(_))));
+    #[cfg(target_os = "linux")]
+    {
+        let err = CmdLineRunner::new("id")
+            .bwrap(ensembler::Bwrap::new())
+            .escalate(ensembler::Escalation::sudo())
+            .execute()
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "bwrap and escalate can't be combined");
+    }
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_exec() {
+    let err = CmdLineRunner::new("/nonexistent/hunter2")
+        .redact(["hunter2".to_string()])
+        .exec()
+        .to_string();
+    assert_eq!(
+        err,
+        "failed to exec /nonexistent/[redacted]: No such file or directory (os error 2)"
+    );
+    // the script would outlive the replaced process
+    let err = CmdLineRunner::new("echo")
+        .temp_script(true)
+        .exec()
+        .to_string();
+    assert!(err.contains("exec can't be used with temp_script"), "{err}");
+
+    // replacing the test process itself has to happen in a child
+    let result = CmdLineRunner::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "test_exec_child",
+            "--nocapture",
+            "--test-threads=1",
+        ])
+        .env("ENSEMBLER_EXEC_CHILD", "1")
+        .allow_non_zero(true)
+        .execute()
+        .await
+        .unwrap();
+    assert!(
+        result.stdout.ends_with("replaced /tmp\n"),
+        "{}",
+        result.stdout
+    );
+    assert_eq!(result.status.code(), Some(3));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_exec_child() {
+    if std::env::var_os("ENSEMBLER_EXEC_CHILD").is_none() {
+        return;
+    }
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "echo $GREETING $PWD; exit 3"])
+        .env("GREETING", "replaced")
+        .current_dir("/tmp")
+        .exec();
+    panic!("{err}");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_audit() {
+    let log = test_dir("audit").join("audit.jsonl");
+    // the audit log can't be uninstalled, so it is installed in a child
+    for _ in 0..2 {
+        CmdLineRunner::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test_audit_child", "--test-threads=1"])
+            .env("ENSEMBLER_AUDIT_LOG", &log)
+            .execute()
+            .await
+            .unwrap();
+    }
+    let head = ensembler::audit::verify(&log).unwrap();
+    assert_eq!(head.records, 8);
+    assert_eq!(ensembler::audit::verify_pinned(&log, &head).unwrap(), head);
+
+    let contents = std::fs::read_to_string(&log).unwrap();
+    let records: Vec<ensembler::audit::AuditRecord> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let ensembler::audit::AuditEvent::Start { argv, user, cwd } = &records[4].event else {
+        panic!("expected a start record, got {:?}", records[4]);
+    };
+    assert_eq!(argv, &["echo", "deploy", "[redacted]"]);
+    assert!(!user.is_empty());
+    assert_eq!(cwd, std::path::Path::new("/tmp"));
+    assert!(matches!(
+        records[7].event,
+        ensembler::audit::AuditEvent::Finish {
+            start: 6,
+            exit_code: Some(3),
+            ..
+        }
+    ));
+    assert!(!contents.contains("hunter2"));
+
+    // dropping records from the end keeps the chain intact
+    let truncated = contents.lines().take(6).collect::<Vec<_>>().join("\n");
+    std::fs::write(&log, truncated).unwrap();
+    assert_eq!(ensembler::audit::verify(&log).unwrap().records, 6);
+    let err = ensembler::audit::verify_pinned(&log, &head).unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("record 8 doesn't match the pinned head"),
+        "{err}"
+    );
+
+    std::fs::write(&log, contents.replacen("deploy", "destroy", 1)).unwrap();
+    let err = ensembler::audit::verify(&log).unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("line 1: record hash doesn't match its contents"),
+        "{err}"
+    );
+    std::fs::write(
+        &log,
+        contents.lines().skip(1).collect::<Vec<_>>().join("\n"),
+    )
+    .unwrap();
+    let err = ensembler::audit::verify(&log).unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("line 1: record is out of sequence"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_audit_child() {
+    let Some(log) = std::env::var_os("ENSEMBLER_AUDIT_LOG") else {
+        return;
+    };
+    ensembler::audit::install(&log).unwrap();
+    assert!(ensembler::audit::install(&log).is_err());
+    CmdLineRunner::new("echo")
+        .args(["deploy", "hunter2"])
+        .current_dir("/tmp")
+        .redact(["hunter2".to_string()])
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new("sh")
+        .args(["-c", "exit 3"])
+        .execute()
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_executor() {
+    use ensembler::executor::{Executor, Invocation, Process};
+
+    #[derive(Debug)]
+    struct Wrapped;
+
+    impl Executor for Wrapped {
+        fn spawn(&self, invocation: &Invocation) -> ensembler::Result<Box<dyn Process>> {
+            assert!(matches!(invocation.program(), "sh" | "sleep"));
+            let mut cmd = invocation
+                .command_with("sh", ["-c", "echo \"wrapped in $PWD\"; exec \"$@\"", "sh"]);
+            Ok(Box::new(cmd.spawn()?))
+        }
+    }
+
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo $SECRET; cat"])
+        .env("SECRET", "hunter2")
+        .current_dir("/tmp")
+        .stdin_string("from stdin")
+        .redact(vec!["hunter2".to_string()])
+        .executor(Wrapped)
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "wrapped in /tmp\n[redacted]\nfrom stdin\n");
+
+    let result = CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(100))
+        .executor(Wrapped)
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_ssh_executor() {
+    use ensembler::executor::Ssh;
+
+    let dir = test_dir("ssh");
+    let log = dir.join("log");
+    // runs the remote command line locally and logs each connection
+    write_script(
+        &dir.join("ssh"),
+        &format!(
+            "#!/bin/sh\nwhile [ \"$1\" != -- ]; do printf '%s ' \"$1\" >> {log}; shift; done\n\
+             shift; echo \"$1\" >> {log}; shift\nexec sh -c \"$1\"\n",
+            log = log.display()
+        ),
+    );
+    let ssh = || Ssh::new("deploy@web1").program(dir.join("ssh"));
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo \"$GREETING $PWD\"; cat"])
+        .env("GREETING", "it's me")
+        .current_dir("/tmp")
+        .stdin_string("from stdin")
+        .executor(ssh().port(2222))
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "it's me /tmp\nfrom stdin\n");
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "-T -o BatchMode=yes -p 2222 deploy@web1\n"
+    );
+
+    std::fs::remove_file(&log).unwrap();
+    let result = CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(200))
+        .executor(ssh())
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+    // the remote process was killed over a second connection
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "-T -o BatchMode=yes deploy@web1\n-T -o BatchMode=yes deploy@web1\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_executor_local_confinement() {
+    let mock = ensembler::testing::MockExecutor::new();
+    let runner = || {
+        CmdLineRunner::new("make")
+            .limit_memory(1 << 30)
+            .executor(mock.clone())
+    };
+    let err = runner().execute().await.unwrap_err();
+    assert!(
+        matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::InvalidInput),
+        "{err:?}"
+    );
+    assert_eq!(
+        err.to_string(),
+        "limit_memory can't be used with an executor"
+    );
+    assert!(matches!(runner().validate(), Err(Error::Preflight(_))));
+    mock.verify();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_container_runner() {
+    use ensembler::executor::{ContainerRunner, ContainerRuntime};
+
+    let dir = test_dir("docker");
+    let log = dir.join("log");
+    // logs its args and its own TOKEN, then runs the command after the image
+    // locally with the env file's vars
+    write_script(
+        &dir.join("docker"),
+        &format!(
+            "#!/bin/sh\necho \"$@\" >> {log}\n[ \"$1\" = kill ] && exit 0\n\
+             echo \"cli TOKEN=$TOKEN\" >> {log}\n\
+             while [ \"$1\" != alpine ]; do\n\
+             [ \"$1\" = --env-file ] && cat \"$2\" >> {log} && set -a && . \"$2\" && set +a\n\
+             shift\ndone\nshift\nexec \"$@\"\n",
+            log = log.display()
+        ),
+    );
+    let docker = || {
+        ContainerRunner::run("alpine")
+            .runtime(ContainerRuntime::Docker)
+            .program(dir.join("docker"))
+    };
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo \"$TOKEN $BASE\"; cat"])
+        .env_snapshot(&[("BASE", "base")].into_iter().collect())
+        .env("TOKEN", "hunter2")
+        .current_dir("/src")
+        .stdin_string("from stdin")
+        .redact(vec!["hunter2".to_string()])
+        .executor(
+            docker()
+                .mount_ro("/etc", "/host-etc")
+                .options(["--network", "none"]),
+        )
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "[redacted] base\nfrom stdin\n");
+    let logged = std::fs::read_to_string(&log).unwrap();
+    let name = logged.split_whitespace().nth(3).unwrap().to_string();
+    assert!(name.starts_with("ensembler-"), "{logged}");
+    let env_file = logged.split_whitespace().nth(8).unwrap().to_string();
+    assert!(
+        !std::path::Path::new(&env_file).exists(),
+        "{env_file} was left behind"
+    );
+    assert_eq!(
+        logged,
+        format!(
+            "run --rm --name {name} -i -w /src --env-file {env_file} -v /etc:/host-etc:ro \
+             --network none alpine sh -c echo \"$TOKEN $BASE\"; cat\n\
+             cli TOKEN=\nBASE=base\nTOKEN=hunter2\n"
+        )
+    );
+
+    std::fs::remove_file(&log).unwrap();
+    let result = CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(200))
+        .grace_period(Duration::from_millis(100))
+        .executor(docker())
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+    let logged = std::fs::read_to_string(&log).unwrap();
+    let name = logged.split_whitespace().nth(3).unwrap();
+    assert_eq!(
+        logged,
+        format!(
+            "run --rm --name {name} alpine sleep 10\ncli TOKEN=\nkill --signal TERM {name}\n\
+             kill --signal KILL {name}\n"
+        )
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_mock_executor() {
+    use ensembler::testing::{MockExecutor, MockResult};
+
+    let mock = MockExecutor::new();
+    mock.expect("git", ["status"]).returns(
+        MockResult::new()
+            .stdout("clean\n")
+            .stderr("warning\n")
+            .exit_code(3),
+    );
+    mock.expect("sleep", ["10"])
+        .returns(MockResult::new().delay(Duration::from_secs(10)))
+        .times(1);
+
+    let result = CmdLineRunner::new("git")
+        .arg("status")
+        .executor(mock.clone())
+        .execute()
+        .await;
+    let Err(Error::ScriptFailed(failure)) = result else {
+        panic!("expected ScriptFailed, got {result:?}");
+    };
+    let result = failure.result();
+    assert_eq!(result.stdout, "clean\n");
+    assert_eq!(result.stderr, "warning\n");
+    assert_eq!(result.status.code(), Some(3));
+
+    let result = CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(50))
+        .executor(mock.clone())
+        .execute()
+        .await;
+    assert!(matches!(result, Err(Error::TimedOut { .. })));
+
+    mock.verify();
+    assert_eq!(mock.invocations().len(), 2);
+    assert_eq!(mock.invocations()[0].program(), "git");
+
+    let unexpected = tokio::spawn(async move {
+        CmdLineRunner::new("sleep")
+            .arg("10")
+            .executor(mock)
+            .execute()
+            .await
+    })
+    .await;
+    let panic = unexpected.unwrap_err().into_panic();
+    assert_eq!(
+        panic.downcast_ref::<String>().unwrap(),
+        "unexpected command: `sleep 10`"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_record_replay() {
+    use ensembler::testing::{MockExecutor, Recorder};
+
+    let fixture = test_dir("record-replay").join("fixture.json");
+    let recorder = Recorder::new(&fixture);
+    for (script, code) in [("echo one; echo err >&2", 0), ("echo two; exit 4", 4)] {
+        let result = CmdLineRunner::new("sh")
+            .args(["-c", script])
+            .current_dir("/tmp")
+            .allow_non_zero(true)
+            .executor(recorder.clone())
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(result.status.code(), Some(code));
+    }
+    let json = std::fs::read_to_string(&fixture).unwrap();
+    assert!(json.contains(r#""stderr": "err\n""#), "{json}");
+    assert!(json.contains(r#""cwd": "/tmp""#), "{json}");
+
+    let mock = MockExecutor::replay(&fixture).unwrap();
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo one; echo err >&2"])
+        .executor(mock.clone())
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "one\n");
+    assert_eq!(result.stderr, "err\n");
+    let result = CmdLineRunner::new("sh")
+        .args(["-c", "echo two; exit 4"])
+        .allow_non_zero(true)
+        .executor(mock.clone())
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "two\n");
+    assert_eq!(result.status.code(), Some(4));
+    mock.verify();
+
+    let err = MockExecutor::replay(fixture.with_file_name("missing.json")).unwrap_err();
+    assert!(err.to_string().contains("failed to read fixture"), "{err}");
+
+    let fixture = fixture.with_file_name("redacted.json");
+    let runner = |executor: &dyn Fn(CmdLineRunner) -> CmdLineRunner| {
+        executor(
+            CmdLineRunner::new("sh")
+                .args(["-c", "echo \"token $0\" >&2", "hunter2"])
+                .current_dir("/tmp")
+                .redact(["hunter2".to_string()]),
+        )
+        .execute()
+    };
+    runner(&|r| r.executor(Recorder::new(&fixture)))
+        .await
+        .unwrap();
+    let json = std::fs::read_to_string(&fixture).unwrap();
+    assert!(!json.contains("hunter2"), "{json}");
+    assert!(json.contains(r#""stderr": "token [redacted]\n""#), "{json}");
+    let mock = MockExecutor::replay(&fixture).unwrap();
+    let result = runner(&|r| r.executor(mock.clone())).await.unwrap();
+    assert_eq!(result.stderr, "token [redacted]\n");
+    mock.verify();
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_fault_injector() {
+    use ensembler::testing::FaultInjector;
+
+    let run = |faults: FaultInjector, script: &str| {
+        CmdLineRunner::new("sh")
+            .args(["-c", script])
+            .allow_non_zero(true)
+            .executor(faults)
+            .execute()
+    };
+
+    let result = run(FaultInjector::new().exit(1.0, 7), "echo ok")
+        .await
+        .unwrap();
+    assert_eq!(result.stdout, "ok\n");
+    assert_eq!(result.status.code(), Some(7));
+
+    let result = run(FaultInjector::new().exit(0.0, 7), "echo ok")
+        .await
+        .unwrap();
+    assert!(result.status.success());
+
+    let result = run(
+        FaultInjector::new().truncate_output(1.0, 5),
+        "echo 0123456789",
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.stdout, "01234\n");
+
+    let start = std::time::Instant::now();
+    let result = run(
+        FaultInjector::new().delay_output(1.0, Duration::from_millis(200)),
+        "echo late",
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.stdout, "late\n");
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    let start = std::time::Instant::now();
+    let result = run(
+        FaultInjector::new().kill(1.0, Duration::from_millis(100)),
+        "echo started; exec sleep 10",
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.stdout, "started\n");
+    assert_eq!(result.status.code(), None);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let rolls = |seed| async move {
+        let faults = FaultInjector::new().seed(seed).exit(0.5, 1);
+        let mut codes = vec![];
+        for _ in 0..8 {
+            let result = run(faults.clone(), "true").await.unwrap();
+            codes.push(result.status.code());
+        }
+        codes
+    };
+    assert_eq!(rolls(3).await, rolls(3).await);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_github_groups() {
+    use ensembler::GithubGroups;
+    use std::sync::Arc;
+
+    let (first, second) = tokio::join!(
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo one; sleep 0.1; echo two"])
+            .reporter(Arc::new(GithubGroups::new()))
+            .execute(),
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo three; exit 1"])
+            .reporter(Arc::new(GithubGroups::new()))
+            .execute(),
+    );
+    assert_eq!(first.unwrap().stdout, "one\ntwo\n");
+    assert!(second.is_err());
+
+    let result = CmdLineRunner::new("echo")
+        .arg("azure")
+        .reporter(Arc::new(ensembler::AzureGroups::new()))
+        .execute()
+        .await;
+    assert_eq!(result.unwrap().stdout, "azure\n");
+
+    let _guard = GLOBAL_STATE.lock().await;
+    ensembler::set_defaults(ensembler::Defaults::new().ci_groups(true));
+    let runner = CmdLineRunner::new("echo").arg("grouped");
+    ensembler::set_defaults(ensembler::Defaults::new());
+    assert_eq!(runner.execute().await.unwrap().stdout, "grouped\n");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_ci_annotations() {
+    use ensembler::{ProgressReporter, ProgressState};
+
+    #[derive(Default)]
+    struct Lines(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressReporter for Lines {
+        fn set_status(&self, _: ProgressState) {}
+        fn prop(&self, _: &str, _: &str) {}
+        fn println(&self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    let _guard = GLOBAL_STATE.lock().await;
+    let vars = ["GITHUB_ACTIONS", "TF_BUILD"].map(|var| (var, std::env::var_os(var)));
+    let mut annotations = vec![];
+    for ci in ["GITHUB_ACTIONS", "TF_BUILD"] {
+        for (var, _) in &vars {
+            std::env::remove_var(var);
+        }
+        std::env::set_var(ci, "true");
+        ensembler::set_defaults(ensembler::Defaults::new().ci_annotations(true));
+        let lines = std::sync::Arc::new(Lines::default());
+        let result = CmdLineRunner::new("sh")
+            .args(["-c", "echo 100%; echo hunter2 >&2; exit 3"])
+            .redact(["hunter2".to_string()])
+            .reporter(lines.clone())
+            .show_stderr_on_error(false)
+            .execute()
+            .await;
+        ensembler::set_defaults(ensembler::Defaults::new());
+        assert!(result.is_err());
+        annotations.extend(lines.0.lock().unwrap().drain(..));
+    }
+    for (var, val) in vars {
+        match val {
+            Some(val) => std::env::set_var(var, val),
+            None => std::env::remove_var(var),
+        }
+    }
+
+    assert_eq!(
+        annotations,
+        [
+            "[redacted]",
+            "::error title=sh -c 'echo 100%25; echo [redacted] >&2; exit 3' failed with exit code 3::100%25%0A[redacted]",
+            "[redacted]",
+            "##vso[task.logissue type=error]sh -c 'echo 100%AZP25; echo [redacted] >&2; exit 3' failed with exit code 3%0A100%AZP25%0A[redacted]",
+        ]
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_junit() {
+    let dir = test_dir("junit");
+    let report = ensembler::JunitReport::new("build");
+    CmdLineRunner::new("echo")
+        .args(["hello", "hunter2"])
+        .redact(["hunter2".to_string()])
+        .junit(report.clone())
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo '<oops>'; exit 3"])
+        .junit(report.clone())
+        .execute()
+        .await
+        .unwrap_err();
+    CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(50))
+        .junit(report.clone())
+        .execute()
+        .await
+        .unwrap_err();
+
+    report.write(dir.join("reports/junit.xml")).unwrap();
+    let xml = std::fs::read_to_string(dir.join("reports/junit.xml")).unwrap();
+    assert_eq!(xml, report.to_xml());
+    assert!(
+        xml.contains("tests=\"3\" failures=\"1\" errors=\"1\""),
+        "{xml}"
+    );
+    assert!(
+        xml.contains("<testcase name=\"echo hello &apos;[redacted]&apos;\" classname=\"build\"")
+    );
+    assert!(
+        xml.contains("<failure message=\"exit code 3\">&lt;oops&gt;\n</failure>"),
+        "{xml}"
+    );
+    assert!(xml.contains("<error message=\""), "{xml}");
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_summary() {
+    let summary = ensembler::Summary::new();
+    let (echo, fail) = tokio::join!(
+        CmdLineRunner::new("echo")
+            .arg("hello")
+            .summary(summary.clone())
+            .execute(),
+        CmdLineRunner::new("sh")
+            .args(["-c", "sleep 0.1; exit 3"])
+            .summary(summary.clone())
+            .execute(),
+    );
+    echo.unwrap();
+    fail.unwrap_err();
+
+    let entries = summary.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].output, "hello\n");
+    assert_eq!(entries[1].exit_code, Some(3));
+    assert!(!summary.success());
+    let table = summary.to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines[0], "STATUS  DURATION  EXIT  OUTPUT  COMMAND");
+    assert!(lines[1].starts_with("ok  ") && lines[1].ends_with("s     0     6 B  echo hello"));
+    assert!(lines[2].starts_with("failed") && lines[2].contains("s     3     0 B  sh -c "));
+    assert!(lines[3].starts_with("1 passed, 1 failed, 0 errors in "));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_teamcity_messages() {
+    use ensembler::TeamcityMessages;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (out, err) = (Buffer::default(), Buffer::default());
+    let (first, second) = tokio::join!(
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo '[one]'; sleep 0.1; echo two"])
+            .reporter(Arc::new(TeamcityMessages::with_writer(out.clone())))
+            .execute(),
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo three >&2; exit 1"])
+            .reporter(Arc::new(TeamcityMessages::with_writer(err.clone())))
+            .execute(),
+    );
+    assert_eq!(first.unwrap().stdout, "[one]\ntwo\n");
+    assert!(second.is_err());
+    let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let err = String::from_utf8(err.0.lock().unwrap().clone()).unwrap();
+    let flow_id = |log: &str| {
+        let id = log
+            .lines()
+            .next()
+            .unwrap()
+            .rsplit(" flowId=")
+            .next()
+            .unwrap();
+        id.to_string()
+    };
+    let (out_flow, err_flow) = (flow_id(&out), flow_id(&err));
+    assert_ne!(out_flow, err_flow);
+    assert!(
+        out.lines()
+            .all(|l| l.ends_with(&format!(" flowId={out_flow}"))),
+        "{out}"
+    );
+    assert!(
+        err.lines()
+            .all(|l| l.ends_with(&format!(" flowId={err_flow}"))),
+        "{err}"
+    );
+
+    let name = "sh -c |'echo |'\\|'|'|[one|]|'\\|'|'; sleep 0.1; echo two|'";
+    let out = out
+        .lines()
+        .map(|l| l.rsplit_once(" flowId=").unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        out[..4],
+        [
+            format!("##teamcity[blockOpened name='{name}'"),
+            format!("##teamcity[testStarted name='{name}'"),
+            "##teamcity[message text='|[one|]'".to_string(),
+            "##teamcity[message text='two'".to_string(),
+        ]
+    );
+    assert!(out[4].starts_with(&format!("##teamcity[testFinished name='{name}' duration=")));
+    assert_eq!(out[5], format!("##teamcity[blockClosed name='{name}'"));
+    assert_eq!(out.len(), 6);
+    assert!(
+        err.contains(
+            "##teamcity[testFailed name='sh -c |'echo three >&2; exit 1|'' \
+             message='command failed' details='three'"
+        ),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_prefix() {
+    use ensembler::{ProgressReporter, ProgressState, Verbosity};
+
+    #[derive(Default)]
+    struct Lines(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressReporter for Lines {
+        fn set_status(&self, _: ProgressState) {}
+        fn prop(&self, _: &str, _: &str) {}
+        fn println(&self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    let lines = std::sync::Arc::new(Lines::default());
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo out; sleep 0.1; echo err >&2"])
+        .prefix("app")
+        .prefix_style(console::Style::new())
+        .reporter(lines.clone())
+        .execute()
+        .await
+        .unwrap();
+    assert_eq!(*lines.0.lock().unwrap(), ["app | out", "app | err"]);
+
+    let lines = std::sync::Arc::new(Lines::default());
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo out; echo err >&2"])
+        .prefix("app")
+        .verbosity(Verbosity::Quiet)
+        .reporter(lines.clone())
+        .execute()
+        .await
+        .unwrap();
+    assert!(lines.0.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_log_mux() {
+    use ensembler::{LogMux, LogStream};
+
+    let mux = LogMux::with_capacity(10);
+    let mut live = mux.follow().label("b");
+    let (a, b) = tokio::join!(
+        CmdLineRunner::new("sh")
+            .args(["-c", "echo a1; sleep 0.1; echo a2"])
+            .log_mux(mux.clone(), "a")
+            .execute(),
+        CmdLineRunner::new("sh")
+            .args(["-c", "sleep 0.05; echo hunter2 >&2"])
+            .redact(["hunter2".to_string()])
+            .log_mux(mux.clone(), "b")
+            .execute(),
+    );
+    a.unwrap();
+    b.unwrap();
+
+    let lines = mux.lines();
+    let texts: Vec<_> = lines
+        .iter()
+        .map(|l| (l.label.as_str(), l.text.as_str()))
+        .collect();
+    assert_eq!(texts, [("a", "a1"), ("b", "[redacted]"), ("a", "a2")]);
+    assert_eq!(lines.iter().map(|l| l.seq).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(lines[1].stream, LogStream::Stderr);
+
+    let line = live.next().await.unwrap();
+    assert_eq!(
+        (line.label.as_str(), line.text.as_str()),
+        ("b", "[redacted]")
+    );
+
+    let mut tail = mux.follow().tail(1).stream(LogStream::Stdout);
+    assert_eq!(tail.next().await.unwrap().text, "a2");
+    mux.push("tool", LogStream::Stdout, "done");
+    assert_eq!(tail.next().await.unwrap().text, "done");
+    drop(mux);
+    assert!(tail.next().await.is_none());
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_labels() {
+    let junit = ensembler::JunitReport::new("build");
+    let report = ensembler::RunReport::new();
+    let err = CmdLineRunner::new("sh")
+        .args(["-c", "echo oops; exit 1"])
+        .label("package", "core")
+        .label("step", "build")
+        .label("step", "test")
+        .junit(junit.clone())
+        .run_report(report.clone())
+        .execute()
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "sh exited with non-zero status: exit code 1 (package=core, step=test)\noops"
+    );
+    let Error::ScriptFailed(failure) = err else {
+        panic!("unexpected error");
+    };
+    let labels = [
+        ("package".to_string(), "core".to_string()),
+        ("step".to_string(), "test".to_string()),
+    ];
+    assert_eq!(failure.result().labels, labels);
+    assert_eq!(
+        report.records()[0].labels,
+        labels
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>()
+    );
+    let xml = junit.to_xml();
+    assert!(
+        xml.contains("<properties>\n        <property name=\"package\" value=\"core\"/>"),
+        "{xml}"
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_hooks() {
+    use std::sync::Mutex;
+    static CALLS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    // hooks see every command in the process, so only record this test's
+    let ours = |spec: &ensembler::hooks::CmdSpec| spec.labels().iter().any(|(k, _)| k == "hooks");
+    ensembler::hooks::register(
+        move |spec| {
+            if ours(spec) {
+                CALLS
+                    .lock()
+                    .unwrap()
+                    .push(format!("before {}", spec.command_line()));
+            }
+        },
+        move |spec, result| {
+            if ours(spec) {
+                let code = result.status.code().unwrap();
+                CALLS
+                    .lock()
+                    .unwrap()
+                    .push(format!("after {} {code}", spec.args()[1]));
+            }
+        },
+    );
+
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo hunter2"])
+        .redact(["hunter2".to_string()])
+        .label("hooks", "1")
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new("sh")
+        .args(["-c", "exit 4"])
+        .label("hooks", "2")
+        .execute()
+        .await
+        .unwrap_err();
+    CmdLineRunner::new("sleep")
+        .arg("10")
+        .timeout(Duration::from_millis(50))
+        .label("hooks", "3")
+        .execute()
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        *CALLS.lock().unwrap(),
+        [
+            "before sh -c 'echo [redacted]'",
+            "after echo [redacted] 0",
+            "before sh -c 'exit 4'",
+            "after exit 4 4",
+            "before sleep 10",
+        ]
+    );
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_run_report() {
+    let dir = test_dir("run-report");
+    let report = ensembler::RunReport::new().max_output(4);
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo hunter2; exit 3"])
+        .env("TOKEN", "hunter2")
+        .redact(["hunter2".to_string()])
+        .wrap(["env", "A=1"])
+        .run_report(report.clone())
+        .execute()
+        .await
+        .unwrap_err();
+
+    let records = report.records();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(
+        record.argv,
+        ["env", "A=1", "sh", "-c", "echo [redacted]; exit 3"]
+    );
+    assert_eq!(record.env, ["TOKEN"]);
+    assert_eq!(record.exit_code, Some(3));
+    assert_eq!(record.output, "ed]\n");
+
+    report.write(dir.join("report.json")).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    let command = &json["commands"][0];
+    assert_eq!(command["env"], serde_json::json!(["TOKEN"]));
+    assert_eq!(command["exit_code"], 3);
+    assert!(command["duration_ms"].is_u64());
+    assert!(!json.to_string().contains("hunter2"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_metrics() {
+    let metrics = ensembler::Metrics::new();
+    CmdLineRunner::new("echo")
+        .arg("hello")
+        .metrics(metrics.clone())
+        .execute()
+        .await
+        .unwrap();
+    CmdLineRunner::new("sh")
+        .args(["-c", "exit 1"])
+        .metrics(metrics.clone())
+        .execute()
+        .await
+        .unwrap_err();
+
+    let text = metrics.render();
+    assert!(text.contains("ensembler_commands_started_total{program=\"echo\"} 1\n"));
+    assert!(text.contains("ensembler_commands_succeeded_total{program=\"echo\"} 1\n"));
+    assert!(text.contains("ensembler_commands_failed_total{program=\"sh\"} 1\n"));
+    assert!(text.contains("ensembler_commands_failed_total{program=\"echo\"} 0\n"));
+    assert!(text.contains("ensembler_command_output_bytes_total{program=\"echo\"} 6\n"));
+    assert!(
+        text.contains("ensembler_command_duration_seconds_bucket{program=\"sh\",le=\"+Inf\"} 1\n")
+    );
+    assert!(text.contains("ensembler_command_duration_seconds_count{program=\"sh\"} 1\n"));
+    assert!(text.contains("# TYPE ensembler_command_duration_seconds histogram\n"));
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn test_stats() {
+    // other tests run commands at the same time, so only check that the
+    // counts went up
+    let before = ensembler::stats();
+    CmdLineRunner::new("true").execute().await.unwrap();
+    CmdLineRunner::new("false").execute().await.unwrap_err();
+
+    let after = ensembler::stats();
+    assert!(after.commands >= before.commands + 2);
+    let failures = |stats: &ensembler::Stats| stats.failures.get("false").copied().unwrap_or(0);
+    assert!(failures(&after) > failures(&before));
+    assert!(after.failed() > before.failed());
+    assert!(after.wall_time > before.wall_time);
+    assert!(after.to_string().contains(" failed ("));
+}
+
+#[tokio::test]
+#[cfg(all(unix, feature = "tracing"))]
+async fn test_tracing() {
+    use std::fmt::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    #[derive(Default)]
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S> Layer<S> for Recorder
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: Context<'_, S>,
+        ) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            let name = attrs.metadata().name();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("span {name}{}", fields.0));
+        }
+
+        fn on_record(
+            &self,
+            _: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _: Context<'_, S>,
+        ) {
+            let mut fields = Fields::default();
+            values.record(&mut fields);
+            self.0.lock().unwrap().push(format!("record{}", fields.0));
+        }
+
+        fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let span = ctx.event_span(event).map(|s| s.name()).unwrap_or("none");
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("event in {span}{}", fields.0));
+        }
+    }
+
+    let recorder = Recorder::default();
+    let subscriber = tracing_subscriber::registry().with(recorder.clone());
+    let _guard = tracing::subscriber::set_default(subscriber);
+    CmdLineRunner::new("sh")
+        .args(["-c", "echo hunter2; exit 2"])
+        .redact(["hunter2".to_string()])
+        .current_dir("/")
+        .execute()
+        .await
+        .unwrap_err();
+
+    let lines = recorder.0.lock().unwrap().clone();
+    assert!(
+        lines[0].starts_with("span execute otel.name=sh program=sh args_hash="),
+        "{lines:?}"
+    );
+    assert!(lines[0].ends_with(" cwd=/"), "{lines:?}");
+    assert!(
+        lines.iter().any(|l| l.starts_with("record pid=")),
+        "{lines:?}"
+    );
+    assert!(
+        lines.contains(&"event in execute stream=\"stdout\" line=[redacted]".to_string()),
+        "{lines:?}"
+    );
+    let outcome = lines.iter().find(|l| l.starts_with("record duration_ms="));
+    assert!(outcome.is_some(), "{lines:?}");
+    for record in [
+        "record exit_code=2",
+        "record stdout_bytes=11",
+        "record stderr_bytes=0",
+        "record otel.status_code=\"ERROR\"",
+    ] {
+        assert!(lines.contains(&record.to_string()), "{lines:?}");
+    }
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("event in execute message=exited outcome=\"exit code 2\"")),
+        "{lines:?}"
+    );
+    assert!(!format!("{lines:?}").contains("hunter2"));
+}